@@ -11,13 +11,16 @@ fn main() {
     let mut writer = hound::WavWriter::create("out.wav", spec).unwrap();
     let song = mod_player::read_mod_file("mod_files/CHIP_SLAYER!.MOD");
     mod_player::textout::print_song_info(&song);
+    println!("Song length: {:?}", song.duration(spec.sample_rate).total);
     let mut player_state: mod_player::PlayerState =
         mod_player::PlayerState::new(song.format.num_channels, spec.sample_rate);
+    let mut buffer = vec![0.0f32; 2 * 4096];
     loop {
-        let (left, right) = mod_player::next_sample(&song, &mut player_state);
-        writer.write_sample(left);
-        writer.write_sample(right);
-        if player_state.song_has_ended || player_state.has_looped {
+        let result = player_state.render(&song, &mut buffer);
+        for sample in &buffer[0..result.frames * 2] {
+            writer.write_sample(*sample).unwrap();
+        }
+        if result.song_has_ended || player_state.has_looped {
             break;
         }
     }
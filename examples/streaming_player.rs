@@ -9,6 +9,25 @@ enum PlayerCommand {
     Stop {},
 }
 
+// Renders integer samples to a device buffer with `channels` interleaved channels. Only the first two channels are written to
+fn render_pcm<T: mod_player::PcmSample + Default>(
+    song: &mod_player::Song,
+    player_state: &mut mod_player::PlayerState,
+    buffer: &mut [T],
+    channels: usize,
+) {
+    if channels == 2 {
+        player_state.render_pcm(song, buffer);
+        return;
+    }
+    let mut stereo = vec![T::default(); buffer.len() / channels * 2];
+    player_state.render_pcm(song, &mut stereo);
+    for (frame, stereo_frame) in buffer.chunks_mut(channels).zip(stereo.chunks(2)) {
+        frame[0] = stereo_frame[0];
+        frame[1] = stereo_frame[1];
+    }
+}
+
 fn setup_stream(song: sync::Arc<mod_player::Song>) -> mpsc::Sender<PlayerCommand> {
     let host = cpal::default_host();
     let device = host.default_output_device().expect("Failed to get default output device");
@@ -59,12 +78,26 @@ fn setup_stream(song: sync::Arc<mod_player::Song>) -> mpsc::Sender<PlayerCommand
                 StreamData::Output {
                     buffer: UnknownTypeOutputBuffer::F32(mut buffer),
                 } => {
-                    for sample in buffer.chunks_mut(format.channels as usize) {
-                        let (left, right) = mod_player::next_sample(&song, &mut player_state);
-                        sample[0] = left;
-                        sample[1] = right;
+                    if format.channels == 2 {
+                        player_state.render(&song, &mut buffer);
+                    } else {
+                        for sample in buffer.chunks_mut(format.channels as usize) {
+                            let (left, right) = mod_player::next_sample(&song, &mut player_state);
+                            sample[0] = left;
+                            sample[1] = right;
+                        }
                     }
                 }
+                StreamData::Output {
+                    buffer: UnknownTypeOutputBuffer::I16(mut buffer),
+                } => {
+                    render_pcm(&song, &mut player_state, &mut buffer, format.channels as usize);
+                }
+                StreamData::Output {
+                    buffer: UnknownTypeOutputBuffer::U16(mut buffer),
+                } => {
+                    render_pcm(&song, &mut player_state, &mut buffer, format.channels as usize);
+                }
                 _ => (),
             }
         });
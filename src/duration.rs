@@ -0,0 +1,118 @@
+//! Working out how long a song plays for
+//!
+//! The length of a song depends on its speed and tempo changes, pattern breaks, jumps, loops and line delays. The duration is
+//! found by running the sequencer tick by tick without mixing any audio.
+use super::{update_tick, PlayerState, Song};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Songs that play for longer than this are assumed to be stuck in a loop
+const MAX_DURATION_SECONDS: u64 = 4 * 60 * 60;
+
+/// How long a song plays for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SongDuration {
+    /// The time from the start of the song until it ends or loops back to an earlier position
+    pub total: Duration,
+    /// The time at which the position the song loops back to was first played. `None` if the song ends rather than loops
+    /// ( or loops to a position that was never played )
+    pub loop_start: Option<Duration>,
+}
+
+impl Song {
+    /// Calculates how long the song plays for without rendering it. Only the pattern data is used; the timing of
+    /// ticks is rounded to whole device samples like it is during playback, so the result depends slightly on the sample rate
+    ///
+    /// # Arguments
+    /// * `sample_rate` - the sample rate the song would be played at
+    ///
+    pub fn duration(&self, sample_rate: u32) -> SongDuration {
+        let to_duration =
+            |frames: u64| Duration::from_nanos(frames * 1_000_000_000 / sample_rate as u64);
+        let mut player_state = PlayerState::new(self.format.num_channels, sample_rate);
+        // the player starts with a block of silence before the first tick
+        let mut frames = player_state.tick_left.len() as u64;
+        player_state.skip_initial_silence();
+
+        // when each position was first played, to look up where a loop goes back to
+        let mut first_played: HashMap<(u32, u32), u64> = HashMap::new();
+        let max_frames = MAX_DURATION_SECONDS * sample_rate as u64;
+        while frames < max_frames {
+            if player_state.is_line_due() {
+                if player_state.is_at_song_end(self) {
+                    break;
+                }
+                let position = player_state.next_line_position(self);
+                if player_state.has_looped {
+                    return SongDuration {
+                        total: to_duration(frames),
+                        loop_start: first_played.get(&position).map(|start| to_duration(*start)),
+                    };
+                }
+                first_played.entry(position).or_insert(frames);
+            }
+            update_tick(self, &mut player_state);
+            frames += player_state.samples_per_vblank as u64;
+        }
+        SongDuration {
+            total: to_duration(frames),
+            loop_start: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::read_mod_file;
+    use super::*;
+
+    // render the song until it ends or loops and count the frames
+    fn rendered_frames(song: &Song, sample_rate: u32) -> u64 {
+        let mut player_state = PlayerState::new(song.format.num_channels, sample_rate);
+        let mut buffer = vec![0.0f32; 2];
+        let mut frames = 0;
+        let mut line_position = (0, 0);
+        loop {
+            let had_looped = player_state.has_looped;
+            let result = player_state.render(song, &mut buffer);
+            if result.song_has_ended {
+                return frames;
+            }
+            let new_line_position = (
+                player_state.song_pattern_position,
+                player_state.current_line,
+            );
+            // a loop is complete once the line after the jump has started playing
+            if had_looped && new_line_position != line_position {
+                return frames;
+            }
+            line_position = new_line_position;
+            frames += 1;
+        }
+    }
+
+    #[test]
+    fn test_duration_of_ending_song() {
+        let song = read_mod_file("mod_files/BUBBLE_BOBBLE.MOD");
+        let duration = song.duration(8000);
+        assert_eq!(duration.loop_start, None);
+        let frames = rendered_frames(&song, 8000);
+        assert_eq!(
+            duration.total,
+            Duration::from_nanos(frames * 1_000_000_000 / 8000)
+        );
+    }
+
+    #[test]
+    fn test_duration_of_looping_song() {
+        let song = read_mod_file("mod_files/19xx.mod");
+        let duration = song.duration(8000);
+        assert!(duration.loop_start.is_some());
+        assert!(duration.loop_start.unwrap() < duration.total);
+        let frames = rendered_frames(&song, 8000);
+        assert_eq!(
+            duration.total,
+            Duration::from_nanos(frames * 1_000_000_000 / 8000)
+        );
+    }
+}
@@ -0,0 +1,59 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Describes why a module file could not be loaded or saved
+#[derive(Debug)]
+pub enum ModError {
+    /// The file could not be read
+    Io(io::Error),
+    /// The data ended before a read of `len` bytes at `offset` could be completed
+    Truncated { offset: usize, len: usize },
+    /// The header contains values that can not be valid
+    BadHeader(String),
+    /// The format tag is recognised but the layout is not supported
+    UnsupportedTag(String),
+    /// The patterns and samples declared in the header need more space than the file has
+    InconsistentSizes { required: usize, available: usize },
+    /// The song has features that can not be stored in the file format being written
+    Unwritable(String),
+}
+
+impl fmt::Display for ModError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ModError::Io(err) => write!(f, "i/o error: {}", err),
+            ModError::Truncated { offset, len } => write!(
+                f,
+                "data truncated: could not read {} bytes at offset {}",
+                len, offset
+            ),
+            ModError::BadHeader(reason) => write!(f, "bad header: {}", reason),
+            ModError::UnsupportedTag(tag) => write!(f, "unsupported format tag: {}", tag),
+            ModError::InconsistentSizes {
+                required,
+                available,
+            } => write!(
+                f,
+                "inconsistent sizes: samples and patterns need {} bytes but only {} are available",
+                required, available
+            ),
+            ModError::Unwritable(reason) => write!(f, "can not write song: {}", reason),
+        }
+    }
+}
+
+impl Error for ModError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ModError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ModError {
+    fn from(err: io::Error) -> ModError {
+        ModError::Io(err)
+    }
+}
@@ -0,0 +1,215 @@
+//! Emulation of the analogue filters on the Amiga audio output
+//!
+//! Every Amiga passes Paula's output through a fixed RC low-pass filter. The "LED" filter is a second, steeper low-pass that
+//! can be switched on and off by software ( it is tied to the power LED, hence the name ). Songs toggle it with the E0x effect.
+//!
+//! Impulse Tracker has a resonant low-pass filter on each channel instead. Its coefficients are worked out here and the mixer
+//! runs the filter.
+use std::f32::consts::PI;
+
+/// Selects which Amiga model's output filters are applied to the mixed output
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AmigaFilter {
+    /// No filtering, the mixer output is passed through as is
+    None,
+    /// A500 with its fixed 4.4 kHz one pole low-pass and the switchable LED filter
+    A500,
+    /// A1200, where the fixed low-pass is at 34 kHz, so in practice only the LED filter is heard
+    A1200,
+}
+
+const A500_FIXED_CUTOFF: f32 = 4420.0;
+const A1200_FIXED_CUTOFF: f32 = 34400.0;
+const LED_CUTOFF: f32 = 3275.0;
+
+/// The coefficients of Impulse Tracker's resonant filter for a cutoff and resonance of 0-127. The filter's output is
+/// `input * a + previous_output * b + output_before_that * c` for the returned `[a, b, c]`
+pub(crate) fn resonant_filter_coefficients(
+    cutoff: f32,
+    resonance: u8,
+    sample_rate: f32,
+) -> [f32; 3] {
+    // the cutoff rises by an octave for every 24 steps from 130 Hz
+    let frequency = (110.0 * (0.25 + cutoff / 24.0).exp2())
+        .clamp(120.0, 10000.0)
+        .min(sample_rate / 2.0);
+    let frequency = frequency * 2.0 * PI / sample_rate;
+    // the resonance damps the filter less as it goes up, by up to 24 dB
+    let damping = 10.0f32.powf(-(24.0 / 128.0) * resonance as f32 / 20.0);
+    let d = ((1.0 - 2.0 * damping) * frequency).min(2.0);
+    let d = (2.0 * damping - d) / frequency;
+    let e = (1.0 / frequency).powi(2);
+    let scale = 1.0 + d + e;
+    [1.0 / scale, (d + e + e) / scale, -e / scale]
+}
+
+/// Two pole Butterworth low-pass. Direct form I
+#[derive(Clone, Copy)]
+struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    x1: f32,
+    x2: f32,
+    y1: f32,
+    y2: f32,
+}
+
+impl Biquad {
+    fn low_pass(cutoff: f32, sample_rate: f32) -> Biquad {
+        let omega = 2.0 * PI * cutoff / sample_rate;
+        let alpha = omega.sin() / (2.0 * std::f32::consts::FRAC_1_SQRT_2);
+        let cos_omega = omega.cos();
+        let a0 = 1.0 + alpha;
+        Biquad {
+            b0: (1.0 - cos_omega) / 2.0 / a0,
+            b1: (1.0 - cos_omega) / a0,
+            b2: (1.0 - cos_omega) / 2.0 / a0,
+            a1: -2.0 * cos_omega / a0,
+            a2: (1.0 - alpha) / a0,
+            x1: 0.0,
+            x2: 0.0,
+            y1: 0.0,
+            y2: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let output = self.b0 * input + self.b1 * self.x1 + self.b2 * self.x2
+            - self.a1 * self.y1
+            - self.a2 * self.y2;
+        self.x2 = self.x1;
+        self.x1 = input;
+        self.y2 = self.y1;
+        self.y1 = output;
+        output
+    }
+}
+
+/// One pole RC low-pass
+#[derive(Clone, Copy)]
+struct OnePole {
+    coefficient: f32,
+    state: f32,
+}
+
+impl OnePole {
+    fn low_pass(cutoff: f32, sample_rate: f32) -> OnePole {
+        OnePole {
+            coefficient: 1.0 - (-2.0 * PI * cutoff / sample_rate).exp(),
+            state: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        self.state += self.coefficient * (input - self.state);
+        self.state
+    }
+}
+
+/// The running state of the output filters for both stereo sides
+pub(crate) struct OutputFilter {
+    model: AmigaFilter,
+    fixed: [OnePole; 2],
+    led: [Biquad; 2],
+}
+
+impl OutputFilter {
+    pub(crate) fn new(model: AmigaFilter, sample_rate: u32) -> OutputFilter {
+        let fixed_cutoff = match model {
+            AmigaFilter::A1200 => A1200_FIXED_CUTOFF,
+            _ => A500_FIXED_CUTOFF,
+        };
+        let fixed = OnePole::low_pass(fixed_cutoff, sample_rate as f32);
+        let led = Biquad::low_pass(LED_CUTOFF, sample_rate as f32);
+        OutputFilter {
+            model,
+            fixed: [fixed, fixed],
+            led: [led, led],
+        }
+    }
+
+    pub(crate) fn model(&self) -> AmigaFilter {
+        self.model
+    }
+
+    /// Filters one side of the output in place
+    pub(crate) fn process(&mut self, side: usize, led_filter_on: bool, buffer: &mut [f32]) {
+        if self.model == AmigaFilter::None {
+            return;
+        }
+        let fixed = &mut self.fixed[side];
+        let led = &mut self.led[side];
+        for value in buffer.iter_mut() {
+            let filtered = fixed.process(*value);
+            // keep the LED filter running even when it is off so that switching it on does not click
+            let led_filtered = led.process(filtered);
+            *value = if led_filter_on {
+                led_filtered
+            } else {
+                filtered
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // peak level of a sine wave after filtering relative to its level before filtering
+    fn filtered_level(model: AmigaFilter, led_filter_on: bool, frequency: f32) -> f32 {
+        let peak = |buffer: &[f32]| {
+            buffer[24000..]
+                .iter()
+                .fold(0.0f32, |max, value| max.max(value.abs()))
+        };
+        let mut filter = OutputFilter::new(model, 48000);
+        let mut buffer: Vec<f32> = (0..48000)
+            .map(|idx| (2.0 * PI * frequency * idx as f32 / 48000.0).sin())
+            .collect();
+        let input_level = peak(&buffer);
+        filter.process(0, led_filter_on, &mut buffer);
+        peak(&buffer) / input_level
+    }
+
+    #[test]
+    fn test_filter_response() {
+        assert_eq!(filtered_level(AmigaFilter::None, true, 8000.0), 1.0);
+        // low frequencies pass through
+        assert!(filtered_level(AmigaFilter::A500, true, 100.0) > 0.99);
+        // the fixed A500 filter is at 3dB down around its cutoff
+        let fixed_level = filtered_level(AmigaFilter::A500, false, A500_FIXED_CUTOFF);
+        assert!((fixed_level - 0.707).abs() < 0.05, "{}", fixed_level);
+        // the A1200 fixed filter is mostly inaudible. The LED filter cuts hard
+        assert!(filtered_level(AmigaFilter::A1200, false, 8000.0) > 0.9);
+        assert!(filtered_level(AmigaFilter::A1200, true, 8000.0) < 0.2);
+    }
+
+    #[test]
+    fn test_resonant_filter() {
+        // peak level of a sine wave after the filter relative to its level before the filter
+        let filtered_level = |cutoff: f32, resonance: u8, frequency: f32| {
+            let [input_gain, feedback_1, feedback_2] =
+                resonant_filter_coefficients(cutoff, resonance, 48000.0);
+            let mut history = [0.0f32; 2];
+            let mut peak = 0.0f32;
+            for idx in 0..48000 {
+                let input = (2.0 * PI * frequency * idx as f32 / 48000.0).sin();
+                let output = input * input_gain + history[0] * feedback_1 + history[1] * feedback_2;
+                history = [output, history[0]];
+                if idx >= 24000 {
+                    peak = peak.max(output.abs());
+                }
+            }
+            peak
+        };
+        // cutoff 64 is at about 830 Hz
+        assert!((filtered_level(64.0, 0, 100.0) - 1.0).abs() < 0.05);
+        assert!(filtered_level(64.0, 0, 8000.0) < 0.05);
+        // resonance boosts the level at the cutoff
+        assert!(filtered_level(64.0, 127, 830.0) > 2.0 * filtered_level(64.0, 0, 830.0));
+    }
+}
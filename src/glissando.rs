@@ -0,0 +1,88 @@
+//! E3x glissando
+//!
+//! With glissando on, tone portamento plays the semitone nearest to the period it has slid to. The slide itself carries
+//! on smoothly underneath, so the channel keeps the smooth period and plays it with an offset to the semitone.
+use super::{fine_tune_period, key_period, static_tables, ChannelInfo, Song};
+
+/// The period of the semitone nearest to `period` in the channel's tuning. Glissando plays it while tone portamento
+/// slides smoothly
+pub(crate) fn nearest_semitone_period(song: &Song, channel: &ChannelInfo, period: u32) -> u32 {
+    let distance = |semitone: &u32| (*semitone as i32 - period as i32).abs();
+    let nearest = if channel.key > 0 {
+        // formats with keys have a period for each key
+        (1..=120)
+            .map(|key| key_period(song, channel, key))
+            .min_by_key(distance)
+    } else {
+        static_tables::FREQUENCY_TABLE
+            .iter()
+            .map(|&semitone| fine_tune_period(semitone, channel.fine_tune, song.has_standard_notes))
+            .min_by_key(distance)
+    };
+    nearest.unwrap_or(period)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::build_mod;
+    use super::super::{update_tick, Effect, PlayerState};
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(
+            Effect::new(14, 0x31),
+            Effect::Glissando {
+                use_smooth_slide: false
+            }
+        );
+        assert_eq!(
+            Effect::new(14, 0x30),
+            Effect::Glissando {
+                use_smooth_slide: true
+            }
+        );
+    }
+
+    #[test]
+    fn test_glissando() {
+        // both channels slide from C-2 to C-3 by 16 a tick over lines 1 and 2. Channel 0 has glissando on so it plays the
+        // nearest semitone
+        let song = build_mod(&[
+            (0, 0, [0x01, 0xac, 0x1e, 0x31]),
+            (0, 1, [0x01, 0xac, 0x10, 0x00]),
+            (1, 0, [0x00, 0xd6, 0x03, 0x10]),
+            (1, 1, [0x00, 0xd6, 0x03, 0x10]),
+            (2, 0, [0, 0, 0x03, 0x00]),
+            (2, 1, [0, 0, 0x03, 0x00]),
+        ]);
+        let mut player_state = PlayerState::new(song.format.num_channels, 44100);
+        let mut played_periods = Vec::new();
+        for _tick in 0..19 {
+            update_tick(&song, &mut player_state);
+            let channel = &player_state.channels[0];
+            played_periods.push(channel.period as i32 + channel.glissando_offset);
+        }
+        assert_eq!(played_periods[12..18], [428, 404, 404, 381, 360, 339]);
+        // line 2 plays on tick 18 and keeps the semitone of the slide it continues
+        assert_eq!(played_periods[18], 339);
+        assert_eq!(player_state.channels[0].period, 332);
+        let channel = &player_state.channels[1];
+        assert_eq!((channel.period, channel.glissando_offset), (332, 0));
+    }
+
+    #[test]
+    fn test_glissando_stops() {
+        // glissando only applies while tone portamento is playing. Line 2 has no effect, so it plays the period the slide
+        // reached
+        let song = build_mod(&[
+            (0, 0, [0x01, 0xac, 0x1e, 0x31]),
+            (1, 0, [0x00, 0xd6, 0x03, 0x10]),
+        ]);
+        let mut player_state = PlayerState::new(song.format.num_channels, 44100);
+        for _tick in 0..19 {
+            update_tick(&song, &mut player_state);
+        }
+        let channel = &player_state.channels[0];
+        assert_eq!((channel.period, channel.glissando_offset), (332, 0));
+    }
+}
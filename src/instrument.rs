@@ -0,0 +1,375 @@
+//! Instruments group samples together and shape them while they play
+//!
+//! Formats with instruments ( XM and IT ) pick the sample that plays each note from the instrument's key map. The
+//! instrument's envelopes change the volume, panning and pitch of the note every tick, and fade the note out once it has been
+//! released. IT instruments also decide what happens to a note that is still playing when the next note starts on its
+//! channel ( the new note action ).
+use super::static_tables;
+use super::ChannelInfo;
+
+/// The fadeout volume of a note that has not started fading
+const FULL_FADEOUT_VOLUME: u32 = 32768;
+
+/// A curve of values over time given as points. The value between the points is interpolated
+pub struct Envelope {
+    pub(crate) points: Vec<(u32, u8)>,
+    pub(crate) sustain_loop: Option<(usize, usize)>,
+    pub(crate) loop_points: Option<(usize, usize)>,
+}
+
+impl Envelope {
+    /// An envelope with no points. Disabled envelopes leave the sound unchanged
+    pub(crate) fn disabled() -> Envelope {
+        Envelope {
+            points: Vec::new(),
+            sustain_loop: None,
+            loop_points: None,
+        }
+    }
+
+    /// Is the envelope used
+    pub fn is_enabled(&self) -> bool {
+        !self.points.is_empty()
+    }
+
+    /// The points of the envelope as ( tick, value ) pairs. Values are 0-64
+    pub fn points(&self) -> &[(u32, u8)] {
+        &self.points
+    }
+
+    /// The first and last point of the section that repeats until the note is released. Envelopes with a single sustain
+    /// point ( XM ) stay at it and give the same point twice
+    pub fn sustain_loop(&self) -> Option<(usize, usize)> {
+        self.sustain_loop
+    }
+
+    /// The first and last point of the section that repeats
+    pub fn loop_points(&self) -> Option<(usize, usize)> {
+        self.loop_points
+    }
+
+    /// The value of the envelope `tick` ticks after the note started. Values before the first point and after the last point
+    /// are the values of those points
+    pub fn value_at(&self, tick: u32) -> u8 {
+        let next = match self.points.iter().position(|point| point.0 > tick) {
+            Some(0) => return self.points[0].1,
+            Some(next) => next,
+            None => return self.points.last().map_or(64, |point| point.1),
+        };
+        let (start_tick, start_value) = self.points[next - 1];
+        let (end_tick, end_value) = self.points[next];
+        let position = (tick - start_tick) as i32;
+        let length = (end_tick - start_tick) as i32;
+        (start_value as i32 + (end_value as i32 - start_value as i32) * position / length) as u8
+    }
+
+    // Moves the envelope on by a tick. Until the note is released the envelope repeats its sustain loop, or waits at the
+    // sustain point. The envelope jumps from the end of the loop back to its start
+    fn advance(&self, tick: &mut u32, released: bool) {
+        if !self.is_enabled() {
+            return;
+        }
+        if let Some((sustain_start, sustain_end)) = self.sustain_loop {
+            if !released && *tick == self.points[sustain_end].0 {
+                *tick = self.points[sustain_start].0;
+                return;
+            }
+        }
+        *tick += 1;
+        if let Some((loop_start, loop_end)) = self.loop_points {
+            if *tick == self.points[loop_end].0 {
+                *tick = self.points[loop_start].0;
+            }
+        }
+    }
+}
+
+/// Vibrato that the instrument applies to every note without an effect
+pub struct AutoVibrato {
+    /// 0 is a sine wave, 1 a square wave, 2 a rising ramp and 3 a falling ramp
+    pub waveform: u8,
+    /// How many ticks the vibrato takes to reach its full depth
+    pub sweep: u8,
+    pub depth: u8,
+    /// How far through the 256 steps of the waveform the vibrato moves each tick
+    pub rate: u8,
+}
+
+impl AutoVibrato {
+    // The waveform at `position`, out of 256 steps, scaled to -255 to 255
+    fn wave_value(&self, position: u32) -> i32 {
+        let position = position & 255;
+        match self.waveform {
+            1 if position < 128 => 255,
+            1 => -255,
+            2 => (position as i32 - 128) * 255 / 128,
+            3 => (128 - position as i32) * 255 / 128,
+            _ => static_tables::VIBRATO_TABLE[(position >> 2) as usize],
+        }
+    }
+}
+
+/// What happens to a note that is still playing when the next note starts on its channel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NewNoteAction {
+    /// The note stops
+    Cut,
+    /// The note carries on playing in the background
+    Continue,
+    /// The note carries on in the background and is released
+    NoteOff,
+    /// The note carries on in the background and fades out
+    NoteFade,
+}
+
+/// Which background notes of an instrument a new note of the same instrument replaces
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DuplicateCheck {
+    /// Background notes are left alone
+    Off,
+    /// Notes of the same key
+    Note,
+    /// Notes playing the same sample
+    Sample,
+    /// All notes of the instrument
+    Instrument,
+}
+
+/// A set of samples with a key map and the envelopes that shape them
+pub struct Instrument {
+    pub(crate) name: String,
+    pub(crate) sample_map: [u8; 120],
+    pub(crate) volume_envelope: Envelope,
+    pub(crate) panning_envelope: Envelope,
+    pub(crate) pitch_envelope: Envelope,
+    pub(crate) has_filter_envelope: bool, // the pitch envelope changes the filter cutoff instead of the pitch
+    pub(crate) fadeout: u32,
+    pub(crate) auto_vibrato: AutoVibrato,
+    pub(crate) pan: Option<u8>,
+    pub(crate) filter_cutoff: Option<u8>,
+    pub(crate) filter_resonance: Option<u8>,
+    pub(crate) new_note_action: NewNoteAction,
+    pub(crate) duplicate_check: DuplicateCheck,
+    pub(crate) duplicate_action: NewNoteAction,
+    pub(crate) release_stops_without_envelope: bool, // XM notes stop when they are released unless they have a volume envelope
+}
+
+impl Instrument {
+    /// The name of the instrument
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The sample, numbered from 1 in the song's samples, that plays `key`. 0 if the instrument has no sample for the key
+    pub fn sample_for_key(&self, key: u8) -> u8 {
+        match key {
+            1..=120 => self.sample_map[key as usize - 1],
+            _ => 0,
+        }
+    }
+
+    /// The envelope that scales the volume of the notes
+    pub fn volume_envelope(&self) -> &Envelope {
+        &self.volume_envelope
+    }
+
+    /// The envelope that moves the notes from the channel's pan position. 32 is the middle
+    pub fn panning_envelope(&self) -> &Envelope {
+        &self.panning_envelope
+    }
+
+    /// The envelope that moves the pitch of the notes up to 16 semitones either way, or the cutoff of the filter if
+    /// `has_filter_envelope`. 32 is the middle
+    pub fn pitch_envelope(&self) -> &Envelope {
+        &self.pitch_envelope
+    }
+
+    /// Does the pitch envelope scale the filter cutoff instead of changing the pitch
+    pub fn has_filter_envelope(&self) -> bool {
+        self.has_filter_envelope
+    }
+
+    /// How much the volume of a released note drops each tick, out of 32768
+    pub fn fadeout(&self) -> u32 {
+        self.fadeout
+    }
+
+    /// The vibrato applied to every note
+    pub fn auto_vibrato(&self) -> &AutoVibrato {
+        &self.auto_vibrato
+    }
+
+    /// The pan position the instrument sets when it is played, 0 is left and 255 right. None if it leaves the pan as it is
+    pub fn pan(&self) -> Option<u8> {
+        self.pan
+    }
+
+    /// The cutoff, 0-127, of the resonant filter the notes start with. None if the instrument leaves the filter as it is
+    pub fn filter_cutoff(&self) -> Option<u8> {
+        self.filter_cutoff
+    }
+
+    /// The resonance, 0-127, of the resonant filter the notes start with. None if the instrument leaves it as it is
+    pub fn filter_resonance(&self) -> Option<u8> {
+        self.filter_resonance
+    }
+
+    /// What happens to a note of the instrument when the next note starts on its channel
+    pub fn new_note_action(&self) -> NewNoteAction {
+        self.new_note_action
+    }
+
+    /// Which background notes a new note of the instrument replaces
+    pub fn duplicate_check(&self) -> DuplicateCheck {
+        self.duplicate_check
+    }
+
+    /// What happens to the background notes that `duplicate_check` finds
+    pub fn duplicate_action(&self) -> NewNoteAction {
+        self.duplicate_action
+    }
+}
+
+impl ChannelInfo {
+    // Restarts the envelopes for a new note
+    pub(crate) fn trigger_instrument(&mut self, instrument: &Instrument) {
+        self.released = false;
+        self.fading = false;
+        self.fadeout_volume = FULL_FADEOUT_VOLUME;
+        self.volume_envelope_tick = 0;
+        self.panning_envelope_tick = 0;
+        self.pitch_envelope_tick = 0;
+        self.auto_vibrato_pos = 0;
+        self.auto_vibrato_ticks = 0;
+        self.new_note_action = instrument.new_note_action;
+        if let Some(cutoff) = instrument.filter_cutoff {
+            self.filter_cutoff = cutoff;
+        }
+        if let Some(resonance) = instrument.filter_resonance {
+            self.filter_resonance = resonance;
+        }
+        self.apply_instrument(instrument);
+    }
+
+    // Lets the envelopes move past their sustain points. In XM a note without a volume envelope stops at once
+    pub(crate) fn release_instrument(&mut self, instrument: &Instrument) {
+        self.released = true;
+        if instrument.release_stops_without_envelope && !instrument.volume_envelope.is_enabled() {
+            self.volume = 0.0;
+        }
+    }
+
+    // Moves the envelopes, fadeout and auto vibrato on by a tick
+    pub(crate) fn update_instrument(&mut self, instrument: &Instrument) {
+        instrument
+            .volume_envelope
+            .advance(&mut self.volume_envelope_tick, self.released);
+        instrument
+            .panning_envelope
+            .advance(&mut self.panning_envelope_tick, self.released);
+        instrument
+            .pitch_envelope
+            .advance(&mut self.pitch_envelope_tick, self.released);
+        if self.released || self.fading {
+            self.fadeout_volume = self.fadeout_volume.saturating_sub(instrument.fadeout);
+        }
+        self.auto_vibrato_pos += instrument.auto_vibrato.rate as u32;
+        self.auto_vibrato_ticks += 1;
+        self.apply_instrument(instrument);
+    }
+
+    // Has the note faded out or reached the end of a volume envelope that ends in silence
+    pub(crate) fn has_faded_out(&self, instrument: &Instrument) -> bool {
+        let envelope = &instrument.volume_envelope;
+        let has_ended = envelope.points.last().is_some_and(|(tick, value)| {
+            *value == 0 && self.volume_envelope_tick >= *tick && envelope.loop_points.is_none()
+        });
+        self.fadeout_volume == 0 || (has_ended && self.released)
+    }
+
+    // Sets the volume, pan, pitch and filter changes for the current envelope positions
+    fn apply_instrument(&mut self, instrument: &Instrument) {
+        self.envelope_volume = self.fadeout_volume as f32 / FULL_FADEOUT_VOLUME as f32;
+        if instrument.volume_envelope.is_enabled() {
+            self.envelope_volume *= instrument
+                .volume_envelope
+                .value_at(self.volume_envelope_tick) as f32
+                / 64.0;
+        }
+        // the envelope pans within the space left between the channel's pan position and the nearest side
+        self.envelope_pan = if instrument.panning_envelope.is_enabled() {
+            let value = instrument
+                .panning_envelope
+                .value_at(self.panning_envelope_tick) as f32;
+            (value - 32.0) / 32.0 * (1.0 - self.pan.abs())
+        } else {
+            0.0
+        };
+        let vibrato = &instrument.auto_vibrato;
+        let mut depth = vibrato.depth as i32;
+        if self.auto_vibrato_ticks < vibrato.sweep as u32 {
+            depth = depth * self.auto_vibrato_ticks as i32 / vibrato.sweep as i32;
+        }
+        self.period_offset = vibrato.wave_value(self.auto_vibrato_pos) * depth / 256;
+        self.envelope_pitch = 0.0;
+        self.envelope_cutoff = 1.0;
+        if instrument.pitch_envelope.is_enabled() {
+            let value = instrument.pitch_envelope.value_at(self.pitch_envelope_tick) as f32;
+            if instrument.has_filter_envelope {
+                self.envelope_cutoff = value / 64.0;
+            } else {
+                // two steps to a semitone
+                self.envelope_pitch = (value - 32.0) / 2.0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelope() -> Envelope {
+        Envelope {
+            points: vec![(0, 0), (4, 64), (8, 32), (12, 16)],
+            sustain_loop: Some((2, 2)),
+            loop_points: None,
+        }
+    }
+
+    #[test]
+    fn test_envelope_value() {
+        let envelope = envelope();
+        assert_eq!(envelope.value_at(0), 0);
+        assert_eq!(envelope.value_at(2), 32);
+        assert_eq!(envelope.value_at(4), 64);
+        assert_eq!(envelope.value_at(6), 48);
+        assert_eq!(envelope.value_at(100), 16);
+    }
+
+    #[test]
+    fn test_envelope_sustain_and_loop() {
+        let mut envelope = envelope();
+        let mut tick = 0;
+        for _tick in 0..20 {
+            envelope.advance(&mut tick, false);
+        }
+        assert_eq!(tick, 8, "held at the sustain point");
+        envelope.advance(&mut tick, true);
+        assert_eq!(tick, 9, "released");
+
+        envelope.sustain_loop = Some((1, 2));
+        let mut tick = 7;
+        envelope.advance(&mut tick, false);
+        envelope.advance(&mut tick, false);
+        assert_eq!(tick, 4, "back to the sustain loop start");
+        envelope.advance(&mut tick, true);
+        assert_eq!(tick, 5, "released from the sustain loop");
+
+        envelope.sustain_loop = None;
+        envelope.loop_points = Some((1, 2));
+        let mut tick = 7;
+        envelope.advance(&mut tick, false);
+        assert_eq!(tick, 4, "back to the loop start");
+    }
+}
@@ -0,0 +1,179 @@
+//! Resampling of the sample data at the playback rate of a channel
+//!
+//! The interpolators read neighbouring sample values through `sample_value` so that looping samples are read across the loop
+//! boundary instead of stopping at the end of the sample data.
+use super::{ChannelInfo, Sample};
+use std::f32::consts::PI;
+
+/// Selects how sample values between the stored sample points are calculated
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interpolation {
+    /// Use the nearest preceding sample point. This is what the Amiga hardware does and gives the characteristic bright sound
+    None,
+    /// Straight line between the two surrounding sample points
+    Linear,
+    /// Cubic Hermite (Catmull-Rom) spline through the four surrounding sample points
+    Cubic,
+    /// Windowed sinc filter evaluated from a polyphase table. The smoothest and the most expensive
+    Sinc,
+}
+
+const SINC_TAPS: usize = 8;
+const SINC_PHASES: usize = 256;
+
+/// Builds the polyphase table for the sinc interpolator. Each phase holds the filter taps for one fractional position
+pub(crate) fn build_sinc_table() -> Vec<f32> {
+    let mut table = Vec::with_capacity(SINC_TAPS * SINC_PHASES);
+    let half_width = (SINC_TAPS / 2) as f32;
+    for phase in 0..SINC_PHASES {
+        let fraction = phase as f32 / SINC_PHASES as f32;
+        let start = table.len();
+        for tap in 0..SINC_TAPS {
+            // distance from the interpolated position to the sample point used by the tap
+            let x = tap as f32 - (half_width - 1.0) - fraction;
+            let sinc = if x.abs() < 1e-6 {
+                1.0
+            } else {
+                (PI * x).sin() / (PI * x)
+            };
+            // Blackman window
+            let window =
+                0.42 + 0.5 * (PI * x / half_width).cos() + 0.08 * (2.0 * PI * x / half_width).cos();
+            table.push(sinc * window);
+        }
+        // normalise so that a constant signal keeps its level in every phase
+        let sum: f32 = table[start..].iter().sum();
+        for weight in &mut table[start..] {
+            *weight /= sum;
+        }
+    }
+    table
+}
+
+/// Reads the sample value at `index`. Indices past the end of the playing part continue from the loop start for looping
+/// samples and are silent for one shot samples. Indices before the loop start wrap to the loop end once the loop has been
+/// entered
+fn sample_value(sample: &Sample, channel_info: &ChannelInfo, index: i64) -> f32 {
+    let loop_start = sample.repeat_offset as i64;
+    let loop_size = sample.repeat_size as i64;
+    let is_looping = loop_size > 2;
+    let end = channel_info.size as i64;
+    let index = if index >= end {
+        if !is_looping {
+            return 0.0;
+        }
+        loop_start + (index - end).rem_euclid(loop_size)
+    } else if is_looping && channel_info.in_loop && index < loop_start {
+        loop_start + (index - loop_start).rem_euclid(loop_size)
+    } else if index < 0 {
+        return 0.0;
+    } else {
+        index
+    };
+    match sample.samples.get(index as usize) {
+        Some(value) => *value as f32,
+        None => 0.0,
+    }
+}
+
+/// Calculates the sample value at the current position of the channel. The result is in the range [-32768, 32767]
+pub(crate) fn interpolate(
+    interpolation: Interpolation,
+    sinc_table: &[f32],
+    sample: &Sample,
+    channel_info: &ChannelInfo,
+) -> f32 {
+    let position = channel_info.sample_pos as i64;
+    let fraction = channel_info.sample_pos - position as f32;
+    let value = |offset: i64| sample_value(sample, channel_info, position + offset);
+    match interpolation {
+        Interpolation::None => value(0),
+        Interpolation::Linear => {
+            let current = value(0);
+            current + (value(1) - current) * fraction
+        }
+        Interpolation::Cubic => {
+            let (p0, p1, p2, p3) = (value(-1), value(0), value(1), value(2));
+            let a = -0.5 * p0 + 1.5 * p1 - 1.5 * p2 + 0.5 * p3;
+            let b = p0 - 2.5 * p1 + 2.0 * p2 - 0.5 * p3;
+            let c = -0.5 * p0 + 0.5 * p2;
+            ((a * fraction + b) * fraction + c) * fraction + p1
+        }
+        Interpolation::Sinc => {
+            let phase = ((fraction * SINC_PHASES as f32) as usize).min(SINC_PHASES - 1);
+            let weights = &sinc_table[phase * SINC_TAPS..(phase + 1) * SINC_TAPS];
+            let first_tap = 1 - (SINC_TAPS / 2) as i64;
+            weights
+                .iter()
+                .enumerate()
+                .map(|(tap, weight)| weight * value(first_tap + tap as i64))
+                .sum()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // builds a sample from 8 bit values. Interpolated values are scaled back to 8 bits by the tests
+    fn test_sample(values: Vec<i8>, repeat_offset: u32, repeat_size: u32) -> Sample {
+        let mut header = [0u8; 30];
+        header[22..24].copy_from_slice(&(values.len() as u16 / 2).to_be_bytes());
+        header[26..28].copy_from_slice(&(repeat_offset as u16 / 2).to_be_bytes());
+        header[28..30].copy_from_slice(&(repeat_size as u16 / 2).to_be_bytes());
+        let mut sample = Sample::new(&header);
+        sample.samples = values.iter().map(|&value| (value as i16) << 8).collect();
+        sample
+    }
+
+    #[test]
+    fn test_reads_across_loop() {
+        let sample = test_sample(vec![0, 10, 20, 30, 40, 50, 60, 70], 4, 4);
+        let mut channel_info = ChannelInfo::new();
+        channel_info.size = 8;
+        channel_info.sample_pos = 7.5;
+        // the point after the end of the sample is the loop start
+        let linear = interpolate(Interpolation::Linear, &[], &sample, &channel_info);
+        assert_eq!(linear / 256.0, 55.0);
+
+        // once inside the loop, the point before the loop start is the loop end
+        channel_info.in_loop = true;
+        channel_info.sample_pos = 4.0;
+        assert_eq!(sample_value(&sample, &channel_info, 3) / 256.0, 70.0);
+        assert_eq!(sample_value(&sample, &channel_info, 9) / 256.0, 50.0);
+    }
+
+    #[test]
+    fn test_one_shot_ends_in_silence() {
+        let sample = test_sample(vec![10, 20, 30, 40], 0, 0);
+        let mut channel_info = ChannelInfo::new();
+        channel_info.size = 4;
+        channel_info.sample_pos = 3.5;
+        let linear = interpolate(Interpolation::Linear, &[], &sample, &channel_info);
+        assert_eq!(linear / 256.0, 20.0);
+    }
+
+    #[test]
+    fn test_interpolators_pass_through_sample_points() {
+        let sample = test_sample(vec![0, 40, -40, 100, 20, -20, 0, 0], 0, 0);
+        let sinc_table = build_sinc_table();
+        let mut channel_info = ChannelInfo::new();
+        channel_info.size = 8;
+        channel_info.sample_pos = 3.0;
+        for interpolation in &[
+            Interpolation::None,
+            Interpolation::Linear,
+            Interpolation::Cubic,
+            Interpolation::Sinc,
+        ] {
+            let value = interpolate(*interpolation, &sinc_table, &sample, &channel_info) / 256.0;
+            assert!(
+                (value - 100.0).abs() < 0.01,
+                "{:?} gave {}",
+                interpolation,
+                value
+            );
+        }
+    }
+}
@@ -4,8 +4,9 @@
 //! that can be streamed to an audio device or a file.
 //!
 //! For playback, only two functions are needed;
-//! * read_mod_file to read the file into a Song structure
-//! * next_sample to get the next sample
+//! * read_mod_file to read the file into a Song structure ( try_read_mod_file returns an error instead of panicking on bad files,
+//!   read_mod and try_read_mod read from any `std::io::Read` source )
+//! * next_sample to get the next sample ( or PlayerState::render to fill a whole buffer at a time )
 //!
 //! To use the library to decode a mod file and save it to disk ( using the hound audio crate for WAV saving )
 //!
@@ -37,33 +38,359 @@
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn test_stereo_separation() {
+        let song = read_mod_file("mod_files/BUBBLE_BOBBLE.MOD");
+        let mut player_state = PlayerState::new(song.format.num_channels, 22050);
+        player_state.stereo_separation = 0;
+        let mut buffer = vec![0.0f32; 2 * 22050];
+        player_state.render(&song, &mut buffer);
+        assert!(buffer.iter().any(|value| *value != 0.0));
+        for frame in buffer.chunks(2) {
+            assert_eq!(frame[0], frame[1]);
+        }
+    }
+
+    #[test]
+    fn test_song_accessors() {
+        let song = read_mod_file("mod_files/BUBBLE_BOBBLE.MOD");
+        for sample in &song.samples {
+            assert_eq!(sample.samples().len(), sample.size() as usize);
+        }
+        let pattern = &song.patterns[0];
+        assert_eq!(pattern.num_lines(), 64);
+        assert_eq!(pattern.line(0).unwrap().len(), 4);
+        assert!(pattern.line(64).is_none());
+        assert!(pattern.note(0, 4).is_none());
+        // every effect decodes back to itself from its command and parameter
+        for pattern in &song.patterns {
+            for line in &pattern.lines {
+                for note in line {
+                    let effect = note.effect();
+                    let decoded =
+                        Effect::new(effect.command().unwrap(), effect.parameter().unwrap() as i8);
+                    assert_eq!(decoded, *effect);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_vibrato() {
+        // the first note of the song that starts a vibrato with its own speed and depth
+        let song = read_mod_file("mod_files/BUBBLE_BOBBLE.MOD");
+        let note = song
+            .patterns
+            .iter()
+            .flat_map(|pattern| pattern.lines.iter().flatten())
+            .find(|note| {
+                note.period != 0
+                    && note.sample_number > 0
+                    && matches!(note.effect, Effect::Vibrato { speed, amplitude } if speed > 0 && amplitude > 0)
+            })
+            .unwrap();
+        let (speed, depth) = match note.effect {
+            Effect::Vibrato { speed, amplitude } => (speed as usize, amplitude as i32),
+            _ => unreachable!(),
+        };
+        let mut player_state = PlayerState::new(song.format.num_channels, 44100);
+        play_note(note, &mut player_state, 0, &song);
+        let period = player_state.channels[0].period;
+        update_effects(&mut player_state, &song);
+        update_effects(&mut player_state, &song);
+        // the vibrato is added to the period on each tick without changing it
+        let channel = &player_state.channels[0];
+        assert_eq!(channel.period, period);
+        assert_eq!(
+            channel.vibrato_offset,
+            static_tables::VIBRATO_TABLE[speed] * depth / 128
+        );
+
+        // a line without an effect stops the vibrato on the same period
+        play_note(
+            &Note::new(&[0; 4], &song.format),
+            &mut player_state,
+            0,
+            &song,
+        );
+        update_effects(&mut player_state, &song);
+        let channel = &player_state.channels[0];
+        assert_eq!((channel.period, channel.vibrato_offset), (period, 0));
+    }
+
+    // A 4 channel MOD holding `notes`, given as their line, channel and 4 bytes. Lines from 64 on are in the following
+    // patterns, which play in order. Samples 1 and 2 are 16 byte loops with volumes 64 and 32, and sample 1 holds the
+    // bytes 0 to 15
+    pub(crate) fn build_mod(notes: &[(usize, usize, [u8; 4])]) -> Song {
+        let num_patterns = notes
+            .iter()
+            .map(|(line, _, _)| line / 64 + 1)
+            .max()
+            .unwrap_or(1);
+        let mut data = vec![0u8; 1084];
+        data[42..50].copy_from_slice(&[0, 8, 0, 64, 0, 0, 0, 8]);
+        data[72..80].copy_from_slice(&[0, 8, 0, 32, 0, 0, 0, 8]);
+        data[950] = num_patterns as u8;
+        for pattern_num in 0..num_patterns {
+            data[952 + pattern_num] = pattern_num as u8;
+        }
+        data[1080..1084].copy_from_slice(b"M.K.");
+        let mut patterns = vec![0u8; num_patterns * 1024];
+        for (line, channel, note) in notes {
+            let offset = (line * 4 + channel) * 4;
+            patterns[offset..offset + 4].copy_from_slice(note);
+        }
+        data.extend(patterns);
+        data.extend(0..16u8);
+        data.extend(vec![0u8; 16]);
+        read_mod_file_slice(&data)
+    }
+
+    #[test]
+    fn test_funk_repeat() {
+        // a 16 byte looped sample played with EFF, which inverts a byte every tick, and stopped by EF0 on the next line
+        let song = build_mod(&[(0, 0, [0x01, 0xac, 0x1e, 0xff]), (1, 0, [0, 0, 0x0e, 0xf0])]);
+        let mut player_state = PlayerState::new(song.format.num_channels, 44100);
+        for _tick in 0..20 {
+            play_tick(&song, &mut player_state);
+        }
+
+        // the line's own tick and the 5 ticks after it invert the bytes after the start of the loop
+        let funk_sample = player_state.channels[0].funk_sample.as_ref().unwrap();
+        let inverted: Vec<i16> = (0..16)
+            .map(|byte: i8| (if (1..=6).contains(&byte) { !byte } else { byte } as i16) << 8)
+            .collect();
+        assert_eq!(funk_sample.samples, inverted);
+        assert_eq!(song.samples[0].samples[1], 1 << 8);
+    }
+
+    #[test]
+    fn test_note_delay() {
+        // channel 0 plays C-1 and then C-2 with sample 2 delayed by 3 ticks and slides back to C-1 with tone portamento.
+        // Channel 1 delays its note by the speed so it never plays
+        let song = build_mod(&[
+            (0, 0, [0x03, 0x58, 0x10, 0x00]),
+            (1, 0, [0x01, 0xac, 0x2e, 0xd3]),
+            (1, 1, [0x01, 0xac, 0x1e, 0xd6]),
+            (2, 0, [0x03, 0x58, 0x03, 0x10]),
+        ]);
+        let mut player_state = PlayerState::new(song.format.num_channels, 44100);
+
+        // line 1 plays on tick 12, so its delayed note is still waiting on tick 14
+        for _tick in 0..15 {
+            update_tick(&song, &mut player_state);
+        }
+        let channel = &player_state.channels[0];
+        assert_eq!((channel.sample_num, channel.period), (1, 856));
+        assert_eq!(channel.volume, 64.0);
+
+        update_tick(&song, &mut player_state);
+        let channel = &player_state.channels[0];
+        assert_eq!((channel.sample_num, channel.period), (2, 428));
+        assert_eq!(channel.volume, 32.0);
+
+        // the tone portamento of line 2 slides from the delayed note
+        for _tick in 0..4 {
+            update_tick(&song, &mut player_state);
+        }
+        let channel = &player_state.channels[0];
+        assert_eq!((channel.period_target, channel.period), (856, 444));
+        let channel = &player_state.channels[1];
+        assert_eq!((channel.sample_num, channel.period), (0, 0));
+        assert!(channel.delayed_note.is_none());
+    }
+
+    #[test]
+    fn test_vibrato_waveforms() {
+        assert_eq!(waveform_value(0, 16), 255);
+        assert_eq!(waveform_value(1, 31), 248);
+        assert_eq!(waveform_value(1, 32), -255);
+        assert_eq!(waveform_value(2, 31), 255);
+        assert_eq!(waveform_value(6, 32), -255);
+        assert_eq!(waveform_value(3, 24), waveform_value(3, 88));
+
+        // channel 0 vibrates with a square wave that restarts with the note on line 2. Channel 1 uses the random
+        // waveform with the no retrigger bit, so it carries on from the vibrato of line 1
+        let song = build_mod(&[
+            (0, 0, [0x01, 0xac, 0x1e, 0x42]),
+            (0, 1, [0x01, 0xac, 0x1e, 0x47]),
+            (1, 0, [0, 0, 0x04, 0x48]),
+            (1, 1, [0, 0, 0x04, 0x48]),
+            (2, 0, [0x01, 0xac, 0x14, 0x48]),
+            (2, 1, [0x01, 0xac, 0x14, 0x48]),
+        ]);
+        let mut player_state = PlayerState::new(song.format.num_channels, 44100);
+        for _tick in 0..19 {
+            update_tick(&song, &mut player_state);
+        }
+        assert_eq!(player_state.channels[0].vibrato_pos, 0);
+        assert_eq!(player_state.channels[1].vibrato_pos, 24);
+
+        update_tick(&song, &mut player_state);
+        let channel = &player_state.channels[0];
+        assert_eq!(
+            (channel.period, channel.vibrato_offset),
+            (428, 255 * 32 / 512)
+        );
+        let channel = &player_state.channels[1];
+        assert_eq!(
+            (channel.period, channel.vibrato_offset),
+            (428, static_tables::RANDOM_TABLE[24] * 32 / 512)
+        );
+    }
+
+    #[test]
+    fn test_tremolo_ramp_quirk() {
+        // the ramp down tremolo runs for 6 ticks without vibrato. On the last tick the tremolo position is in the second
+        // half of the ramp but the vibrato position is not, so the volume drops by the ramp's first half
+        let song = build_mod(&[(0, 0, [0x01, 0xac, 0x1e, 0x71]), (1, 0, [0, 0, 0x07, 0x88])]);
+        let mut player_state = PlayerState::new(song.format.num_channels, 44100);
+        for _tick in 0..19 {
+            update_tick(&song, &mut player_state);
+        }
+        let channel = &player_state.channels[0];
+        assert_eq!(channel.tremolo_pos, 48);
+        assert_eq!(channel.volume, (64 - 64 * 8 / 64) as f32);
+    }
+
+    // The (order, line) of every line the song plays until it ends
+    fn played_lines(song: &Song) -> Vec<(u32, u32)> {
+        let mut player_state = PlayerState::new(song.format.num_channels, 44100);
+        let mut lines = Vec::new();
+        while !player_state.is_at_song_end(song) && lines.len() < 1000 {
+            if player_state.is_line_due() {
+                lines.push(player_state.next_line_position(song));
+            }
+            update_tick(song, &mut player_state);
+        }
+        lines
+    }
+
+    #[test]
+    fn test_pattern_loops() {
+        let loop_start = [0, 0, 0x0e, 0x60];
+        let loop_once = [0, 0, 0x0e, 0x61];
+        let song = build_mod(&[
+            // the loop on channel 1 repeats lines 1 and 2 inside the loop of channel 0 over lines 0 to 3
+            (0, 0, loop_start),
+            (1, 1, loop_start),
+            (2, 1, loop_once),
+            (3, 0, loop_once),
+            // a second loop end after a finished loop repeats only its own line
+            (10, 2, loop_start),
+            (11, 2, loop_once),
+            (12, 2, loop_once),
+            // the next pattern has no loop start so its loop goes back to its first line
+            (66, 2, loop_once),
+        ]);
+
+        let mut expected: Vec<(u32, u32)> = Vec::new();
+        let mut add_lines = |order: u32, lines: &[u32]| {
+            expected.extend(lines.iter().map(|line| (order, *line)));
+        };
+        add_lines(0, &[0, 1, 2, 1, 2, 3, 0, 1, 2, 1, 2, 3]);
+        add_lines(0, &(4..10).collect::<Vec<u32>>());
+        add_lines(0, &[10, 11, 10, 11, 12, 12]);
+        add_lines(0, &(13..64).collect::<Vec<u32>>());
+        add_lines(1, &[0, 1, 2, 0, 1, 2]);
+        add_lines(1, &(3..64).collect::<Vec<u32>>());
+        assert_eq!(played_lines(&song), expected);
+    }
+
+    #[test]
+    fn test_simultaneous_pattern_loops() {
+        // two loops end on the same line. The loop of the last channel is played, and the loop on channel 0 restarts
+        // from the line after its end once it has finished
+        let song = build_mod(&[
+            (5, 0, [0, 0, 0x0e, 0x60]),
+            (6, 3, [0, 0, 0x0e, 0x60]),
+            (7, 0, [0, 0, 0x0e, 0x61]),
+            (7, 3, [0, 0, 0x0e, 0x62]),
+        ]);
+        let lines: Vec<u32> = played_lines(&song).iter().map(|(_, line)| *line).collect();
+        assert_eq!(lines[5..13], [5, 6, 7, 6, 7, 6, 7, 8]);
+        assert_eq!(lines.len(), 64 + 4);
+    }
 }
 
+mod duration;
+pub use duration::SongDuration;
+mod error;
+pub use error::ModError;
+mod filter;
+pub use filter::AmigaFilter;
+use filter::OutputFilter;
+mod glissando;
+use glissando::nearest_semitone_period;
+mod instrument;
+pub use instrument::{AutoVibrato, DuplicateCheck, Envelope, Instrument, NewNoteAction};
+mod interpolation;
+pub use interpolation::Interpolation;
 mod loader;
+pub use loader::detect_format;
+pub use loader::probe;
+pub use loader::read_mod;
 pub use loader::read_mod_file;
 pub use loader::read_mod_file_slice;
+pub use loader::read_mod_info;
+pub use loader::try_read_mod;
+pub use loader::try_read_mod_file;
+pub use loader::try_read_mod_file_slice;
+pub use loader::Confidence;
+pub use loader::ModInfo;
+mod pcm;
+pub use pcm::{Dither, PcmSample};
+mod render;
+pub use render::RenderResult;
+mod seek;
 mod static_tables;
 pub mod textout;
+mod writer;
 
 const CLOCK_TICKS_PERS_SECOND: f32 = 3579545.0; // Amiga hw clcok ticks per second
                                                 // const CLOCK_TICKS_PERS_SECOND: f32 = 3579545.0; // NTSC
+const DEFAULT_C2SPD: u32 = 8363; // the playback rate of middle C on the Amiga
+const S3M_CLOCK_TICKS_PER_SECOND: f32 = 14317056.0; // Scream Tracker periods are in quarters of an Amiga period
+const XM_CLOCK_TICKS_PER_SECOND: f32 = 14317456.0; // FastTracker 2 Amiga periods play middle C at 8363 Hz with period 1712
 
 fn fine_tune_period(period: u32, fine_tune: u32, use_fine_tune_table: bool) -> u32 {
     if use_fine_tune_table {
-        let index: i32 = static_tables::FREQUENCY_TABLE
-            .binary_search(&period)
-            .expect("Unexpected period value") as i32;
-        return static_tables::FINE_TUNE_TABLE[fine_tune as usize][index as usize];
+        let index = frequency_table_index(period);
+        return static_tables::FINE_TUNE_TABLE[fine_tune as usize][index];
     } else {
         return (period as f32 * static_tables::SCALE_FINE_TUNE[fine_tune as usize]) as u32;
     }
 }
 
+// The index of the period in the frequency table. Periods that are not in the table, which corrupt files can play, get
+// the index of the nearest period
+fn frequency_table_index(period: u32) -> usize {
+    let table = &static_tables::FREQUENCY_TABLE;
+    match table.binary_search(&period) {
+        Ok(index) => index,
+        Err(0) => 0,
+        Err(index) if index == table.len() || period - table[index - 1] < table[index] - period => {
+            index - 1
+        }
+        Err(index) => index,
+    }
+}
+
+/// Decodes a name stored in a module. Names are byte strings in the Amiga's Latin-1 character set so every byte maps to
+/// exactly one character
+fn latin1_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|&byte| byte as char).collect()
+}
+
 /// Holds the info and sample data for a sample
+#[derive(Clone)]
 pub struct Sample {
     name: String,
     size: u32,
@@ -71,141 +398,214 @@ pub struct Sample {
     fine_tune: u8,
     repeat_offset: u32,
     repeat_size: u32,
-    samples: Vec<i8>,
+    c2spd: u32,
+    relative_pitch: i16, // in 128ths of a semitone
+    pan: Option<u8>,
+    sustain_loop: Option<(u32, u32)>, // start and end of the loop played until the note is released
+    samples: Vec<i16>,                // 8 bit samples are stored in the high byte
 }
 
 impl Sample {
     fn new(sample_info: &[u8]) -> Sample {
-        let sample_name = String::from_utf8_lossy(&sample_info[0..22]);
+        let sample_name = latin1_string(&sample_info[0..22]);
         let sample_size: u32 = ((sample_info[23] as u32) + (sample_info[22] as u32) * 256) * 2;
-        let fine_tune = sample_info[24];
+        // only the low nibble holds the fine tune
+        let fine_tune = sample_info[24] & 0x0f;
         let volume = sample_info[25];
 
         // the repeat offset appears to be in bytes ...
         let mut repeat_offset: u32 =
             ((sample_info[27] as u32) + (sample_info[26] as u32) * 256) * 2;
         // .. but the size is in word?
-        let repeat_size: u32 = ((sample_info[29] as u32) + (sample_info[28] as u32) * 256) * 2;
+        let mut repeat_size: u32 = ((sample_info[29] as u32) + (sample_info[28] as u32) * 256) * 2;
 
-        if sample_size > 0 {
-            if repeat_offset + repeat_size > sample_size {
-                repeat_offset -= (repeat_offset + repeat_size) - sample_size;
-            }
+        if sample_size > 0 && repeat_offset + repeat_size > sample_size {
+            repeat_offset =
+                repeat_offset.saturating_sub((repeat_offset + repeat_size) - sample_size);
+            // a loop longer than the sample itself can only cover the whole sample
+            repeat_size = repeat_size.min(sample_size - repeat_offset);
         }
 
         Sample {
-            name: String::from(sample_name),
+            name: sample_name,
             size: sample_size,
             volume: volume,
             fine_tune: fine_tune,
             repeat_offset: repeat_offset,
             repeat_size: repeat_size,
+            c2spd: DEFAULT_C2SPD,
+            relative_pitch: 0,
+            pan: None,
+            sustain_loop: None,
             samples: Vec::new(),
         }
     }
+
+    /// The name of the sample. Trackers often use sample names for messages so it may not describe the sound
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The length of the sample in bytes ( i.e. sample points )
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// The default volume of the sample, 0-64
+    pub fn volume(&self) -> u8 {
+        self.volume
+    }
+
+    /// The fine tune of the sample in eighths of a semitone, -8 to 7
+    pub fn fine_tune(&self) -> i8 {
+        ((self.fine_tune << 4) as i8) >> 4
+    }
+
+    /// Where the loop starts in bytes from the start of the sample
+    pub fn repeat_offset(&self) -> u32 {
+        self.repeat_offset
+    }
+
+    /// The length of the loop in bytes. Samples with a loop of 2 bytes or less do not loop
+    pub fn repeat_size(&self) -> u32 {
+        self.repeat_size
+    }
+
+    /// The playback rate in Hz that plays the sample at middle C. MOD samples are tuned with `fine_tune` instead and always
+    /// report 8363
+    pub fn c2spd(&self) -> u32 {
+        self.c2spd
+    }
+
+    /// How far the sample is tuned from middle C in 128ths of a semitone. Used by XM samples, other formats report 0
+    pub fn relative_pitch(&self) -> i16 {
+        self.relative_pitch
+    }
+
+    /// The pan position the sample sets when it is played, 0 is left and 255 right. None if the sample leaves the pan as it is
+    pub fn pan(&self) -> Option<u8> {
+        self.pan
+    }
+
+    /// The start and end of the loop that plays until the note is released, in sample points. Only IT samples have sustain
+    /// loops
+    pub fn sustain_loop(&self) -> Option<(u32, u32)> {
+        self.sustain_loop
+    }
+
+    /// The signed PCM data of the sample at 16 bits. 8 bit samples are stored in the high byte
+    pub fn samples(&self) -> &[i16] {
+        &self.samples
+    }
 }
 
-enum Effect {
-    /*  SetPanningPosition = 8,
-     */
-    None, // 0
+/// An effect applied to a note. The variants are named after the ProTracker effects and their documentation starts with the
+/// effect command as it appears in a tracker
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Effect {
+    /// No effect
+    None,
+    /// 0xy: cycle between the note and the notes `x` and `y` semitones above it
     Arpeggio {
         chord_offset_1: u8,
         chord_offset_2: u8,
     },
-    SlideUp {
-        speed: u8,
-    }, // 1
-    SlideDown {
-        speed: u8,
-    }, // 2
-    TonePortamento {
-        speed: u8,
-    }, // 3
-    Vibrato {
-        speed: u8,
-        amplitude: u8,
-    }, // 4
-    TonePortamentoVolumeSlide {
-        volume_change: i8,
-    }, //5
-    VibratoVolumeSlide {
-        volume_change: i8,
-    }, // 6
-    Tremolo {
-        speed: u8,
-        amplitude: u8,
-    }, // 7
-    Pan {
-        position: u8,
-    }, // 8
-    SetSampleOffset {
-        offset: u8,
-    }, // 9
-    VolumeSlide {
-        volume_change: i8,
-    }, // 10
-    PositionJump {
-        next_pattern: u8,
-    }, // 11,
-    SetVolume {
-        volume: u8,
-    }, // 12
-    PatternBreak {
-        next_pattern_pos: u8,
-    }, //13
-    SetSpeed {
-        speed: u8,
-    }, // 15
-
-    SetHardwareFilter {
-        new_state: u8,
-    }, //E0
-    FinePortaUp {
-        period_change: u8,
-    }, //E1
-    FinePortaDown {
-        period_change: u8,
-    }, //E2
-    Glissando {
-        use_smooth_slide: bool,
-    }, //E2
-    PatternLoop {
-        arg: u8,
-    }, //E6
-    TremoloWaveform {
-        wave: u8,
-    }, // E7
-    CoarsePan {
-        pan_pos: u8,
-    }, //E8
-    RetriggerSample {
+    /// 1xx: slide the pitch up ( decrease the period ) every tick
+    SlideUp { speed: u8 },
+    /// 2xx: slide the pitch down ( increase the period ) every tick
+    SlideDown { speed: u8 },
+    /// 3xx: slide the pitch towards the note
+    TonePortamento { speed: u8 },
+    /// 4xy: vibrato with speed `x` and depth `y`
+    Vibrato { speed: u8, amplitude: u8 },
+    /// 5xy: continue the tone portamento and slide the volume
+    TonePortamentoVolumeSlide { volume_change: i8 },
+    /// 6xy: continue the vibrato and slide the volume
+    VibratoVolumeSlide { volume_change: i8 },
+    /// 7xy: tremolo with speed `x` and depth `y`
+    Tremolo { speed: u8, amplitude: u8 },
+    /// 8xx: set the panning position, 0 is left and 255 right
+    Pan { position: u8 },
+    /// 9xx: start the sample from `offset` * 256 bytes
+    SetSampleOffset { offset: u8 },
+    /// Axy: slide the volume up by `x` or down by `y` every tick
+    VolumeSlide { volume_change: i8 },
+    /// Bxx: continue from position `next_pattern` in the pattern table
+    PositionJump { next_pattern: u8 },
+    /// Cxx: set the channel volume
+    SetVolume { volume: u8 },
+    /// Dxx: continue from line `next_pattern_pos` of the next pattern
+    PatternBreak { next_pattern_pos: u8 },
+    /// Fxx: set the ticks per line, or the tempo for values of 32 and up
+    SetSpeed { speed: u8 },
+    /// E0x: switch the Amiga LED filter on ( 0 ) or off ( 1 )
+    SetHardwareFilter { new_state: u8 },
+    /// E1x: slide the pitch up once
+    FinePortaUp { period_change: u8 },
+    /// E2x: slide the pitch down once
+    FinePortaDown { period_change: u8 },
+    /// E3x: make tone portamento slide in semitones. E30 turns it off again and the slide is smooth
+    Glissando { use_smooth_slide: bool },
+    /// E4x: select the vibrato waveform
+    SetVibratoWave { wave: u8 },
+    /// E5x: set the fine tune of the note
+    SetFineTune { fine_tune: u8 },
+    /// E6x: set the loop start ( x = 0 ) or loop back `x` times
+    PatternLoop { arg: u8 },
+    /// E7x: select the tremolo waveform
+    TremoloWaveform { wave: u8 },
+    /// E8x: set the panning position, 0 is left and 15 right
+    CoarsePan { pan_pos: u8 },
+    /// E9x: retrigger the sample every `x` ticks
+    RetriggerSample { retrigger_delay: u8 },
+    /// EAx: slide the volume up once
+    FineVolumeSlideUp { volume_change: u8 },
+    /// EBx: slide the volume down once
+    FineVolumeSlideDown { volume_change: u8 },
+    /// ECx: cut the note after `x` ticks
+    CutNote { delay: u8 },
+    /// EDx: start the note after `x` ticks
+    DelayedSample { delay_ticks: u8 },
+    /// EEx: delay the next line by `x` lines
+    DelayedLine { delay_ticks: u8 },
+    /// EFx: funk repeat
+    InvertLoop { loop_position: u8 },
+    /// Set the ticks per line. Unlike `SetSpeed` any value is a speed
+    SetTicksPerLine { ticks: u8 },
+    /// Set the tempo in beats per minute
+    SetTempo { bpm: u8 },
+    /// Set the volume of all channels, 0-64
+    SetGlobalVolume { volume: u8 },
+    /// Play the note for `on_ticks` ticks and silence it for `off_ticks` ticks, repeatedly
+    Tremor { on_ticks: u8, off_ticks: u8 },
+    /// Retrigger the sample every `retrigger_delay` ticks and change the volume each time. `volume_change` selects the
+    /// change from the Scream Tracker table ( 1-5 subtract, 9-13 add, 6, 7, 14 and 15 scale )
+    RetriggerVolumeSlide {
         retrigger_delay: u8,
-    }, //E9
-    FineVolumeSlideUp {
-        volume_change: u8,
-    }, //EA
-    FineVolumeSlideDown {
         volume_change: u8,
-    }, //EB
-    CutNote {
-        delay: u8,
-    }, //EC
-    DelayedSample {
-        delay_ticks: u8,
-    }, //ED
-    DelayedLine {
-        delay_ticks: u8,
-    }, //EE
-    InvertLoop {
-        loop_position: u8,
-    }, //EF
-    SetVibratoWave {
-        wave: u8,
-    },
-    SetFineTune {
-        fine_tune: u8,
     },
+    /// Vibrato with a quarter of the depth of `Vibrato`
+    FineVibrato { speed: u8, amplitude: u8 },
+    /// Slide the pitch up once by a quarter of a fine slide
+    ExtraFinePortaUp { period_change: u8 },
+    /// Slide the pitch down once by a quarter of a fine slide
+    ExtraFinePortaDown { period_change: u8 },
+    /// Slide the volume of all channels every tick
+    GlobalVolumeSlide { volume_change: i8 },
+    /// Slide the pan position every tick. Positive values move right
+    PanSlide { pan_change: i8 },
+    /// Release the note after `tick` ticks
+    KeyOff { tick: u8 },
+    /// Move the instrument's envelopes to `position` ticks
+    SetEnvelopePosition { position: u8 },
+    /// Set the cutoff of the channel's resonant filter, 0-127. 127 with no resonance switches the filter off
+    SetFilterCutoff { cutoff: u8 },
+    /// Set the resonance of the channel's resonant filter, 0-127
+    SetFilterResonance { resonance: u8 },
+    /// Cut, release or fade the notes that the channel has left playing in the background
+    PastNoteAction { action: NewNoteAction },
+    /// Change the new note action of the note playing on the channel
+    SetNewNoteAction { action: NewNoteAction },
 }
 
 impl Effect {
@@ -299,7 +699,7 @@ impl Effect {
                         period_change: extended_argument as u8,
                     },
                     3 => Effect::Glissando {
-                        use_smooth_slide: extended_argument != 0,
+                        use_smooth_slide: extended_argument == 0,
                     },
                     4 => Effect::SetVibratoWave {
                         wave: extended_argument,
@@ -334,29 +734,125 @@ impl Effect {
                     14 => Effect::DelayedLine {
                         delay_ticks: extended_argument as u8,
                     },
-                    15 => Effect::InvertLoop {
+                    // the extended effect is a nibble so 15 is the only value left
+                    _ => Effect::InvertLoop {
                         loop_position: extended_argument as u8,
                     },
-                    _ => panic!(format!(
-                        "unhandled extended effect number: {}",
-                        extended_effect
-                    )),
                 }
             }
-            15 => Effect::SetSpeed {
+            // the effect number is a nibble so 15 is the only value left
+            _ => Effect::SetSpeed {
                 speed: effect_argument as u8,
             },
-            _ => panic!(format!("unhandled effect number: {}", effect_number)),
         }
     }
+
+    /// The ProTracker effect command, 0-15 ( 0-F in a tracker ). Extended effects ( Exy ) have the command 14. None for
+    /// effects that ProTracker does not have
+    pub fn command(&self) -> Option<u8> {
+        self.encode().map(|(command, _)| command)
+    }
+
+    /// The parameter of the effect as it is stored in ProTracker pattern data. For extended effects the high nibble is the
+    /// extended command. Volume slides that have both the up and down values set are stored with just the one that is played.
+    /// None for effects that ProTracker does not have
+    pub fn parameter(&self) -> Option<u8> {
+        self.encode().map(|(_, parameter)| parameter)
+    }
+
+    /// Encodes the effect back to its ProTracker command and parameter bytes
+    fn encode(&self) -> Option<(u8, u8)> {
+        // volume slides store an increase in the high nibble and a decrease in the low nibble
+        let volume_slide = |volume_change: i8| {
+            if volume_change > 0 {
+                (volume_change as u8) << 4
+            } else {
+                volume_change.unsigned_abs()
+            }
+        };
+        let nibbles = |high: u8, low: u8| (high << 4) | (low & 0x0f);
+        let encoded = match *self {
+            Effect::None => (0, 0),
+            Effect::Arpeggio {
+                chord_offset_1,
+                chord_offset_2,
+            } => (0, nibbles(chord_offset_1, chord_offset_2)),
+            Effect::SlideUp { speed } => (1, speed),
+            Effect::SlideDown { speed } => (2, speed),
+            Effect::TonePortamento { speed } => (3, speed),
+            Effect::Vibrato { speed, amplitude } => (4, nibbles(speed, amplitude)),
+            Effect::TonePortamentoVolumeSlide { volume_change } => (5, volume_slide(volume_change)),
+            Effect::VibratoVolumeSlide { volume_change } => (6, volume_slide(volume_change)),
+            Effect::Tremolo { speed, amplitude } => (7, nibbles(speed, amplitude)),
+            Effect::Pan { position } => (8, position),
+            Effect::SetSampleOffset { offset } => (9, offset),
+            Effect::VolumeSlide { volume_change } => (10, volume_slide(volume_change)),
+            Effect::PositionJump { next_pattern } => (11, next_pattern),
+            Effect::SetVolume { volume } => (12, volume),
+            // the line is stored as two decimal digits
+            Effect::PatternBreak { next_pattern_pos } => {
+                (13, nibbles(next_pattern_pos / 10, next_pattern_pos % 10))
+            }
+            Effect::SetHardwareFilter { new_state } => (14, nibbles(0, new_state)),
+            Effect::FinePortaUp { period_change } => (14, nibbles(1, period_change)),
+            Effect::FinePortaDown { period_change } => (14, nibbles(2, period_change)),
+            Effect::Glissando { use_smooth_slide } => (14, nibbles(3, !use_smooth_slide as u8)),
+            Effect::SetVibratoWave { wave } => (14, nibbles(4, wave)),
+            Effect::SetFineTune { fine_tune } => (14, nibbles(5, fine_tune)),
+            Effect::PatternLoop { arg } => (14, nibbles(6, arg)),
+            Effect::TremoloWaveform { wave } => (14, nibbles(7, wave)),
+            Effect::CoarsePan { pan_pos } => (14, nibbles(8, pan_pos)),
+            Effect::RetriggerSample { retrigger_delay } => (14, nibbles(9, retrigger_delay)),
+            Effect::FineVolumeSlideUp { volume_change } => (14, nibbles(10, volume_change)),
+            Effect::FineVolumeSlideDown { volume_change } => (14, nibbles(11, volume_change)),
+            Effect::CutNote { delay } => (14, nibbles(12, delay)),
+            Effect::DelayedSample { delay_ticks } => (14, nibbles(13, delay_ticks)),
+            Effect::DelayedLine { delay_ticks } => (14, nibbles(14, delay_ticks)),
+            Effect::InvertLoop { loop_position } => (14, nibbles(15, loop_position)),
+            Effect::SetSpeed { speed } => (15, speed),
+            // the speed and tempo share a command in ProTracker
+            Effect::SetTicksPerLine { ticks } if ticks < 32 => (15, ticks),
+            Effect::SetTempo { bpm } if bpm >= 32 => (15, bpm),
+            _ => return None,
+        };
+        Some(encoded)
+    }
 }
 
 /// Describes what sound sample to play and an effect (if any) that should be applied.
+#[derive(Debug, Clone, PartialEq)]
 pub struct Note {
     sample_number: u8,
     period: u32,
     /// how many clock ticks each sample is held for
     effect: Effect,
+    key: u8,            // for formats that store notes rather than periods. See `Note::key`
+    volume: Option<u8>, // the volume column
+    volume_effect: Effect, // effects in the volume column
+    // the effect as stored in the file, in the numbering of its format. Used by formats with effect memory
+    effect_command: u8,
+    effect_parameter: u8,
+    // the bytes of a MOD note, including any stray bits above the sample number. Written back while the note is unchanged
+    data: [u8; 4],
+}
+
+/// The key of a note that stops the sound playing on the channel
+const KEY_CUT: u8 = 254;
+/// The key of a note that releases the note playing on the channel
+const KEY_OFF: u8 = 255;
+/// The key of a note that fades out the note playing on the channel
+const KEY_FADE: u8 = 253;
+
+/// The value of a vibrato or tremolo waveform, from -255 to 255. The waveform is set by E4x or E7x, where the low two
+/// bits choose sine, ramp down, square or random, and the position is out of the 64 steps of a cycle
+fn waveform_value(waveform: u8, position: u32) -> i32 {
+    let table = match waveform & 3 {
+        0 => &static_tables::VIBRATO_TABLE,
+        1 => &static_tables::RAMP_DOWN_TABLE,
+        2 => &static_tables::SQUARE_TABLE,
+        _ => &static_tables::RANDOM_TABLE,
+    };
+    table[(position & 63) as usize]
 }
 
 fn change_note(current_period: u32, change: i32) -> u32 {
@@ -371,6 +867,14 @@ fn change_note(current_period: u32, change: i32) -> u32 {
     result as u32
 }
 
+// Slides a period. MOD, Soundtracker and Oktalyzer periods stay within the ProTracker note range
+fn slide_period(song: &Song, period: u32, change: i32) -> u32 {
+    match song.format.kind {
+        FormatKind::Mod | FormatKind::Soundtracker | FormatKind::Okt => change_note(period, change),
+        _ => (period as i32 + change).clamp(1, 0x7fff) as u32,
+    }
+}
+
 impl Note {
     fn new(note_data: &[u8], format_description: &FormatDescription) -> Note {
         let mut sample_number = ((note_data[2] & 0xf0) >> 4) + (note_data[0] & 0xf0);
@@ -388,31 +892,203 @@ impl Note {
             sample_number,
             period,
             effect,
+            key: 0,
+            volume: None,
+            volume_effect: Effect::None,
+            effect_command: effect_number,
+            effect_parameter: note_data[3],
+            data: [note_data[0], note_data[1], note_data[2], note_data[3]],
+        }
+    }
+
+    // A note that does nothing. Used to fill lines in formats that only store the channels in use
+    fn empty() -> Note {
+        Note {
+            sample_number: 0,
+            period: 0,
+            effect: Effect::None,
+            key: 0,
+            volume: None,
+            volume_effect: Effect::None,
+            effect_command: 0,
+            effect_parameter: 0,
+            data: [0; 4],
         }
     }
+
+    /// The sample to play, starting from 1. 0 keeps the sample the channel is already using
+    pub fn sample_number(&self) -> u8 {
+        self.sample_number
+    }
+
+    /// The Amiga period of the note. Lower periods are higher notes. 0 if the line does not start a note
+    pub fn period(&self) -> u32 {
+        self.period
+    }
+
+    /// The effect applied on the line
+    pub fn effect(&self) -> &Effect {
+        &self.effect
+    }
+
+    /// The note to play in semitones for formats that store notes rather than Amiga periods. 1 is C-0 and there are 12
+    /// keys to an octave. 0 if the line does not start a note, 254 if it stops the note playing, 255 if it releases it and
+    /// 253 if it fades it out
+    pub fn key(&self) -> u8 {
+        self.key
+    }
+
+    /// The value of the volume column. None if it is empty, holds an effect or the format does not have one
+    pub fn volume(&self) -> Option<u8> {
+        self.volume
+    }
+
+    /// The effect in the volume column. Effect::None if there is none
+    pub fn volume_effect(&self) -> &Effect {
+        &self.volume_effect
+    }
 }
 
+/// A block of lines of notes, 64 in most formats. Songs are made up of patterns played in the order given by the pattern table
+#[derive(Debug, PartialEq)]
 pub struct Pattern {
     lines: Vec<Vec<Note>>, // outer vector is the lines (64). Inner vector holds the notes for the line
 }
 
 impl Pattern {
     fn new() -> Pattern {
+        Pattern::with_lines(64)
+    }
+
+    fn with_lines(num_lines: usize) -> Pattern {
         let mut lines: Vec<Vec<Note>> = Vec::new();
-        for _line in 0..64 {
+        for _line in 0..num_lines {
             lines.push(Vec::new());
         }
         Pattern { lines }
     }
+
+    /// The number of lines in the pattern
+    pub fn num_lines(&self) -> usize {
+        self.lines.len()
+    }
+
+    /// The notes on `line`, one for each channel, or None if the line is past the end of the pattern
+    pub fn line(&self, line: usize) -> Option<&[Note]> {
+        self.lines.get(line).map(|notes| notes.as_slice())
+    }
+
+    /// The note on `line` for `channel` or None if there is no such line or channel
+    pub fn note(&self, line: usize, channel: usize) -> Option<&Note> {
+        self.lines.get(line).and_then(|notes| notes.get(channel))
+    }
+}
+
+/// The file format a song was loaded from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FormatKind {
+    /// ProTracker and compatible MOD files
+    Mod,
+    /// Scream Tracker 3 modules
+    S3m,
+    /// FastTracker 2 extended modules
+    Xm,
+    /// Impulse Tracker modules
+    It,
+    /// Ultimate Soundtracker and Soundtracker 2.x modules, the 15 sample MOD files that came before ProTracker. They play
+    /// in Soundtracker compatibility mode, where Fxx always sets the speed because Soundtracker has no tempo command. A
+    /// MOD song can be played in the same mode by changing its kind
+    Soundtracker,
+    /// Oktalyzer modules
+    Okt,
+    /// MED and OctaMED modules ( MMD0 to MMD3 )
+    Med,
+    /// Composer 669 and UNIS 669 modules
+    Composer669,
+    /// MultiTracker modules
+    Mtm,
+    /// Scream Tracker 2 modules
+    Stm,
+    /// UltraTracker modules
+    Ult,
+    /// Farandole Composer modules
+    Far,
+}
+
+impl FormatKind {
+    // The clock that the note periods of the format are divided from
+    fn period_clock(self) -> f32 {
+        match self {
+            FormatKind::Mod | FormatKind::Soundtracker | FormatKind::Okt | FormatKind::Med => {
+                CLOCK_TICKS_PERS_SECOND
+            }
+            FormatKind::S3m
+            | FormatKind::It
+            | FormatKind::Composer669
+            | FormatKind::Mtm
+            | FormatKind::Stm
+            | FormatKind::Ult
+            | FormatKind::Far => S3M_CLOCK_TICKS_PER_SECOND,
+            FormatKind::Xm => XM_CLOCK_TICKS_PER_SECOND,
+        }
+    }
+
+    // How many period units the slide effects move for each step of their parameter
+    fn period_scale(self) -> i32 {
+        match self {
+            FormatKind::Mod | FormatKind::Soundtracker | FormatKind::Okt | FormatKind::Med => 1,
+            FormatKind::S3m
+            | FormatKind::Xm
+            | FormatKind::It
+            | FormatKind::Composer669
+            | FormatKind::Mtm
+            | FormatKind::Stm
+            | FormatKind::Ult
+            | FormatKind::Far => 4,
+        }
+    }
 }
 
 /// The features of the song
 pub struct FormatDescription {
+    /// The file format the song was loaded from
+    pub kind: FormatKind,
     pub num_channels: u32,
     pub num_samples: u32,
     /// Is the format description based on a tag. Most mod file have a tag descriptor that makes it possible to identify the file with some
     /// certainty. The very earliest files do not have a tag but are assumed to support 4 channels and 15 samples.
     pub has_tag: bool,
+    /// The format tag as it appears in the file ( e.g. "M.K." or "16CH" ). Empty if the file has no tag
+    pub tag: String,
+    /// Are the periods linear in pitch rather than Amiga periods. Only XM and IT files can use linear periods
+    pub has_linear_periods: bool,
+}
+
+impl Song {
+    // The number of lines in the pattern at `order` in the pattern table. Positions past the end of the table have 64 lines
+    fn order_line_count(&self, order: u32) -> usize {
+        self.pattern_table
+            .get(order as usize)
+            .and_then(|pattern_idx| self.patterns.get(*pattern_idx as usize))
+            .map_or(64, |pattern| pattern.lines.len())
+    }
+}
+
+impl FormatDescription {
+    /// FLT8 files store each 8 channel pattern as two consecutive 4 channel patterns
+    fn has_split_patterns(&self) -> bool {
+        self.tag == "FLT8"
+    }
+
+    /// The channels in each block of 64 lines in the pattern data, in the order the blocks are stored
+    fn pattern_channel_blocks(&self) -> Vec<std::ops::Range<u32>> {
+        if self.has_split_patterns() {
+            vec![0..4, 4..8]
+        } else {
+            let all_channels = 0..self.num_channels;
+            vec![all_channels]
+        }
+    }
 }
 
 /// Contains the entire mod song
@@ -423,6 +1099,8 @@ pub struct Song {
     pub format: FormatDescription,
     /// The audio samples used by the song
     pub samples: Vec<Sample>,
+    /// The instruments that notes refer to in formats with instruments. Empty for formats where notes refer to samples
+    pub instruments: Vec<Instrument>,
     /// Patterns contain all the note data
     pub patterns: Vec<Pattern>,
     /// Specifies the order in whcih the patterns should be played in. The same pattern may played several times in the same song
@@ -433,8 +1111,17 @@ pub struct Song {
     pub end_position: u32,
     /// Set to true if all the notes are standard notes (i.e. conforming to the standard period table)
     pub has_standard_notes: bool,
+    /// How many ticks each line is played for at the start of the song
+    pub initial_speed: u32,
+    /// The tempo in beats per minute at the start of the song
+    pub initial_tempo: u32,
+    /// The global volume, 0-64, at the start of the song
+    pub initial_global_volume: u32,
+    /// The pan position of each channel at the start of the song. -1.0 is left and 1.0 is right
+    pub channel_pans: Vec<f32>,
 }
 
+#[derive(Clone)]
 struct ChannelInfo {
     sample_num: u8, // which sample is playing
     sample_pos: f32,
@@ -452,10 +1139,13 @@ struct ChannelInfo {
     vibrato_pos: u32,
     vibrato_speed: u32,
     vibrato_depth: i32,
+    vibrato_offset: i32, // added to the period by the vibrato on each tick
 
     tremolo_pos: u32,
     tremolo_speed: u32,
     tremolo_depth: i32,
+    vibrato_waveform: u8, // the waveform set by E4x. Adding 4 keeps the position when a note is played
+    tremolo_waveform: u8, // the waveform set by E7x
 
     retrigger_delay: u32,
     retrigger_counter: u32,
@@ -463,6 +1153,48 @@ struct ChannelInfo {
     cut_note_delay: u32,
     arpeggio_counter: u32,
     arpeggio_offsets: [u32; 2],
+    in_loop: bool, // set once the sample has wrapped to its loop
+    pan: f32,      // -1.0 is left, 1.0 is right
+
+    key: u8,    // the last key played in formats that store keys instead of periods
+    c2spd: u32, // the rate at which the playing sample plays middle C
+    effect_memory: [u8; 36], // the last non-zero parameters of effects with memory. See `loader::effect_memory_slot`
+    tremor_on_ticks: u32,    // 0 when tremor is off
+    tremor_off_ticks: u32,
+    tremor_counter: u32,
+    muted: bool,                 // silenced by tremor
+    retrigger_volume_change: u8, // how the volume changes on each retrigger
+    pan_change: f32,             // pan slide per tick
+
+    instrument: u8, // the instrument of formats with instruments, starting from 1. 0 if there is none
+    relative_pitch: i16, // the tuning of the playing sample in 128ths of a semitone
+    released: bool, // the note has been released and its envelopes and sample move past their sustain loops
+    fading: bool,   // the note fades out without being released
+    key_off_delay: u32,
+    fadeout_volume: u32, // out of 32768
+    volume_envelope_tick: u32,
+    panning_envelope_tick: u32,
+    pitch_envelope_tick: u32,
+    auto_vibrato_pos: u32,
+    auto_vibrato_ticks: u32,
+    envelope_volume: f32, // the volume scale from the instrument envelope and fadeout. 1.0 plays at the channel volume
+    envelope_pan: f32,    // added to the pan by the instrument
+    period_offset: i32,   // added to the period by the instrument's auto vibrato
+    glissando: bool,      // tone portamento plays in semitones. Set by E3x
+    glissando_offset: i32, // added to the period to play the semitone nearest to the tone portamento's period
+    pattern_loop_start: u32, // the line that the channel's pattern loop ( E6x ) jumps back to
+    pattern_loop_count: u32, // how many more times the channel's pattern loop repeats. 0 when it is not looping
+    envelope_pitch: f32,     // semitones added by the instrument's pitch envelope
+    envelope_cutoff: f32,    // the filter cutoff scale from the instrument's filter envelope
+    new_note_action: NewNoteAction,
+    filter_cutoff: u8, // the resonant filter is off at a cutoff of 127 and no resonance
+    filter_resonance: u8,
+    filter_history: [f32; 2], // the last two outputs of the resonant filter
+    funk_speed: u8,           // the funk repeat speed of EFx, 0 when it is off
+    funk_counter: u32,        // a byte of the loop is inverted when this reaches 128
+    funk_position: u32,       // the position in the loop of the byte inverted last
+    funk_sample: Option<Box<Sample>>, // the channel's copy of its sample, with the bytes that funk repeat has inverted
+    delayed_note: Option<(u32, Note)>, // the note held back by EDx and the tick of the line it plays on
 }
 
 impl ChannelInfo {
@@ -484,87 +1216,417 @@ impl ChannelInfo {
             vibrato_pos: 0,
             vibrato_speed: 0,
             vibrato_depth: 0,
+            vibrato_offset: 0,
 
             tremolo_pos: 0,
             tremolo_speed: 0,
             tremolo_depth: 0,
+            vibrato_waveform: 0,
+            tremolo_waveform: 0,
 
             retrigger_delay: 0,
             retrigger_counter: 0,
             cut_note_delay: 0,
             arpeggio_counter: 0,
             arpeggio_offsets: [0, 0],
+            in_loop: false,
+            pan: 0.0,
+
+            key: 0,
+            c2spd: DEFAULT_C2SPD,
+            effect_memory: [0; 36],
+            tremor_on_ticks: 0,
+            tremor_off_ticks: 0,
+            tremor_counter: 0,
+            muted: false,
+            retrigger_volume_change: 0,
+            pan_change: 0.0,
+
+            instrument: 0,
+            relative_pitch: 0,
+            released: false,
+            fading: false,
+            key_off_delay: 0,
+            fadeout_volume: 0,
+            volume_envelope_tick: 0,
+            panning_envelope_tick: 0,
+            pitch_envelope_tick: 0,
+            auto_vibrato_pos: 0,
+            auto_vibrato_ticks: 0,
+            envelope_volume: 1.0,
+            envelope_pan: 0.0,
+            period_offset: 0,
+            glissando: false,
+            glissando_offset: 0,
+            pattern_loop_start: 0,
+            pattern_loop_count: 0,
+            envelope_pitch: 0.0,
+            envelope_cutoff: 1.0,
+            new_note_action: NewNoteAction::Cut,
+            filter_cutoff: 127,
+            filter_resonance: 0,
+            filter_history: [0.0; 2],
+            funk_speed: 0,
+            funk_counter: 0,
+            funk_position: 0,
+            funk_sample: None,
+            delayed_note: None,
         }
     }
+
+    // ProTracker's funk repeat. The counter moves on by the speed's step from the funk table and each time it passes 128
+    // the next byte of the sample's loop is inverted, wrapping around to the start of the loop
+    fn update_funk(&mut self, song: &Song) {
+        if self.funk_speed == 0 || self.sample_num == 0 {
+            return;
+        }
+        self.funk_counter += static_tables::FUNK_TABLE[self.funk_speed as usize & 0x0f];
+        if self.funk_counter < 128 {
+            return;
+        }
+        self.funk_counter = 0;
+        let song_sample = match song.samples.get(self.sample_num as usize - 1) {
+            Some(sample) if sample.repeat_size > 0 => sample,
+            _ => return,
+        };
+        let sample = self
+            .funk_sample
+            .get_or_insert_with(|| Box::new(song_sample.clone()));
+        self.funk_position = (self.funk_position + 1) % sample.repeat_size;
+        let point = &mut sample.samples[(sample.repeat_offset + self.funk_position) as usize];
+        *point = (!(*point >> 8) as i8 as i16) << 8;
+    }
 }
 /// Keeps track of all the dynamic state required for playing the song.
 pub struct PlayerState {
     channels: Vec<ChannelInfo>,
+    // notes that carry on playing after a new note has started on their channel, with the number of the channel
+    background_voices: Vec<(usize, ChannelInfo)>,
     // where in the pattern table are we currently
     pub song_pattern_position: u32,
-    /// current line position in the pattern. Every pattern has 64 lines
+    /// current line position in the pattern
     pub current_line: u32,
     /// set when the song stops playing
     pub song_has_ended: bool,
     /// set when the song loops. The player does not unset this flag after it has been set. To detect subsequent loops the flag to be manually unset by the client
     pub has_looped: bool,
+    /// how samples are reduced to integer precision by `render_pcm`
+    pub dither: Dither,
+    dither_noise: pcm::DitherNoise,
+    /// how sample values are calculated between sample points. Defaults to `Interpolation::None` like the Amiga
+    pub interpolation: Interpolation,
+    sinc_table: Vec<f32>, // built when the sinc interpolator is first used
+    /// which Amiga model's output filters to emulate. Defaults to `AmigaFilter::None`
+    pub amiga_filter: AmigaFilter,
+    /// the state of the LED filter. The song switches it on and off with the E0x effect
+    pub led_filter_on: bool,
+    output_filter: OutputFilter,
+    /// how far apart the channels are panned in percent. 0 plays everything in mono and 100 (the default) uses the hard
+    /// left/right panning of the Amiga
+    pub stereo_separation: u32,
     device_sample_rate: u32,
-    song_speed: u32,                    // in vblanks
-    current_vblank: u32,                // how many vblanks since last play line
-    samples_per_vblank: u32,            // how many device samples per 'vblank'
-    clock_ticks_per_device_sample: f32, // how many amiga hardware clock ticks per device sample
+    song_speed: u32,            // in vblanks
+    current_vblank: u32,        // how many vblanks since last play line
+    samples_per_vblank: u32,    // how many device samples per 'vblank'
+    global_volume: u32,         // 0-64, scales all channels
+    global_volume_change: i32,  // global volume slide per tick
+    has_started: bool,          // set once the song's initial speed, tempo and pans are in use
     current_vblank_sample: u32, // how many device samples have we played for the current 'vblank'
+    tick_left: Vec<f32>,        // the mixed output of the current 'vblank'
+    tick_right: Vec<f32>,
 
     next_pattern_pos: i32, // on  next line if == -1 do nothing else  go to next pattern on line next_pattern_pos
     next_position: i32, // on next line if == 1 do nothing else go to beginning of the this pattern
     delay_line: u32,    // how many extra ticks to delay before playing next line
+    pattern_loop_line: Option<u32>, // the line that a pattern loop on the current line jumps back to
+}
 
-    pattern_loop_position: Option<u32>, // set if we have a good position to loop to
-    pattern_loop: i32,
-    set_pattern_position: bool, // set to jump
+/// The pan position of a channel on the Amiga. Channels 0 and 3 play on the left and 1 and 2 on the right. The pattern
+/// repeats for files with more channels
+fn amiga_channel_pan(channel_number: u32) -> f32 {
+    let channel_selector = channel_number & 0x0003;
+    if channel_selector == 0 || channel_selector == 3 {
+        -1.0
+    } else {
+        1.0
+    }
 }
 
 impl PlayerState {
     pub fn new(num_channels: u32, device_sample_rate: u32) -> PlayerState {
         let mut channels = Vec::new();
-        for _channel in 0..num_channels {
-            channels.push(ChannelInfo::new())
+        for channel_number in 0..num_channels {
+            let mut channel = ChannelInfo::new();
+            channel.pan = amiga_channel_pan(channel_number);
+            channels.push(channel)
         }
+        // The player starts with one silent vblank before the first line is played
+        let samples_per_vblank = device_sample_rate / 50;
         PlayerState {
             channels,
+            background_voices: Vec::new(),
             song_pattern_position: 0,
             current_line: 0,
             current_vblank: 0,
             current_vblank_sample: 0,
+            tick_left: vec![0.0; samples_per_vblank as usize],
+            tick_right: vec![0.0; samples_per_vblank as usize],
             device_sample_rate: device_sample_rate,
             song_speed: 6,
-            samples_per_vblank: device_sample_rate / 50,
-            clock_ticks_per_device_sample: CLOCK_TICKS_PERS_SECOND / device_sample_rate as f32,
+            samples_per_vblank,
+            global_volume: 64,
+            global_volume_change: 0,
+            has_started: false,
             next_pattern_pos: -1,
             next_position: -1,
             delay_line: 0,
             song_has_ended: false,
             has_looped: false,
+            dither: Dither::Triangular,
+            dither_noise: pcm::DitherNoise::new(),
+            interpolation: Interpolation::None,
+            sinc_table: Vec::new(),
+            amiga_filter: AmigaFilter::None,
+            led_filter_on: false,
+            output_filter: OutputFilter::new(AmigaFilter::None, device_sample_rate),
+            stereo_separation: 100,
 
-            pattern_loop_position: None,
-            pattern_loop: 0,
-            set_pattern_position: false,
+            pattern_loop_line: None,
         }
     }
 
     pub fn get_song_line<'a>(&self, song: &'a Song) -> &'a Vec<Note> {
-        let pattern_idx = song.pattern_table[self.song_pattern_position as usize];
+        // formats with a pattern table that ends at the last position play the first pattern once the song has ended
+        let pattern_idx = song
+            .pattern_table
+            .get(self.song_pattern_position as usize)
+            .copied()
+            .unwrap_or(0);
         let pattern = &song.patterns[pattern_idx as usize];
         let line = &pattern.lines[self.current_line as usize];
         line
     }
+
+    // Sets the speed, tempo, global volume and channel pans that the song starts with
+    fn start_song(&mut self, song: &Song) {
+        self.has_started = true;
+        self.song_speed = song.initial_speed;
+        self.set_tempo(song.initial_tempo);
+        self.global_volume = song.initial_global_volume;
+        for (channel, pan) in self.channels.iter_mut().zip(&song.channel_pans) {
+            channel.pan = *pan;
+        }
+    }
+
+    // Changes how long each tick is
+    fn set_tempo(&mut self, bpm: u32) {
+        // default is 125 bpm => 500 => ticks per minute ( by default each tick is 6 vblanks ) = > 3000 vblanks per minute or 50 vblanks per sec
+        // new BPM * 4 => ticks per minute * 6 / 60 => vblanks per sec = BPM * 0.4
+        let vblanks_per_sec = bpm as f32 * 0.4;
+        self.samples_per_vblank = (self.device_sample_rate as f32 / vblanks_per_sec) as u32
+    }
+
+    // Drops the silent block at the start of a song so that the next render starts with a new tick
+    fn skip_initial_silence(&mut self) {
+        self.tick_left.clear();
+        self.tick_right.clear();
+        self.current_vblank_sample = 0;
+    }
+
+    // Pattern loops only repeat lines of the pattern they are in, so a new pattern starts without any loops
+    fn reset_pattern_loops(&mut self) {
+        for channel in &mut self.channels {
+            channel.pattern_loop_start = 0;
+            channel.pattern_loop_count = 0;
+        }
+    }
+
+    // Is a new line played on the next tick
+    fn is_line_due(&self) -> bool {
+        self.current_vblank >= self.song_speed && self.delay_line == 0
+    }
+
+    // The (order, row) position that the next played line will come from once pending breaks and jumps have been applied
+    fn next_line_position(&self, song: &Song) -> (u32, u32) {
+        let (mut order, row) = if self.next_pattern_pos != -1 {
+            (self.song_pattern_position + 1, self.next_pattern_pos as u32)
+        } else if self.next_position != -1 {
+            (self.next_position as u32, 0)
+        } else {
+            (self.song_pattern_position, self.current_line)
+        };
+        if order >= song.num_used_patterns && song.end_position < song.num_used_patterns {
+            order = song.end_position;
+        }
+        (order, row)
+    }
+
+    // Has the song reached its end. The song ends when the last line has played out or when playback would continue
+    // past the last position of the pattern table
+    fn is_at_song_end(&self, song: &Song) -> bool {
+        self.is_line_due()
+            && (self.song_has_ended || self.next_line_position(song).0 >= song.num_used_patterns)
+    }
+}
+
+// The instrument the channel is playing in formats with instruments
+fn channel_instrument<'a>(song: &'a Song, channel: &ChannelInfo) -> Option<&'a Instrument> {
+    if channel.instrument == 0 {
+        return None;
+    }
+    song.instruments.get(channel.instrument as usize - 1)
+}
+
+// The sample that a note plays. Formats with instruments pick the sample from the instrument's key map for the note's key, or
+// the channel's last key for lines with just an instrument
+fn note_sample_number(note: &Note, channel: &mut ChannelInfo, song: &Song) -> u8 {
+    if song.instruments.is_empty() {
+        return note.sample_number;
+    }
+    if note.sample_number > 0 {
+        channel.instrument = note.sample_number;
+    }
+    let key = match note.key {
+        1..=120 => note.key,
+        _ if note.sample_number > 0 => channel.key,
+        _ => return 0,
+    };
+    channel_instrument(song, channel).map_or(0, |instrument| instrument.sample_for_key(key))
+}
+
+// The period of `key` for the sample playing on the channel
+fn key_period(song: &Song, channel: &ChannelInfo, key: u8) -> u32 {
+    loader::key_period(&song.format, key, channel.c2spd, channel.relative_pitch)
+}
+
+/// The most notes that play in the background at the same time. The oldest note stops when another one is needed
+const MAX_BACKGROUND_VOICES: usize = 64;
+
+// Releases the note on the channel. The instrument's envelopes move past their sustain points and the sample plays on from
+// its sustain loop
+fn release_note(channel: &mut ChannelInfo, song: &Song) {
+    channel.released = true;
+    if let Some(instrument) = channel_instrument(song, channel) {
+        channel.release_instrument(instrument);
+    }
+    let sample = (channel.sample_num as usize)
+        .checked_sub(1)
+        .and_then(|index| song.samples.get(index));
+    if let Some(sample) = sample {
+        if sample
+            .sustain_loop
+            .is_some_and(|(_, end)| channel.size == end)
+        {
+            channel.size = sample.size;
+        }
+    }
+}
+
+// Cuts, releases or fades out a note. Returns false if the note was cut
+fn apply_note_action(voice: &mut ChannelInfo, action: NewNoteAction, song: &Song) -> bool {
+    match action {
+        NewNoteAction::Cut => return false,
+        NewNoteAction::Continue => {}
+        NewNoteAction::NoteOff => release_note(voice, song),
+        NewNoteAction::NoteFade => voice.fading = true,
+    }
+    true
+}
+
+// Moves the note playing on the channel to the background before the channel starts a new note. What happens to the note
+// there is up to its new note action
+fn start_background_voice(
+    voices: &mut Vec<(usize, ChannelInfo)>,
+    channel: &ChannelInfo,
+    channel_num: usize,
+    song: &Song,
+) {
+    if channel.size <= 2
+        || channel.new_note_action == NewNoteAction::Cut
+        || channel_instrument(song, channel).is_none()
+    {
+        return;
+    }
+    let mut voice = channel.clone();
+    voice.muted = false;
+    if !apply_note_action(&mut voice, channel.new_note_action, song) {
+        return;
+    }
+    if voices.len() >= MAX_BACKGROUND_VOICES {
+        voices.remove(0);
+    }
+    voices.push((channel_num, voice));
+}
+
+// Applies the duplicate check of the instrument that has started on the channel to the notes the channel left in the
+// background
+fn check_duplicate_notes(
+    voices: &mut Vec<(usize, ChannelInfo)>,
+    channel: &ChannelInfo,
+    channel_num: usize,
+    song: &Song,
+) {
+    let instrument = match channel_instrument(song, channel) {
+        Some(instrument) => instrument,
+        None => return,
+    };
+    voices.retain_mut(|(voice_channel, voice)| {
+        let is_duplicate = *voice_channel == channel_num
+            && voice.instrument == channel.instrument
+            && match instrument.duplicate_check {
+                DuplicateCheck::Off => false,
+                DuplicateCheck::Note => voice.key == channel.key,
+                DuplicateCheck::Sample => voice.sample_num == channel.sample_num,
+                DuplicateCheck::Instrument => true,
+            };
+        !is_duplicate || apply_note_action(voice, instrument.duplicate_action, song)
+    });
 }
 
 fn play_note(note: &Note, player_state: &mut PlayerState, channel_num: usize, song: &Song) {
+    // EDx holds back the note, sample and volume until tick x of the line. The rest of the line plays now and the note
+    // is played with the volume column on its tick
+    if let Effect::DelayedSample { delay_ticks } = note.effect {
+        let has_note =
+            note.key != 0 || note.period != 0 || note.sample_number != 0 || note.volume.is_some();
+        if delay_ticks > 0 && has_note {
+            let mut line_effect = Note::empty();
+            line_effect.effect = note.effect;
+            line_effect.effect_command = note.effect_command;
+            line_effect.effect_parameter = note.effect_parameter;
+            play_note(&line_effect, player_state, channel_num, song);
+
+            let mut delayed_note = note.clone();
+            delayed_note.effect = Effect::None;
+            delayed_note.effect_command = 0;
+            delayed_note.effect_parameter = 0;
+            player_state.channels[channel_num].delayed_note =
+                Some((delay_ticks as u32, delayed_note));
+            return;
+        }
+    }
+
     let channel = &mut player_state.channels[channel_num];
+    // a note delayed past the end of the previous line is never played
+    channel.delayed_note = None;
+    let period_scale = song.format.kind.period_scale();
+    // a new note, rather than a portamento to the note, takes over the channel
+    let slides_to_note = [note.effect, note.volume_effect].iter().any(|effect| {
+        matches!(
+            effect,
+            Effect::TonePortamento { .. } | Effect::TonePortamentoVolumeSlide { .. }
+        )
+    });
+    if (1..=120).contains(&note.key) && !slides_to_note {
+        start_background_voice(
+            &mut player_state.background_voices,
+            channel,
+            channel_num,
+            song,
+        );
+    }
 
     let old_period = channel.period;
-    let old_vibrato_pos = channel.vibrato_pos;
     let old_vibrato_speed = channel.vibrato_speed;
     let old_vibrato_depth = channel.vibrato_depth;
     let old_tremolo_speed = channel.tremolo_speed;
@@ -572,52 +1634,194 @@ fn play_note(note: &Note, player_state: &mut PlayerState, channel_num: usize, so
     let old_sample_pos = channel.sample_pos;
     let old_sample_num = channel.sample_num;
 
-    if note.sample_number > 0 {
+    let sample_number = note_sample_number(note, channel, song);
+    if sample_number > 0 && sample_number as usize <= song.samples.len() {
         // sample number 0, means that the sample keeps playing. The sample indices starts at one, so subtract 1 to get to 0 based index
-        let current_sample: &Sample = &song.samples[(note.sample_number - 1) as usize];
-        channel.volume = current_sample.volume as f32; // Get volume from sample
-                                                       //        channel.size =  current_sample.repeat_size + current_sample.repeat_offset;
+        let current_sample: &Sample = &song.samples[(sample_number - 1) as usize];
+        // notes without a sample or instrument number keep the volume
+        if note.sample_number > 0 {
+            channel.volume = current_sample.volume as f32; // Get volume from sample
+
+            // the sample's pan takes priority over the instrument's
+            if let Some(pan) =
+                channel_instrument(song, channel).and_then(|instrument| instrument.pan)
+            {
+                channel.pan = pan as f32 / 255.0 * 2.0 - 1.0;
+            }
+            if let Some(pan) = current_sample.pan {
+                channel.pan = pan as f32 / 255.0 * 2.0 - 1.0;
+            }
+        }
+        //        channel.size =  current_sample.repeat_size + current_sample.repeat_offset;
+        // funk repeat inverts the loop of the channel's copy of the sample. It starts from the start of the loop again
+        // for each sample number and the copy is kept until the channel plays a different sample
+        if sample_number != channel.sample_num {
+            channel.funk_sample = None;
+        }
+        if note.sample_number > 0 {
+            channel.funk_position = 0;
+        }
         channel.size = current_sample.size;
-        channel.sample_num = note.sample_number;
+        channel.in_loop = false;
+        channel.sample_num = sample_number;
         channel.fine_tune = current_sample.fine_tune as u32;
+        channel.c2spd = current_sample.c2spd;
+        channel.relative_pitch = current_sample.relative_pitch;
+    }
+
+    // In formats with effect memory a parameter of 0 repeats the last parameter given to the effect
+    let mut effect = note.effect;
+    if let Some(slot) = loader::effect_memory_slot(song.format.kind, note.effect_command) {
+        if note.effect_parameter == 0 {
+            effect = loader::decode_effect(
+                song.format.kind,
+                note.effect_command,
+                channel.effect_memory[slot],
+            );
+        } else {
+            channel.effect_memory[slot] = note.effect_parameter;
+        }
     }
 
+    // The volume column effect is played as the effect when the line has no other effect
+    let column_effect = if effect == Effect::None {
+        effect = note.volume_effect;
+        Effect::None
+    } else {
+        note.volume_effect
+    };
+
     channel.volume_change = 0.0;
+    channel.pan_change = 0.0;
+    channel.key_off_delay = 0;
     channel.note_change = 0;
     channel.retrigger_delay = 0;
+    channel.retrigger_volume_change = 0;
+    channel.tremor_on_ticks = 0;
+    channel.muted = false;
+    if !matches!(effect, Effect::Tremor { .. }) {
+        channel.tremor_counter = 0;
+    }
     channel.vibrato_speed = 0;
     channel.vibrato_depth = 0;
+    channel.vibrato_offset = 0;
     channel.tremolo_speed = 0;
     channel.tremolo_depth = 0;
 
     channel.arpeggio_counter = 0;
     channel.arpeggio_offsets[0] = 0;
     channel.arpeggio_offsets[1] = 0;
-    if note.period != 0 {
-        channel.period = fine_tune_period(note.period, channel.fine_tune, song.has_standard_notes);
-        channel.base_period = note.period;
+    // Formats that store keys start a note with any playable key
+    let starts_note =
+        note.period != 0 || (note.key > 0 && note.key <= 120 && channel.sample_num > 0);
+    if note.key == KEY_CUT {
+        channel.size = 0;
+    }
+    if starts_note {
+        if note.key > 0 {
+            channel.key = note.key;
+            channel.period = key_period(song, channel, note.key);
+            channel.base_period = channel.period;
+        } else {
+            channel.period =
+                fine_tune_period(note.period, channel.fine_tune, song.has_standard_notes);
+            channel.base_period = note.period;
+        }
         channel.sample_pos = 0.0;
         // If a note period was played we need to reset the size to start playing from the start
         // ( and redo any sample loops.  sample.size changes as the sample repeats )
         if channel.sample_num > 0 {
             let current_sample: &Sample = &song.samples[(channel.sample_num - 1) as usize];
             channel.size = current_sample.size;
+            channel.in_loop = false;
+        }
+    }
+    let is_tone_portamento = matches!(
+        effect,
+        Effect::TonePortamento { .. } | Effect::TonePortamentoVolumeSlide { .. }
+    );
+    // a tone portamento that carries on keeps playing its semitone until its next slide
+    if !is_tone_portamento {
+        channel.glissando_offset = 0;
+    }
+    if let Some(instrument) = channel_instrument(song, channel) {
+        if (starts_note && !is_tone_portamento) || note.sample_number > 0 {
+            channel.trigger_instrument(instrument);
+        }
+    } else if starts_note {
+        channel.released = false;
+    }
+    if starts_note && !is_tone_portamento {
+        // the waveforms restart with each note unless the no retrigger bit is set
+        if channel.vibrato_waveform & 4 == 0 {
+            channel.vibrato_pos = 0;
         }
+        if channel.tremolo_waveform & 4 == 0 {
+            channel.tremolo_pos = 0;
+        }
+    }
+    if starts_note && !is_tone_portamento && channel.sample_num > 0 {
+        channel.filter_history = [0.0; 2];
+        // the sample repeats its sustain loop until the note is released
+        if let Some((_, end)) = song.samples[(channel.sample_num - 1) as usize].sustain_loop {
+            channel.size = end;
+        }
+        check_duplicate_notes(
+            &mut player_state.background_voices,
+            channel,
+            channel_num,
+            song,
+        );
+    }
+    match note.key {
+        KEY_OFF => release_note(channel, song),
+        KEY_FADE => channel.fading = true,
+        _ => {}
+    }
+    if let Some(volume) = note.volume {
+        channel.volume = volume as f32;
     }
 
-    match note.effect {
+    match column_effect {
+        Effect::VolumeSlide { volume_change } => {
+            channel.volume_change = volume_change as f32;
+        }
+        Effect::FineVolumeSlideUp { volume_change } => {
+            channel.volume = (channel.volume + volume_change as f32).min(64.0);
+        }
+        Effect::FineVolumeSlideDown { volume_change } => {
+            channel.volume = (channel.volume - volume_change as f32).max(0.0);
+        }
+        Effect::Vibrato { speed, amplitude } => {
+            channel.vibrato_speed = if speed == 0 {
+                old_vibrato_speed
+            } else {
+                speed as u32
+            };
+            channel.vibrato_depth = if amplitude == 0 {
+                old_vibrato_depth
+            } else {
+                amplitude as i32 * 4
+            };
+        }
+        Effect::Pan { position } => {
+            channel.pan = position as f32 / 255.0 * 2.0 - 1.0;
+        }
+        Effect::PanSlide { pan_change } => {
+            channel.pan_change = pan_change as f32 / 255.0 * 2.0;
+        }
+        _ => {}
+    }
+
+    match effect {
         Effect::SetSpeed { speed } => {
-            // depending on argument the speed is either sets as VBI counts or Beats Per Minute
-            if speed <= 31 {
+            // depending on argument the speed is either sets as VBI counts or Beats Per Minute. Soundtracker only has VBI counts
+            if speed <= 31 || song.format.kind == FormatKind::Soundtracker {
                 // VBI countsa
                 player_state.song_speed = speed as u32;
             } else {
-                // BPM changes the timing between ticks ( easiest way to do that is to )
-                // default is 125 bpm => 500 => ticks per minute ( by default each tick is 6 vblanks ) = > 3000 vblanks per minute or 50 vblanks per sec
-                // new BPM * 4 => ticks per minute * 6 / 60 => vblanks per sec = BPM * 0.4
-                let vblanks_per_sec = speed as f32 * 0.4;
-                player_state.samples_per_vblank =
-                    (player_state.device_sample_rate as f32 / vblanks_per_sec) as u32
+                // BPM changes the timing between ticks
+                player_state.set_tempo(speed as u32);
             }
         }
         Effect::Arpeggio {
@@ -629,14 +1833,14 @@ fn play_note(note: &Note, player_state: &mut PlayerState, channel_num: usize, so
             channel.arpeggio_counter = 0;
         }
         Effect::SlideUp { speed } => {
-            channel.note_change = -(speed as i32);
+            channel.note_change = -(speed as i32) * period_scale;
         }
         Effect::SlideDown { speed } => {
-            channel.note_change = speed as i32;
+            channel.note_change = speed as i32 * period_scale;
         }
         Effect::TonePortamento { speed } => {
             // if a new sound was played ( period was so on the note ) that is the new target. otherwise carry on with old target
-            if note.period != 0 {
+            if starts_note {
                 channel.period_target = channel.period; // use channel.period which has already been fine-tuned
             } else {
                 if channel.last_porta_target != 0 {
@@ -650,7 +1854,7 @@ fn play_note(note: &Note, player_state: &mut PlayerState, channel_num: usize, so
             channel.period = old_period; // reset back to old after we used it
             if speed != 0 {
                 // only change speed if it non-zero. ( zero means to carry on with the effects as before)
-                channel.note_change = speed as i32;
+                channel.note_change = speed as i32 * period_scale;
             } else {
                 channel.note_change = channel.last_porta_speed;
             }
@@ -662,18 +1866,28 @@ fn play_note(note: &Note, player_state: &mut PlayerState, channel_num: usize, so
                 channel.sample_pos = old_sample_pos;
             }
         }
-        Effect::Vibrato { speed, amplitude } => {
-            if speed == 0 {
-                channel.vibrato_speed = old_vibrato_speed;
-            }
-            if amplitude == 0 {
-                channel.vibrato_depth = old_vibrato_depth;
-            }
+        // The depth is kept in the units of fine vibrato which moves a quarter as far as vibrato
+        Effect::Vibrato { speed, amplitude } | Effect::FineVibrato { speed, amplitude } => {
+            let depth_scale = if let Effect::Vibrato { .. } = effect {
+                4
+            } else {
+                1
+            };
+            channel.vibrato_speed = if speed == 0 {
+                old_vibrato_speed
+            } else {
+                speed as u32
+            };
+            channel.vibrato_depth = if amplitude == 0 {
+                old_vibrato_depth
+            } else {
+                amplitude as i32 * depth_scale
+            };
         }
         Effect::TonePortamentoVolumeSlide { volume_change } => {
             // Continue
             channel.volume_change = volume_change as f32;
-            if note.period != 0 {
+            if starts_note {
                 channel.period_target = channel.period;
             } else {
                 channel.period_target = channel.last_porta_target;
@@ -685,7 +1899,6 @@ fn play_note(note: &Note, player_state: &mut PlayerState, channel_num: usize, so
         }
         Effect::VibratoVolumeSlide { volume_change } => {
             channel.volume_change = volume_change as f32;
-            channel.vibrato_pos = old_vibrato_pos as u32;
             channel.vibrato_speed = old_vibrato_speed as u32;
             channel.vibrato_depth = old_vibrato_depth as i32;
         }
@@ -698,15 +1911,16 @@ fn play_note(note: &Note, player_state: &mut PlayerState, channel_num: usize, so
                 channel.tremolo_speed = speed as u32;
             }
         }
-        Effect::SetSampleOffset { offset } => {
-            // Ignore, unless we are also playing a new sound
-            if note.period != 0 && channel.sample_num > 0 {
-                channel.sample_pos = (offset as f32) * 256.0;
-                // Does the offset go past the end of the sound
-                let current_sample: &Sample = &song.samples[(channel.sample_num - 1) as usize];
-                if channel.sample_pos as u32 > current_sample.size {
-                    channel.sample_pos = (channel.sample_pos as u32 % current_sample.size) as f32
-                }
+        Effect::Pan { position } => {
+            channel.pan = position as f32 / 255.0 * 2.0 - 1.0;
+        }
+        // Ignore, unless we are also playing a new sound
+        Effect::SetSampleOffset { offset } if starts_note && channel.sample_num > 0 => {
+            channel.sample_pos = (offset as f32) * 256.0;
+            // Does the offset go past the end of the sound
+            let current_sample: &Sample = &song.samples[(channel.sample_num - 1) as usize];
+            if channel.sample_pos as u32 > current_sample.size && current_sample.size > 0 {
+                channel.sample_pos = (channel.sample_pos as u32 % current_sample.size) as f32
             }
         }
         Effect::VolumeSlide { volume_change } => {
@@ -717,8 +1931,10 @@ fn play_note(note: &Note, player_state: &mut PlayerState, channel_num: usize, so
         }
         Effect::PatternBreak { next_pattern_pos } => {
             player_state.next_pattern_pos = next_pattern_pos as i32;
-            if player_state.next_pattern_pos > 63 {
-                // only possible to jump to index 63 at most. Anything highrt interpreted as jumping to beginning of next pattern
+            if next_pattern_pos as usize
+                >= song.order_line_count(player_state.song_pattern_position + 1)
+            {
+                // only possible to jump to the last line at most. Anything higher interpreted as jumping to beginning of next pattern
                 player_state.next_pattern_pos = 0;
             }
         }
@@ -729,34 +1945,45 @@ fn play_note(note: &Note, player_state: &mut PlayerState, channel_num: usize, so
             player_state.next_position = next_pattern as i32;
         }
         Effect::FinePortaUp { period_change } => {
-            channel.period = change_note(channel.period, -(period_change as i32));
+            channel.period =
+                slide_period(song, channel.period, -(period_change as i32) * period_scale);
         }
         Effect::FinePortaDown { period_change } => {
-            channel.period = change_note(channel.period, period_change as i32);
+            channel.period =
+                slide_period(song, channel.period, period_change as i32 * period_scale);
         }
         Effect::PatternLoop { arg } => {
+            // each channel has its own loop, so loops on different channels nest
             if arg == 0 {
                 // arg 0 marks the loop start position
-                player_state.pattern_loop_position = Some(player_state.current_line);
+                channel.pattern_loop_start = player_state.current_line;
             } else {
-                if player_state.pattern_loop == 0 {
-                    player_state.pattern_loop = arg as i32;
+                if channel.pattern_loop_count == 0 {
+                    channel.pattern_loop_count = arg as u32;
                 } else {
-                    player_state.pattern_loop -= 1;
+                    channel.pattern_loop_count -= 1;
                 }
-                if player_state.pattern_loop > 0 && player_state.pattern_loop_position.is_some() {
-                    player_state.set_pattern_position = true;
+                if channel.pattern_loop_count > 0 {
+                    // when loops on several channels end on the same line the last channel's loop start is used
+                    player_state.pattern_loop_line = Some(channel.pattern_loop_start);
                 } else {
-                    // Double loops ( loops start followed by two or more loops can confuse the player. Once a loop is passed. invalidate the loop marker)
-                    player_state.pattern_loop_position = None;
+                    // ProTracker plays forever when a second loop end follows a finished loop on the channel. Starting
+                    // the next loop after this one stops it jumping back into the finished loop
+                    channel.pattern_loop_start = player_state.current_line + 1;
                 }
             }
         }
-        Effect::TremoloWaveform { wave: _ } => {
-            // println!("set tremolo wave");
+        Effect::Glissando { use_smooth_slide } => {
+            channel.glissando = !use_smooth_slide;
         }
-        Effect::CoarsePan { pan_pos: _ } => {
-            // Skip pan for now
+        Effect::SetVibratoWave { wave } => {
+            channel.vibrato_waveform = wave;
+        }
+        Effect::TremoloWaveform { wave } => {
+            channel.tremolo_waveform = wave;
+        }
+        Effect::CoarsePan { pan_pos } => {
+            channel.pan = pan_pos as f32 / 15.0 * 2.0 - 1.0;
         }
 
         Effect::RetriggerSample { retrigger_delay } => {
@@ -778,14 +2005,80 @@ fn play_note(note: &Note, player_state: &mut PlayerState, channel_num: usize, so
         Effect::CutNote { delay } => {
             channel.cut_note_delay = delay as u32;
         }
-        Effect::SetHardwareFilter { new_state: _ } => {
-            // not much to do. only works on the a500
+        Effect::SetHardwareFilter { new_state } => {
+            // E00 turns the LED filter on, E01 turns it off
+            player_state.led_filter_on = new_state & 1 == 0;
         }
         Effect::DelayedLine { delay_ticks } => {
             player_state.delay_line = delay_ticks as u32;
         }
-        Effect::InvertLoop { loop_position: _ } => {
-            //Ignore for now
+        Effect::InvertLoop { loop_position } => {
+            channel.funk_speed = loop_position;
+            channel.update_funk(song);
+        }
+        Effect::SetTicksPerLine { ticks } => {
+            player_state.song_speed = ticks as u32;
+        }
+        Effect::SetTempo { bpm } => {
+            player_state.set_tempo(bpm as u32);
+        }
+        Effect::SetGlobalVolume { volume } => {
+            player_state.global_volume = (volume as u32).min(64);
+        }
+        Effect::Tremor {
+            on_ticks,
+            off_ticks,
+        } => {
+            // the sound is on for one tick more than the parameter and then off for one tick more than the parameter
+            channel.tremor_on_ticks = on_ticks as u32 + 1;
+            channel.tremor_off_ticks = off_ticks as u32 + 1;
+        }
+        Effect::RetriggerVolumeSlide {
+            retrigger_delay,
+            volume_change,
+        } => {
+            channel.retrigger_delay = retrigger_delay as u32;
+            channel.retrigger_counter = 0;
+            channel.retrigger_volume_change = volume_change;
+        }
+        Effect::ExtraFinePortaUp { period_change } => {
+            channel.period = slide_period(song, channel.period, -(period_change as i32));
+        }
+        Effect::ExtraFinePortaDown { period_change } => {
+            channel.period = slide_period(song, channel.period, period_change as i32);
+        }
+        Effect::GlobalVolumeSlide { volume_change } => {
+            player_state.global_volume_change = volume_change as i32;
+        }
+        Effect::PanSlide { pan_change } => {
+            channel.pan_change = pan_change as f32 / 255.0 * 2.0;
+        }
+        Effect::KeyOff { tick } => {
+            if tick == 0 {
+                release_note(channel, song);
+            } else {
+                channel.key_off_delay = tick as u32;
+            }
+        }
+        Effect::SetEnvelopePosition { position } => {
+            channel.volume_envelope_tick = position as u32;
+            channel.panning_envelope_tick = position as u32;
+        }
+        Effect::SetFilterCutoff { cutoff } => {
+            channel.filter_cutoff = cutoff.min(127);
+        }
+        Effect::SetFilterResonance { resonance } => {
+            channel.filter_resonance = resonance.min(127);
+        }
+        Effect::PastNoteAction { action } => {
+            player_state
+                .background_voices
+                .retain_mut(|(voice_channel, voice)| {
+                    *voice_channel != channel_num || apply_note_action(voice, action, song)
+                });
+        }
+        Effect::SetNewNoteAction { action } => {
+            channel.new_note_action = action;
         }
         Effect::None => {}
         _ => {
@@ -800,10 +2093,12 @@ fn play_line(song: &Song, player_state: &mut PlayerState) {
         player_state.song_pattern_position += 1;
         player_state.current_line = player_state.next_pattern_pos as u32;
         player_state.next_pattern_pos = -1;
+        player_state.reset_pattern_loops();
     } else if player_state.next_position != -1 {
         player_state.song_pattern_position = player_state.next_position as u32;
         player_state.current_line = 0;
         player_state.next_position = -1;
+        player_state.reset_pattern_loops();
     }
 
     // We could have been place past the end of the song
@@ -816,6 +2111,7 @@ fn play_line(song: &Song, player_state: &mut PlayerState) {
         }
     }
 
+    player_state.global_volume_change = 0;
     let line = player_state.get_song_line(song);
     for channel_number in 0..line.len() {
         play_note(
@@ -826,26 +2122,62 @@ fn play_line(song: &Song, player_state: &mut PlayerState) {
         );
     }
 
-    if player_state.set_pattern_position && player_state.pattern_loop_position.is_some() {
+    if let Some(line) = player_state.pattern_loop_line.take() {
         // jump to pattern loop position of the pattern loop was triggered
-        player_state.set_pattern_position = false;
-        player_state.current_line = player_state.pattern_loop_position.unwrap();
+        player_state.current_line = line;
     } else {
         // othwerwise advance to next pattern
         player_state.current_line += 1;
-        if player_state.current_line >= 64 {
+        if player_state.current_line as usize
+            >= song.order_line_count(player_state.song_pattern_position)
+        {
             player_state.song_pattern_position += 1;
             if player_state.song_pattern_position >= song.num_used_patterns {
                 player_state.song_has_ended = true;
             }
             player_state.current_line = 0;
+            player_state.reset_pattern_loops();
+        }
+    }
+}
+
+/// Plays the notes held back by EDx on the tick they are delayed to. Like ProTracker, notes delayed by at least the
+/// speed never play
+fn play_delayed_notes(song: &Song, player_state: &mut PlayerState) {
+    let tick = player_state.current_vblank;
+    for channel_num in 0..player_state.channels.len() {
+        let channel = &mut player_state.channels[channel_num];
+        let is_due = matches!(channel.delayed_note, Some((delay, _)) if delay == tick);
+        if is_due && tick < player_state.song_speed {
+            if let Some((_, note)) = channel.delayed_note.take() {
+                play_note(&note, player_state, channel_num, song);
+            }
         }
     }
 }
 
 fn update_effects(player_state: &mut PlayerState, song: &Song) {
+    let period_scale = song.format.kind.period_scale();
+    // funk repeat runs on the ticks after the line and EFx runs it on the line's own tick
+    let is_line_due = player_state.is_line_due();
+    player_state.global_volume =
+        (player_state.global_volume as i32 + player_state.global_volume_change).clamp(0, 64) as u32;
     for channel in &mut player_state.channels {
         if channel.sample_num != 0 {
+            if channel.key_off_delay > 0 {
+                channel.key_off_delay -= 1;
+                if channel.key_off_delay == 0 {
+                    release_note(channel, song);
+                }
+            }
+            if let Some(instrument) = channel_instrument(song, channel) {
+                channel.update_instrument(instrument);
+            }
+            channel.pan = (channel.pan + channel.pan_change).clamp(-1.0, 1.0);
+            if !is_line_due {
+                channel.update_funk(song);
+            }
+
             if channel.cut_note_delay > 0 {
                 channel.cut_note_delay -= 1;
                 if channel.cut_note_delay == 0 {
@@ -860,15 +2192,37 @@ fn update_effects(player_state: &mut PlayerState, song: &Song) {
                 if channel.retrigger_delay == channel.retrigger_counter {
                     channel.sample_pos = 0.0;
                     channel.retrigger_counter = 0;
+                    channel.volume =
+                        retrigger_volume(channel.volume, channel.retrigger_volume_change);
                 }
             }
+            if channel.tremor_on_ticks > 0 {
+                channel.muted = channel.tremor_counter
+                    % (channel.tremor_on_ticks + channel.tremor_off_ticks)
+                    >= channel.tremor_on_ticks;
+                channel.tremor_counter += 1;
+            }
             channel.volume += channel.volume_change;
             if channel.tremolo_depth > 0 {
                 let base_volume = song.samples[(channel.sample_num - 1) as usize].volume as i32;
-                let tremolo_size: i32 = (static_tables::VIBRATO_TABLE
-                    [(channel.tremolo_pos & 63) as usize]
-                    * channel.tremolo_depth)
-                    / 64;
+                let mut tremolo = waveform_value(channel.tremolo_waveform, channel.tremolo_pos);
+                // ProTracker and FastTracker 2 pick the half of the tremolo's ramp from the vibrato position. Only the
+                // sign follows the tremolo position
+                if channel.tremolo_waveform & 3 == 1
+                    && matches!(
+                        song.format.kind,
+                        FormatKind::Mod | FormatKind::Soundtracker | FormatKind::Xm
+                    )
+                {
+                    let ramp =
+                        waveform_value(1, (channel.tremolo_pos & 31) | (channel.vibrato_pos & 32));
+                    tremolo = if channel.tremolo_pos & 32 == 0 {
+                        ramp.abs()
+                    } else {
+                        -ramp.abs()
+                    };
+                }
+                let tremolo_size: i32 = (tremolo * channel.tremolo_depth) / 64;
                 let volume = base_volume + tremolo_size;
                 channel.tremolo_pos += channel.tremolo_speed;
                 channel.volume = volume as f32;
@@ -880,16 +2234,24 @@ fn update_effects(player_state: &mut PlayerState, song: &Song) {
                 channel.volume = 64.0
             }
 
-            if channel.arpeggio_offsets[0] != 0 || channel.arpeggio_offsets[1] != 0 {
+            if (channel.arpeggio_offsets[0] != 0 || channel.arpeggio_offsets[1] != 0)
+                && channel.key > 0
+            {
+                // formats with keys go up from the key that was played
+                let offset = if channel.arpeggio_counter > 0 {
+                    channel.arpeggio_offsets[(channel.arpeggio_counter - 1) as usize]
+                } else {
+                    0
+                };
+                channel.period =
+                    key_period(song, channel, (channel.key as u32 + offset).min(120) as u8);
+                channel.arpeggio_counter += 1;
+                if channel.arpeggio_counter >= 3 {
+                    channel.arpeggio_counter = 0;
+                }
+            } else if channel.arpeggio_offsets[0] != 0 || channel.arpeggio_offsets[1] != 0 {
                 let new_period: u32;
-                let index = static_tables::FREQUENCY_TABLE
-                    .binary_search(&channel.base_period)
-                    .expect(&format!(
-                        "Unexpected period value at arpeggio {}, {}:{}",
-                        channel.base_period,
-                        player_state.song_pattern_position,
-                        player_state.current_line
-                    )) as i32;
+                let index = frequency_table_index(channel.base_period) as i32;
                 if channel.arpeggio_counter > 0 {
                     let mut note_offset = index
                         - channel.arpeggio_offsets[(channel.arpeggio_counter - 1) as usize] as i32;
@@ -909,106 +2271,327 @@ fn update_effects(player_state: &mut PlayerState, song: &Song) {
                 }
             }
             if channel.vibrato_depth > 0 {
-                let period = fine_tune_period(
-                    channel.base_period,
-                    channel.fine_tune,
-                    song.has_standard_notes,
-                );
-                channel.period = ((period as i32)
-                    + (static_tables::VIBRATO_TABLE[(channel.vibrato_pos & 63) as usize]
-                        * channel.vibrato_depth)
-                        / 32) as u32;
+                // the vibrato moves the pitch around the channel's period without changing it, so the note returns to
+                // its period, or carries on from a slide, when the vibrato stops
+                channel.vibrato_offset =
+                    waveform_value(channel.vibrato_waveform, channel.vibrato_pos)
+                        * channel.vibrato_depth
+                        * period_scale
+                        / 512;
                 channel.vibrato_pos += channel.vibrato_speed;
             } else if channel.note_change != 0 {
                 // changing note to a target
                 if channel.period_target != 0 {
                     if channel.period_target > channel.period {
-                        channel.period = change_note(channel.period, channel.note_change);
+                        channel.period = slide_period(song, channel.period, channel.note_change);
                         if channel.period >= channel.period_target {
                             channel.period = channel.period_target;
                         }
                     } else {
-                        channel.period = change_note(channel.period, -channel.note_change);
+                        channel.period = slide_period(song, channel.period, -channel.note_change);
                         if channel.period <= channel.period_target {
                             channel.period = channel.period_target;
                         }
                     }
+                    // the period keeps sliding smoothly underneath the semitones that glissando plays
+                    if channel.glissando {
+                        channel.glissando_offset =
+                            nearest_semitone_period(song, channel, channel.period) as i32
+                                - channel.period as i32;
+                    }
                 } else {
                     // or just moving it
-                    channel.period = change_note(channel.period, channel.note_change);
+                    channel.period = slide_period(song, channel.period, channel.note_change);
                 }
             }
         }
     }
+
+    // background notes only follow their instrument. They are dropped once they can no longer be heard
+    player_state.background_voices.retain_mut(|(_, voice)| {
+        let instrument = match channel_instrument(song, voice) {
+            Some(instrument) => instrument,
+            None => return false,
+        };
+        voice.update_instrument(instrument);
+        voice.size > 2 && voice.volume > 0.0 && !voice.has_faded_out(instrument)
+    });
 }
 
-/// Calculates the next sample pair (left, right) to be played from the song. The returned samples have the range [-1, 1]
-pub fn next_sample(song: &Song, player_state: &mut PlayerState) -> (f32, f32) {
-    let mut left = 0.0;
-    let mut right = 0.0;
+// The volume after a retrigger that also slides the volume
+fn retrigger_volume(volume: f32, change: u8) -> f32 {
+    let volume = match change {
+        1..=5 => volume - (1 << (change - 1)) as f32,
+        6 => volume * 2.0 / 3.0,
+        7 => volume / 2.0,
+        9..=0xd => volume + (1 << (change - 9)) as f32,
+        0xe => volume * 3.0 / 2.0,
+        0xf => volume * 2.0,
+        _ => volume,
+    };
+    volume.clamp(0.0, 64.0)
+}
 
-    // Have we reached a new vblank
-    if player_state.current_vblank_sample >= player_state.samples_per_vblank {
-        player_state.current_vblank_sample = 0;
+/// Processes the next vblank tick and mixes its audio into the tick buffers
+fn play_tick(song: &Song, player_state: &mut PlayerState) {
+    update_tick(song, player_state);
+    mix_tick(song, player_state);
+}
 
-        update_effects(player_state, song);
+/// Updates the effects and plays the next line when it is due. Does not produce any audio
+fn update_tick(song: &Song, player_state: &mut PlayerState) {
+    player_state.current_vblank_sample = 0;
+    if !player_state.has_started {
+        player_state.start_song(song);
+    }
 
-        // Is it time to play a new note line either by VBI counting or BPM counting
-        if player_state.current_vblank >= player_state.song_speed {
-            if player_state.delay_line > 0 {
-                player_state.delay_line -= 1;
-            } else {
-                player_state.current_vblank = 0;
-                play_line(song, player_state);
+    update_effects(player_state, song);
+    play_delayed_notes(song, player_state);
+
+    // Is it time to play a new note line either by VBI counting or BPM counting
+    if player_state.current_vblank >= player_state.song_speed {
+        if player_state.delay_line > 0 {
+            player_state.delay_line -= 1;
+        } else {
+            player_state.current_vblank = 0;
+            play_line(song, player_state);
+        }
+    }
+    // apply on every vblank but only after the line has been processed
+    player_state.current_vblank += 1;
+}
+
+/// Settings that apply to all the channels mixed in a tick
+struct MixSettings<'a> {
+    clock_ticks_per_device_sample: f32,
+    device_sample_rate: u32,
+    global_volume: f32, // from 0.0 to 1.0
+    interpolation: Interpolation,
+    sinc_table: &'a [f32],
+    stereo_separation: f32, // from 0.0 (mono) to 1.0 (channel pans used as is)
+}
+
+/// Mixes all the channels for the duration of one tick into the tick buffers
+fn mix_tick(song: &Song, player_state: &mut PlayerState) {
+    let num_frames = player_state.samples_per_vblank as usize;
+    if player_state.interpolation == Interpolation::Sinc && player_state.sinc_table.is_empty() {
+        player_state.sinc_table = interpolation::build_sinc_table();
+    }
+    let settings = MixSettings {
+        clock_ticks_per_device_sample: song.format.kind.period_clock()
+            / player_state.device_sample_rate as f32,
+        device_sample_rate: player_state.device_sample_rate,
+        global_volume: player_state.global_volume.min(64) as f32 / 64.0,
+        interpolation: player_state.interpolation,
+        sinc_table: &player_state.sinc_table,
+        stereo_separation: player_state.stereo_separation.min(100) as f32 / 100.0,
+    };
+    let tick_left = &mut player_state.tick_left;
+    let tick_right = &mut player_state.tick_right;
+    tick_left.clear();
+    tick_left.resize(num_frames, 0.0);
+    tick_right.clear();
+    tick_right.resize(num_frames, 0.0);
+
+    let background_voices = player_state
+        .background_voices
+        .iter_mut()
+        .map(|(_, voice)| voice);
+    for channel_info in player_state.channels.iter_mut().chain(background_voices) {
+        mix_channel(song, channel_info, &settings, tick_left, tick_right);
+    }
+
+    // The Amiga output filters are applied to the final mix
+    if player_state.amiga_filter != player_state.output_filter.model() {
+        player_state.output_filter =
+            OutputFilter::new(player_state.amiga_filter, player_state.device_sample_rate);
+    }
+    let led_filter_on = player_state.led_filter_on;
+    player_state
+        .output_filter
+        .process(0, led_filter_on, &mut player_state.tick_left);
+    player_state
+        .output_filter
+        .process(1, led_filter_on, &mut player_state.tick_right);
+}
+
+/// Moves the play position to the sample loop if the position has gone past the end of the playing part.
+/// Returns false if the sample has stopped playing
+fn wrap_sample_position(channel_info: &mut ChannelInfo, current_sample: &Sample) -> bool {
+    if channel_info.sample_pos >= channel_info.size as f32 {
+        let overflow: f32 = channel_info.sample_pos - channel_info.size as f32;
+        if let Some((start, end)) = current_sample.sustain_loop {
+            if !channel_info.released {
+                channel_info.sample_pos = start as f32 + overflow;
+                channel_info.size = end;
+                return true;
             }
         }
-        // apply on every vblank but only after the line has been processed
-        player_state.current_vblank += 1;
+        channel_info.sample_pos = current_sample.repeat_offset as f32 + overflow;
+        channel_info.size = current_sample.repeat_size + current_sample.repeat_offset;
+        channel_info.in_loop = true;
+        if channel_info.size <= 2 {
+            return false;
+        }
     }
-    player_state.current_vblank_sample += 1;
+    true
+}
 
-    for channel_number in 0..player_state.channels.len() {
-        let channel_info: &mut ChannelInfo = &mut player_state.channels[channel_number];
-        if channel_info.size > 2 {
-            let current_sample: &Sample = &song.samples[(channel_info.sample_num - 1) as usize];
-
-            //  check if we have reached the end of the sample ( do this before getting the sample as some note data can change the
-            // postions past available data.  )
-            if channel_info.sample_pos >= channel_info.size as f32 {
-                let overflow: f32 = channel_info.sample_pos - channel_info.size as f32;
-                channel_info.sample_pos = current_sample.repeat_offset as f32 + overflow;
-                channel_info.size = current_sample.repeat_size + current_sample.repeat_offset;
-                if channel_info.size <= 2 {
-                    continue;
-                }
+// How far through the sample the channel moves for each device sample
+fn sample_step(
+    song: &Song,
+    channel_info: &ChannelInfo,
+    clock_ticks_per_device_sample: f32,
+    device_sample_rate: u32,
+) -> f32 {
+    let period = (channel_info.period as i32
+        + channel_info.period_offset
+        + channel_info.glissando_offset
+        + channel_info.vibrato_offset)
+        .max(1);
+    let step = if song.format.has_linear_periods {
+        // linear periods are 768 to an octave and period 4608 plays at 8363 Hz
+        let frequency = DEFAULT_C2SPD as f32 * ((4608 - period) as f32 / 768.0).exp2();
+        frequency / device_sample_rate as f32
+    } else {
+        clock_ticks_per_device_sample / period as f32
+    };
+    if channel_info.envelope_pitch != 0.0 {
+        step * (channel_info.envelope_pitch / 12.0).exp2()
+    } else {
+        step
+    }
+}
+
+/// Moves the play positions of all channels forward by `num_frames` exactly as mixing would, but without producing audio
+fn advance_tick(song: &Song, player_state: &mut PlayerState, num_frames: usize) {
+    let clock_ticks_per_device_sample =
+        song.format.kind.period_clock() / player_state.device_sample_rate as f32;
+    let background_voices = player_state
+        .background_voices
+        .iter_mut()
+        .map(|(_, voice)| voice);
+    for channel_info in player_state.channels.iter_mut().chain(background_voices) {
+        if channel_info.size <= 2 || channel_info.period == 0 {
+            continue;
+        }
+        let current_sample: &Sample = &song.samples[(channel_info.sample_num - 1) as usize];
+        let sample_step = sample_step(
+            song,
+            channel_info,
+            clock_ticks_per_device_sample,
+            player_state.device_sample_rate,
+        );
+        for _frame in 0..num_frames {
+            if !wrap_sample_position(channel_info, current_sample) {
+                break;
             }
+            channel_info.sample_pos += sample_step;
+        }
+    }
+}
+
+/// Adds the output of a single channel to the left and right output buffers
+fn mix_channel(
+    song: &Song,
+    channel_info: &mut ChannelInfo,
+    settings: &MixSettings,
+    left: &mut [f32],
+    right: &mut [f32],
+) {
+    // a channel without a period has nothing to play
+    if channel_info.size <= 2 || channel_info.period == 0 {
+        return;
+    }
+    // funk repeat plays the channel's own copy of the sample. It is taken out of the channel while mixing
+    let funk_sample = channel_info.funk_sample.take();
+    let current_sample: &Sample = match &funk_sample {
+        Some(sample) => sample,
+        None => &song.samples[(channel_info.sample_num - 1) as usize],
+    };
+    // max channel vol (64), sample range [ -32768,32767] scaled to [-1,1]
+    // tremor silences the channel without stopping it
+    let volume = if channel_info.muted {
+        0.0
+    } else {
+        channel_info.volume * channel_info.envelope_volume
+    };
+    let volume_scale = volume / (128.0 * 64.0 * 256.0) * settings.global_volume;
+    let sample_step = sample_step(
+        song,
+        channel_info,
+        settings.clock_ticks_per_device_sample,
+        settings.device_sample_rate,
+    );
+    // linear pan law. A hard panned channel has a gain of exactly 1.0 on one side and 0.0 on the other
+    let pan = (channel_info.pan + channel_info.envelope_pan) * settings.stereo_separation;
+    let left_gain = (1.0 - pan) * 0.5;
+    let right_gain = (1.0 + pan) * 0.5;
+    let filter_cutoff = channel_info.filter_cutoff as f32 * channel_info.envelope_cutoff;
+    let resonant_filter = if filter_cutoff < 127.0 || channel_info.filter_resonance > 0 {
+        Some(filter::resonant_filter_coefficients(
+            filter_cutoff,
+            channel_info.filter_resonance,
+            settings.device_sample_rate as f32,
+        ))
+    } else {
+        None
+    };
+
+    for frame in 0..left.len().min(right.len()) {
+        //  check if we have reached the end of the sample ( do this before getting the sample as some note data can change the
+        // postions past available data.  )
+        if !wrap_sample_position(channel_info, current_sample) {
+            break;
+        }
 
+        // [ -32768, 32767 ]
+        let channel_value: f32 = if settings.interpolation == Interpolation::None {
             // Grab the sample, no filtering
-            let mut channel_value: f32 =
-                current_sample.samples[(channel_info.sample_pos as u32) as usize] as f32; // [ -127, 127 ]
-
-            //     let left_pos = channel_info.sample_pos as u32;
-            //     let left_weight: f32 = 1.0 - (channel_info.sample_pos - left_pos as f32);
-            //     let mut channel_value: f32 = current_sample.samples[ left_pos as usize] as f32;   // [ -127, 127 ]
-            //     if left_pos < (current_sample.size - 1) as u32 {
-            //        let right_value = current_sample.samples[(left_pos + 1) as usize] as f32;
-            //        channel_value = left_weight * channel_value + (1.0 - left_weight) * right_value;
-            //    }
-
-            // max channel vol (64), sample range [ -128,127] scaled to [-1,1]
-            channel_value *= channel_info.volume / (128.0 * 64.0);
-
-            // update position
-            channel_info.sample_pos +=
-                player_state.clock_ticks_per_device_sample / channel_info.period as f32;
-
-            let channel_selector = (channel_number as u8) & 0x0003;
-            if channel_selector == 0 || channel_selector == 3 {
-                left += channel_value;
-            } else {
-                right += channel_value;
+            current_sample.samples[(channel_info.sample_pos as u32) as usize] as f32
+        } else {
+            interpolation::interpolate(
+                settings.interpolation,
+                settings.sinc_table,
+                current_sample,
+                channel_info,
+            )
+        };
+        let channel_value = match resonant_filter {
+            Some([input_gain, feedback_1, feedback_2]) => {
+                let [previous_1, previous_2] = channel_info.filter_history;
+                let filtered =
+                    channel_value * input_gain + previous_1 * feedback_1 + previous_2 * feedback_2;
+                channel_info.filter_history = [filtered, previous_1];
+                filtered
             }
+            None => channel_value,
+        };
+        let channel_value = channel_value * volume_scale;
+
+        // skip silent sides rather than adding zeros to them
+        if left_gain != 0.0 {
+            left[frame] += channel_value * left_gain;
         }
+        if right_gain != 0.0 {
+            right[frame] += channel_value * right_gain;
+        }
+
+        // update position
+        channel_info.sample_pos += sample_step;
     }
-    (left, right)
+    channel_info.funk_sample = funk_sample;
+}
+
+/// Calculates the next sample pair (left, right) to be played from the song. The returned samples have the range [-1, 1]
+///
+/// To fill whole buffers at a time use `PlayerState::render` and its variants instead
+pub fn next_sample(song: &Song, player_state: &mut PlayerState) -> (f32, f32) {
+    // Have we played all the samples of the current vblank
+    while player_state.current_vblank_sample as usize >= player_state.tick_left.len() {
+        play_tick(song, player_state);
+    }
+    let pos = player_state.current_vblank_sample as usize;
+    player_state.current_vblank_sample += 1;
+    (player_state.tick_left[pos], player_state.tick_right[pos])
 }
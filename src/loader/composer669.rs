@@ -0,0 +1,352 @@
+//! Composer 669 and UNIS 669 modules
+//!
+//! The songs have eight channels and up to 64 samples. Each pattern has its own speed and ends at its break line, both
+//! given in the header. Notes are three bytes: the note and sample, the volume ( 0-15 ) and an effect whose parameter
+//! is a nibble. Samples are 8 bit unsigned.
+use super::super::{
+    latin1_string, Effect, FormatDescription, FormatKind, ModError, Note, Pattern, Sample, Song,
+    DEFAULT_C2SPD,
+};
+use super::{fit_loop, read_bytes, read_u32};
+
+const HEADER_SIZE: usize = 0x1f1;
+const SAMPLE_HEADER_SIZE: usize = 25;
+const NUM_CHANNELS: usize = 8;
+const PATTERN_SIZE: usize = 64 * NUM_CHANNELS * 3;
+// The order list ends at the first end marker
+const ORDER_END: u8 = 0xff;
+// Note bytes with no note, and with only a volume
+const NO_NOTE: u8 = 0xff;
+const VOLUME_ONLY: u8 = 0xfe;
+const NO_EFFECT: u8 = 0xff;
+
+/// Is the data a Composer 669 or UNIS 669 module. The magic is only two letters so the header is checked as well
+pub(crate) fn is_669(file_data: &[u8]) -> bool {
+    let header = match file_data.get(0..HEADER_SIZE) {
+        Some(header) => header,
+        None => return false,
+    };
+    (header.starts_with(b"if") || header.starts_with(b"JN"))
+        && header[110] <= 64
+        && header[111] <= 128
+        && header[112] < 128
+        && header[113..241]
+            .iter()
+            .all(|order| *order == ORDER_END || *order < header[111])
+        && header[369..497].iter().all(|line| *line < 64)
+}
+
+/// Decodes a 669 effect. The UNIS 669 pan slide and retrigger are not played
+pub(crate) fn decode_effect(command: u8, parameter: u8) -> Effect {
+    match command {
+        0 => Effect::SlideUp { speed: parameter },
+        1 => Effect::SlideDown { speed: parameter },
+        2 => Effect::TonePortamento { speed: parameter },
+        3 => Effect::FinePortaUp {
+            period_change: parameter,
+        },
+        4 => Effect::Vibrato {
+            speed: 8,
+            amplitude: parameter,
+        },
+        5 => Effect::SetTicksPerLine { ticks: parameter },
+        _ => Effect::None,
+    }
+}
+
+/// Is the effect a slide, which keeps going until the channel has another effect
+fn is_slide(effect: &Effect) -> bool {
+    matches!(
+        effect,
+        Effect::SlideUp { .. } | Effect::SlideDown { .. } | Effect::TonePortamento { .. }
+    )
+}
+
+/// Reads a pattern. Slides are repeated on the following lines of their channel up to the next effect or the end of the
+/// pattern
+fn read_pattern(data: &[u8], num_lines: usize) -> Pattern {
+    let mut pattern = Pattern::with_lines(num_lines);
+    let mut slides: [Option<(u8, u8, Effect)>; NUM_CHANNELS] = [None; NUM_CHANNELS];
+    for (line_num, line) in pattern.lines.iter_mut().enumerate() {
+        for (channel, slide) in slides.iter_mut().enumerate() {
+            let offset = (line_num * NUM_CHANNELS + channel) * 3;
+            let bytes = &data[offset..offset + 3];
+            let mut note = Note::empty();
+            if bytes[0] < VOLUME_ONLY {
+                note.key = (bytes[0] >> 2) + 25;
+                note.sample_number = ((bytes[0] & 3) << 4 | bytes[1] >> 4) + 1;
+            }
+            if bytes[0] != NO_NOTE {
+                note.volume = Some(((bytes[1] & 0x0f) as u32 * 64 / 15) as u8);
+            }
+            if bytes[2] != NO_EFFECT {
+                note.effect_command = bytes[2] >> 4;
+                note.effect_parameter = bytes[2] & 0x0f;
+                note.effect = decode_effect(note.effect_command, note.effect_parameter);
+                *slide = if is_slide(&note.effect) {
+                    Some((note.effect_command, note.effect_parameter, note.effect))
+                } else {
+                    None
+                };
+            } else if let Some((command, parameter, effect)) = *slide {
+                note.effect_command = command;
+                note.effect_parameter = parameter;
+                note.effect = effect;
+            }
+            line.push(note);
+        }
+    }
+    pattern
+}
+
+/// Reads a Composer 669 or UNIS 669 module
+pub(crate) fn read_669(file_data: &[u8]) -> Result<Song, ModError> {
+    let header = read_bytes(file_data, 0, HEADER_SIZE)?;
+    let num_samples = header[110] as usize;
+    let num_patterns = header[111] as usize;
+    let speeds = &header[241..369];
+    let breaks = &header[369..497];
+
+    let mut offset = HEADER_SIZE;
+    let mut samples = Vec::new();
+    for _ in 0..num_samples {
+        let sample_header = read_bytes(file_data, offset, SAMPLE_HEADER_SIZE)?;
+        let size = read_u32(sample_header, 13)?;
+        let loop_start = read_u32(sample_header, 17)?;
+        let loop_end = read_u32(sample_header, 21)?;
+        // samples without a loop have a loop end past the end of the sample
+        let loops = loop_end <= size && loop_end > loop_start;
+        samples.push(Sample {
+            name: latin1_string(&sample_header[0..13]),
+            size,
+            volume: 64,
+            fine_tune: 0,
+            repeat_offset: if loops { loop_start } else { 0 },
+            repeat_size: if loops { loop_end - loop_start } else { 0 },
+            c2spd: DEFAULT_C2SPD,
+            relative_pitch: 0,
+            pan: None,
+            sustain_loop: None,
+            samples: Vec::new(),
+        });
+        offset += SAMPLE_HEADER_SIZE;
+    }
+
+    let mut patterns = Vec::new();
+    for pattern_num in 0..num_patterns {
+        let data = read_bytes(file_data, offset, PATTERN_SIZE)?;
+        let mut pattern = read_pattern(data, breaks[pattern_num] as usize + 1);
+        // the speed is set at the start of each pattern by the first channel without an effect
+        if let Some(note) = pattern.lines[0]
+            .iter_mut()
+            .find(|note| note.effect == Effect::None)
+        {
+            note.effect = Effect::SetTicksPerLine {
+                ticks: speeds[pattern_num].max(1),
+            };
+        }
+        patterns.push(pattern);
+        offset += PATTERN_SIZE;
+    }
+
+    // The last sample is often cut short. Play what there is of it
+    for sample in &mut samples {
+        let end = (offset + sample.size as usize).min(file_data.len());
+        sample.samples = file_data
+            .get(offset..end)
+            .unwrap_or(&[])
+            .iter()
+            .map(|&byte| ((byte ^ 0x80) as i8 as i16) << 8)
+            .collect();
+        offset += sample.size as usize;
+        fit_loop(sample);
+    }
+
+    let pattern_table: Vec<u8> = header[113..241]
+        .iter()
+        .take_while(|order| **order != ORDER_END)
+        .copied()
+        .collect();
+    if pattern_table.is_empty() {
+        return Err(ModError::BadHeader(String::from(
+            "the order list does not play any patterns",
+        )));
+    }
+
+    // the channels alternate between the left and right
+    let channel_pans = (0..NUM_CHANNELS)
+        .map(|channel| if channel % 2 == 0 { 0x30 } else { 0xd0 })
+        .map(|pan| pan as f32 / 255.0 * 2.0 - 1.0)
+        .collect();
+    let initial_speed = speeds[pattern_table[0] as usize].max(1) as u32;
+    let num_used_patterns = pattern_table.len() as u32;
+    Ok(Song {
+        // the first line of the song message is used as the name
+        name: latin1_string(&header[2..38]),
+        format: FormatDescription {
+            kind: FormatKind::Composer669,
+            num_channels: NUM_CHANNELS as u32,
+            num_samples: num_samples as u32,
+            has_tag: true,
+            tag: latin1_string(&header[0..2]),
+            has_linear_periods: false,
+        },
+        samples,
+        instruments: Vec::new(),
+        patterns,
+        pattern_table,
+        num_used_patterns,
+        end_position: header[112] as u32,
+        has_standard_notes: false,
+        initial_speed,
+        initial_tempo: 78,
+        initial_global_volume: 64,
+        channel_pans,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::{update_tick, PlayerState};
+    use super::*;
+
+    // A song with one 8 byte sample that plays the patterns given in order. Each pattern has a speed, the line it
+    // breaks after and the notes ( line, channel and the three note bytes ) in it
+    fn build_669(patterns: &[(u8, u8, &[(usize, usize, [u8; 3])])]) -> Vec<u8> {
+        let mut data = vec![0u8; HEADER_SIZE];
+        data[0..2].copy_from_slice(b"if");
+        data[2..6].copy_from_slice(b"test");
+        data[110] = 1;
+        data[111] = patterns.len() as u8;
+        for order in &mut data[113..241] {
+            *order = ORDER_END;
+        }
+        for (pattern_num, (speed, break_line, _)) in patterns.iter().enumerate() {
+            data[113 + pattern_num] = pattern_num as u8;
+            data[241 + pattern_num] = *speed;
+            data[369 + pattern_num] = *break_line;
+        }
+        let mut sample = vec![0u8; SAMPLE_HEADER_SIZE];
+        sample[0..6].copy_from_slice(b"sample");
+        sample[13] = 8;
+        sample[17] = 2;
+        sample[21] = 6;
+        data.extend(sample);
+        for (_, _, notes) in patterns {
+            let mut pattern = vec![0u8; PATTERN_SIZE];
+            for (index, byte) in pattern.iter_mut().enumerate() {
+                *byte = if index % 3 == 2 { NO_EFFECT } else { NO_NOTE };
+            }
+            for (line, channel, bytes) in notes.iter() {
+                let offset = (line * NUM_CHANNELS + channel) * 3;
+                pattern[offset..offset + 3].copy_from_slice(bytes);
+            }
+            data.extend(pattern);
+        }
+        data.extend(&[0x80, 0x90, 0xa0, 0xb0, 0xc0, 0xd0, 0xe0, 0xf0]);
+        data
+    }
+
+    // C-4 ( note 24 ) with sample 0 and volume 15
+    const C4: [u8; 3] = [24 << 2, 0x0f, NO_EFFECT];
+
+    #[test]
+    fn test_read_669() {
+        // line 1 slides up on channel 1
+        let data = build_669(&[(3, 1, &[(0, 0, C4), (1, 1, [NO_NOTE, 0, 0x02])])]);
+        assert!(is_669(&data));
+        let song = read_669(&data).unwrap();
+        assert_eq!(song.format.kind, FormatKind::Composer669);
+        assert_eq!(song.name.trim_end_matches('\0'), "test");
+        assert_eq!(song.pattern_table, vec![0]);
+        assert_eq!(song.initial_speed, 3);
+
+        let sample = &song.samples[0];
+        assert_eq!(sample.name().trim_end_matches('\0'), "sample");
+        assert_eq!(sample.samples()[0..2], [0, 0x10 << 8]);
+        assert_eq!((sample.repeat_offset(), sample.repeat_size()), (2, 4));
+
+        let pattern = &song.patterns[0];
+        assert_eq!(pattern.num_lines(), 2);
+        let note = pattern.note(0, 0).unwrap();
+        assert_eq!((note.key(), note.sample_number()), (49, 1));
+        assert_eq!(note.volume(), Some(64));
+        assert_eq!(*note.effect(), Effect::SetTicksPerLine { ticks: 3 });
+        assert_eq!(
+            *pattern.note(1, 1).unwrap().effect(),
+            Effect::SlideUp { speed: 2 }
+        );
+        assert_eq!(pattern.note(1, 2).unwrap().volume(), None);
+    }
+
+    #[test]
+    fn test_pattern_speeds_and_breaks() {
+        let song = read_669(&build_669(&[(3, 1, &[(0, 0, C4)]), (5, 2, &[])])).unwrap();
+        let mut player_state = PlayerState::new(song.format.num_channels, 44100);
+        // the position and tick of the lines played
+        let mut lines = Vec::new();
+        let mut tick = 0;
+        while lines.len() < 6 {
+            if player_state.is_line_due() {
+                lines.push((player_state.next_line_position(&song), tick));
+            }
+            update_tick(&song, &mut player_state);
+            tick += 1;
+        }
+        assert_eq!(
+            lines,
+            vec![
+                ((0, 0), 3),
+                ((0, 1), 6),
+                ((1, 0), 9),
+                ((1, 1), 14),
+                ((1, 2), 19),
+                ((0, 0), 24)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_slides_keep_going() {
+        let slide_up = [NO_NOTE, 0, 0x04];
+        let data = build_669(&[(
+            3,
+            5,
+            &[
+                (0, 0, C4),
+                (1, 0, slide_up),
+                // a note does not stop the slide but another effect does
+                (3, 0, C4),
+                (4, 0, [NO_NOTE, 0, 0x33]),
+            ],
+        )]);
+        let song = read_669(&data).unwrap();
+        let effects: Vec<Effect> = (1..6)
+            .map(|line| *song.patterns[0].note(line, 0).unwrap().effect())
+            .collect();
+        assert_eq!(
+            effects,
+            vec![
+                Effect::SlideUp { speed: 4 },
+                Effect::SlideUp { speed: 4 },
+                Effect::SlideUp { speed: 4 },
+                Effect::FinePortaUp { period_change: 3 },
+                Effect::None
+            ]
+        );
+
+        // the period at the end of lines 0 to 3
+        let mut player_state = PlayerState::new(song.format.num_channels, 44100);
+        let mut periods = Vec::new();
+        for tick in 0..15 {
+            update_tick(&song, &mut player_state);
+            if tick >= 5 && tick % 3 == 2 {
+                periods.push(player_state.channels[0].period);
+            }
+        }
+        let slide = 2 * 4 * FormatKind::Composer669.period_scale() as u32;
+        assert_eq!(periods[0..2], [1712, 1712 - slide]);
+        assert!(periods[2] < periods[1]);
+        assert_eq!(periods[3], 1712 - slide);
+    }
+}
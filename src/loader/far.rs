@@ -0,0 +1,357 @@
+//! Farandole Composer (FAR) modules
+//!
+//! The songs have 16 channels and up to 64 samples. Each pattern has its own length and a break line where it ends.
+//! Notes are four bytes: the note, sample, volume ( 0-15 ) and an effect whose parameter is a nibble. Only the samples
+//! that are used are stored, marked by a bit map in front of them. Samples are 8 or 16 bit signed.
+use super::super::{
+    latin1_string, Effect, FormatDescription, FormatKind, ModError, Note, Pattern, Sample, Song,
+    DEFAULT_C2SPD,
+};
+use super::{fit_loop, read_bytes, read_u16, read_u32};
+
+const MAGIC: &[u8] = b"FAR\xfe";
+const HEADER_SIZE: usize = 98;
+const ORDER_HEADER_SIZE: usize = 256 + 3 + 256 * 2;
+const SAMPLE_HEADER_SIZE: usize = 48;
+const NUM_CHANNELS: usize = 16;
+const MAX_SAMPLES: usize = 64;
+// Farandole Composer's default tempo. The speed sets the ticks per line
+const TEMPO: u32 = 80;
+
+// Sample flags
+const SAMPLE_16_BIT: u8 = 1;
+const SAMPLE_LOOPS: u8 = 8;
+
+/// Is the data a Farandole Composer module
+pub(crate) fn is_far(file_data: &[u8]) -> bool {
+    file_data.get(0..4) == Some(MAGIC) && file_data.get(44..47) == Some(b"\r\n\x1a")
+}
+
+/// Decodes a Farandole Composer effect. The vibrato effects set either the depth or the speed. The sustained vibrato,
+/// volume portamento, note offset and fine tempo effects are not played
+pub(crate) fn decode_effect(command: u8, parameter: u8) -> Effect {
+    match command {
+        1 => Effect::FinePortaUp {
+            period_change: parameter,
+        },
+        2 => Effect::FinePortaDown {
+            period_change: parameter,
+        },
+        3 => Effect::TonePortamento {
+            speed: parameter << 2,
+        },
+        // the note plays 1 + x times a line
+        4 => Effect::RetriggerSample {
+            retrigger_delay: 6 / (1 + parameter) + 1,
+        },
+        5 => Effect::Vibrato {
+            speed: 0,
+            amplitude: parameter,
+        },
+        6 => Effect::Vibrato {
+            speed: parameter,
+            amplitude: 0,
+        },
+        7 => Effect::VolumeSlide {
+            volume_change: parameter as i8,
+        },
+        8 => Effect::VolumeSlide {
+            volume_change: -(parameter as i8),
+        },
+        11 => Effect::CoarsePan { pan_pos: parameter },
+        15 if parameter > 0 => Effect::SetTicksPerLine { ticks: parameter },
+        _ => Effect::None,
+    }
+}
+
+fn read_note(bytes: &[u8]) -> Note {
+    let mut note = Note::empty();
+    if bytes[0] > 0 {
+        note.key = bytes[0] + 24;
+        note.sample_number = bytes[1] + 1;
+    }
+    if (1..=16).contains(&bytes[2]) {
+        note.volume = Some(((bytes[2] - 1) as u32 * 64 / 15) as u8);
+    }
+    note.effect_command = bytes[3] >> 4;
+    note.effect_parameter = bytes[3] & 0x0f;
+    note.effect = decode_effect(note.effect_command, note.effect_parameter);
+    note
+}
+
+/// Reads a pattern, which ends after its break line
+fn read_pattern(data: &[u8]) -> Pattern {
+    let num_lines = (data.len() - 2) / (NUM_CHANNELS * 4);
+    let break_line = data[0] as usize;
+    // the break line is one less than the last line played
+    let num_lines = if break_line > 0 && break_line + 2 < num_lines {
+        break_line + 2
+    } else {
+        num_lines
+    };
+    let mut pattern = Pattern::with_lines(num_lines);
+    for (line_num, line) in pattern.lines.iter_mut().enumerate() {
+        for channel in 0..NUM_CHANNELS {
+            let offset = 2 + (line_num * NUM_CHANNELS + channel) * 4;
+            line.push(read_note(&data[offset..offset + 4]));
+        }
+    }
+    pattern
+}
+
+fn read_sample(header: &[u8]) -> Result<Sample, ModError> {
+    let bytes_per_point = if header[46] & SAMPLE_16_BIT != 0 {
+        2
+    } else {
+        1
+    };
+    let loop_start = read_u32(header, 38)? / bytes_per_point;
+    let loop_end = read_u32(header, 42)? / bytes_per_point;
+    let loops = header[47] & SAMPLE_LOOPS != 0 && loop_end > loop_start;
+    Ok(Sample {
+        name: latin1_string(&header[0..32]),
+        size: read_u32(header, 32)?,
+        volume: (header[37] as u32 * 4).min(64) as u8,
+        fine_tune: 0,
+        repeat_offset: if loops { loop_start } else { 0 },
+        repeat_size: if loops { loop_end - loop_start } else { 0 },
+        c2spd: DEFAULT_C2SPD,
+        relative_pitch: 0,
+        pan: None,
+        sustain_loop: None,
+        samples: Vec::new(),
+    })
+}
+
+fn empty_sample() -> Sample {
+    Sample {
+        name: String::new(),
+        size: 0,
+        volume: 0,
+        fine_tune: 0,
+        repeat_offset: 0,
+        repeat_size: 0,
+        c2spd: DEFAULT_C2SPD,
+        relative_pitch: 0,
+        pan: None,
+        sustain_loop: None,
+        samples: Vec::new(),
+    }
+}
+
+/// Reads a Farandole Composer module
+pub(crate) fn read_far(file_data: &[u8]) -> Result<Song, ModError> {
+    let header = read_bytes(file_data, 0, HEADER_SIZE)?;
+    let header_length = read_u16(header, 47)? as usize;
+    let message_length = read_u16(header, 96)? as usize;
+    let channel_pans = header[76..76 + NUM_CHANNELS]
+        .iter()
+        .map(|pan| (pan & 0x0f) as f32 / 15.0 * 2.0 - 1.0)
+        .collect();
+
+    let order_header = read_bytes(file_data, HEADER_SIZE + message_length, ORDER_HEADER_SIZE)?;
+    let song_length = order_header[257] as usize;
+    let restart = order_header[258] as u32;
+
+    // the patterns follow the header, which can be longer than the parts read here
+    let mut offset = header_length.max(HEADER_SIZE + message_length + ORDER_HEADER_SIZE);
+    let mut patterns = Vec::new();
+    for pattern_num in 0..256 {
+        let size = read_u16(order_header, 259 + pattern_num * 2)? as usize;
+        if size == 0 {
+            patterns.push(Pattern::with_lines(0));
+            continue;
+        }
+        if size < 2 + NUM_CHANNELS * 4 {
+            return Err(ModError::BadHeader(format!(
+                "pattern {} is {} bytes, too short to hold a line",
+                pattern_num, size
+            )));
+        }
+        patterns.push(read_pattern(read_bytes(file_data, offset, size)?));
+        offset += size;
+    }
+    // drop the unused patterns at the end
+    while patterns
+        .last()
+        .is_some_and(|pattern| pattern.num_lines() == 0)
+    {
+        patterns.pop();
+    }
+
+    let sample_map = read_bytes(file_data, offset, MAX_SAMPLES / 8)?;
+    offset += MAX_SAMPLES / 8;
+    let mut samples = Vec::new();
+    for sample_num in 0..MAX_SAMPLES {
+        if sample_map[sample_num / 8] & (1 << (sample_num % 8)) == 0 {
+            samples.push(empty_sample());
+            continue;
+        }
+        let sample_header = read_bytes(file_data, offset, SAMPLE_HEADER_SIZE)?;
+        offset += SAMPLE_HEADER_SIZE;
+        let mut sample = read_sample(sample_header)?;
+        // The last sample is often cut short. Play what there is of it
+        let end = (offset + sample.size as usize).min(file_data.len());
+        let data = file_data.get(offset..end).unwrap_or(&[]);
+        sample.samples = if sample_header[46] & SAMPLE_16_BIT != 0 {
+            data.chunks_exact(2)
+                .map(|point| i16::from_le_bytes([point[0], point[1]]))
+                .collect()
+        } else {
+            data.iter().map(|&byte| (byte as i8 as i16) << 8).collect()
+        };
+        offset += sample.size as usize;
+        fit_loop(&mut sample);
+        samples.push(sample);
+    }
+    while samples.last().is_some_and(|sample| sample.size == 0) {
+        samples.pop();
+    }
+
+    let pattern_table: Vec<u8> = order_header[0..song_length.min(256)]
+        .iter()
+        .filter(|order| {
+            patterns
+                .get(**order as usize)
+                .is_some_and(|pattern| pattern.num_lines() > 0)
+        })
+        .copied()
+        .collect();
+    if pattern_table.is_empty() {
+        return Err(ModError::BadHeader(String::from(
+            "the order list does not play any patterns",
+        )));
+    }
+
+    let num_used_patterns = pattern_table.len() as u32;
+    let num_samples = samples.len() as u32;
+    Ok(Song {
+        name: latin1_string(&header[4..44]),
+        format: FormatDescription {
+            kind: FormatKind::Far,
+            num_channels: NUM_CHANNELS as u32,
+            num_samples,
+            has_tag: true,
+            tag: String::from("FAR"),
+            has_linear_periods: false,
+        },
+        samples,
+        instruments: Vec::new(),
+        patterns,
+        pattern_table,
+        num_used_patterns,
+        end_position: restart.min(num_used_patterns - 1),
+        has_standard_notes: false,
+        initial_speed: header[75].max(1) as u32,
+        initial_tempo: TEMPO,
+        initial_global_volume: 64,
+        channel_pans,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::update_tick;
+    use super::super::tests::play_ticks;
+    use super::*;
+
+    // A song with one 8 byte sample ( sample 2, sample 1 is not stored ) and one pattern of 4 lines with the break line
+    // and notes ( line, channel and the four note bytes ) given
+    fn build_far(break_line: u8, notes: &[(usize, usize, [u8; 4])]) -> Vec<u8> {
+        let mut data = vec![0u8; HEADER_SIZE];
+        data[0..4].copy_from_slice(MAGIC);
+        data[4..8].copy_from_slice(b"test");
+        data[44..47].copy_from_slice(b"\r\n\x1a");
+        let header_length = (HEADER_SIZE + 4 + ORDER_HEADER_SIZE) as u16;
+        data[47..49].copy_from_slice(&header_length.to_le_bytes());
+        data[75] = 4;
+        data[77] = 15;
+        data[96] = 4;
+        data.extend(b"note");
+        let mut order_header = vec![0u8; ORDER_HEADER_SIZE];
+        order_header[256] = 1;
+        order_header[257] = 1;
+        let pattern_size = (2 + 4 * NUM_CHANNELS * 4) as u16;
+        order_header[259..261].copy_from_slice(&pattern_size.to_le_bytes());
+        data.extend(order_header);
+        let mut pattern = vec![0u8; pattern_size as usize];
+        pattern[0] = break_line;
+        for (line, channel, bytes) in notes {
+            let offset = 2 + (line * NUM_CHANNELS + channel) * 4;
+            pattern[offset..offset + 4].copy_from_slice(bytes);
+        }
+        data.extend(pattern);
+        data.extend(&[0b10, 0, 0, 0, 0, 0, 0, 0]);
+        let mut sample = vec![0u8; SAMPLE_HEADER_SIZE];
+        sample[0..6].copy_from_slice(b"sample");
+        sample[32] = 8;
+        sample[37] = 15;
+        sample[38] = 2;
+        sample[42] = 6;
+        sample[47] = SAMPLE_LOOPS;
+        data.extend(sample);
+        data.extend(&[0, 16, 32, 48, 64, 80, 96, 112]);
+        data
+    }
+
+    #[test]
+    fn test_read_far() {
+        // the pattern breaks after line 1. Line 0 plays C-4 with the sample at full volume on channel 0 and line 1 slides
+        // the volume down on channel 1
+        let data = build_far(1, &[(0, 0, [25, 1, 16, 0]), (1, 1, [0, 0, 0, 0x82])]);
+        assert!(is_far(&data));
+        let song = read_far(&data).unwrap();
+        assert_eq!(song.format.kind, FormatKind::Far);
+        assert_eq!(song.name.trim_end_matches('\0'), "test");
+        assert_eq!(song.pattern_table, vec![0]);
+        assert_eq!((song.initial_speed, song.initial_tempo), (4, TEMPO));
+        assert_eq!(song.channel_pans[0..2], [-1.0, 1.0]);
+
+        assert_eq!(song.samples.len(), 2);
+        assert_eq!(song.samples[0].size(), 0);
+        let sample = &song.samples[1];
+        assert_eq!(sample.name().trim_end_matches('\0'), "sample");
+        assert_eq!(sample.volume(), 60);
+        assert_eq!(sample.samples()[0..2], [0, 16 << 8]);
+        assert_eq!((sample.repeat_offset(), sample.repeat_size()), (2, 4));
+
+        let pattern = &song.patterns[0];
+        assert_eq!(pattern.num_lines(), 3);
+        let note = pattern.note(0, 0).unwrap();
+        assert_eq!((note.key(), note.sample_number()), (49, 2));
+        assert_eq!(note.volume(), Some(64));
+        assert_eq!(
+            *pattern.note(1, 1).unwrap().effect(),
+            Effect::VolumeSlide { volume_change: -2 }
+        );
+    }
+
+    #[test]
+    fn test_effects() {
+        let song = read_far(&build_far(
+            0,
+            &[
+                // C-4 and a speed of 2
+                (0, 0, [25, 1, 16, 0xf2]),
+                // a fine slide up and the note played 3 times
+                (1, 0, [0, 0, 0, 0x13]),
+                (1, 1, [25, 1, 16, 0x42]),
+                // the vibrato depth and then the vibrato speed
+                (2, 0, [0, 0, 0, 0x54]),
+                (3, 0, [0, 0, 0, 0x63]),
+            ],
+        ))
+        .unwrap();
+        // the speed of 4 from the header plays line 0 on tick 4, then a line is played every 2 ticks
+        let mut player_state = play_ticks(&song, 7);
+        let period_change = 3 * FormatKind::Far.period_scale() as u32;
+        assert_eq!(player_state.channels[0].period, 1712 - period_change);
+        assert_eq!(player_state.channels[1].retrigger_delay, 3);
+        for _tick in 0..4 {
+            update_tick(&song, &mut player_state);
+        }
+        let channel = &player_state.channels[0];
+        assert_eq!((channel.vibrato_speed, channel.vibrato_depth), (3, 4 * 4));
+        assert_eq!(channel.period, 1712 - period_change);
+    }
+}
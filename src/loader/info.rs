@@ -0,0 +1,197 @@
+//! Reading the description of a module without loading it
+//!
+//! MOD files are described from their header alone: the sample sizes are in the sample headers and the number of
+//! patterns follows from the size of the file. S3M, XM and IT modules are described from the headers their pointers lead
+//! to and the sample data is skipped. IT patterns are read to count the channels they use. The other formats can not
+//! be described without loading them.
+use super::super::{latin1_string, FormatKind, ModError, Sample};
+use super::{detect_format, it, mod_tag_channel_count, read_bytes, s3m, xm};
+use std::io::{Read, Seek, SeekFrom};
+
+// The size of a MOD header with 31 samples and a tag. It holds everything that is checked to detect any of the formats
+const MOD_HEADER_SIZE: u64 = 1084;
+
+/// The description of a module, read without loading its samples
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModInfo {
+    /// The name of the song
+    pub name: String,
+    /// The file format of the module
+    pub format: FormatKind,
+    pub num_channels: u32,
+    /// The names of the samples. Trackers often use sample names for messages
+    pub sample_names: Vec<String>,
+    /// The size of each sample in sample points, as given by the sample headers
+    pub sample_sizes: Vec<u32>,
+    /// The number of positions in the song's order list
+    pub num_orders: u32,
+    /// The number of patterns stored in the file
+    pub num_patterns: u32,
+}
+
+/// Reads `len` bytes at `offset`
+pub(super) fn read_at<R: Read + Seek>(
+    reader: &mut R,
+    offset: usize,
+    len: usize,
+) -> Result<Vec<u8>, ModError> {
+    reader.seek(SeekFrom::Start(offset as u64))?;
+    let mut data = Vec::new();
+    reader.by_ref().take(len as u64).read_to_end(&mut data)?;
+    if data.len() < len {
+        return Err(ModError::Truncated { offset, len });
+    }
+    Ok(data)
+}
+
+/// Describes a MOD file from its header and the size of the file. The checks match the ones made when loading it
+fn read_mod_header(header: &[u8], file_size: usize) -> Result<ModInfo, ModError> {
+    let tag = header.get(1080..1084).unwrap_or(&[]);
+    let (format, num_channels, num_samples) = match mod_tag_channel_count(tag) {
+        Some(num_channels) => (FormatKind::Mod, num_channels, 31),
+        None => (FormatKind::Soundtracker, 4, 15),
+    };
+    let samples: Vec<Sample> = (0..num_samples)
+        .map(|sample_num| read_bytes(header, 20 + sample_num * 30, 30).map(Sample::new))
+        .collect::<Result<_, _>>()?;
+    let mut offset = 20 + num_samples * 30;
+    let num_orders = read_bytes(header, offset, 1)?[0] as usize;
+    if num_orders == 0 || num_orders > 128 {
+        return Err(ModError::BadHeader(format!(
+            "song length {} is not in the range 1-128",
+            num_orders
+        )));
+    }
+    let pattern_table = read_bytes(header, offset + 2, 128)?;
+    offset += 2 + 128;
+    if format == FormatKind::Mod {
+        offset += 4;
+    }
+
+    let total_sample_size: usize = samples.iter().map(|sample| sample.size as usize).sum();
+    if offset + total_sample_size > file_size {
+        return Err(ModError::InconsistentSizes {
+            required: offset + total_sample_size,
+            available: file_size,
+        });
+    }
+    // the patterns take up the space that the samples do not, but there are at least as many as the song plays
+    let pattern_size = num_channels as usize * 4 * 64;
+    let last_pattern = *pattern_table[0..num_orders].iter().max().unwrap() as usize;
+    // FLT8 files refer to the first of the two 4 channel patterns that make up each 8 channel pattern
+    let min_patterns = if tag == b"FLT8" {
+        last_pattern / 2 + 1
+    } else {
+        last_pattern + 1
+    };
+    let num_patterns = ((file_size - offset - total_sample_size) / pattern_size).max(min_patterns);
+
+    Ok(ModInfo {
+        name: latin1_string(&header[0..20]),
+        format,
+        num_channels,
+        sample_names: samples.iter().map(|sample| sample.name.clone()).collect(),
+        sample_sizes: samples.iter().map(|sample| sample.size).collect(),
+        num_orders: num_orders as u32,
+        num_patterns: num_patterns as u32,
+    })
+}
+
+/// Reads the description of a module: its name, format, sample names and sizes and the number of orders and patterns.
+/// Only the headers are read, which makes it quicker than loading the song when indexing collections of modules
+///
+/// # Arguments
+/// * `reader` - the module data, from its start
+///
+/// # Errors
+/// Returns `ModError::UnsupportedTag` for formats other than MOD, Soundtracker, S3M, XM and IT
+///
+pub fn read_mod_info<R: Read + Seek>(reader: &mut R) -> Result<ModInfo, ModError> {
+    let file_size = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(0))?;
+    let mut file_data = Vec::new();
+    reader
+        .by_ref()
+        .take(MOD_HEADER_SIZE)
+        .read_to_end(&mut file_data)?;
+    match detect_format(&file_data) {
+        FormatKind::Mod | FormatKind::Soundtracker => {
+            read_mod_header(&file_data, file_size as usize)
+        }
+        FormatKind::S3m => s3m::read_s3m_info(reader),
+        FormatKind::Xm => xm::read_xm_info(reader),
+        FormatKind::It => it::read_it_info(reader),
+        kind => Err(ModError::UnsupportedTag(format!(
+            "{:?} modules can not be described without loading them",
+            kind
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::Song;
+    use super::super::try_read_mod_file_slice;
+    use super::*;
+    use std::fs;
+    use std::io::Cursor;
+
+    fn song_info(song: &Song) -> ModInfo {
+        ModInfo {
+            name: song.name.clone(),
+            format: song.format.kind,
+            num_channels: song.format.num_channels,
+            sample_names: song
+                .samples
+                .iter()
+                .map(|sample| sample.name.clone())
+                .collect(),
+            sample_sizes: song.samples.iter().map(|sample| sample.size).collect(),
+            num_orders: song.num_used_patterns,
+            num_patterns: song.patterns.len() as u32,
+        }
+    }
+
+    #[test]
+    fn test_mod_info_matches_song() {
+        for file_name in &[
+            "BUBBLE_BOBBLE.MOD",
+            "bubble_bobble_flt8.mod",
+            "star-rai_cd61.mod",
+            "19xx.mod",
+        ] {
+            let file_data = fs::read(format!("mod_files/{}", file_name)).unwrap();
+            let song = try_read_mod_file_slice(&file_data).unwrap();
+            let info = read_mod_info(&mut Cursor::new(&file_data)).unwrap();
+            assert_eq!(info, song_info(&song), "{}", file_name);
+        }
+    }
+
+    #[test]
+    fn test_mod_info_errors() {
+        let mut data = vec![0u8; 1084];
+        data[1080..1084].copy_from_slice(b"M.K.");
+        assert!(matches!(
+            read_mod_info(&mut Cursor::new(&data)),
+            Err(ModError::BadHeader(_))
+        ));
+        data[950] = 1;
+        // the first sample is bigger than the file
+        data[42] = 0x10;
+        assert!(matches!(
+            read_mod_info(&mut Cursor::new(&data)),
+            Err(ModError::InconsistentSizes { .. })
+        ));
+        assert!(matches!(
+            read_mod_info(&mut Cursor::new(&data[0..100])),
+            Err(ModError::Truncated { .. })
+        ));
+
+        let mut ult = vec![0u8; 1084];
+        ult[0..15].copy_from_slice(b"MAS_UTrack_V004");
+        assert!(matches!(
+            read_mod_info(&mut Cursor::new(&ult)),
+            Err(ModError::UnsupportedTag(_))
+        ));
+    }
+}
@@ -0,0 +1,1000 @@
+//! Impulse Tracker modules (IT)
+//!
+//! The header is followed by the order list and tables of offsets to the instruments, samples and patterns. Songs play their
+//! samples either directly or through instruments, which add a pitch or filter envelope, a resonant filter and new note
+//! actions to what XM instruments do. Sample data may be compressed with the IT214 or IT215 schemes.
+use super::super::instrument::{AutoVibrato, DuplicateCheck, Envelope, Instrument, NewNoteAction};
+use super::super::{
+    latin1_string, Effect, FormatDescription, FormatKind, ModError, Note, Pattern, Sample, Song,
+    KEY_CUT, KEY_FADE, KEY_OFF,
+};
+use super::info::{read_at, ModInfo};
+use super::{read_bytes, read_u16, read_u32, s3m};
+use std::io::{Read, Seek};
+
+const HEADER_SIZE: usize = 0xc0;
+const INSTRUMENT_SIZE: usize = 0x22a;
+const SAMPLE_HEADER_SIZE: usize = 0x50;
+const MAX_CHANNELS: usize = 64;
+const MAX_ENVELOPE_POINTS: usize = 25;
+// The channel pan that plays the channel on both sides
+const SURROUND_PAN: u8 = 100;
+
+// Header flags
+const STEREO: u16 = 1;
+const USES_INSTRUMENTS: u16 = 4;
+const LINEAR_SLIDES: u16 = 8;
+
+// Sample flags
+const SAMPLE_PRESENT: u8 = 1;
+const SAMPLE_16_BIT: u8 = 2;
+const SAMPLE_STEREO: u8 = 4;
+const SAMPLE_COMPRESSED: u8 = 8;
+const SAMPLE_LOOPS: u8 = 0x10;
+const SAMPLE_SUSTAIN_LOOPS: u8 = 0x20;
+const PING_PONG_LOOP: u8 = 0x40;
+
+// Sample conversion flags
+const SIGNED_SAMPLES: u8 = 1;
+const IT215_COMPRESSION: u8 = 4;
+
+// Envelope flags
+const ENVELOPE_ON: u8 = 1;
+const ENVELOPE_LOOP: u8 = 2;
+const ENVELOPE_SUSTAIN: u8 = 4;
+const FILTER_ENVELOPE: u8 = 0x80;
+
+/// Portamento speeds of the volume column's G effect
+const PORTAMENTO_SPEEDS: [u8; 10] = [0, 1, 4, 8, 16, 32, 64, 96, 128, 255];
+
+/// Is the data an Impulse Tracker module
+pub(crate) fn is_it(file_data: &[u8]) -> bool {
+    file_data.starts_with(b"IMPM")
+}
+
+/// The period of a note for a sample that plays C-5 ( key 61 ) at `c5speed` Hz. Linear periods fall by 64 for each
+/// semitone, Amiga periods are in quarters of an Amiga period
+pub(crate) fn note_period(key: u8, c5speed: u32, linear_periods: bool) -> u32 {
+    let semitones =
+        key.clamp(1, 120) as f32 - 61.0 + (c5speed.max(1) as f32 / 8363.0).log2() * 12.0;
+    let period = if linear_periods {
+        4608.0 - semitones * 64.0
+    } else {
+        1712.0 * (-semitones / 12.0).exp2()
+    };
+    (period.round() as u32).max(1)
+}
+
+/// The slot of the effect memory that an effect with a parameter of 0 uses. The volume slides share a slot, as do the
+/// portamento slides. The other effects with memory have their own
+pub(crate) fn effect_memory_slot(command: u8) -> Option<usize> {
+    match command {
+        4 | 11 | 12 => Some(4),
+        5 | 6 => Some(5),
+        9 | 10 | 15 | 16 | 17 | 18 | 23 => Some(command as usize),
+        _ => None,
+    }
+}
+
+// Slides up by the high nibble or, if it is 0, down by the low nibble
+fn slide(parameter: u8) -> i8 {
+    if parameter >> 4 != 0 {
+        (parameter >> 4) as i8
+    } else {
+        -((parameter & 0x0f) as i8)
+    }
+}
+
+fn note_action(action: u8) -> NewNoteAction {
+    match action {
+        1 => NewNoteAction::Continue,
+        2 => NewNoteAction::NoteOff,
+        3 => NewNoteAction::NoteFade,
+        _ => NewNoteAction::Cut,
+    }
+}
+
+/// Decodes an effect. The commands are numbered from 1 for A and follow Scream Tracker 3 where the trackers share an effect
+pub(crate) fn decode_effect(command: u8, parameter: u8) -> Effect {
+    let high = parameter >> 4;
+    let low = parameter & 0x0f;
+    match command {
+        // channel volume and panbrello are not supported
+        13 | 14 | 25 => Effect::None,
+        // pan slides move left with the high nibble, four pan steps for each step of the parameter
+        16 => Effect::PanSlide {
+            pan_change: -slide(parameter) * 4,
+        },
+        19 => match high {
+            0x7 => match low {
+                0..=2 => Effect::PastNoteAction {
+                    action: match low {
+                        0 => NewNoteAction::Cut,
+                        1 => NewNoteAction::NoteOff,
+                        _ => NewNoteAction::NoteFade,
+                    },
+                },
+                3..=6 => Effect::SetNewNoteAction {
+                    action: note_action(low - 3),
+                },
+                _ => Effect::None,
+            },
+            _ => s3m::decode_effect(command, parameter),
+        },
+        // the global volume goes up to 128
+        22 => Effect::SetGlobalVolume {
+            volume: parameter.min(128) / 2,
+        },
+        23 => Effect::GlobalVolumeSlide {
+            volume_change: slide(parameter),
+        },
+        24 => Effect::Pan {
+            position: parameter,
+        },
+        // the default MIDI macros set the filter cutoff with Z00-Z7F and the resonance with Z80-Z8F
+        26 => match parameter {
+            0..=0x7f => Effect::SetFilterCutoff { cutoff: parameter },
+            0x80..=0x8f => Effect::SetFilterResonance {
+                resonance: (parameter & 0x0f) * 8,
+            },
+            _ => Effect::None,
+        },
+        _ => s3m::decode_effect(command, parameter),
+    }
+}
+
+// Decodes the volume column. Values up to 64 set the volume and values from 128 to 192 the pan, the rest are effects
+fn decode_volume_column(value: u8) -> (Option<u8>, Effect) {
+    let effect = match value {
+        0..=64 => return (Some(value), Effect::None),
+        65..=74 => Effect::FineVolumeSlideUp {
+            volume_change: value - 65,
+        },
+        75..=84 => Effect::FineVolumeSlideDown {
+            volume_change: value - 75,
+        },
+        85..=94 => Effect::VolumeSlide {
+            volume_change: (value - 85) as i8,
+        },
+        95..=104 => Effect::VolumeSlide {
+            volume_change: -((value - 95) as i8),
+        },
+        105..=114 => Effect::SlideDown {
+            speed: (value - 105) * 4,
+        },
+        115..=124 => Effect::SlideUp {
+            speed: (value - 115) * 4,
+        },
+        128..=192 => Effect::Pan {
+            position: ((value - 128) as u32 * 255 / 64) as u8,
+        },
+        193..=202 => Effect::TonePortamento {
+            speed: PORTAMENTO_SPEEDS[(value - 193) as usize],
+        },
+        203..=212 => Effect::Vibrato {
+            speed: 0,
+            amplitude: value - 203,
+        },
+        _ => Effect::None,
+    };
+    (None, effect)
+}
+
+// Patterns without data are 64 empty lines
+fn empty_pattern() -> Pattern {
+    let mut pattern = Pattern::new();
+    for line in &mut pattern.lines {
+        line.resize_with(MAX_CHANNELS, Note::empty);
+    }
+    pattern
+}
+
+// Reads a pattern for all 64 channels. Returns the pattern and the number of channels up to the last one the pattern uses
+fn read_pattern(file_data: &[u8], offset: usize) -> Result<(Pattern, usize), ModError> {
+    let packed_size = read_u16(file_data, offset)? as usize;
+    let num_lines = (read_u16(file_data, offset + 2)? as usize).clamp(1, 200);
+    let data = read_bytes(file_data, offset + 8, packed_size)?;
+    let mut pattern = Pattern::with_lines(num_lines);
+    for line in &mut pattern.lines {
+        line.resize_with(MAX_CHANNELS, Note::empty);
+    }
+
+    // each channel remembers which columns it last had and their values, so that repeated values can be left out
+    let mut masks = [0u8; MAX_CHANNELS];
+    let mut last_values = [[0u8; 5]; MAX_CHANNELS];
+    let mut num_channels = 0;
+    let mut position = 0;
+    let mut next_byte = || -> Result<u8, ModError> {
+        let byte = data.get(position).copied().ok_or(ModError::Truncated {
+            offset: offset + 8 + position,
+            len: 1,
+        });
+        position += 1;
+        byte
+    };
+    for line in &mut pattern.lines {
+        loop {
+            let channel_byte = next_byte()?;
+            if channel_byte == 0 {
+                break;
+            }
+            let channel = (channel_byte as usize - 1) & (MAX_CHANNELS - 1);
+            if channel_byte & 0x80 != 0 {
+                masks[channel] = next_byte()?;
+            }
+            let mask = masks[channel];
+            let values = &mut last_values[channel];
+            if mask & 1 != 0 {
+                values[0] = next_byte()?;
+            }
+            if mask & 2 != 0 {
+                values[1] = next_byte()?;
+            }
+            if mask & 4 != 0 {
+                values[2] = next_byte()?;
+            }
+            if mask & 8 != 0 {
+                values[3] = next_byte()?;
+                values[4] = next_byte()?;
+            }
+            // the low bits read a new value and the high bits repeat the last one
+            let note = &mut line[channel];
+            if mask & 0x11 != 0 {
+                note.key = match values[0] {
+                    0..=119 => values[0] + 1,
+                    255 => KEY_OFF,
+                    254 => KEY_CUT,
+                    _ => KEY_FADE,
+                };
+            }
+            if mask & 0x22 != 0 {
+                note.sample_number = values[1];
+            }
+            if mask & 0x44 != 0 {
+                let (volume, volume_effect) = decode_volume_column(values[2]);
+                note.volume = volume;
+                note.volume_effect = volume_effect;
+            }
+            if mask & 0x88 != 0 {
+                note.effect_command = values[3];
+                note.effect_parameter = values[4];
+                note.effect = decode_effect(values[3], values[4]);
+            }
+            num_channels = num_channels.max(channel + 1);
+        }
+    }
+    Ok((pattern, num_channels))
+}
+
+// Reads an envelope. Pan and pitch envelopes store values from -32 to 32 that are moved up to 0-64
+fn read_envelope(data: &[u8], is_signed: bool) -> Envelope {
+    let flags = data[0];
+    let num_points = (data[1] as usize).min(MAX_ENVELOPE_POINTS);
+    if flags & ENVELOPE_ON == 0 || num_points == 0 {
+        return Envelope::disabled();
+    }
+    let mut points: Vec<(u32, u8)> = Vec::new();
+    for point in data[6..6 + num_points * 3].chunks(3) {
+        let value = if is_signed {
+            (point[0] as i8 as i32 + 32).clamp(0, 64) as u8
+        } else {
+            point[0].min(64)
+        };
+        let tick = u16::from_le_bytes([point[1], point[2]]) as u32;
+        // the points must move forward in time. Any after one that does not are dropped
+        if points.last().is_some_and(|last| last.0 >= tick) {
+            break;
+        }
+        points.push((tick, value));
+    }
+    let num_points = points.len();
+    let section = |enabled: bool, start: u8, end: u8| {
+        Some((start as usize, end as usize))
+            .filter(|(start, end)| enabled && start <= end && *end < num_points)
+    };
+    Envelope {
+        points,
+        sustain_loop: section(flags & ENVELOPE_SUSTAIN != 0, data[4], data[5]),
+        loop_points: section(flags & ENVELOPE_LOOP != 0, data[2], data[3]),
+    }
+}
+
+fn read_instrument(
+    file_data: &[u8],
+    offset: usize,
+    num_samples: usize,
+) -> Result<Instrument, ModError> {
+    let header = read_bytes(file_data, offset, INSTRUMENT_SIZE)?;
+    if &header[0..4] != b"IMPI" {
+        return Err(ModError::BadHeader(format!(
+            "no instrument header at offset {}",
+            offset
+        )));
+    }
+    // the top bit says whether the pan is left out and whether the filter settings are used
+    let filter_setting = |value: u8| Some(value & 0x7f).filter(|_| value & 0x80 != 0);
+    let mut sample_map = [0u8; 120];
+    for (key, sample) in sample_map.iter_mut().enumerate() {
+        let instrument_sample = header[0x41 + key * 2];
+        if (instrument_sample as usize) <= num_samples {
+            *sample = instrument_sample;
+        }
+    }
+    let pitch_envelope_data = &header[0x1d4..0x226];
+    Ok(Instrument {
+        name: latin1_string(&header[0x20..0x3a]),
+        sample_map,
+        volume_envelope: read_envelope(&header[0x130..0x182], false),
+        panning_envelope: read_envelope(&header[0x182..0x1d4], true),
+        pitch_envelope: read_envelope(pitch_envelope_data, true),
+        has_filter_envelope: pitch_envelope_data[0] & FILTER_ENVELOPE != 0,
+        // the fadeout counts down from 2048 steps
+        fadeout: read_u16(header, 0x14)?.min(2048) as u32 * 16,
+        auto_vibrato: AutoVibrato {
+            waveform: 0,
+            sweep: 0,
+            depth: 0,
+            rate: 0,
+        },
+        pan: Some(header[0x19])
+            .filter(|pan| pan & 0x80 == 0)
+            .map(|pan| (pan.min(64) as u32 * 255 / 64) as u8),
+        filter_cutoff: filter_setting(header[0x3a]),
+        filter_resonance: filter_setting(header[0x3b]),
+        new_note_action: note_action(header[0x11]),
+        duplicate_check: match header[0x12] {
+            1 => DuplicateCheck::Note,
+            2 => DuplicateCheck::Sample,
+            3 => DuplicateCheck::Instrument,
+            _ => DuplicateCheck::Off,
+        },
+        duplicate_action: match header[0x13] {
+            1 => NewNoteAction::NoteOff,
+            2 => NewNoteAction::NoteFade,
+            _ => NewNoteAction::Cut,
+        },
+        release_stops_without_envelope: false,
+    })
+}
+
+// Reads the bits of compressed sample data, lowest bit first
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    bit: u32,
+}
+
+impl<'a> BitReader<'a> {
+    // Reads a `width` bit value. The data reads as zeros past its end
+    fn read(&mut self, width: u32) -> u32 {
+        let mut value = 0;
+        for bit in 0..width {
+            let byte = self.data.get(self.position).copied().unwrap_or(0);
+            value |= ((byte as u32 >> self.bit) & 1) << bit;
+            self.bit += 1;
+            if self.bit == 8 {
+                self.bit = 0;
+                self.position += 1;
+            }
+        }
+        value
+    }
+}
+
+/// Decompresses `length` points of IT214 sample data, or IT215 data if `is_it215`. The data is in blocks that each start
+/// with their size in bytes. Returns the points and how many bytes of data they took
+fn decompress(data: &[u8], length: usize, is_16_bit: bool, is_it215: bool) -> (Vec<i16>, usize) {
+    // the widths are in bits. The widest width holds the sample size and a flag bit
+    let (block_length, max_width, width_bits, sample_bits, border_range) = if is_16_bit {
+        (0x4000, 17, 4, 16, 16)
+    } else {
+        (0x8000, 9, 3, 8, 8)
+    };
+    let wrap = |value: i32| {
+        if is_16_bit {
+            value as i16 as i32
+        } else {
+            value as i8 as i32
+        }
+    };
+    let mut points = Vec::with_capacity(length);
+    let mut position = 0;
+    while points.len() < length {
+        let block_size = match data.get(position..position + 2) {
+            Some(bytes) => u16::from_le_bytes([bytes[0], bytes[1]]) as usize,
+            None => break,
+        };
+        let block_start = (position + 2).min(data.len());
+        let block_end = (position + 2 + block_size).min(data.len());
+        position += 2 + block_size;
+        let mut bits = BitReader {
+            data: &data[block_start..block_end],
+            position: 0,
+            bit: 0,
+        };
+        let block_points = (length - points.len()).min(block_length);
+        let mut width = max_width;
+        let mut delta = 0;
+        let mut delta_of_delta = 0;
+        let mut unpacked = 0;
+        while unpacked < block_points {
+            let value = bits.read(width);
+            // values at the edges of the range of the width change the width. How depends on the width
+            if width < 7 {
+                if value == 1 << (width - 1) {
+                    let new_width = bits.read(width_bits) + 1;
+                    width = if new_width < width {
+                        new_width
+                    } else {
+                        new_width + 1
+                    };
+                    continue;
+                }
+            } else if width < max_width {
+                let border = (((1 << sample_bits) - 1) >> (max_width - width)) - border_range / 2;
+                if value > border && value <= border + border_range {
+                    let new_width = value - border;
+                    width = if new_width < width {
+                        new_width
+                    } else {
+                        new_width + 1
+                    };
+                    continue;
+                }
+            } else if value & (1 << (max_width - 1)) != 0 {
+                width = (value + 1) & 0xff;
+                if width == 0 || width > max_width {
+                    // the data is corrupt
+                    return (points, position);
+                }
+                continue;
+            }
+            let value_bits = width.min(sample_bits);
+            let value = ((value << (32 - value_bits)) as i32) >> (32 - value_bits);
+            delta = wrap(delta + value);
+            delta_of_delta = wrap(delta_of_delta + delta);
+            let point = if is_it215 { delta_of_delta } else { delta };
+            points.push(if is_16_bit {
+                point as i16
+            } else {
+                (point as i16) << 8
+            });
+            unpacked += 1;
+        }
+    }
+    (points, position.min(data.len()))
+}
+
+// Reads `length` points of uncompressed sample data. The last sample is often cut short and only what there is is read
+fn read_pcm(data: &[u8], length: usize, is_16_bit: bool, is_signed: bool) -> Vec<i16> {
+    if is_16_bit {
+        data.chunks_exact(2)
+            .take(length)
+            .map(|bytes| {
+                let raw = u16::from_le_bytes([bytes[0], bytes[1]]);
+                if is_signed {
+                    raw as i16
+                } else {
+                    (raw ^ 0x8000) as i16
+                }
+            })
+            .collect()
+    } else {
+        data.iter()
+            .take(length)
+            .map(|raw| {
+                let raw = if is_signed { *raw } else { raw ^ 0x80 };
+                (raw as i8 as i16) << 8
+            })
+            .collect()
+    }
+}
+
+fn read_sample(file_data: &[u8], offset: usize) -> Result<Sample, ModError> {
+    let header = read_bytes(file_data, offset, SAMPLE_HEADER_SIZE)?;
+    if &header[0..4] != b"IMPS" {
+        return Err(ModError::BadHeader(format!(
+            "no sample header at offset {}",
+            offset
+        )));
+    }
+    let flags = header[0x12];
+    let conversion = header[0x2e];
+    let default_pan = header[0x2f];
+    let mut sample = Sample {
+        name: latin1_string(&header[0x14..0x2e]),
+        size: 0,
+        volume: header[0x13].min(64),
+        fine_tune: 0,
+        repeat_offset: 0,
+        repeat_size: 0,
+        c2spd: read_u32(header, 0x3c)?.max(1),
+        relative_pitch: 0,
+        pan: Some(default_pan)
+            .filter(|pan| pan & 0x80 != 0)
+            .map(|pan| ((pan & 0x7f).min(64) as u32 * 255 / 64) as u8),
+        sustain_loop: None,
+        samples: Vec::new(),
+    };
+    if flags & SAMPLE_PRESENT == 0 {
+        return Ok(sample);
+    }
+    let is_16_bit = flags & SAMPLE_16_BIT != 0;
+    let length = read_u32(header, 0x30)? as usize;
+    let data_offset = read_u32(header, 0x48)? as usize;
+    let data = &file_data[data_offset.min(file_data.len())..];
+    // stereo samples store all of the left side before the right side. They are played in mono
+    let num_sides = if flags & SAMPLE_STEREO != 0 { 2 } else { 1 };
+    let mut sides = Vec::new();
+    let mut position = 0;
+    for _side in 0..num_sides {
+        let data = &data[position.min(data.len())..];
+        if flags & SAMPLE_COMPRESSED != 0 {
+            let is_it215 = conversion & IT215_COMPRESSION != 0;
+            let (points, used) = decompress(data, length, is_16_bit, is_it215);
+            sides.push(points);
+            position += used;
+        } else {
+            let is_signed = conversion & SIGNED_SAMPLES != 0;
+            sides.push(read_pcm(data, length, is_16_bit, is_signed));
+            position += length * if is_16_bit { 2 } else { 1 };
+        }
+    }
+    let mut points = sides.remove(0);
+    if let Some(right) = sides.first() {
+        points.truncate(right.len());
+        for (left, right) in points.iter_mut().zip(right) {
+            *left = ((*left as i32 + *right as i32) / 2) as i16;
+        }
+    }
+    // the sample's global volume is applied to the data
+    let global_volume = header[0x11].min(64) as i32;
+    if global_volume < 64 {
+        for point in &mut points {
+            *point = (*point as i32 * global_volume / 64) as i16;
+        }
+    }
+    sample.samples = points;
+    sample.size = sample.samples.len() as u32;
+
+    let loop_start = read_u32(header, 0x34)?;
+    let loop_end = read_u32(header, 0x38)?.min(sample.size);
+    if flags & SAMPLE_LOOPS != 0 && loop_end > loop_start {
+        // ping-pong loops are played by appending the loop backwards so that the loop plays forwards and then backwards
+        sample.samples.truncate(loop_end as usize);
+        if flags & PING_PONG_LOOP != 0 {
+            let backwards: Vec<i16> = sample.samples[loop_start as usize..]
+                .iter()
+                .rev()
+                .copied()
+                .collect();
+            sample.samples.extend(backwards);
+        }
+        sample.size = sample.samples.len() as u32;
+        sample.repeat_offset = loop_start;
+        sample.repeat_size = sample.size - loop_start;
+    }
+    // ping-pong sustain loops are played forwards
+    let sustain_start = read_u32(header, 0x40)?;
+    let sustain_end = read_u32(header, 0x44)?.min(sample.size);
+    if flags & SAMPLE_SUSTAIN_LOOPS != 0 && sustain_end > sustain_start {
+        sample.sustain_loop = Some((sustain_start, sustain_end));
+    }
+    Ok(sample)
+}
+
+// Reads a table of offsets to the instruments, samples or patterns
+fn read_offsets(file_data: &[u8], offset: usize, count: usize) -> Result<Vec<usize>, ModError> {
+    (0..count)
+        .map(|index| Ok(read_u32(file_data, offset + index * 4)? as usize))
+        .collect()
+}
+
+/// Describes an Impulse Tracker module from its header and sample headers. The song has as many channels as its
+/// patterns use, so the patterns are read as well
+pub(crate) fn read_it_info<R: Read + Seek>(reader: &mut R) -> Result<ModInfo, ModError> {
+    let header = read_at(reader, 0, HEADER_SIZE)?;
+    let num_orders = read_u16(&header, 0x20)? as usize;
+    let num_instruments = read_u16(&header, 0x22)? as usize;
+    let num_samples = read_u16(&header, 0x24)? as usize;
+    let num_patterns = read_u16(&header, 0x26)? as usize;
+    let orders = read_at(
+        reader,
+        HEADER_SIZE,
+        num_orders + (num_instruments + num_samples + num_patterns) * 4,
+    )?;
+    let pattern_table = s3m::pattern_table(&orders[..num_orders], num_patterns)?;
+    let sample_offsets = read_offsets(&orders, num_orders + num_instruments * 4, num_samples)?;
+    let pattern_offsets = read_offsets(
+        &orders,
+        num_orders + (num_instruments + num_samples) * 4,
+        num_patterns,
+    )?;
+
+    let mut sample_names = Vec::new();
+    let mut sample_sizes = Vec::new();
+    for offset in sample_offsets {
+        let sample_header = read_at(reader, offset, SAMPLE_HEADER_SIZE)?;
+        if &sample_header[0..4] != b"IMPS" {
+            return Err(ModError::BadHeader(format!(
+                "no sample header at offset {}",
+                offset
+            )));
+        }
+        sample_names.push(latin1_string(&sample_header[0x14..0x2e]));
+        sample_sizes.push(if sample_header[0x12] & SAMPLE_PRESENT != 0 {
+            read_u32(&sample_header, 0x30)?
+        } else {
+            0
+        });
+    }
+    let mut num_channels = 1;
+    for offset in pattern_offsets.into_iter().filter(|offset| *offset > 0) {
+        let packed_size = read_u16(&read_at(reader, offset, 2)?, 0)? as usize;
+        let pattern_data = read_at(reader, offset, 8 + packed_size)?;
+        num_channels = num_channels.max(read_pattern(&pattern_data, 0)?.1);
+    }
+    Ok(ModInfo {
+        name: latin1_string(&header[4..30]),
+        format: FormatKind::It,
+        num_channels: num_channels as u32,
+        sample_names,
+        sample_sizes,
+        num_orders: pattern_table.len() as u32,
+        num_patterns: num_patterns as u32,
+    })
+}
+
+/// Reads an Impulse Tracker module
+pub(crate) fn read_it(file_data: &[u8]) -> Result<Song, ModError> {
+    let header = read_bytes(file_data, 0, HEADER_SIZE)?;
+    let num_orders = read_u16(header, 0x20)? as usize;
+    let num_instruments = read_u16(header, 0x22)? as usize;
+    let num_samples = read_u16(header, 0x24)? as usize;
+    let num_patterns = read_u16(header, 0x26)? as usize;
+    let compatible_version = read_u16(header, 0x2a)?;
+    let flags = read_u16(header, 0x2c)?;
+    if num_patterns > 256 {
+        return Err(ModError::BadHeader(format!(
+            "{} patterns is more than the 256 an order list can refer to",
+            num_patterns
+        )));
+    }
+    let uses_instruments = flags & USES_INSTRUMENTS != 0;
+    if uses_instruments && compatible_version < 0x200 {
+        return Err(ModError::UnsupportedTag(String::from(
+            "IT instruments from before Impulse Tracker 2",
+        )));
+    }
+
+    let orders = read_bytes(file_data, HEADER_SIZE, num_orders)?;
+    let mut offset = HEADER_SIZE + num_orders;
+    let instrument_offsets = read_offsets(file_data, offset, num_instruments)?;
+    offset += num_instruments * 4;
+    let sample_offsets = read_offsets(file_data, offset, num_samples)?;
+    offset += num_samples * 4;
+    let pattern_offsets = read_offsets(file_data, offset, num_patterns)?;
+
+    let pattern_table = s3m::pattern_table(orders, num_patterns)?;
+
+    let mut samples = Vec::new();
+    for offset in sample_offsets {
+        samples.push(read_sample(file_data, offset)?);
+    }
+    let mut instruments = Vec::new();
+    if uses_instruments {
+        for offset in instrument_offsets {
+            instruments.push(read_instrument(file_data, offset, num_samples)?);
+        }
+    }
+    // the song has as many channels as its patterns use
+    let mut patterns = Vec::new();
+    let mut num_channels = 1;
+    for offset in pattern_offsets {
+        let (pattern, pattern_channels) = if offset == 0 {
+            (empty_pattern(), 0)
+        } else {
+            read_pattern(file_data, offset)?
+        };
+        num_channels = num_channels.max(pattern_channels);
+        patterns.push(pattern);
+    }
+    for pattern in &mut patterns {
+        for line in &mut pattern.lines {
+            line.truncate(num_channels);
+        }
+    }
+
+    // pans go from 0 to 64. Surround channels are played in the middle
+    let channel_pans = header[0x40..0x40 + num_channels]
+        .iter()
+        .map(|pan| match pan & 0x7f {
+            _ if flags & STEREO == 0 => 0.0,
+            SURROUND_PAN => 0.0,
+            pan => pan.min(64) as f32 / 32.0 - 1.0,
+        })
+        .collect();
+
+    let num_used_patterns = pattern_table.len() as u32;
+    Ok(Song {
+        name: latin1_string(&header[4..30]),
+        format: FormatDescription {
+            kind: FormatKind::It,
+            num_channels: num_channels as u32,
+            num_samples: num_samples as u32,
+            has_tag: true,
+            tag: String::from("IMPM"),
+            has_linear_periods: flags & LINEAR_SLIDES != 0,
+        },
+        samples,
+        instruments,
+        patterns,
+        pattern_table,
+        num_used_patterns,
+        // the song ends after the last order
+        end_position: num_used_patterns,
+        has_standard_notes: false,
+        initial_speed: header[0x32].max(1) as u32,
+        initial_tempo: header[0x33].max(32) as u32,
+        // the global volume goes up to 128
+        initial_global_volume: header[0x30].min(128) as u32 / 2,
+        channel_pans,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::{advance_tick, update_tick, PlayerState};
+    use super::super::tests::{play_ticks, put_u16, put_u32};
+    use super::*;
+    use std::io::Cursor;
+
+    // Packs values of the given bit widths, lowest bit first
+    fn pack_bits(values: &[(u32, u32)]) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut num_bits = 0;
+        for (value, width) in values {
+            for bit in 0..*width {
+                if num_bits % 8 == 0 {
+                    data.push(0);
+                }
+                data[num_bits / 8] |= (((value >> bit) & 1) as u8) << (num_bits % 8);
+                num_bits += 1;
+            }
+        }
+        data
+    }
+
+    // One block of 8 bit compressed data. Three deltas at the full width of 9 bits, a switch to 4 bits and two more deltas
+    fn compressed_block() -> Vec<u8> {
+        let bits = pack_bits(&[(1, 9), (2, 9), (0xfd, 9), (0x103, 9), (3, 4), (0xf, 4)]);
+        let mut block = (bits.len() as u16).to_le_bytes().to_vec();
+        block.extend(bits);
+        block
+    }
+
+    // A song with one instrument and two samples. The instrument continues its notes in the background, has a pitch envelope
+    // that holds at 12 semitones up and starts the filter at a cutoff of 100. The first sample has a sustain loop and the
+    // second is compressed. The pattern plays two notes and releases the second
+    fn build_it() -> Vec<u8> {
+        let mut data = vec![0u8; 0x540];
+        data[0..4].copy_from_slice(b"IMPM");
+        data[4..11].copy_from_slice(b"test it");
+        put_u16(&mut data, 0x20, 2);
+        put_u16(&mut data, 0x22, 1);
+        put_u16(&mut data, 0x24, 2);
+        put_u16(&mut data, 0x26, 1);
+        put_u16(&mut data, 0x2a, 0x214);
+        put_u16(&mut data, 0x2c, STEREO | USES_INSTRUMENTS | LINEAR_SLIDES);
+        data[0x30] = 128;
+        data[0x32] = 1;
+        data[0x33] = 125;
+        data[0x40] = 0;
+        data[0x41] = 64;
+        data[0xc0..0xc2].copy_from_slice(&[0, s3m::ORDER_END]);
+        put_u32(&mut data, 0xc2, 0x100);
+        put_u32(&mut data, 0xc6, 0x340);
+        put_u32(&mut data, 0xca, 0x390);
+        put_u32(&mut data, 0xce, 0x400);
+
+        let instrument = &mut data[0x100..0x100 + INSTRUMENT_SIZE];
+        instrument[0..4].copy_from_slice(b"IMPI");
+        instrument[0x11] = 1;
+        put_u16(instrument, 0x14, 8);
+        instrument[0x19] = 0x80;
+        instrument[0x20..0x2a].copy_from_slice(b"instrument");
+        instrument[0x3a] = 0x80 | 100;
+        for key in 0..120 {
+            instrument[0x40 + key * 2] = key as u8;
+            instrument[0x41 + key * 2] = 1;
+        }
+        let envelope = &mut instrument[0x130..0x182];
+        envelope[0..6].copy_from_slice(&[ENVELOPE_ON | ENVELOPE_SUSTAIN, 2, 0, 0, 1, 1]);
+        envelope[6..12].copy_from_slice(&[64, 0, 0, 32, 4, 0]);
+        let envelope = &mut instrument[0x1d4..0x226];
+        envelope[0..6].copy_from_slice(&[ENVELOPE_ON, 1, 0, 0, 0, 0]);
+        envelope[6..9].copy_from_slice(&[24, 0, 0]);
+
+        let sample = &mut data[0x340..0x390];
+        sample[0..4].copy_from_slice(b"IMPS");
+        sample[0x11] = 64;
+        sample[0x12] = SAMPLE_PRESENT | SAMPLE_SUSTAIN_LOOPS;
+        sample[0x13] = 48;
+        sample[0x14..0x1a].copy_from_slice(b"looped");
+        sample[0x2e] = SIGNED_SAMPLES;
+        sample[0x2f] = 0x80 | 32;
+        put_u32(sample, 0x30, 16);
+        put_u32(sample, 0x3c, 8363);
+        put_u32(sample, 0x40, 4);
+        put_u32(sample, 0x44, 8);
+        put_u32(sample, 0x48, 0x500);
+        let sample = &mut data[0x390..0x3e0];
+        sample[0..4].copy_from_slice(b"IMPS");
+        sample[0x11] = 64;
+        sample[0x12] = SAMPLE_PRESENT | SAMPLE_COMPRESSED;
+        sample[0x13] = 64;
+        sample[0x2e] = SIGNED_SAMPLES;
+        put_u32(sample, 0x30, 5);
+        put_u32(sample, 0x3c, 16726);
+        put_u32(sample, 0x48, 0x520);
+
+        let packed = [
+            // C-5 with instrument 1 at volume 64 and a filter cutoff of 0x40 on the second channel
+            0x81, 0x07, 60, 1, 64, 0x82, 0x08, 26, 0x40, 0,
+            // D-5 with the same columns as the last note
+            0x01, 62, 1, 32, 0, // note off
+            0x81, 0x01, 255, 0, 0,
+        ];
+        put_u16(&mut data, 0x400, packed.len() as u16);
+        put_u16(&mut data, 0x402, 4);
+        data[0x408..0x408 + packed.len()].copy_from_slice(&packed);
+
+        for (index, value) in data[0x500..0x510].iter_mut().enumerate() {
+            *value = index as u8 * 4;
+        }
+        let block = compressed_block();
+        data[0x520..0x520 + block.len()].copy_from_slice(&block);
+        data
+    }
+
+    #[test]
+    fn test_read_it() {
+        let song = read_it(&build_it()).unwrap();
+        assert_eq!(song.name.trim_end_matches('\0'), "test it");
+        assert_eq!(song.format.kind, FormatKind::It);
+        assert!(song.format.has_linear_periods);
+        assert_eq!(song.format.num_channels, 2);
+        assert_eq!(song.pattern_table, vec![0]);
+        assert_eq!(song.initial_global_volume, 64);
+        assert_eq!(song.channel_pans, vec![-1.0, 1.0]);
+
+        let pattern = &song.patterns[0];
+        assert_eq!(pattern.num_lines(), 4);
+        let note = pattern.note(0, 0).unwrap();
+        assert_eq!(
+            (note.key(), note.sample_number(), note.volume()),
+            (61, 1, Some(64))
+        );
+        assert_eq!(
+            *pattern.note(0, 1).unwrap().effect(),
+            Effect::SetFilterCutoff { cutoff: 0x40 }
+        );
+        let note = pattern.note(1, 0).unwrap();
+        assert_eq!(
+            (note.key(), note.sample_number(), note.volume()),
+            (63, 1, Some(32))
+        );
+        assert_eq!(pattern.note(2, 0).unwrap().key(), KEY_OFF);
+
+        let instrument = &song.instruments[0];
+        assert_eq!(instrument.name().trim_end_matches('\0'), "instrument");
+        assert_eq!(instrument.sample_for_key(120), 1);
+        assert_eq!(instrument.new_note_action(), NewNoteAction::Continue);
+        assert_eq!(instrument.filter_cutoff(), Some(100));
+        assert_eq!(instrument.filter_resonance(), None);
+        assert_eq!(instrument.volume_envelope().sustain_loop(), Some((1, 1)));
+        assert_eq!(instrument.pitch_envelope().points(), &[(0, 56)]);
+        assert!(!instrument.has_filter_envelope());
+
+        let sample = &song.samples[0];
+        assert_eq!(sample.name().trim_end_matches('\0'), "looped");
+        assert_eq!(sample.size(), 16);
+        assert_eq!(sample.sustain_loop(), Some((4, 8)));
+        assert_eq!(sample.pan(), Some(127));
+        assert_eq!(sample.samples()[3], 12 << 8);
+        let sample = &song.samples[1];
+        assert_eq!(sample.c2spd(), 16726);
+        let points: Vec<i16> = [1, 3, 0, 3, 2].iter().map(|point| point << 8).collect();
+        assert_eq!(sample.samples(), points.as_slice());
+    }
+
+    #[test]
+    fn test_read_it_info() {
+        let data = build_it();
+        let song = read_it(&data).unwrap();
+        // the description does not need the sample data
+        let info = read_it_info(&mut Cursor::new(&data[0..0x500])).unwrap();
+        assert_eq!(info.name, song.name);
+        assert_eq!(info.format, FormatKind::It);
+        assert_eq!(info.num_channels, 2);
+        let sample_names: Vec<String> = song
+            .samples
+            .iter()
+            .map(|sample| sample.name().to_string())
+            .collect();
+        assert_eq!(info.sample_names, sample_names);
+        assert_eq!(info.sample_sizes, vec![16, 5]);
+        assert_eq!((info.num_orders, info.num_patterns), (1, 1));
+    }
+
+    #[test]
+    fn test_decompress() {
+        let block = compressed_block();
+        let (points, used) = decompress(&block, 5, false, false);
+        assert_eq!(used, block.len());
+        assert_eq!(points, vec![1 << 8, 3 << 8, 0, 3 << 8, 2 << 8]);
+        // IT215 adds up the deltas twice
+        let (points, _) = decompress(&block, 5, false, true);
+        assert_eq!(points, vec![1 << 8, 4 << 8, 4 << 8, 7 << 8, 9 << 8]);
+    }
+
+    #[test]
+    fn test_note_period() {
+        assert_eq!(note_period(61, 8363, true), 4608);
+        assert_eq!(note_period(73, 8363, true), 4608 - 768);
+        assert_eq!(note_period(61, 16726, true), 4608 - 768);
+        assert_eq!(note_period(61, 8363, false), 1712);
+        assert_eq!(note_period(49, 8363, false), 3424);
+    }
+
+    #[test]
+    fn test_play_it() {
+        let song = read_it(&build_it()).unwrap();
+        let mut player_state = PlayerState::new(song.format.num_channels, 8000);
+        // the first tick is silent
+        update_tick(&song, &mut player_state);
+        update_tick(&song, &mut player_state);
+        let channel = &player_state.channels[0];
+        assert_eq!(channel.envelope_pitch, 12.0);
+        assert_eq!(channel.filter_cutoff, 100);
+        assert_eq!(player_state.channels[1].filter_cutoff, 0x40);
+        // the sample repeats its sustain loop
+        advance_tick(&song, &mut player_state, 200);
+        let channel = &player_state.channels[0];
+        assert_eq!(channel.size, 8);
+        assert!(channel.sample_pos >= 4.0 && channel.sample_pos < 8.0);
+
+        // the first note carries on in the background under the next one
+        update_tick(&song, &mut player_state);
+        assert_eq!(player_state.background_voices.len(), 1);
+        let (voice_channel, voice) = &player_state.background_voices[0];
+        assert_eq!((*voice_channel, voice.key), (0, 61));
+        assert_eq!(player_state.channels[0].key, 63);
+
+        // releasing the note lets the sample play past its sustain loop
+        update_tick(&song, &mut player_state);
+        let channel = &player_state.channels[0];
+        assert!(channel.released);
+        assert_eq!(channel.size, 16);
+        assert!(!player_state.background_voices[0].1.released);
+    }
+
+    #[test]
+    fn test_new_note_actions() {
+        let mut song = read_it(&build_it()).unwrap();
+        song.instruments[0].new_note_action = NewNoteAction::NoteOff;
+        let player_state = play_ticks(&song, 3);
+        assert!(player_state.background_voices[0].1.released);
+
+        // the duplicate check cuts the background note of the same instrument
+        song.instruments[0].duplicate_check = DuplicateCheck::Instrument;
+        let player_state = play_ticks(&song, 3);
+        assert!(player_state.background_voices.is_empty());
+
+        // a cut note does not go to the background
+        song.instruments[0].duplicate_check = DuplicateCheck::Off;
+        song.instruments[0].new_note_action = NewNoteAction::Cut;
+        let player_state = play_ticks(&song, 3);
+        assert!(player_state.background_voices.is_empty());
+    }
+}
@@ -0,0 +1,575 @@
+//! MED and OctaMED modules ( MMD0 to MMD3 )
+//!
+//! The header points to the song, the blocks ( patterns ), the instruments and an expansion with names and fine tunes.
+//! MMD0 blocks pack each note into three bytes and MMD1 and later use four. MMD0 and MMD1 play the blocks in the order
+//! of a play sequence in the song; MMD2 and MMD3 play a list of sections, each of which is one of several play
+//! sequences. Only sampled instruments play. Synthetic, hybrid and multi octave instruments are left silent. MED tempos
+//! are converted to ProTracker tempos.
+use super::super::{
+    amiga_channel_pan, latin1_string, Effect, FormatDescription, FormatKind, ModError, Note,
+    Pattern, Sample, Song, DEFAULT_C2SPD,
+};
+use super::{
+    amiga_note_period, fit_loop, has_standard_notes_only, read_bytes, read_u16_be, read_u32_be,
+};
+
+const HEADER_SIZE: usize = 52;
+const SONG_SIZE: usize = 788;
+const SAMPLE_INFO_SIZE: usize = 8;
+const MAX_SAMPLES: usize = 63;
+
+// Song flags
+const FLAG_VOLUME_HEX: u8 = 0x10;
+const FLAG2_LINES_PER_BEAT: u8 = 0x1f;
+const FLAG2_BPM: u8 = 0x20;
+
+// Instrument types
+const SAMPLE_8_BIT: i16 = 0;
+const SAMPLE_16_BIT: i16 = 0x10;
+
+// Play sequence entries from this value up are commands rather than blocks
+const SEQUENCE_COMMAND: u16 = 0x8000;
+
+/// Is the data a MED or OctaMED module
+pub(crate) fn is_med(file_data: &[u8]) -> bool {
+    match file_data.get(0..4) {
+        Some([b'M', b'M', b'D', version]) => (b'0'..=b'3').contains(version),
+        _ => false,
+    }
+}
+
+/// The song settings that change how effects are decoded
+#[derive(Clone, Copy)]
+pub(crate) struct SongFlags {
+    /// Is the volume command in hex rather than decimal
+    volume_hex: bool,
+    /// The lines in a beat for songs that give their tempo in beats per minute
+    lines_per_beat: Option<u32>,
+    /// The ticks per line the song starts with
+    ticks_per_line: u32,
+}
+
+impl Default for SongFlags {
+    fn default() -> SongFlags {
+        SongFlags {
+            volume_hex: false,
+            lines_per_beat: None,
+            ticks_per_line: 6,
+        }
+    }
+}
+
+impl SongFlags {
+    fn new(song: &[u8]) -> SongFlags {
+        let flags2 = song[768];
+        SongFlags {
+            volume_hex: song[767] & FLAG_VOLUME_HEX != 0,
+            lines_per_beat: if flags2 & FLAG2_BPM != 0 {
+                Some((flags2 & FLAG2_LINES_PER_BEAT) as u32 + 1)
+            } else {
+                None
+            },
+            ticks_per_line: song[769].max(1) as u32,
+        }
+    }
+
+    /// The effect that sets a MED tempo. In BPM mode a beat is `lines_per_beat` lines. Otherwise the tempo is in MED's
+    /// own units, where 33 is the ProTracker default, and values up to 10 are Soundtracker speeds
+    fn tempo_effect(&self, tempo: u32) -> Effect {
+        let bpm = match self.lines_per_beat {
+            // a ProTracker beat is four lines
+            Some(lines_per_beat) => tempo * lines_per_beat / 4,
+            None if tempo <= 10 => {
+                return Effect::SetTicksPerLine {
+                    ticks: tempo.max(1) as u8,
+                }
+            }
+            None => tempo * 125 / 33,
+        };
+        Effect::SetTempo {
+            bpm: bpm.clamp(32, 255) as u8,
+        }
+    }
+}
+
+/// Decodes a MED effect. Commands from 0x10 up are only in MMD1 and later. Hold and decay ( 8 ), synth jumps ( E ) and
+/// the FFx commands that play a note several times in a line are not played
+pub(crate) fn decode_effect(command: u8, parameter: u8, flags: &SongFlags) -> Effect {
+    let high = parameter >> 4;
+    let low = parameter & 0x0f;
+    match command {
+        0 if parameter != 0 => Effect::Arpeggio {
+            chord_offset_1: high,
+            chord_offset_2: low,
+        },
+        1 => Effect::SlideUp { speed: parameter },
+        2 => Effect::SlideDown { speed: parameter },
+        3 => Effect::TonePortamento { speed: parameter },
+        4 | 0x14 => Effect::Vibrato {
+            speed: high,
+            amplitude: low,
+        },
+        // the volume slides are the same as ProTracker's
+        5 | 6 => Effect::new(command, parameter as i8),
+        7 => Effect::Tremolo {
+            speed: high,
+            amplitude: low,
+        },
+        9 if (1..=0x20).contains(&parameter) => Effect::SetTicksPerLine { ticks: parameter },
+        0xa | 0xd => Effect::new(0xa, parameter as i8),
+        0xb => Effect::PositionJump {
+            next_pattern: parameter,
+        },
+        0xc => Effect::SetVolume {
+            volume: if flags.volume_hex {
+                parameter.min(64)
+            } else {
+                (high * 10 + low).min(64)
+            },
+        },
+        0xf => match parameter {
+            0 => Effect::PatternBreak {
+                next_pattern_pos: 0,
+            },
+            1..=0xf0 => flags.tempo_effect(parameter as u32),
+            0xf8 => Effect::SetHardwareFilter { new_state: 1 },
+            0xf9 => Effect::SetHardwareFilter { new_state: 0 },
+            0xff => Effect::CutNote { delay: 0 },
+            _ => Effect::None,
+        },
+        0x11 => Effect::FinePortaUp {
+            period_change: parameter,
+        },
+        0x12 => Effect::FinePortaDown {
+            period_change: parameter,
+        },
+        0x15 => Effect::SetFineTune { fine_tune: low },
+        0x16 => Effect::PatternLoop { arg: parameter },
+        0x18 => Effect::CutNote { delay: parameter },
+        0x19 => Effect::SetSampleOffset { offset: parameter },
+        0x1a => Effect::FineVolumeSlideUp {
+            volume_change: parameter,
+        },
+        0x1b => Effect::FineVolumeSlideDown {
+            volume_change: parameter,
+        },
+        0x1d => Effect::PatternBreak {
+            next_pattern_pos: parameter,
+        },
+        0x1e => Effect::DelayedLine {
+            delay_ticks: parameter,
+        },
+        // the high nibble delays the note and the low nibble retriggers it
+        0x1f if high != 0 => Effect::DelayedSample { delay_ticks: high },
+        0x1f if low != 0 => Effect::RetriggerSample {
+            retrigger_delay: low,
+        },
+        _ => Effect::None,
+    }
+}
+
+/// The number of tracks and lines of the block at `offset`, and where its notes start
+fn block_layout(
+    file_data: &[u8],
+    offset: usize,
+    is_mmd0: bool,
+) -> Result<(usize, usize, usize), ModError> {
+    if is_mmd0 {
+        let bytes = read_bytes(file_data, offset, 2)?;
+        Ok((bytes[0] as usize, bytes[1] as usize + 1, offset + 2))
+    } else {
+        let num_tracks = read_u16_be(file_data, offset)? as usize;
+        let num_lines = read_u16_be(file_data, offset + 2)? as usize + 1;
+        Ok((num_tracks, num_lines, offset + 8))
+    }
+}
+
+/// Reads a block. `transposes` are the semitones each instrument's notes are moved by
+fn read_block(
+    file_data: &[u8],
+    offset: usize,
+    is_mmd0: bool,
+    num_channels: usize,
+    transposes: &[i32],
+    flags: &SongFlags,
+) -> Result<Pattern, ModError> {
+    let (num_tracks, num_lines, mut offset) = block_layout(file_data, offset, is_mmd0)?;
+    let note_size = if is_mmd0 { 3 } else { 4 };
+    let mut pattern = Pattern::with_lines(num_lines);
+    for line in &mut pattern.lines {
+        for _track in 0..num_tracks {
+            let bytes = read_bytes(file_data, offset, note_size)?;
+            // MMD0 notes keep the top two bits of the instrument number above the note
+            let (key, instrument, command, parameter) = if is_mmd0 {
+                let instrument = (bytes[1] >> 4) | (bytes[0] & 0x80) >> 3 | (bytes[0] & 0x40) >> 1;
+                (bytes[0] & 0x3f, instrument, bytes[1] & 0x0f, bytes[2])
+            } else {
+                (bytes[0] & 0x7f, bytes[1] & 0x3f, bytes[2], bytes[3])
+            };
+            let mut note = Note::empty();
+            if key > 0 {
+                let transpose = transposes
+                    .get(instrument as usize)
+                    .copied()
+                    .unwrap_or(transposes[0]);
+                note.period = amiga_note_period(key as i32 + transpose);
+            }
+            note.sample_number = instrument;
+            note.effect_command = command;
+            note.effect_parameter = parameter;
+            note.effect = decode_effect(command, parameter, flags);
+            line.push(note);
+            offset += note_size;
+        }
+        line.resize_with(num_channels, Note::empty);
+    }
+    Ok(pattern)
+}
+
+/// The sample data of the instrument at `offset`. Instruments that are not sampled have no data
+fn read_sample_data(file_data: &[u8], offset: usize) -> Result<Vec<i16>, ModError> {
+    let length = read_u32_be(file_data, offset)? as usize;
+    let instrument_type = read_u16_be(file_data, offset + 4)? as i16;
+    // The last sample is often cut short. Play what there is of it
+    let data = file_data.get(offset + 6..).unwrap_or(&[]);
+    let data = &data[0..length.min(data.len())];
+    Ok(match instrument_type {
+        SAMPLE_8_BIT => data.iter().map(|&byte| (byte as i8 as i16) << 8).collect(),
+        SAMPLE_16_BIT => data
+            .chunks_exact(2)
+            .map(|point| i16::from_be_bytes([point[0], point[1]]))
+            .collect(),
+        _ => Vec::new(),
+    })
+}
+
+/// Reads the instrument names and fine tunes from the expansion at `offset` and returns the song name
+fn read_expansion(
+    file_data: &[u8],
+    offset: usize,
+    samples: &mut [Sample],
+) -> Result<String, ModError> {
+    let expansion = read_bytes(file_data, offset, 52)?;
+    let sample_extensions = read_u32_be(expansion, 4)? as usize;
+    let num_sample_extensions = read_u16_be(expansion, 8)? as usize;
+    let sample_extension_size = read_u16_be(expansion, 10)? as usize;
+    if sample_extensions != 0 && sample_extension_size >= 4 {
+        for (index, sample) in samples.iter_mut().take(num_sample_extensions).enumerate() {
+            let fine_tune = read_bytes(
+                file_data,
+                sample_extensions + index * sample_extension_size + 3,
+                1,
+            )?[0];
+            sample.fine_tune = fine_tune & 0x0f;
+        }
+    }
+
+    let instrument_infos = read_u32_be(expansion, 20)? as usize;
+    let num_instrument_infos = read_u16_be(expansion, 24)? as usize;
+    let instrument_info_size = read_u16_be(expansion, 26)? as usize;
+    if instrument_infos != 0 && instrument_info_size >= 40 {
+        for (index, sample) in samples.iter_mut().take(num_instrument_infos).enumerate() {
+            sample.name = latin1_string(read_bytes(
+                file_data,
+                instrument_infos + index * instrument_info_size,
+                40,
+            )?);
+        }
+    }
+
+    let song_name = read_u32_be(expansion, 44)? as usize;
+    if song_name == 0 {
+        return Ok(String::new());
+    }
+    let song_name_length = read_u32_be(expansion, 48)? as usize;
+    Ok(latin1_string(read_bytes(
+        file_data,
+        song_name,
+        song_name_length,
+    )?))
+}
+
+/// The blocks played by each position of the song. MMD2 and later songs play a list of sections
+fn read_block_sequence(file_data: &[u8], song: &[u8], version: u8) -> Result<Vec<u16>, ModError> {
+    let song_length = read_u16_be(song, 506)? as usize;
+    if version < 2 {
+        return Ok(read_bytes(song, 508, song_length.min(256))?
+            .iter()
+            .map(|block| *block as u16)
+            .collect());
+    }
+    let play_sequences = read_u32_be(song, 508)? as usize;
+    let sections = read_u32_be(song, 512)? as usize;
+    let num_play_sequences = read_u16_be(song, 522)? as usize;
+    let mut block_sequence = Vec::new();
+    for section in 0..song_length {
+        let play_sequence_num = read_u16_be(file_data, sections + section * 2)? as usize;
+        if play_sequence_num >= num_play_sequences {
+            return Err(ModError::BadHeader(format!(
+                "section {} plays sequence {} but the song has {}",
+                section, play_sequence_num, num_play_sequences
+            )));
+        }
+        let play_sequence =
+            read_u32_be(file_data, play_sequences + play_sequence_num * 4)? as usize;
+        let length = read_u16_be(file_data, play_sequence + 40)? as usize;
+        for index in 0..length {
+            block_sequence.push(read_u16_be(file_data, play_sequence + 42 + index * 2)?);
+        }
+    }
+    Ok(block_sequence)
+}
+
+/// Reads a MED or OctaMED module
+pub(crate) fn read_med(file_data: &[u8]) -> Result<Song, ModError> {
+    let header = read_bytes(file_data, 0, HEADER_SIZE)?;
+    let version = header[3] - b'0';
+    let is_mmd0 = version == 0;
+    let song = read_bytes(file_data, read_u32_be(header, 8)? as usize, SONG_SIZE)?;
+    let flags = SongFlags::new(song);
+
+    let num_samples = (song[787] as usize).min(MAX_SAMPLES);
+    let sample_array = read_u32_be(header, 24)? as usize;
+    let mut samples = Vec::new();
+    // notes are moved by the song's transpose and the transpose of their instrument. Instrument 0 plays on with the
+    // channel's instrument so it only has the song's transpose
+    let song_transpose = song[766] as i8 as i32;
+    let mut transposes = vec![song_transpose];
+    for sample_num in 0..num_samples {
+        let info = &song[sample_num * SAMPLE_INFO_SIZE..(sample_num + 1) * SAMPLE_INFO_SIZE];
+        transposes.push(song_transpose + info[7] as i8 as i32);
+        let instrument = if sample_array == 0 {
+            0
+        } else {
+            read_u32_be(file_data, sample_array + sample_num * 4)? as usize
+        };
+        let mut sample = Sample {
+            name: String::new(),
+            size: 0,
+            volume: info[6].min(64),
+            fine_tune: 0,
+            // the loop is in words
+            repeat_offset: read_u16_be(info, 0)? as u32 * 2,
+            repeat_size: read_u16_be(info, 2)? as u32 * 2,
+            c2spd: DEFAULT_C2SPD,
+            relative_pitch: 0,
+            pan: None,
+            sustain_loop: None,
+            samples: Vec::new(),
+        };
+        if instrument != 0 {
+            sample.samples = read_sample_data(file_data, instrument)?;
+        }
+        fit_loop(&mut sample);
+        samples.push(sample);
+    }
+    let expansion = read_u32_be(header, 32)? as usize;
+    let name = if expansion == 0 {
+        String::new()
+    } else {
+        read_expansion(file_data, expansion, &mut samples)?
+    };
+
+    let num_blocks = read_u16_be(song, 504)? as usize;
+    let block_array = read_u32_be(header, 16)? as usize;
+    let mut block_offsets = Vec::new();
+    for block in 0..num_blocks {
+        block_offsets.push(read_u32_be(file_data, block_array + block * 4)? as usize);
+    }
+    let mut num_channels = 0;
+    for offset in &block_offsets {
+        let (num_tracks, _, _) = block_layout(file_data, *offset, is_mmd0)?;
+        num_channels = num_channels.max(num_tracks);
+    }
+    let mut patterns = Vec::new();
+    for offset in block_offsets {
+        patterns.push(read_block(
+            file_data,
+            offset,
+            is_mmd0,
+            num_channels,
+            &transposes,
+            &flags,
+        )?);
+    }
+
+    // The pattern table can only refer to the first 256 blocks. Commands in the play sequences are skipped
+    let pattern_table: Vec<u8> = read_block_sequence(file_data, song, version)?
+        .iter()
+        .filter(|block| **block < SEQUENCE_COMMAND && (**block as usize) < num_blocks.min(256))
+        .map(|block| *block as u8)
+        .collect();
+    if pattern_table.is_empty() {
+        return Err(ModError::BadHeader(String::from(
+            "the play sequence does not play any blocks",
+        )));
+    }
+
+    // MMD2 and later can pan each track from -16 ( left ) to 16 ( right )
+    let track_pans = if version >= 2 {
+        read_u32_be(song, 524)? as usize
+    } else {
+        0
+    };
+    let mut channel_pans = Vec::new();
+    for channel in 0..num_channels {
+        channel_pans.push(if track_pans == 0 {
+            amiga_channel_pan(channel as u32)
+        } else {
+            (read_bytes(file_data, track_pans + channel, 1)?[0] as i8).clamp(-16, 16) as f32 / 16.0
+        });
+    }
+
+    let (initial_speed, initial_tempo) = match flags.tempo_effect(read_u16_be(song, 764)? as u32) {
+        Effect::SetTicksPerLine { ticks } => (ticks as u32, 125),
+        Effect::SetTempo { bpm } => (flags.ticks_per_line, bpm as u32),
+        _ => (flags.ticks_per_line, 125),
+    };
+    let has_standard_notes = has_standard_notes_only(&patterns, &pattern_table);
+    let num_used_patterns = pattern_table.len() as u32;
+    Ok(Song {
+        name,
+        format: FormatDescription {
+            kind: FormatKind::Med,
+            num_channels: num_channels as u32,
+            num_samples: samples.len() as u32,
+            has_tag: true,
+            tag: latin1_string(&header[0..4]),
+            has_linear_periods: false,
+        },
+        samples,
+        instruments: Vec::new(),
+        patterns,
+        pattern_table,
+        num_used_patterns,
+        // the song ends after the last position
+        end_position: num_used_patterns,
+        has_standard_notes,
+        initial_speed,
+        initial_tempo,
+        initial_global_volume: 64,
+        channel_pans,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{play_ticks, put_u16_be, put_u32_be};
+    use super::*;
+
+    // A song with one 8 byte sample and one two line block played twice. Line 0 plays C-2 with the sample and volume 20
+    // on track 0 and an instrument with the high bits set on track 1. Line 1 slides the volume of track 0 down by 2.
+    // MMD0 songs give the tempo in MED units and MMD2 songs in beats per minute
+    fn build_med(version: u8) -> Vec<u8> {
+        let song = HEADER_SIZE;
+        let mut data = vec![0u8; HEADER_SIZE + SONG_SIZE];
+        data[0..4].copy_from_slice(b"MMD0");
+        data[3] += version;
+        put_u32_be(&mut data, 8, song as u32);
+        // the sample loops 4 bytes from byte 2 and is transposed up an octave
+        put_u16_be(&mut data, song, 1);
+        put_u16_be(&mut data, song + 2, 2);
+        data[song + 6] = 40;
+        data[song + 7] = 12;
+        put_u16_be(&mut data, song + 504, 1);
+        data[song + 766] = -12i8 as u8;
+        data[song + 769] = 3;
+        data[song + 787] = 1;
+
+        let block_array = data.len();
+        put_u32_be(&mut data, 16, block_array as u32);
+        data.extend(&(block_array as u32 + 4).to_be_bytes());
+        if version == 0 {
+            data.extend(&[4, 1]);
+            data.extend(&[13, 0x1c, 0x20, 0x41, 0x10, 0, 0, 0, 0, 0, 0, 0]);
+            data.extend(&[0, 0x0d, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        } else {
+            data.extend(&[0, 4, 0, 1, 0, 0, 0, 0]);
+            data.extend(&[13, 1, 0x0c, 0x20, 1, 0x21, 0, 0]);
+            data.extend(&[0; 8]);
+            data.extend(&[0, 0, 0x0d, 0x02]);
+            data.extend(&[0; 12]);
+        }
+        if version < 2 {
+            put_u16_be(&mut data, song + 506, 2);
+            put_u16_be(&mut data, song + 764, 33);
+        } else {
+            // one section playing a sequence with a command between the two plays of the block
+            let sequence = data.len();
+            data.extend(&[0; 40]);
+            data.extend(&[0, 3, 0, 0, 0x80, 0x01, 0, 0]);
+            let sequence_table = data.len();
+            data.extend(&(sequence as u32).to_be_bytes());
+            let sections = data.len();
+            data.extend(&[0, 0]);
+            put_u16_be(&mut data, song + 506, 1);
+            put_u32_be(&mut data, song + 508, sequence_table as u32);
+            put_u32_be(&mut data, song + 512, sections as u32);
+            put_u16_be(&mut data, song + 522, 1);
+            put_u16_be(&mut data, song + 764, 100);
+            data[song + 768] = FLAG2_BPM | 3;
+        }
+
+        let sample_array = data.len();
+        put_u32_be(&mut data, 24, sample_array as u32);
+        data.extend(&(sample_array as u32 + 4).to_be_bytes());
+        data.extend(&[0, 0, 0, 8, 0, 0]);
+        data.extend(&[0, 16, 32, 48, 64, 80, 96, 112]);
+        data
+    }
+
+    #[test]
+    fn test_read_med() {
+        for version in 0..=2 {
+            let song = read_med(&build_med(version)).unwrap();
+            assert_eq!(song.format.kind, FormatKind::Med);
+            assert_eq!(song.format.num_channels, 4);
+            assert_eq!(song.pattern_table, vec![0, 0]);
+            if version == 2 {
+                assert_eq!((song.initial_speed, song.initial_tempo), (3, 100));
+            } else {
+                assert_eq!((song.initial_speed, song.initial_tempo), (3, 125));
+            }
+
+            let sample = &song.samples[0];
+            assert_eq!(sample.volume(), 40);
+            assert_eq!(sample.samples()[1], 16 << 8);
+            assert_eq!((sample.repeat_offset(), sample.repeat_size()), (2, 4));
+
+            let pattern = &song.patterns[0];
+            assert_eq!(pattern.num_lines(), 2);
+            let note = pattern.note(0, 0).unwrap();
+            // the song and sample transposes cancel out
+            assert_eq!((note.period(), note.sample_number()), (428, 1));
+            assert_eq!(*note.effect(), Effect::SetVolume { volume: 20 });
+            assert_eq!(pattern.note(0, 1).unwrap().sample_number(), 0x21);
+            assert_eq!(
+                *pattern.note(1, 0).unwrap().effect(),
+                Effect::VolumeSlide { volume_change: -2 }
+            );
+        }
+    }
+
+    #[test]
+    fn test_tempo() {
+        let flags = SongFlags::default();
+        assert_eq!(flags.tempo_effect(33), Effect::SetTempo { bpm: 125 });
+        assert_eq!(flags.tempo_effect(4), Effect::SetTicksPerLine { ticks: 4 });
+        let bpm_flags = SongFlags {
+            lines_per_beat: Some(8),
+            ..flags
+        };
+        assert_eq!(bpm_flags.tempo_effect(60), Effect::SetTempo { bpm: 120 });
+    }
+
+    #[test]
+    fn test_play_med() {
+        let song = read_med(&build_med(0)).unwrap();
+        // the first line plays on tick 3 and the second line on tick 6. The slide is applied on the tick after
+        let player_state = play_ticks(&song, 8);
+        let channel = &player_state.channels[0];
+        assert_eq!(channel.sample_num, 1);
+        assert_eq!(channel.period, 428);
+        assert_eq!(channel.volume, 18.0);
+    }
+}
@@ -0,0 +1,685 @@
+use super::static_tables;
+use super::{
+    amiga_channel_pan, latin1_string, Effect, FormatDescription, FormatKind, ModError, Note,
+    Pattern, Sample, Song, DEFAULT_C2SPD,
+};
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+pub use info::{read_mod_info, ModInfo};
+pub use probe::{probe, Confidence};
+
+mod composer669;
+mod far;
+mod info;
+mod it;
+mod med;
+mod mtm;
+mod okt;
+mod probe;
+mod s3m;
+mod soundtracker;
+mod stm;
+mod ult;
+mod xm;
+
+fn is_standard_note_period(period: u32) -> bool {
+    // treat 0 as  standard note because it is not a playable note
+    if period == 0 {
+        return true;
+    }
+    static_tables::FREQUENCY_TABLE
+        .binary_search(&period)
+        .is_ok()
+}
+
+// Go through all the notes to determine if it uses only standard notes
+// ( this is a requirement for using table based fine tunes )
+fn has_standard_notes_only(patterns: &[Pattern], pattern_table: &[u8]) -> bool {
+    for pattern_idx in pattern_table {
+        if *pattern_idx as usize >= patterns.len() {
+            continue;
+        }
+        let pattern = &patterns[*pattern_idx as usize];
+
+        for line in &pattern.lines {
+            for note in line {
+                if !is_standard_note_period(note.period) {
+                    return false;
+                }
+            }
+        }
+    }
+    true
+}
+
+/// The Amiga period of `note` in formats that number their notes from 1 for ProTracker's C-1 ( period 856 ). Notes
+/// outside of the ProTracker table are worked out from the equal tempered scale
+fn amiga_note_period(note: i32) -> u32 {
+    let table_index = 48 - note;
+    if (0..60).contains(&table_index) {
+        static_tables::FREQUENCY_TABLE[table_index as usize]
+    } else {
+        (856.0 * 2.0f32.powf((1 - note) as f32 / 12.0))
+            .round()
+            .max(1.0) as u32
+    }
+}
+
+/// Makes the size of a sample the length of its data and keeps its loop within the data. Loops of 2 bytes or less do not
+/// loop
+fn fit_loop(sample: &mut Sample) {
+    sample.size = sample.samples.len() as u32;
+    if sample.repeat_size <= 2 || sample.repeat_offset >= sample.size {
+        sample.repeat_offset = 0;
+        sample.repeat_size = 0;
+    } else {
+        sample.repeat_size = sample.repeat_size.min(sample.size - sample.repeat_offset);
+    }
+}
+
+/// The C2Spd of a sample with a fine tune in eighths of a semitone, for formats that store fine tunes rather than C2Spds
+fn fine_tune_c2spd(fine_tune: i8) -> u32 {
+    (DEFAULT_C2SPD as f32 * 2.0f32.powf(fine_tune as f32 / 96.0)).round() as u32
+}
+
+/// Returns `len` bytes starting at `offset` or an error if the data is too short
+fn read_bytes(file_data: &[u8], offset: usize, len: usize) -> Result<&[u8], ModError> {
+    file_data
+        .get(offset..offset + len)
+        .ok_or(ModError::Truncated { offset, len })
+}
+
+/// Reads a little endian 16 bit value at `offset`
+pub(crate) fn read_u16(data: &[u8], offset: usize) -> Result<u16, ModError> {
+    let bytes = read_bytes(data, offset, 2)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+/// Reads a little endian 32 bit value at `offset`
+pub(crate) fn read_u32(data: &[u8], offset: usize) -> Result<u32, ModError> {
+    let bytes = read_bytes(data, offset, 4)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Reads a big endian 16 bit value at `offset`
+pub(crate) fn read_u16_be(data: &[u8], offset: usize) -> Result<u16, ModError> {
+    let bytes = read_bytes(data, offset, 2)?;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+/// Reads a big endian 32 bit value at `offset`
+pub(crate) fn read_u32_be(data: &[u8], offset: usize) -> Result<u32, ModError> {
+    let bytes = read_bytes(data, offset, 4)?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Reads the channel count from the generic multichannel tags: nCHN ( 1-9 channels ), nnCH ( 10-32 channels ) and
+/// TDZn ( 1-3 channels, written by TakeTracker )
+fn tag_channel_count(tag: &[u8]) -> Option<u32> {
+    let digits = match tag {
+        [_, b'C', b'H', b'N'] => &tag[0..1],
+        [_, _, b'C', b'H'] => &tag[0..2],
+        [b'T', b'D', b'Z', _] => &tag[3..4],
+        _ => return None,
+    };
+    if !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    let num_channels: u32 = std::str::from_utf8(digits).ok()?.parse().ok()?;
+    if (1..=32).contains(&num_channels) {
+        Some(num_channels)
+    } else {
+        None
+    }
+}
+
+/// The slot in a channel's effect memory that an effect with a parameter of 0 takes its parameter from. None for effects
+/// without memory
+pub(crate) fn effect_memory_slot(kind: FormatKind, command: u8) -> Option<usize> {
+    match kind {
+        // all the effects with memory share the same memory
+        FormatKind::S3m if s3m::uses_effect_memory(command) => Some(0),
+        FormatKind::Xm => xm::effect_memory_slot(command),
+        FormatKind::It => it::effect_memory_slot(command),
+        _ => None,
+    }
+}
+
+/// Decodes an effect command and parameter of the format
+pub(crate) fn decode_effect(kind: FormatKind, command: u8, parameter: u8) -> Effect {
+    match kind {
+        FormatKind::Mod | FormatKind::Soundtracker => Effect::new(command, parameter as i8),
+        FormatKind::S3m => s3m::decode_effect(command, parameter),
+        FormatKind::Xm => xm::decode_effect(command, parameter),
+        FormatKind::It => it::decode_effect(command, parameter),
+        FormatKind::Okt => okt::decode_effect(command, parameter),
+        FormatKind::Med => med::decode_effect(command, parameter, &med::SongFlags::default()),
+        FormatKind::Composer669 => composer669::decode_effect(command, parameter),
+        FormatKind::Mtm => Effect::new(command, parameter as i8),
+        // Scream Tracker 2.21 stores the speed in hex
+        FormatKind::Stm => stm::decode_effect(command, parameter, 21),
+        FormatKind::Ult => ult::decode_effect(command, parameter),
+        FormatKind::Far => far::decode_effect(command, parameter),
+    }
+}
+
+/// The period of `key` for formats that store keys rather than periods. The sample's tuning is given by `c2spd` or
+/// `relative_pitch` depending on the format
+pub(crate) fn key_period(
+    format: &FormatDescription,
+    key: u8,
+    c2spd: u32,
+    relative_pitch: i16,
+) -> u32 {
+    match format.kind {
+        FormatKind::Xm => xm::note_period(key, relative_pitch, format.has_linear_periods),
+        FormatKind::It => it::note_period(key, c2spd, format.has_linear_periods),
+        _ => s3m::note_period(key, c2spd),
+    }
+}
+
+/// The number of channels of a MOD file with the tag, or None if it is not a known MOD tag
+fn mod_tag_channel_count(tag: &[u8]) -> Option<u32> {
+    match tag {
+        b"M.K." | b"FLT4" | b"M!K!" => Some(4),
+        // StarTrekker and Octalyser eight channel files
+        b"FLT8" | b"OCTA" | b"CD81" => Some(8),
+        b"CD61" => Some(6),
+        _ => tag_channel_count(tag),
+    }
+}
+
+/// Works out the format of a module from its magic numbers and tags. The formats with long magic numbers are checked
+/// first and the ones with weak or no magic numbers last. Data that is not recognised is taken to be a 15 sample
+/// Soundtracker module, which has no tag
+///
+/// # Arguments
+/// * `file_data` - the slice of bytes of the module
+///
+pub fn detect_format(file_data: &[u8]) -> FormatKind {
+    if s3m::is_s3m(file_data) {
+        FormatKind::S3m
+    } else if xm::is_xm(file_data) {
+        FormatKind::Xm
+    } else if it::is_it(file_data) {
+        FormatKind::It
+    } else if okt::is_okt(file_data) {
+        FormatKind::Okt
+    } else if med::is_med(file_data) {
+        FormatKind::Med
+    } else if ult::is_ult(file_data) {
+        FormatKind::Ult
+    } else if far::is_far(file_data) {
+        FormatKind::Far
+    } else if file_data
+        .get(1080..1084)
+        .and_then(mod_tag_channel_count)
+        .is_some()
+    {
+        FormatKind::Mod
+    } else if mtm::is_mtm(file_data) {
+        FormatKind::Mtm
+    } else if stm::is_stm(file_data) {
+        FormatKind::Stm
+    } else if composer669::is_669(file_data) {
+        FormatKind::Composer669
+    } else {
+        FormatKind::Soundtracker
+    }
+}
+
+/**
+ * Identify the mod format version based on the tag. If there is not identifiable that it is assumed to be an original
+ * 15 sample Soundtracker mod.
+ */
+fn get_format(file_data: &[u8]) -> Result<FormatDescription, ModError> {
+    let original_mod = FormatDescription {
+        kind: FormatKind::Soundtracker,
+        num_channels: 4,
+        num_samples: 15,
+        has_tag: false,
+        tag: String::new(),
+        has_linear_periods: false,
+    };
+    // Files too short to hold a tag can only be original 15 sample mods
+    let tag_bytes = match file_data.get(1080..1084) {
+        Some(tag) => tag,
+        None => return Ok(original_mod),
+    };
+    let format_tag = latin1_string(tag_bytes);
+    println!("formtat tag: {}", format_tag);
+    match mod_tag_channel_count(tag_bytes) {
+        Some(num_channels) => Ok(FormatDescription {
+            kind: FormatKind::Mod,
+            num_channels,
+            num_samples: 31,
+            has_tag: true,
+            tag: format_tag,
+            has_linear_periods: false,
+        }),
+        None => Ok(original_mod),
+    }
+}
+
+/// Reads a module music file and returns a song structure ready for playing
+///
+/// Panics if the file can not be read or is not a valid mod file. Use `try_read_mod_file` to handle errors.
+///
+/// # Arguments
+/// * `file_name` - the mod file on disk
+///
+pub fn read_mod_file<P: AsRef<Path>>(file_name: P) -> Song {
+    let file_name = file_name.as_ref();
+    match try_read_mod_file(file_name) {
+        Ok(song) => song,
+        Err(err) => panic!("Cant read file {}: {}", file_name.display(), err),
+    }
+}
+
+/// Reads a module music file (in byte slice form) and returns a song structure ready for playing
+///
+/// Panics if the data is not a valid mod file. Use `try_read_mod_file_slice` to handle errors.
+///
+/// # Arguments
+/// * `file_data` - the slice of bytes to load from
+///
+pub fn read_mod_file_slice(file_data: &[u8]) -> Song {
+    match try_read_mod_file_slice(file_data) {
+        Ok(song) => song,
+        Err(err) => panic!("Cant read mod data: {}", err),
+    }
+}
+
+/// Reads a module music file and returns a song structure ready for playing or an error describing why it could not be loaded
+///
+/// # Arguments
+/// * `file_name` - the mod file on disk
+///
+pub fn try_read_mod_file<P: AsRef<Path>>(file_name: P) -> Result<Song, ModError> {
+    let file_data: Vec<u8> = fs::read(file_name)?;
+    try_read_mod_file_slice(&file_data)
+}
+
+/// Reads a module music file from a reader, such as a file in an archive or a network stream, and returns a song structure
+/// ready for playing
+///
+/// Panics if the data can not be read or is not a valid mod file. Use `try_read_mod` to handle errors.
+///
+/// # Arguments
+/// * `reader` - the source of the module data, read to its end
+///
+pub fn read_mod<R: Read>(reader: R) -> Song {
+    match try_read_mod(reader) {
+        Ok(song) => song,
+        Err(err) => panic!("Cant read mod data: {}", err),
+    }
+}
+
+/// Reads a module music file from a reader and returns a song structure ready for playing or an error describing why it
+/// could not be loaded. Sources that can seek, such as files, are read the same way
+///
+/// The data is read to its end before it is loaded because some formats can only be worked out from the total length,
+/// like MOD files where the sample data is measured from the back of the file.
+///
+/// # Arguments
+/// * `reader` - the source of the module data, read to its end
+///
+pub fn try_read_mod<R: Read>(mut reader: R) -> Result<Song, ModError> {
+    let mut file_data = Vec::new();
+    reader.read_to_end(&mut file_data)?;
+    try_read_mod_file_slice(&file_data)
+}
+
+/// Reads a module music file (in byte slice form) and returns a song structure ready for playing or an error describing
+/// why it could not be loaded
+///
+/// # Arguments
+/// * `file_data` - the slice of bytes to load from
+///
+pub fn try_read_mod_file_slice(file_data: &[u8]) -> Result<Song, ModError> {
+    read_format(detect_format(file_data), file_data)
+}
+
+/// Reads the data as a module in the format `kind`
+fn read_format(kind: FormatKind, file_data: &[u8]) -> Result<Song, ModError> {
+    match kind {
+        FormatKind::S3m => s3m::read_s3m(file_data),
+        FormatKind::Xm => xm::read_xm(file_data),
+        FormatKind::It => it::read_it(file_data),
+        FormatKind::Okt => okt::read_okt(file_data),
+        FormatKind::Med => med::read_med(file_data),
+        FormatKind::Ult => ult::read_ult(file_data),
+        FormatKind::Far => far::read_far(file_data),
+        FormatKind::Mtm => mtm::read_mtm(file_data),
+        FormatKind::Stm => stm::read_stm(file_data),
+        FormatKind::Composer669 => composer669::read_669(file_data),
+        FormatKind::Mod | FormatKind::Soundtracker => read_mod_data(file_data),
+    }
+}
+
+/// Reads a ProTracker or Soundtracker MOD file
+fn read_mod_data(file_data: &[u8]) -> Result<Song, ModError> {
+    let song_name = latin1_string(read_bytes(file_data, 0, 20)?);
+    let format = get_format(file_data)?;
+
+    let mut samples: Vec<Sample> = Vec::new();
+    let mut offset: usize = 20;
+    for _sample_num in 0..format.num_samples {
+        samples.push(Sample::new(read_bytes(file_data, offset, 30)?));
+        offset += 30;
+    }
+
+    // Figure out where to stop and repeat pos ( with option to repeat in the player )
+    let song_positions = read_bytes(file_data, offset, 2)?;
+    let num_used_patterns: u8 = song_positions[0];
+    let end_position: u8 = song_positions[1];
+    offset += 2;
+    let mut pattern_table: Vec<u8> = read_bytes(file_data, offset, 128)?.to_vec();
+    offset += 128;
+    // FLT8 files refer to the first of the two 4 channel patterns that make up each 8 channel pattern
+    if format.has_split_patterns() {
+        for pattern_idx in pattern_table.iter_mut() {
+            *pattern_idx /= 2;
+        }
+    }
+    if num_used_patterns == 0 || num_used_patterns > 128 {
+        return Err(ModError::BadHeader(format!(
+            "song length {} is not in the range 1-128",
+            num_used_patterns
+        )));
+    }
+
+    // Skip the tag if one has been identified
+    if format.has_tag {
+        offset += 4;
+    }
+
+    // Work out how the total size of the sample data at tbe back od the file
+    let total_sample_size: usize = samples.iter().map(|sample| sample.size as usize).sum();
+    if offset + total_sample_size > file_data.len() {
+        return Err(ModError::InconsistentSizes {
+            required: offset + total_sample_size,
+            available: file_data.len(),
+        });
+    }
+
+    // The pattern take up all the space that remains after everything else has been accounted for
+    let total_pattern_size = file_data.len() - offset - total_sample_size;
+    let single_pattern_size = format.num_channels as usize * 4 * 64;
+    let mut num_patterns = total_pattern_size / single_pattern_size;
+    // Find the highest pattern referenced within the used patter references. This is the minimum number of patterns we must load
+    let slc = &pattern_table[0..(num_used_patterns as usize)];
+    let min_pattern_required = *slc.iter().max().unwrap() as usize + 1;
+    // we must read AT LEAST the max_pattern_required patterns
+    if min_pattern_required > num_patterns {
+        num_patterns = min_pattern_required;
+    }
+
+    // Read the patterns
+    let mut patterns: Vec<Pattern> = Vec::new();
+    for _pattern_number in 0..num_patterns {
+        let mut pattern = Pattern::new();
+        for channels in format.pattern_channel_blocks() {
+            for line in 0..64 {
+                for _channel in channels.clone() {
+                    let note = Note::new(read_bytes(file_data, offset, 4)?, &format);
+                    pattern.lines[line].push(note);
+                    offset += 4;
+                }
+            }
+        }
+        patterns.push(pattern);
+    }
+
+    // Some mods have weird garbage between the end of the pattern data and the samples
+    // ( and some weird files do not have enough for both patterns ans samples. Effectively some storage is used for both!!)
+    // Skip the potential garbage by working out the sample position from the back of the file
+    offset = file_data.len() - total_sample_size;
+
+    for sample in &mut samples {
+        let length = sample.size as usize;
+        sample.samples = file_data[offset..offset + length]
+            .iter()
+            .map(|&byte| (byte as i8 as i16) << 8)
+            .collect();
+        offset += length;
+    }
+
+    let mut initial_tempo = 125;
+    if format.kind == FormatKind::Soundtracker {
+        initial_tempo = soundtracker::tempo(end_position);
+        if soundtracker::is_ultimate_soundtracker(&patterns) {
+            let sample_headers = read_bytes(file_data, 20, samples.len() * 30)?;
+            soundtracker::convert_ultimate(&mut patterns, &mut samples, sample_headers);
+        }
+    }
+
+    // there are non standard notes, we cant use table based fine tune
+    let has_standard_notes = has_standard_notes_only(&patterns, &pattern_table);
+
+    let channel_pans = (0..format.num_channels).map(amiga_channel_pan).collect();
+    Ok(Song {
+        name: song_name,
+        format,
+        samples,
+        instruments: Vec::new(),
+        patterns,
+        pattern_table,
+        num_used_patterns: num_used_patterns as u32,
+        end_position: end_position as u32,
+        has_standard_notes,
+        initial_speed: 6,
+        initial_tempo,
+        initial_global_volume: 64,
+        channel_pans,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{next_sample, update_tick, PlayerState};
+    use super::*;
+
+    // Helpers for the tests of the format loaders that build their files in memory
+    pub(super) fn put_u16(data: &mut [u8], offset: usize, value: u16) {
+        data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    pub(super) fn put_u32(data: &mut [u8], offset: usize, value: u32) {
+        data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    pub(super) fn put_u16_be(data: &mut [u8], offset: usize, value: u16) {
+        data[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+    }
+
+    pub(super) fn put_u32_be(data: &mut [u8], offset: usize, value: u32) {
+        data[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+    }
+
+    /// Plays the first `num_ticks` ticks of the song
+    pub(super) fn play_ticks(song: &Song, num_ticks: usize) -> PlayerState {
+        let mut player_state = PlayerState::new(song.format.num_channels, 44100);
+        for _tick in 0..num_ticks {
+            update_tick(song, &mut player_state);
+        }
+        player_state
+    }
+
+    fn build_mod(tag: &[u8; 4], sample_size_words: u16) -> Vec<u8> {
+        let mut data = vec![0u8; 1084];
+        // first sample
+        data[20 + 22..20 + 24].copy_from_slice(&sample_size_words.to_be_bytes());
+        data[20 + 25] = 64;
+        // one position playing pattern 0
+        data[950] = 1;
+        data[1080..1084].copy_from_slice(tag);
+        data.extend(vec![0u8; 4 * 4 * 64]);
+        data.extend(vec![0u8; sample_size_words as usize * 2]);
+        data
+    }
+
+    #[test]
+    fn test_load_minimal() {
+        let song = try_read_mod_file_slice(&build_mod(b"M.K.", 8)).expect("valid mod");
+        assert_eq!(song.patterns.len(), 1);
+        assert_eq!(song.samples[0].samples.len(), 16);
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let data = build_mod(b"M.K.", 8);
+        assert!(matches!(
+            try_read_mod_file_slice(&data[0..500]),
+            Err(ModError::Truncated { .. })
+        ));
+        assert!(matches!(
+            try_read_mod_file_slice(&build_mod(b"M.K.", 1000)[0..2000]),
+            Err(ModError::InconsistentSizes { .. })
+        ));
+
+        let mut no_positions = data.clone();
+        no_positions[950] = 0;
+        assert!(matches!(
+            try_read_mod_file_slice(&no_positions),
+            Err(ModError::BadHeader(_))
+        ));
+
+        let mut missing_pattern = data;
+        missing_pattern[952] = 3;
+        assert!(matches!(
+            try_read_mod_file_slice(&missing_pattern),
+            Err(ModError::Truncated { .. })
+        ));
+    }
+
+    #[test]
+    fn test_corrupt_mod_plays() {
+        let mut data = build_mod(b"M.K.", 8);
+        // a fine tune with the high nibble set
+        data[20 + 24] = 0xf7;
+        // an arpeggio on a period that is not in the period table
+        data[1084..1088].copy_from_slice(&[0x01, 0xae, 0x10, 0x37]);
+        let song = try_read_mod_file_slice(&data).unwrap();
+        assert_eq!(song.samples[0].fine_tune(), 7);
+        assert!(!song.has_standard_notes);
+
+        let mut player_state = PlayerState::new(song.format.num_channels, 44100);
+        for _sample in 0..44100 {
+            next_sample(&song, &mut player_state);
+        }
+        assert!(player_state.channels[0].period > 0);
+    }
+
+    #[test]
+    fn test_multichannel_tags() {
+        for (tag, num_channels) in &[
+            (b"2CHN", 2),
+            (b"4CHN", 4),
+            (b"9CHN", 9),
+            (b"10CH", 10),
+            (b"16CH", 16),
+            (b"32CH", 32),
+            (b"TDZ1", 1),
+            (b"TDZ3", 3),
+        ] {
+            let format = get_format(&build_mod(tag, 8)).unwrap();
+            assert_eq!(format.num_channels, *num_channels);
+            assert_eq!(format.num_samples, 31);
+            assert_eq!(format.tag.as_bytes(), *tag);
+        }
+        // out of range or malformed tags are not multichannel tags
+        for tag in &[b"0CHN", b"33CH", b"+8CH", b"TDZx"] {
+            let format = get_format(&build_mod(tag, 8)).unwrap();
+            assert!(!format.has_tag);
+            assert_eq!(format.tag, "");
+        }
+
+        let mut song_data = build_mod(b"16CH", 8);
+        // a 16 channel pattern is four times the size of the 4 channel one build_mod adds
+        song_data.splice(1084..1084, vec![0u8; 3 * 4 * 4 * 64]);
+        let song = try_read_mod_file_slice(&song_data).expect("valid mod");
+        assert_eq!(song.patterns.len(), 1);
+        assert_eq!(song.patterns[0].lines[0].len(), 16);
+    }
+
+    #[test]
+    fn test_eight_channel_layouts() {
+        // the same song stored in the normal eight channel layout and in the FLT8 layout
+        let octa = read_mod_file("mod_files/bubble_bobble_octa.mod");
+        let flt8 = read_mod_file("mod_files/bubble_bobble_flt8.mod");
+        assert_eq!(flt8.format.num_channels, 8);
+        assert_eq!(flt8.pattern_table, octa.pattern_table);
+        assert_eq!(flt8.patterns.len(), octa.patterns.len());
+        for (flt8_pattern, octa_pattern) in flt8.patterns.iter().zip(&octa.patterns) {
+            for (flt8_line, octa_line) in flt8_pattern.lines.iter().zip(&octa_pattern.lines) {
+                assert_eq!(flt8_line.len(), 8);
+                for (flt8_note, octa_note) in flt8_line.iter().zip(octa_line) {
+                    assert_eq!(flt8_note, octa_note);
+                }
+            }
+        }
+        let cd61 = read_mod_file("mod_files/star-rai_cd61.mod");
+        assert_eq!(cd61.format.num_channels, 6);
+    }
+
+    #[test]
+    fn test_detect_format() {
+        assert_eq!(detect_format(&build_mod(b"M.K.", 8)), FormatKind::Mod);
+        assert_eq!(detect_format(&build_mod(b"8CHN", 8)), FormatKind::Mod);
+        assert_eq!(
+            detect_format(&build_mod(b"????", 8)),
+            FormatKind::Soundtracker
+        );
+        assert_eq!(detect_format(&[]), FormatKind::Soundtracker);
+
+        let mut ult = b"MAS_UTrack_V003".to_vec();
+        assert_eq!(detect_format(&ult), FormatKind::Ult);
+        ult[14] = b'5';
+        assert_eq!(detect_format(&ult), FormatKind::Soundtracker);
+
+        let mut far = vec![0u8; 98];
+        far[0..4].copy_from_slice(b"FAR\xfe");
+        far[44..47].copy_from_slice(b"\r\n\x1a");
+        assert_eq!(detect_format(&far), FormatKind::Far);
+
+        assert_eq!(detect_format(b"MTM\x10"), FormatKind::Mtm);
+        let mut stm = vec![0u8; 48];
+        stm[28..31].copy_from_slice(&[0x1a, 2, 2]);
+        assert_eq!(detect_format(&stm), FormatKind::Stm);
+
+        // 669 files are only recognised when their header makes sense
+        let mut composer669 = vec![0u8; 0x1f1];
+        composer669[0..2].copy_from_slice(b"if");
+        composer669[111] = 1;
+        assert_eq!(detect_format(&composer669), FormatKind::Composer669);
+        composer669[369] = 64;
+        assert_eq!(detect_format(&composer669), FormatKind::Soundtracker);
+    }
+
+    #[test]
+    fn test_read_from_reader() {
+        let data = build_mod(b"M.K.", 8);
+        let song = try_read_mod(std::io::Cursor::new(&data)).expect("valid mod");
+        assert_eq!(song.samples[0].samples.len(), 16);
+        // a byte slice is a reader that can not seek
+        let song = read_mod(&data[..]);
+        assert_eq!(song.patterns.len(), 1);
+
+        let file = fs::File::open("mod_files/BUBBLE_BOBBLE.MOD").unwrap();
+        let song = try_read_mod(std::io::BufReader::new(file)).unwrap();
+        let from_path = read_mod_file(Path::new("mod_files/BUBBLE_BOBBLE.MOD"));
+        assert_eq!(song.patterns.len(), from_path.patterns.len());
+        assert_eq!(song.samples[1].samples, from_path.samples[1].samples);
+    }
+
+    #[test]
+    fn test_missing_file() {
+        assert!(matches!(
+            try_read_mod_file("mod_files/does_not_exist.mod"),
+            Err(ModError::Io(_))
+        ));
+    }
+}
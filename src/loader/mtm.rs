@@ -0,0 +1,301 @@
+//! MultiTracker modules
+//!
+//! Patterns are made of tracks. Each pattern lists the track played on each of its 32 channels, so a track can be used
+//! by several patterns. Tracks are lines of three byte notes with the ProTracker effects. Samples are 8 bit unsigned or
+//! 16 bit.
+use super::super::{
+    latin1_string, Effect, FormatDescription, FormatKind, ModError, Note, Pattern, Sample, Song,
+};
+use super::{fine_tune_c2spd, fit_loop, read_bytes, read_u16, read_u32};
+
+const HEADER_SIZE: usize = 66;
+const SAMPLE_HEADER_SIZE: usize = 37;
+const MAX_CHANNELS: usize = 32;
+const SAMPLE_16_BIT: u8 = 1;
+
+/// Is the data a MultiTracker module
+pub(crate) fn is_mtm(file_data: &[u8]) -> bool {
+    match file_data.get(0..4) {
+        Some([b'M', b'T', b'M', version]) => version >> 4 == 1,
+        _ => false,
+    }
+}
+
+fn read_sample(header: &[u8]) -> Result<Sample, ModError> {
+    let bytes_per_point = if header[36] & SAMPLE_16_BIT != 0 {
+        2
+    } else {
+        1
+    };
+    let loop_start = read_u32(header, 26)? / bytes_per_point;
+    let loop_end = read_u32(header, 30)? / bytes_per_point;
+    Ok(Sample {
+        name: latin1_string(&header[0..22]),
+        size: read_u32(header, 22)?,
+        volume: header[35].min(64),
+        fine_tune: 0,
+        repeat_offset: loop_start,
+        repeat_size: loop_end.saturating_sub(loop_start),
+        c2spd: fine_tune_c2spd(header[34] as i8),
+        relative_pitch: 0,
+        pan: None,
+        sustain_loop: None,
+        samples: Vec::new(),
+    })
+}
+
+fn read_note(bytes: &[u8]) -> Note {
+    let mut note = Note::empty();
+    if bytes[0] >> 2 != 0 {
+        note.key = (bytes[0] >> 2) + 25;
+    }
+    note.sample_number = (bytes[0] & 3) << 4 | bytes[1] >> 4;
+    note.effect_command = bytes[1] & 0x0f;
+    note.effect_parameter = bytes[2];
+    note.effect = Effect::new(note.effect_command, note.effect_parameter as i8);
+    note
+}
+
+/// Reads a MultiTracker module
+pub(crate) fn read_mtm(file_data: &[u8]) -> Result<Song, ModError> {
+    let header = read_bytes(file_data, 0, HEADER_SIZE)?;
+    let num_tracks = read_u16(header, 24)? as usize;
+    let num_patterns = header[26] as usize + 1;
+    let num_orders = header[27] as usize + 1;
+    let comment_length = read_u16(header, 28)? as usize;
+    let num_samples = header[30] as usize;
+    let num_lines = header[32].max(1) as usize;
+    let num_channels = header[33] as usize;
+    if num_channels == 0 || num_channels > MAX_CHANNELS {
+        return Err(ModError::BadHeader(format!(
+            "{} channels is not in the range 1-{}",
+            num_channels, MAX_CHANNELS
+        )));
+    }
+    let channel_pans = header[34..34 + num_channels]
+        .iter()
+        .map(|pan| (pan & 0x0f) as f32 / 15.0 * 2.0 - 1.0)
+        .collect();
+
+    let mut offset = HEADER_SIZE;
+    let mut samples = Vec::new();
+    let mut is_16_bit = Vec::new();
+    for _ in 0..num_samples {
+        let sample_header = read_bytes(file_data, offset, SAMPLE_HEADER_SIZE)?;
+        samples.push(read_sample(sample_header)?);
+        is_16_bit.push(sample_header[36] & SAMPLE_16_BIT != 0);
+        offset += SAMPLE_HEADER_SIZE;
+    }
+    let orders = read_bytes(file_data, offset, 128)?;
+    offset += 128;
+
+    // track 0 is an empty track that is not stored
+    let track_size = num_lines * 3;
+    let tracks = read_bytes(file_data, offset, num_tracks * track_size)?;
+    offset += num_tracks * track_size;
+    let mut patterns = Vec::new();
+    for _ in 0..num_patterns {
+        let mut pattern = Pattern::with_lines(num_lines);
+        for channel in 0..MAX_CHANNELS {
+            let track = read_u16(file_data, offset + channel * 2)? as usize;
+            if channel >= num_channels {
+                continue;
+            }
+            if track > num_tracks {
+                return Err(ModError::BadHeader(format!(
+                    "track {} is played but the song has {}",
+                    track, num_tracks
+                )));
+            }
+            for (line_num, line) in pattern.lines.iter_mut().enumerate() {
+                line.push(if track == 0 {
+                    Note::empty()
+                } else {
+                    let note_offset = (track - 1) * track_size + line_num * 3;
+                    read_note(&tracks[note_offset..note_offset + 3])
+                });
+            }
+        }
+        patterns.push(pattern);
+        offset += MAX_CHANNELS * 2;
+    }
+    offset += comment_length;
+
+    // The last sample is often cut short. Play what there is of it
+    for (sample, is_16_bit) in samples.iter_mut().zip(is_16_bit) {
+        let end = (offset + sample.size as usize).min(file_data.len());
+        let data = file_data.get(offset..end).unwrap_or(&[]);
+        sample.samples = if is_16_bit {
+            data.chunks_exact(2)
+                .map(|point| i16::from_le_bytes([point[0], point[1]]))
+                .collect()
+        } else {
+            data.iter()
+                .map(|&byte| ((byte ^ 0x80) as i8 as i16) << 8)
+                .collect()
+        };
+        offset += sample.size as usize;
+        fit_loop(sample);
+    }
+
+    let pattern_table: Vec<u8> = orders
+        .iter()
+        .take(num_orders)
+        .filter(|order| (**order as usize) < num_patterns)
+        .copied()
+        .collect();
+    if pattern_table.is_empty() {
+        return Err(ModError::BadHeader(String::from(
+            "the order list does not play any patterns",
+        )));
+    }
+
+    let num_used_patterns = pattern_table.len() as u32;
+    Ok(Song {
+        name: latin1_string(&header[4..24]),
+        format: FormatDescription {
+            kind: FormatKind::Mtm,
+            num_channels: num_channels as u32,
+            num_samples: num_samples as u32,
+            has_tag: true,
+            tag: String::from("MTM"),
+            has_linear_periods: false,
+        },
+        samples,
+        instruments: Vec::new(),
+        patterns,
+        pattern_table,
+        num_used_patterns,
+        // the song ends after the last order
+        end_position: num_used_patterns,
+        has_standard_notes: false,
+        initial_speed: 6,
+        initial_tempo: 125,
+        initial_global_volume: 64,
+        channel_pans,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::{update_tick, PlayerState};
+    use super::super::tests::put_u16;
+    use super::*;
+
+    // A two channel song with one 8 byte sample and one pattern that plays track 1 on channel 0 and track 2 on
+    // channel 1. Track 1 holds the notes given ( line and the three note bytes ) and track 2 slides the volume on line 1
+    fn build_mtm(track_1: &[(usize, [u8; 3])]) -> Vec<u8> {
+        let mut data = vec![0u8; HEADER_SIZE];
+        data[0..4].copy_from_slice(b"MTM\x10");
+        data[4..8].copy_from_slice(b"test");
+        put_u16(&mut data, 24, 2);
+        put_u16(&mut data, 28, 4);
+        data[30] = 1;
+        data[32] = 64;
+        data[33] = 2;
+        data[34] = 0;
+        data[35] = 15;
+        let mut sample = vec![0u8; SAMPLE_HEADER_SIZE];
+        sample[0..6].copy_from_slice(b"sample");
+        sample[22] = 8;
+        sample[26] = 2;
+        sample[30] = 6;
+        sample[34] = 0;
+        sample[35] = 48;
+        data.extend(sample);
+        data.extend(&[0u8; 128]);
+        let mut tracks = vec![0u8; 2 * 64 * 3];
+        for (line, bytes) in track_1 {
+            tracks[line * 3..line * 3 + 3].copy_from_slice(bytes);
+        }
+        tracks[(64 + 1) * 3..(64 + 2) * 3].copy_from_slice(&[0, 0x0a, 0x02]);
+        data.extend(tracks);
+        let mut sequence = vec![0u8; MAX_CHANNELS * 2];
+        sequence[0] = 1;
+        sequence[2] = 2;
+        data.extend(sequence);
+        data.extend(b"note");
+        data.extend(&[0x80, 0x90, 0xa0, 0xb0, 0xc0, 0xd0, 0xe0, 0xf0]);
+        data
+    }
+
+    // C-4 with the sample and volume 32
+    const C4: [u8; 3] = [24 << 2, 0x1c, 0x20];
+
+    #[test]
+    fn test_read_mtm() {
+        let data = build_mtm(&[(0, C4)]);
+        assert!(is_mtm(&data));
+        let song = read_mtm(&data).unwrap();
+        assert_eq!(song.format.kind, FormatKind::Mtm);
+        assert_eq!(song.name.trim_end_matches('\0'), "test");
+        assert_eq!(song.format.num_channels, 2);
+        assert_eq!(song.channel_pans, vec![-1.0, 1.0]);
+        assert_eq!(song.pattern_table, vec![0]);
+
+        let sample = &song.samples[0];
+        assert_eq!(sample.c2spd(), 8363);
+        assert_eq!(sample.volume(), 48);
+        assert_eq!(sample.samples()[0..2], [0, 0x10 << 8]);
+        assert_eq!((sample.repeat_offset(), sample.repeat_size()), (2, 4));
+
+        let pattern = &song.patterns[0];
+        let note = pattern.note(0, 0).unwrap();
+        assert_eq!((note.key(), note.sample_number()), (49, 1));
+        assert_eq!(*note.effect(), Effect::SetVolume { volume: 32 });
+        assert_eq!(
+            *pattern.note(1, 1).unwrap().effect(),
+            Effect::VolumeSlide { volume_change: -2 }
+        );
+    }
+
+    #[test]
+    fn test_fine_tune() {
+        let mut data = build_mtm(&[(0, C4)]);
+        data[HEADER_SIZE + 34] = -8i8 as u8;
+        let song = read_mtm(&data).unwrap();
+        // a fine tune of -8 is a semitone down
+        assert_eq!(song.samples[0].c2spd(), 7894);
+    }
+
+    #[test]
+    fn test_effects() {
+        let song = read_mtm(&build_mtm(&[
+            // C-4 and a speed of 3
+            (0, [24 << 2, 0x1f, 0x03]),
+            // pan to the right, then a fine volume slide down
+            (1, [0, 0x08, 0xff]),
+            (2, [0, 0x0e, 0xb4]),
+            // a pattern break to line 2 of the next position
+            (3, [0, 0x0d, 0x02]),
+        ]))
+        .unwrap();
+        let mut player_state = PlayerState::new(song.format.num_channels, 44100);
+        // the position and tick of the lines played
+        let mut lines = Vec::new();
+        let mut tick = 0;
+        while lines.len() < 5 {
+            if player_state.is_line_due() {
+                lines.push((player_state.next_line_position(&song), tick));
+            }
+            update_tick(&song, &mut player_state);
+            tick += 1;
+            if tick == 10 {
+                let channel = &player_state.channels[0];
+                assert_eq!(channel.pan, 1.0);
+                assert_eq!(channel.volume, 48.0);
+            }
+        }
+        assert_eq!(
+            lines,
+            vec![
+                ((0, 0), 6),
+                ((0, 1), 9),
+                ((0, 2), 12),
+                ((0, 3), 15),
+                ((1, 2), 18)
+            ]
+        );
+        assert_eq!(player_state.channels[0].volume, 48.0 - 2.0 * 4.0);
+    }
+}
@@ -0,0 +1,320 @@
+//! Oktalyzer modules
+//!
+//! The file is "OKTASONG" followed by IFF style chunks, each a four letter name and a big endian length. The song is
+//! played on the four Amiga channels and each of them can be split into a pair of channels that share its side. Notes
+//! are stored four bytes per channel in patterns of up to 128 lines. The sample data is in one chunk per sample, in the
+//! order of the samples that have data.
+use super::super::{
+    amiga_channel_pan, latin1_string, Effect, FormatDescription, FormatKind, ModError, Note,
+    Pattern, Sample, Song, DEFAULT_C2SPD,
+};
+use super::{
+    amiga_note_period, fit_loop, has_standard_notes_only, read_bytes, read_u16_be, read_u32_be,
+};
+
+const SAMPLE_HEADER_SIZE: usize = 32;
+
+/// Is the data an Oktalyzer module
+pub(crate) fn is_okt(file_data: &[u8]) -> bool {
+    file_data.get(0..8) == Some(b"OKTASONG")
+}
+
+/// The name and data of a chunk
+type Chunk<'a> = (&'a [u8], &'a [u8]);
+
+/// The chunks in the order they are stored. The last chunk is often cut short so its data is whatever there is of it
+fn read_chunks(file_data: &[u8]) -> Result<Vec<Chunk<'_>>, ModError> {
+    let mut chunks = Vec::new();
+    let mut offset = 8;
+    while offset + 8 <= file_data.len() {
+        let name = read_bytes(file_data, offset, 4)?;
+        let length = read_u32_be(file_data, offset + 4)? as usize;
+        let end = (offset + 8).saturating_add(length);
+        chunks.push((name, &file_data[offset + 8..end.min(file_data.len())]));
+        offset = end;
+    }
+    Ok(chunks)
+}
+
+/// The data of the first chunk called `name`
+fn find_chunk<'a>(chunks: &[Chunk<'a>], name: &[u8]) -> Result<&'a [u8], ModError> {
+    chunks
+        .iter()
+        .find(|(chunk_name, _)| *chunk_name == name)
+        .map(|(_, data)| *data)
+        .ok_or_else(|| ModError::BadHeader(format!("the {} chunk is missing", latin1_string(name))))
+}
+
+/// Decodes an Oktalyzer effect. The note slides ( 13, 17, 21 and 30 ) and sample release ( 27 ) are not played. The three
+/// arpeggio orders all play as the ProTracker arpeggio
+pub(crate) fn decode_effect(command: u8, parameter: u8) -> Effect {
+    match command {
+        1 => Effect::SlideUp { speed: parameter },
+        2 => Effect::SlideDown { speed: parameter },
+        10..=12 if parameter != 0 => Effect::Arpeggio {
+            chord_offset_1: parameter >> 4,
+            chord_offset_2: parameter & 0x0f,
+        },
+        // any parameter but 0 switches the filter on
+        15 => Effect::SetHardwareFilter {
+            new_state: (parameter == 0) as u8,
+        },
+        25 => Effect::PositionJump {
+            next_pattern: parameter,
+        },
+        28 => Effect::SetTicksPerLine {
+            ticks: parameter & 0x0f,
+        },
+        // The volume command sets the volume up to 64. Past that are slides every tick and fine slides, 16 values each
+        31 => match parameter {
+            0..=0x40 => Effect::SetVolume { volume: parameter },
+            0x41..=0x50 => Effect::VolumeSlide {
+                volume_change: -((parameter - 0x40) as i8),
+            },
+            0x51..=0x60 => Effect::VolumeSlide {
+                volume_change: (parameter - 0x50) as i8,
+            },
+            0x61..=0x70 => Effect::FineVolumeSlideDown {
+                volume_change: parameter - 0x60,
+            },
+            0x71..=0x80 => Effect::FineVolumeSlideUp {
+                volume_change: parameter - 0x70,
+            },
+            _ => Effect::None,
+        },
+        _ => Effect::None,
+    }
+}
+
+fn read_pattern(data: &[u8], num_channels: usize) -> Result<Pattern, ModError> {
+    let num_lines = read_u16_be(data, 0)? as usize;
+    if num_lines == 0 {
+        return Err(ModError::BadHeader(String::from(
+            "a pattern does not have any lines",
+        )));
+    }
+    let mut pattern = Pattern::with_lines(num_lines);
+    let mut offset = 2;
+    for line in &mut pattern.lines {
+        for _channel in 0..num_channels {
+            let bytes = read_bytes(data, offset, 4)?;
+            let mut note = Note::empty();
+            // the sample number is only used with a note and counts from 0
+            if bytes[0] > 0 {
+                note.period = amiga_note_period(bytes[0] as i32);
+                note.sample_number = bytes[1] + 1;
+            }
+            note.effect_command = bytes[2];
+            note.effect_parameter = bytes[3];
+            note.effect = decode_effect(bytes[2], bytes[3]);
+            line.push(note);
+            offset += 4;
+        }
+    }
+    Ok(pattern)
+}
+
+fn read_sample(header: &[u8]) -> Result<Sample, ModError> {
+    let size = read_u32_be(header, 20)?;
+    // the loop is in words
+    let repeat_offset = read_u16_be(header, 24)? as u32 * 2;
+    let repeat_size = read_u16_be(header, 26)? as u32 * 2;
+    Ok(Sample {
+        name: latin1_string(&header[0..20]),
+        size,
+        volume: header[29].min(64),
+        fine_tune: 0,
+        repeat_offset,
+        repeat_size,
+        c2spd: DEFAULT_C2SPD,
+        relative_pitch: 0,
+        pan: None,
+        sustain_loop: None,
+        samples: Vec::new(),
+    })
+}
+
+/// Reads an Oktalyzer module
+pub(crate) fn read_okt(file_data: &[u8]) -> Result<Song, ModError> {
+    let chunks = read_chunks(file_data)?;
+
+    // Each Amiga channel is played as one channel or, if it is split, as two channels on the same side
+    let channel_modes = find_chunk(&chunks, b"CMOD")?;
+    let mut channel_pans = Vec::new();
+    for amiga_channel in 0..4 {
+        let pan = amiga_channel_pan(amiga_channel as u32);
+        channel_pans.push(pan);
+        if read_u16_be(channel_modes, amiga_channel * 2)? != 0 {
+            channel_pans.push(pan);
+        }
+    }
+    let num_channels = channel_pans.len();
+
+    let sample_headers = find_chunk(&chunks, b"SAMP")?;
+    let mut samples = Vec::new();
+    for header in sample_headers.chunks_exact(SAMPLE_HEADER_SIZE) {
+        samples.push(read_sample(header)?);
+    }
+    let sample_data = chunks.iter().filter(|(name, _)| *name == b"SBOD");
+    for (sample, (_, data)) in samples
+        .iter_mut()
+        .filter(|sample| sample.size > 0)
+        .zip(sample_data)
+    {
+        let length = (sample.size as usize).min(data.len());
+        sample.samples = data[0..length]
+            .iter()
+            .map(|&byte| (byte as i8 as i16) << 8)
+            .collect();
+    }
+    // samples without data play nothing
+    samples.iter_mut().for_each(fit_loop);
+
+    let mut patterns = Vec::new();
+    for (_, data) in chunks.iter().filter(|(name, _)| *name == b"PBOD") {
+        patterns.push(read_pattern(data, num_channels)?);
+    }
+
+    let num_used_patterns = read_u16_be(find_chunk(&chunks, b"PLEN")?, 0)? as usize;
+    let pattern_table: Vec<u8> = find_chunk(&chunks, b"PATT")?
+        .iter()
+        .take(num_used_patterns)
+        .copied()
+        .collect();
+    if pattern_table.is_empty() {
+        return Err(ModError::BadHeader(String::from(
+            "the pattern table does not play any patterns",
+        )));
+    }
+    if let Some(missing) = pattern_table
+        .iter()
+        .find(|pattern_idx| **pattern_idx as usize >= patterns.len())
+    {
+        return Err(ModError::BadHeader(format!(
+            "pattern {} is played but the file has {} patterns",
+            missing,
+            patterns.len()
+        )));
+    }
+
+    let has_standard_notes = has_standard_notes_only(&patterns, &pattern_table);
+    let num_used_patterns = pattern_table.len() as u32;
+    Ok(Song {
+        name: String::new(),
+        format: FormatDescription {
+            kind: FormatKind::Okt,
+            num_channels: num_channels as u32,
+            num_samples: samples.len() as u32,
+            has_tag: true,
+            tag: String::from("OKTASONG"),
+            has_linear_periods: false,
+        },
+        samples,
+        instruments: Vec::new(),
+        patterns,
+        pattern_table,
+        num_used_patterns,
+        // the song ends after the last position
+        end_position: num_used_patterns,
+        has_standard_notes,
+        initial_speed: read_u16_be(find_chunk(&chunks, b"SPEE")?, 0)?.max(1) as u32,
+        initial_tempo: 125,
+        initial_global_volume: 64,
+        channel_pans,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::play_ticks;
+    use super::*;
+
+    fn chunk(name: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = name.to_vec();
+        chunk.extend(&(data.len() as u32).to_be_bytes());
+        chunk.extend(data);
+        chunk
+    }
+
+    // A song with the second Amiga channel split, two samples ( the first without data ) and one two line pattern
+    fn build_okt() -> Vec<u8> {
+        let mut data = b"OKTASONG".to_vec();
+        data.extend(chunk(b"CMOD", &[0, 0, 0, 1, 0, 0, 0, 0]));
+        let mut samples = vec![0u8; 2 * SAMPLE_HEADER_SIZE];
+        samples[32..38].copy_from_slice(b"sample");
+        samples[32 + 23] = 8;
+        // a 4 byte loop from byte 2
+        samples[32 + 25] = 1;
+        samples[32 + 27] = 2;
+        samples[32 + 29] = 48;
+        data.extend(chunk(b"SAMP", &samples));
+        data.extend(chunk(b"SPEE", &[0, 3]));
+        data.extend(chunk(b"SLEN", &[0, 1]));
+        data.extend(chunk(b"PLEN", &[0, 2]));
+        let mut positions = vec![0u8; 128];
+        positions[2] = 1;
+        data.extend(chunk(b"PATT", &positions));
+        let mut pattern = vec![0, 2];
+        // line 0: note 13 ( C-2 ) with the second sample on the second channel and volume 32 on the fifth
+        pattern.extend(&[
+            0, 0, 0, 0, 13, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 31, 32,
+        ]);
+        // line 1: a volume slide down by 2 on the second channel
+        pattern.extend(&[
+            0, 0, 0, 0, 0, 0, 31, 0x42, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ]);
+        data.extend(chunk(b"PBOD", &pattern));
+        data.extend(chunk(b"SBOD", &[0, 16, 32, 48, 64, 80, 96, 112]));
+        data
+    }
+
+    #[test]
+    fn test_read_okt() {
+        let song = read_okt(&build_okt()).unwrap();
+        assert_eq!(song.format.kind, FormatKind::Okt);
+        assert_eq!(song.format.num_channels, 5);
+        assert_eq!(song.channel_pans, vec![-1.0, 1.0, 1.0, 1.0, -1.0]);
+        assert_eq!(song.pattern_table, vec![0, 0]);
+        assert_eq!(song.initial_speed, 3);
+
+        assert_eq!(song.samples.len(), 2);
+        assert_eq!(song.samples[0].size(), 0);
+        let sample = &song.samples[1];
+        assert_eq!(sample.name().trim_end_matches('\0'), "sample");
+        assert_eq!(sample.volume(), 48);
+        assert_eq!(sample.samples()[1], 16 << 8);
+        assert_eq!((sample.repeat_offset(), sample.repeat_size()), (2, 4));
+
+        let pattern = &song.patterns[0];
+        assert_eq!(pattern.num_lines(), 2);
+        let note = pattern.note(0, 1).unwrap();
+        assert_eq!((note.period(), note.sample_number()), (428, 2));
+        assert_eq!(
+            *pattern.note(0, 4).unwrap().effect(),
+            Effect::SetVolume { volume: 32 }
+        );
+        assert_eq!(
+            *pattern.note(1, 1).unwrap().effect(),
+            Effect::VolumeSlide { volume_change: -2 }
+        );
+    }
+
+    #[test]
+    fn test_missing_chunk() {
+        let data = build_okt();
+        // cut the file off in the middle of the pattern chunk so the pattern is incomplete
+        assert!(read_okt(&data[0..data.len() - 40]).is_err());
+        assert!(matches!(read_okt(b"OKTASONG"), Err(ModError::BadHeader(_))));
+    }
+
+    #[test]
+    fn test_play_okt() {
+        let song = read_okt(&build_okt()).unwrap();
+        // the first line plays on tick 3 and the second line on tick 6. The slide is applied on the tick after
+        let player_state = play_ticks(&song, 8);
+        let channel = &player_state.channels[1];
+        assert_eq!(channel.sample_num, 2);
+        assert_eq!(channel.period, 428);
+        assert_eq!(channel.volume, 46.0);
+    }
+}
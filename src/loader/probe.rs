@@ -0,0 +1,233 @@
+//! Working out which formats some data could be in and how likely each one is
+//!
+//! Most formats start with a magic number long enough to be trusted, and loading the rest of the file confirms them.
+//! MOD files have a four letter tag in the middle of the file, and the 15 sample Soundtracker modules have nothing at
+//! all, so their headers are checked for values that a tracker could not have written.
+use super::super::FormatKind;
+use super::{
+    composer669, far, it, med, mod_tag_channel_count, mtm, okt, read_format, s3m, stm, ult, xm,
+};
+use std::cmp::Reverse;
+
+/// How sure `probe` is that the data is in a format
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Confidence {
+    /// The data has a magic number or tag but its header or contents do not add up, so loading it will probably fail
+    Low,
+    /// The data has no magic number, or only a short one, but its header is consistent with the format
+    Medium,
+    /// The data has the format's magic number or tag and its header is consistent with the format
+    High,
+}
+
+type MagicCheck = fn(&[u8]) -> bool;
+
+// The formats recognised by a magic number and whether the magic number is long enough to be trusted on its own
+const MAGIC_FORMATS: [(FormatKind, MagicCheck, bool); 10] = [
+    (FormatKind::S3m, s3m::is_s3m, true),
+    (FormatKind::Xm, xm::is_xm, true),
+    (FormatKind::It, it::is_it, true),
+    (FormatKind::Okt, okt::is_okt, true),
+    (FormatKind::Med, med::is_med, true),
+    (FormatKind::Ult, ult::is_ult, true),
+    (FormatKind::Far, far::is_far, true),
+    (FormatKind::Mtm, mtm::is_mtm, true),
+    (FormatKind::Stm, stm::is_stm, false),
+    (FormatKind::Composer669, composer669::is_669, false),
+];
+
+const TAG_OFFSET: usize = 1080;
+const SAMPLE_HEADER_SIZE: usize = 30;
+
+/// Checks the header of a MOD file with `num_samples` samples. The sample headers must have valid volumes and fine
+/// tunes, the order table must only play patterns that a tracker could have saved and the file must be big enough to
+/// hold the patterns and samples
+fn is_mod_header_valid(file_data: &[u8], num_samples: usize, num_channels: usize) -> bool {
+    let has_tag = num_samples == 31;
+    let positions_offset = 20 + num_samples * SAMPLE_HEADER_SIZE;
+    let header_size = positions_offset + 2 + 128 + if has_tag { 4 } else { 0 };
+    let header = match file_data.get(0..header_size) {
+        Some(header) => header,
+        None => return false,
+    };
+
+    let mut total_sample_size = 0;
+    for sample in header[20..positions_offset].chunks(SAMPLE_HEADER_SIZE) {
+        // the fine tune is a nibble and the volume 0-64
+        if sample[24] > 15 || sample[25] > 64 {
+            return false;
+        }
+        total_sample_size += u16::from_be_bytes([sample[22], sample[23]]) as usize * 2;
+    }
+
+    let song_length = header[positions_offset] as usize;
+    let orders = &header[positions_offset + 2..positions_offset + 130];
+    // Soundtracker can only save 64 patterns and ProTracker 128
+    let max_patterns = if has_tag { 128 } else { 64 };
+    if song_length == 0 || song_length > 128 || orders.iter().any(|order| *order >= max_patterns) {
+        return false;
+    }
+    let last_pattern = *orders[0..song_length].iter().max().unwrap_or(&0) as usize;
+    // FLT8 files store each 8 channel pattern as two 4 channel patterns
+    let num_patterns = if file_data.get(TAG_OFFSET..TAG_OFFSET + 4) == Some(b"FLT8") {
+        last_pattern / 2 + 1
+    } else {
+        last_pattern + 1
+    };
+    let pattern_size = num_channels * 4 * 64;
+    header_size + num_patterns * pattern_size <= file_data.len()
+        && header_size + total_sample_size <= file_data.len()
+}
+
+/// Soundtracker modules have no tag so the song and sample names must be text as well
+fn is_soundtracker_header_valid(file_data: &[u8]) -> bool {
+    let names_are_text = match file_data.get(0..20 + 15 * SAMPLE_HEADER_SIZE) {
+        Some(header) => {
+            let is_text = |name: &[u8]| {
+                name.iter()
+                    .all(|&byte| byte == 0 || (32..127).contains(&byte))
+            };
+            is_text(&header[0..20])
+                && header[20..]
+                    .chunks(SAMPLE_HEADER_SIZE)
+                    .all(|sample| is_text(&sample[0..22]))
+        }
+        None => false,
+    };
+    names_are_text && is_mod_header_valid(file_data, 15, 4)
+}
+
+/// Lists the formats that the data could be in, most likely first. An empty list means that the data is not a module
+/// in any of the supported formats.
+///
+/// Formats with a magic number are confirmed by loading the data, so probing large modules takes about as long as
+/// loading them.
+///
+/// # Arguments
+/// * `file_data` - the slice of bytes to check
+///
+pub fn probe(file_data: &[u8]) -> Vec<(FormatKind, Confidence)> {
+    let mut formats = Vec::new();
+    for (kind, has_magic, is_strong_magic) in MAGIC_FORMATS.iter().copied() {
+        if !has_magic(file_data) {
+            continue;
+        }
+        // a short magic number on data that does not load is most likely a coincidence
+        match (is_strong_magic, read_format(kind, file_data).is_ok()) {
+            (true, true) => formats.push((kind, Confidence::High)),
+            (false, true) => formats.push((kind, Confidence::Medium)),
+            (true, false) => formats.push((kind, Confidence::Low)),
+            (false, false) => {}
+        }
+    }
+
+    match file_data
+        .get(TAG_OFFSET..TAG_OFFSET + 4)
+        .and_then(mod_tag_channel_count)
+    {
+        Some(num_channels) => {
+            let confidence = if is_mod_header_valid(file_data, 31, num_channels as usize) {
+                Confidence::High
+            } else {
+                Confidence::Low
+            };
+            formats.push((FormatKind::Mod, confidence));
+        }
+        None => {
+            if is_soundtracker_header_valid(file_data) {
+                formats.push((FormatKind::Soundtracker, Confidence::Medium));
+            }
+        }
+    }
+
+    // the sort is stable so formats with the same confidence stay in the order they are checked
+    formats.sort_by_key(|(_, confidence)| Reverse(*confidence));
+    formats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    // A 15 sample Soundtracker module with one pattern and one 8 byte sample
+    fn build_soundtracker() -> Vec<u8> {
+        let mut data = vec![0u8; 600];
+        data[0..4].copy_from_slice(b"song");
+        data[20..26].copy_from_slice(b"sample");
+        data[20 + 23] = 4;
+        data[20 + 25] = 64;
+        data[470] = 1;
+        data[471] = 0x78;
+        data.extend(vec![0u8; 1024]);
+        data.extend(vec![0u8; 8]);
+        data
+    }
+
+    #[test]
+    fn test_probe_mod_files() {
+        for entry in fs::read_dir("mod_files").unwrap() {
+            let path = entry.unwrap().path();
+            let file_data = fs::read(&path).unwrap();
+            assert_eq!(
+                probe(&file_data).first(),
+                Some(&(FormatKind::Mod, Confidence::High)),
+                "{:?}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn test_probe_soundtracker() {
+        let data = build_soundtracker();
+        assert_eq!(
+            probe(&data),
+            vec![(FormatKind::Soundtracker, Confidence::Medium)]
+        );
+
+        let mut bad_volume = data.clone();
+        bad_volume[20 + 25] = 65;
+        assert!(probe(&bad_volume).is_empty());
+
+        let mut bad_order = data.clone();
+        bad_order[472] = 64;
+        assert!(probe(&bad_order).is_empty());
+
+        // the pattern the song plays is not in the file
+        let mut missing_pattern = data.clone();
+        missing_pattern[472] = 1;
+        assert!(probe(&missing_pattern).is_empty());
+
+        let mut binary_name = data;
+        binary_name[1] = 0x1b;
+        assert!(probe(&binary_name).is_empty());
+    }
+
+    #[test]
+    fn test_probe_not_a_module() {
+        assert!(probe(&[]).is_empty());
+        let text = "not a module\n".repeat(200);
+        assert!(probe(text.as_bytes()).is_empty());
+        let noise: Vec<u8> = (0..5000u32)
+            .map(|index| (index.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        assert!(probe(&noise).is_empty());
+    }
+
+    #[test]
+    fn test_probe_bad_magic() {
+        // a MOD tag on data that is too short for its patterns
+        let mut data = vec![0u8; 1084];
+        data[950] = 1;
+        data[1080..1084].copy_from_slice(b"M.K.");
+        assert_eq!(probe(&data), vec![(FormatKind::Mod, Confidence::Low)]);
+        data.extend(vec![0u8; 1024]);
+        assert_eq!(probe(&data), vec![(FormatKind::Mod, Confidence::High)]);
+
+        // a MultiTracker magic number on a header that can not be loaded
+        let mut mtm = b"MTM\x10".to_vec();
+        mtm.extend(vec![0u8; 100]);
+        assert_eq!(probe(&mtm), vec![(FormatKind::Mtm, Confidence::Low)]);
+    }
+}
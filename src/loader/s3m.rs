@@ -0,0 +1,569 @@
+//! Scream Tracker 3 (S3M) modules
+//!
+//! The header points to the samples ( called instruments in Scream Tracker ) and the patterns with "parapointers", offsets
+//! in 16 byte paragraphs. Patterns are packed so that each line only stores the channels that have data on it.
+use super::super::{
+    latin1_string, Effect, FormatDescription, FormatKind, ModError, Note, Pattern, Sample, Song,
+    KEY_CUT,
+};
+use super::info::{read_at, ModInfo};
+use super::{read_bytes, read_u16, read_u32};
+use std::io::{Read, Seek};
+
+const HEADER_SIZE: usize = 0x60;
+const SAMPLE_HEADER_SIZE: usize = 0x50;
+// Order list entries that do not refer to patterns
+pub(crate) const ORDER_END: u8 = 255;
+const ORDER_MARKER: u8 = 254;
+// The default pan value that says the file has a pan table
+const HAS_PAN_TABLE: u8 = 0xfc;
+
+// Sample flags
+const SAMPLE_LOOPS: u8 = 1;
+const SAMPLE_STEREO: u8 = 2;
+const SAMPLE_16_BIT: u8 = 4;
+
+/// Periods of the notes in octave 0 before they are divided down to the octave and scaled by the sample's C2Spd
+const PERIOD_TABLE: [u32; 12] = [
+    1712, 1616, 1524, 1440, 1356, 1280, 1208, 1140, 1076, 1016, 960, 907,
+];
+
+/// Is the data a Scream Tracker 3 module
+pub(crate) fn is_s3m(file_data: &[u8]) -> bool {
+    file_data.get(0x2c..0x30) == Some(b"SCRM")
+}
+
+/// The period of a note in Scream Tracker units ( a quarter of an Amiga period ) for a sample with the given C2Spd
+pub(crate) fn note_period(key: u8, c2spd: u32) -> u32 {
+    let key = key.clamp(1, 120) as usize - 1;
+    let octave = key / 12;
+    let period = 8363 * 16 * (PERIOD_TABLE[key % 12] >> octave) / c2spd.max(1);
+    period.max(1)
+}
+
+/// Effects that take their parameter from the channel's effect memory when the parameter is 0
+pub(crate) fn uses_effect_memory(command: u8) -> bool {
+    // D, E, F, I, J, K, L, Q and R
+    matches!(command, 4 | 5 | 6 | 9 | 10 | 11 | 12 | 17 | 18)
+}
+
+// Volume slides have the increase in the high nibble and the decrease in the low nibble. Fine slides are not part of the
+// combined vibrato and portamento slides
+fn volume_slide(parameter: u8) -> i8 {
+    let up = parameter >> 4;
+    let down = parameter & 0x0f;
+    if down == 0 {
+        up as i8
+    } else {
+        -(down as i8)
+    }
+}
+
+/// Decodes an effect. The commands are numbered from 1 for A
+pub(crate) fn decode_effect(command: u8, parameter: u8) -> Effect {
+    let high = parameter >> 4;
+    let low = parameter & 0x0f;
+    match command {
+        1 if parameter > 0 => Effect::SetTicksPerLine { ticks: parameter },
+        2 => Effect::PositionJump {
+            next_pattern: parameter,
+        },
+        3 => Effect::PatternBreak {
+            next_pattern_pos: high * 10 + low,
+        },
+        4 => match (high, low) {
+            (0x0f, 0) => Effect::VolumeSlide { volume_change: 15 },
+            (0, 0x0f) => Effect::VolumeSlide { volume_change: -15 },
+            (0x0f, down) => Effect::FineVolumeSlideDown {
+                volume_change: down,
+            },
+            (up, 0x0f) => Effect::FineVolumeSlideUp { volume_change: up },
+            _ => Effect::VolumeSlide {
+                volume_change: volume_slide(parameter),
+            },
+        },
+        5 => match high {
+            0x0f => Effect::FinePortaDown { period_change: low },
+            0x0e => Effect::ExtraFinePortaDown { period_change: low },
+            _ => Effect::SlideDown { speed: parameter },
+        },
+        6 => match high {
+            0x0f => Effect::FinePortaUp { period_change: low },
+            0x0e => Effect::ExtraFinePortaUp { period_change: low },
+            _ => Effect::SlideUp { speed: parameter },
+        },
+        7 => Effect::TonePortamento { speed: parameter },
+        8 => Effect::Vibrato {
+            speed: high,
+            amplitude: low,
+        },
+        9 => Effect::Tremor {
+            on_ticks: high,
+            off_ticks: low,
+        },
+        10 => Effect::Arpeggio {
+            chord_offset_1: high,
+            chord_offset_2: low,
+        },
+        11 => Effect::VibratoVolumeSlide {
+            volume_change: volume_slide(parameter),
+        },
+        12 => Effect::TonePortamentoVolumeSlide {
+            volume_change: volume_slide(parameter),
+        },
+        15 => Effect::SetSampleOffset { offset: parameter },
+        17 => Effect::RetriggerVolumeSlide {
+            retrigger_delay: low,
+            volume_change: high,
+        },
+        18 => Effect::Tremolo {
+            speed: high,
+            amplitude: low,
+        },
+        19 => match high {
+            0x1 => Effect::Glissando {
+                use_smooth_slide: low == 0,
+            },
+            0x2 => Effect::SetFineTune { fine_tune: low },
+            0x3 => Effect::SetVibratoWave { wave: low },
+            0x4 => Effect::TremoloWaveform { wave: low },
+            0x8 => Effect::CoarsePan { pan_pos: low },
+            0xb => Effect::PatternLoop { arg: low },
+            0xc => Effect::CutNote { delay: low },
+            0xd => Effect::DelayedSample { delay_ticks: low },
+            0xe => Effect::DelayedLine { delay_ticks: low },
+            _ => Effect::None,
+        },
+        20 if parameter >= 0x20 => Effect::SetTempo { bpm: parameter },
+        21 => Effect::FineVibrato {
+            speed: high,
+            amplitude: low,
+        },
+        22 => Effect::SetGlobalVolume { volume: parameter },
+        // 0x80 is hard right. 0xa4 is surround which is played in the middle
+        24 => Effect::Pan {
+            position: match parameter {
+                0xa4 => 0x80,
+                _ => (parameter.min(0x80) as u32 * 255 / 0x80) as u8,
+            },
+        },
+        _ => Effect::None,
+    }
+}
+
+fn read_sample(file_data: &[u8], offset: usize, signed_samples: bool) -> Result<Sample, ModError> {
+    let header = read_bytes(file_data, offset, SAMPLE_HEADER_SIZE)?;
+    let mut sample = Sample {
+        name: latin1_string(&header[0x30..0x4c]),
+        size: 0,
+        volume: header[0x1c].min(64),
+        fine_tune: 0,
+        repeat_offset: 0,
+        repeat_size: 0,
+        c2spd: read_u32(header, 0x20)?.max(1),
+        relative_pitch: 0,
+        pan: None,
+        sustain_loop: None,
+        samples: Vec::new(),
+    };
+    // type 1 is a sampled instrument. Adlib instruments and empty slots play nothing
+    if header[0] != 1 {
+        return Ok(sample);
+    }
+    if header[0x1e] != 0 {
+        return Err(ModError::UnsupportedTag(String::from("packed S3M samples")));
+    }
+    let flags = header[0x1f];
+    let bytes_per_point = if flags & SAMPLE_16_BIT != 0 { 2 } else { 1 };
+    let num_sides = if flags & SAMPLE_STEREO != 0 { 2 } else { 1 };
+    let data_offset = ((header[0x0d] as usize) << 16 | read_u16(header, 0x0e)? as usize) * 16;
+    let mut length = read_u32(header, 0x10)? as usize;
+
+    // The last sample is often cut short. Play what there is of it
+    let available = file_data.len().saturating_sub(data_offset) / (bytes_per_point * num_sides);
+    length = length.min(available);
+    let data = &file_data[data_offset.min(file_data.len())..];
+    let point = |index: usize| -> i32 {
+        let value = if bytes_per_point == 2 {
+            let raw = u16::from_le_bytes([data[index * 2], data[index * 2 + 1]]);
+            if signed_samples {
+                raw as i16
+            } else {
+                (raw ^ 0x8000) as i16
+            }
+        } else {
+            let raw = data[index];
+            let raw = if signed_samples { raw } else { raw ^ 0x80 };
+            (raw as i8 as i16) << 8
+        };
+        value as i32
+    };
+    // Stereo samples store all of the left side before the right side. They are played in mono
+    sample.samples = (0..length)
+        .map(|index| {
+            if num_sides == 2 {
+                ((point(index) + point(index + length)) / 2) as i16
+            } else {
+                point(index) as i16
+            }
+        })
+        .collect();
+    sample.size = length as u32;
+
+    let loop_start = read_u32(header, 0x14)?;
+    let loop_end = read_u32(header, 0x18)?.min(sample.size);
+    if flags & SAMPLE_LOOPS != 0 && loop_end > loop_start {
+        sample.repeat_offset = loop_start;
+        sample.repeat_size = loop_end - loop_start;
+        // nothing is played after the loop
+        sample.size = loop_end;
+    }
+    Ok(sample)
+}
+
+fn read_pattern(
+    file_data: &[u8],
+    offset: usize,
+    channel_map: &[Option<usize>],
+    num_channels: usize,
+) -> Result<Pattern, ModError> {
+    let mut pattern = Pattern::new();
+    for line in &mut pattern.lines {
+        line.resize_with(num_channels, Note::empty);
+    }
+    // patterns without data are empty
+    if offset == 0 {
+        return Ok(pattern);
+    }
+    let packed_size = read_u16(file_data, offset)? as usize;
+    let end = offset + packed_size.max(2);
+    let mut position = offset + 2;
+    let mut line = 0;
+    while line < 64 && position < end {
+        let what = read_bytes(file_data, position, 1)?[0];
+        position += 1;
+        if what == 0 {
+            line += 1;
+            continue;
+        }
+        let mut note = Note::empty();
+        if what & 0x20 != 0 {
+            let bytes = read_bytes(file_data, position, 2)?;
+            note.key = match bytes[0] {
+                255 => 0,
+                254 => KEY_CUT,
+                key if key & 0x0f < 12 => (key >> 4) * 12 + (key & 0x0f) + 1,
+                _ => 0,
+            };
+            note.sample_number = bytes[1];
+            position += 2;
+        }
+        if what & 0x40 != 0 {
+            note.volume = Some(read_bytes(file_data, position, 1)?[0].min(64));
+            position += 1;
+        }
+        if what & 0x80 != 0 {
+            let bytes = read_bytes(file_data, position, 2)?;
+            note.effect_command = bytes[0];
+            note.effect_parameter = bytes[1];
+            note.effect = decode_effect(bytes[0], bytes[1]);
+            position += 2;
+        }
+        if let Some(channel) = channel_map[(what & 0x1f) as usize] {
+            pattern.lines[line][channel] = note;
+        }
+    }
+    Ok(pattern)
+}
+
+/// The patterns played by the order list. Markers are skipped while playing and the song ends at the first end marker.
+/// Impulse Tracker uses the same order list
+pub(crate) fn pattern_table(orders: &[u8], num_patterns: usize) -> Result<Vec<u8>, ModError> {
+    let pattern_table: Vec<u8> = orders
+        .iter()
+        .take_while(|order| **order != ORDER_END)
+        .filter(|order| **order != ORDER_MARKER && (**order as usize) < num_patterns)
+        .copied()
+        .collect();
+    if pattern_table.is_empty() {
+        return Err(ModError::BadHeader(String::from(
+            "the order list does not play any patterns",
+        )));
+    }
+    Ok(pattern_table)
+}
+
+/// Describes a Scream Tracker 3 module from its header and sample headers
+pub(crate) fn read_s3m_info<R: Read + Seek>(reader: &mut R) -> Result<ModInfo, ModError> {
+    let header = read_at(reader, 0, HEADER_SIZE)?;
+    let num_orders = read_u16(&header, 0x20)? as usize;
+    let num_samples = read_u16(&header, 0x22)? as usize;
+    let num_patterns = read_u16(&header, 0x24)? as usize;
+    let orders = read_at(reader, HEADER_SIZE, num_orders + num_samples * 2)?;
+    let pattern_table = pattern_table(&orders[..num_orders], num_patterns)?;
+
+    let mut sample_names = Vec::new();
+    let mut sample_sizes = Vec::new();
+    for pointer in orders[num_orders..].chunks_exact(2) {
+        let offset = u16::from_le_bytes([pointer[0], pointer[1]]) as usize * 16;
+        let sample_header = read_at(reader, offset, SAMPLE_HEADER_SIZE)?;
+        sample_names.push(latin1_string(&sample_header[0x30..0x4c]));
+        // only sampled instruments ( type 1 ) have sample data
+        sample_sizes.push(if sample_header[0] == 1 {
+            read_u32(&sample_header, 0x10)?
+        } else {
+            0
+        });
+    }
+    Ok(ModInfo {
+        name: latin1_string(&header[0..28]),
+        format: FormatKind::S3m,
+        // settings 16 and up are Adlib channels
+        num_channels: header[0x40..0x60]
+            .iter()
+            .filter(|setting| **setting < 16)
+            .count() as u32,
+        sample_names,
+        sample_sizes,
+        num_orders: pattern_table.len() as u32,
+        num_patterns: num_patterns as u32,
+    })
+}
+
+/// Reads a Scream Tracker 3 module
+pub(crate) fn read_s3m(file_data: &[u8]) -> Result<Song, ModError> {
+    let header = read_bytes(file_data, 0, HEADER_SIZE)?;
+    let num_orders = read_u16(header, 0x20)? as usize;
+    let num_samples = read_u16(header, 0x22)? as usize;
+    let num_patterns = read_u16(header, 0x24)? as usize;
+    if num_patterns > 256 {
+        return Err(ModError::BadHeader(format!(
+            "{} patterns is more than the 256 an order list can refer to",
+            num_patterns
+        )));
+    }
+    // 1 is signed, 2 is unsigned
+    let signed_samples = read_u16(header, 0x2a)? == 1;
+    let is_stereo = header[0x33] & 0x80 != 0;
+
+    let orders = read_bytes(file_data, HEADER_SIZE, num_orders)?;
+    let mut offset = HEADER_SIZE + num_orders;
+    let mut sample_pointers = Vec::new();
+    for _ in 0..num_samples {
+        sample_pointers.push(read_u16(file_data, offset)? as usize * 16);
+        offset += 2;
+    }
+    let mut pattern_pointers = Vec::new();
+    for _ in 0..num_patterns {
+        pattern_pointers.push(read_u16(file_data, offset)? as usize * 16);
+        offset += 2;
+    }
+    let pan_table = if header[0x35] == HAS_PAN_TABLE {
+        Some(read_bytes(file_data, offset, 32)?)
+    } else {
+        None
+    };
+
+    // Only the enabled sample channels are played ( settings 16 and up are Adlib channels ). They are packed together
+    let mut channel_map = [None; 32];
+    let mut channel_pans = Vec::new();
+    for (channel, setting) in header[0x40..0x60].iter().enumerate() {
+        if *setting >= 16 {
+            continue;
+        }
+        channel_map[channel] = Some(channel_pans.len());
+        let mut pan = if *setting < 8 { 0x3 } else { 0xc };
+        if let Some(pan_table) = pan_table {
+            if pan_table[channel] & 0x20 != 0 {
+                pan = pan_table[channel] & 0x0f;
+            }
+        }
+        channel_pans.push(if is_stereo {
+            pan as f32 / 15.0 * 2.0 - 1.0
+        } else {
+            0.0
+        });
+    }
+    let num_channels = channel_pans.len();
+
+    let pattern_table = pattern_table(orders, num_patterns)?;
+
+    let mut samples = Vec::new();
+    for pointer in sample_pointers {
+        samples.push(read_sample(file_data, pointer, signed_samples)?);
+    }
+    let mut patterns = Vec::new();
+    for pointer in pattern_pointers {
+        patterns.push(read_pattern(
+            file_data,
+            pointer,
+            &channel_map,
+            num_channels,
+        )?);
+    }
+
+    let num_used_patterns = pattern_table.len() as u32;
+    Ok(Song {
+        name: latin1_string(&header[0..28]),
+        format: FormatDescription {
+            kind: FormatKind::S3m,
+            num_channels: num_channels as u32,
+            num_samples: num_samples as u32,
+            has_tag: true,
+            tag: String::from("SCRM"),
+            has_linear_periods: false,
+        },
+        samples,
+        instruments: Vec::new(),
+        patterns,
+        pattern_table,
+        num_used_patterns,
+        // the song ends after the last order
+        end_position: num_used_patterns,
+        has_standard_notes: false,
+        initial_speed: header[0x31].max(1) as u32,
+        initial_tempo: header[0x32].max(32) as u32,
+        initial_global_volume: header[0x30].min(64) as u32,
+        channel_pans,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{play_ticks, put_u16, put_u32};
+    use super::*;
+    use std::io::Cursor;
+
+    // A stereo song with channels 0 and 2 enabled, one unsigned 16 bit sample and two patterns. The second pattern is empty
+    fn build_s3m() -> Vec<u8> {
+        let mut data = vec![0u8; 0x140];
+        data[0..9].copy_from_slice(b"test song");
+        put_u16(&mut data, 0x20, 4);
+        put_u16(&mut data, 0x22, 1);
+        put_u16(&mut data, 0x24, 2);
+        put_u16(&mut data, 0x2a, 2);
+        data[0x2c..0x30].copy_from_slice(b"SCRM");
+        data[0x30] = 48;
+        data[0x31] = 3;
+        data[0x32] = 150;
+        data[0x33] = 0x80 | 48;
+        data[0x35] = HAS_PAN_TABLE;
+        for setting in &mut data[0x40..0x60] {
+            *setting = 255;
+        }
+        data[0x40] = 0;
+        data[0x42] = 8;
+        data[0x60..0x64].copy_from_slice(&[0, ORDER_MARKER, 1, ORDER_END]);
+        put_u16(&mut data, 0x64, 0x9);
+        put_u16(&mut data, 0x66, 0x10);
+        put_u16(&mut data, 0x68, 0);
+        // the pan table sets channel 2 and leaves channel 0 at its default
+        data[0x6a + 2] = 0x20 | 5;
+
+        let sample = 0x90;
+        data[sample] = 1;
+        put_u16(&mut data, sample + 0x0e, 0xe);
+        put_u32(&mut data, sample + 0x10, 8);
+        data[sample + 0x1c] = 40;
+        data[sample + 0x1f] = SAMPLE_16_BIT;
+        put_u32(&mut data, sample + 0x20, 8363);
+        for (index, value) in [
+            0x8000u16, 0xffff, 0x0000, 0x8000, 0xc000, 0x4000, 0x8000, 0x8000,
+        ]
+        .iter()
+        .enumerate()
+        {
+            put_u16(&mut data, 0xe0 + index * 2, *value);
+        }
+
+        let packed: Vec<u8> = vec![
+            // line 0: C-4, sample 1, volume 32 and D02 on channel 0
+            0xe0, 0x40, 1, 32, 4, 0x02, 0, //
+            // line 1: D00 repeats the slide
+            0x80, 4, 0x00, 0, //
+            // line 2: a note on the disabled channel 1
+            0x21, 0x40, 1, 0,
+        ];
+        put_u16(&mut data, 0x100, packed.len() as u16 + 2);
+        data[0x102..0x102 + packed.len()].copy_from_slice(&packed);
+        data
+    }
+
+    #[test]
+    fn test_read_s3m() {
+        let song = read_s3m(&build_s3m()).unwrap();
+        assert_eq!(song.name.trim_end_matches('\0'), "test song");
+        assert_eq!(song.format.kind, FormatKind::S3m);
+        assert_eq!(song.format.num_channels, 2);
+        // the marker and the end of the order list are not played
+        assert_eq!(song.pattern_table, vec![0, 1]);
+        assert_eq!(song.end_position, 2);
+        assert_eq!(
+            (
+                song.initial_speed,
+                song.initial_tempo,
+                song.initial_global_volume
+            ),
+            (3, 150, 48)
+        );
+        assert!((song.channel_pans[0] - (3.0 / 15.0 * 2.0 - 1.0)).abs() < 1e-6);
+        assert!((song.channel_pans[1] - (5.0 / 15.0 * 2.0 - 1.0)).abs() < 1e-6);
+
+        let sample = &song.samples[0];
+        assert_eq!(sample.c2spd(), 8363);
+        assert_eq!(sample.volume(), 40);
+        assert_eq!(sample.samples()[0..3], [0, 32767, -32768]);
+
+        let note = song.patterns[0].note(0, 0).unwrap();
+        assert_eq!(note.key(), 49);
+        assert_eq!(note.sample_number(), 1);
+        assert_eq!(note.volume(), Some(32));
+        assert_eq!(*note.effect(), Effect::VolumeSlide { volume_change: -2 });
+        assert!(song.patterns[0]
+            .line(2)
+            .unwrap()
+            .iter()
+            .all(|note| note.key() == 0));
+        assert!(song.patterns[1]
+            .line(0)
+            .unwrap()
+            .iter()
+            .all(|note| note.key() == 0));
+    }
+
+    #[test]
+    fn test_read_s3m_info() {
+        let data = build_s3m();
+        let song = read_s3m(&data).unwrap();
+        // the description does not need the sample data or the patterns
+        let info = read_s3m_info(&mut Cursor::new(&data[0..0xe0])).unwrap();
+        assert_eq!(info.name, song.name);
+        assert_eq!(info.format, FormatKind::S3m);
+        assert_eq!(info.num_channels, 2);
+        assert_eq!(info.sample_names, vec![song.samples[0].name().to_string()]);
+        assert_eq!(info.sample_sizes, vec![8]);
+        assert_eq!((info.num_orders, info.num_patterns), (2, 2));
+    }
+
+    #[test]
+    fn test_note_period() {
+        // middle C of a sample with the standard C2Spd plays at the C2Spd
+        assert_eq!(note_period(49, 8363), 1712);
+        // each octave halves the table period before it is scaled so some precision is lost, as it is in Scream Tracker
+        assert_eq!(note_period(61, 8363), 848);
+        assert_eq!(note_period(49, 16726), 856);
+    }
+
+    #[test]
+    fn test_play_s3m() {
+        let song = read_s3m(&build_s3m()).unwrap();
+        // the first line plays on tick 3 and the third line on tick 9. The slide is applied on the 6 ticks between them
+        let player_state = play_ticks(&song, 10);
+        assert_eq!(player_state.song_speed, 3);
+        assert_eq!(player_state.global_volume, 48);
+        let channel = &player_state.channels[0];
+        assert_eq!(channel.period, 1712);
+        // D00 continues sliding with the parameter of the line before it
+        assert_eq!(channel.volume, 20.0);
+    }
+}
@@ -0,0 +1,157 @@
+//! Ultimate Soundtracker and Soundtracker 2.x modules
+//!
+//! These are the 15 sample MOD files that came before ProTracker. They are read like MOD files, with some differences.
+//! Ultimate Soundtracker only has two effects, 1xy arpeggio and 2xy pitch bend. Its loop starts are in bytes rather
+//! than words, and looping samples only play their loop. Later Soundtrackers use the ProTracker effect numbers. Both
+//! store the tempo where ProTracker stores the restart position.
+use super::super::{Effect, Pattern, Sample};
+
+/// The tempo byte of songs that play at the vertical blank rate
+const VBLANK_TEMPO: u8 = 0x78;
+
+/// The tempo in beats per minute of a Soundtracker tempo byte. Other values than the default set the CIA timer
+pub(crate) fn tempo(tempo_byte: u8) -> u32 {
+    if tempo_byte == 0 || tempo_byte == VBLANK_TEMPO {
+        return 125;
+    }
+    let timer = (240 - tempo_byte.min(239) as u32) * 122;
+    (1_773_447 / timer).clamp(32, 255)
+}
+
+/// Does the song only use the effects of Ultimate Soundtracker. An arpeggio ( 0xy ) or any effect past 2 means that the
+/// song is from a later Soundtracker that uses the ProTracker effect numbers
+pub(crate) fn is_ultimate_soundtracker(patterns: &[Pattern]) -> bool {
+    patterns
+        .iter()
+        .flat_map(|pattern| pattern.lines.iter().flatten())
+        .all(|note| match note.effect_command {
+            0 => note.effect_parameter == 0,
+            1 | 2 => true,
+            _ => false,
+        })
+}
+
+/// Decodes an Ultimate Soundtracker effect
+pub(crate) fn decode_ultimate_effect(command: u8, parameter: u8) -> Effect {
+    match (command, parameter >> 4, parameter & 0x0f) {
+        (1, 0, 0) => Effect::None,
+        (1, high, low) => Effect::Arpeggio {
+            chord_offset_1: high,
+            chord_offset_2: low,
+        },
+        // the pitch bends up by the low nibble or, if it is 0, down by the high nibble
+        (2, _, low) if low != 0 => Effect::SlideUp { speed: low },
+        (2, high, _) if high != 0 => Effect::SlideDown { speed: high },
+        _ => Effect::None,
+    }
+}
+
+/// Changes a song read as a MOD file to Ultimate Soundtracker effects and loops. `sample_headers` are the 15 sample
+/// headers from the file
+pub(crate) fn convert_ultimate(
+    patterns: &mut [Pattern],
+    samples: &mut [Sample],
+    sample_headers: &[u8],
+) {
+    for note in patterns
+        .iter_mut()
+        .flat_map(|pattern| pattern.lines.iter_mut().flatten())
+    {
+        note.effect = decode_ultimate_effect(note.effect_command, note.effect_parameter);
+    }
+
+    for (sample, header) in samples.iter_mut().zip(sample_headers.chunks(30)) {
+        let loop_start = u16::from_be_bytes([header[26], header[27]]) as usize;
+        let loop_size = u16::from_be_bytes([header[28], header[29]]) as usize * 2;
+        if loop_size <= 2 || loop_start >= sample.samples.len() {
+            continue;
+        }
+        // only the loop is played so the rest of the sample is dropped
+        let loop_end = (loop_start + loop_size).min(sample.samples.len());
+        sample.samples = sample.samples[loop_start..loop_end].to_vec();
+        sample.size = sample.samples.len() as u32;
+        sample.repeat_offset = 0;
+        sample.repeat_size = sample.size;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::{update_tick, FormatKind, PlayerState};
+    use super::super::try_read_mod_file_slice;
+    use super::*;
+
+    // A 15 sample song with one looping sample and one pattern. Line 0 plays the sample with `effect`
+    fn build_soundtracker(tempo_byte: u8, effect: [u8; 2]) -> Vec<u8> {
+        let mut data = vec![0u8; 600];
+        data[0..4].copy_from_slice(b"test");
+        // 16 bytes with an 8 byte loop starting at byte 4
+        data[20 + 23] = 8;
+        data[20 + 25] = 64;
+        data[20 + 27] = 4;
+        data[20 + 29] = 4;
+        data[470] = 1;
+        data[471] = tempo_byte;
+        // C-2 played by sample 1
+        let mut pattern = vec![0u8; 4 * 4 * 64];
+        pattern[0..4].copy_from_slice(&[0x01, 0xac, 0x10 | effect[0], effect[1]]);
+        data.extend(pattern);
+        data.extend((0..16).map(|value| value as u8));
+        data
+    }
+
+    #[test]
+    fn test_tempo() {
+        assert_eq!(tempo(VBLANK_TEMPO), 125);
+        assert_eq!(tempo(0), 125);
+        assert_eq!(tempo(0x80), 129);
+    }
+
+    #[test]
+    fn test_ultimate_soundtracker() {
+        let song = try_read_mod_file_slice(&build_soundtracker(0x80, [2, 0x30])).unwrap();
+        assert_eq!(song.format.kind, FormatKind::Soundtracker);
+        assert_eq!(song.format.num_samples, 15);
+        assert_eq!(song.initial_tempo, 129);
+        let note = song.patterns[0].note(0, 0).unwrap();
+        assert_eq!(*note.effect(), Effect::SlideDown { speed: 3 });
+        // the loop start is in bytes and only the loop is kept
+        let sample = &song.samples[0];
+        assert_eq!(sample.samples().len(), 8);
+        assert_eq!(sample.samples()[0], 4 << 8);
+        assert_eq!((sample.repeat_offset(), sample.repeat_size()), (0, 8));
+    }
+
+    #[test]
+    fn test_soundtracker_2() {
+        // an arpeggio is not an Ultimate Soundtracker effect so the song uses the ProTracker effect numbers
+        let mut data = build_soundtracker(VBLANK_TEMPO, [2, 0x30]);
+        data[600 + 16 + 2..600 + 16 + 4].copy_from_slice(&[0, 0x37]);
+        let song = try_read_mod_file_slice(&data).unwrap();
+        assert_eq!(song.initial_tempo, 125);
+        let note = song.patterns[0].note(0, 0).unwrap();
+        assert_eq!(*note.effect(), Effect::SlideDown { speed: 0x30 });
+        assert_eq!(song.samples[0].samples().len(), 16);
+        assert_eq!(song.samples[0].repeat_offset(), 8);
+    }
+
+    #[test]
+    fn test_soundtracker_speed() {
+        // F40 is a tempo in ProTracker but a speed in Soundtracker
+        let mut song =
+            try_read_mod_file_slice(&build_soundtracker(VBLANK_TEMPO, [0xf, 0x40])).unwrap();
+        for kind in &[FormatKind::Soundtracker, FormatKind::Mod] {
+            song.format.kind = *kind;
+            let mut player_state = PlayerState::new(song.format.num_channels, 44100);
+            // the first line plays on tick 6
+            for _tick in 0..7 {
+                update_tick(&song, &mut player_state);
+            }
+            if *kind == FormatKind::Soundtracker {
+                assert_eq!(player_state.song_speed, 0x40);
+            } else {
+                assert_eq!(player_state.song_speed, 6);
+            }
+        }
+    }
+}
@@ -0,0 +1,327 @@
+//! Scream Tracker 2 (STM) modules
+//!
+//! The songs have four channels, 31 samples and up to 64 patterns of 64 lines. Notes are four bytes holding the note,
+//! sample, volume and one of the effects A to J. Each sample's data is at a paragraph ( 16 byte ) offset given in its
+//! header. Samples are 8 bit signed.
+use super::super::{
+    amiga_channel_pan, latin1_string, Effect, FormatDescription, FormatKind, ModError, Note,
+    Pattern, Sample, Song, KEY_CUT,
+};
+use super::{fit_loop, read_bytes, read_u16};
+
+const HEADER_SIZE: usize = 48;
+const SAMPLE_HEADER_SIZE: usize = 32;
+const NUM_SAMPLES: usize = 31;
+const NUM_CHANNELS: usize = 4;
+const PATTERN_SIZE: usize = 64 * NUM_CHANNELS * 4;
+// The end of the order list
+const ORDER_END: u8 = 99;
+// Note bytes that do not play a note, and the one that stops the note playing
+const EMPTY_NOTES: [u8; 3] = [251, 252, 253];
+const NOTE_CUT: u8 = 254;
+// Volumes above 64 leave the volume unchanged
+const NO_VOLUME: u8 = 65;
+// Samples without a loop have this loop end
+const NO_LOOP: u16 = 0xffff;
+
+/// Is the data a Scream Tracker 2 module
+pub(crate) fn is_stm(file_data: &[u8]) -> bool {
+    match file_data.get(0..HEADER_SIZE) {
+        // the file type 2 is a module, 1 is a song without samples
+        Some(header) => header[28] == 0x1a && header[29] == 2 && header[30] == 2,
+        None => false,
+    }
+}
+
+/// Before version 2.21 the speed was stored in decimal. It is converted to hex, where the high nibble is the speed
+fn speed_byte(value: u8, version: u8) -> u8 {
+    if version < 21 {
+        ((value / 10) << 4) | (value % 10)
+    } else {
+        value
+    }
+}
+
+/// Decodes a Scream Tracker 2 effect. Commands 1 to 10 are the letters A to J. Effects with a parameter of 0 do nothing
+pub(crate) fn decode_effect(command: u8, parameter: u8, version: u8) -> Effect {
+    let high = parameter >> 4;
+    let low = parameter & 0x0f;
+    if parameter == 0 {
+        return Effect::None;
+    }
+    match command {
+        1 => Effect::SetTicksPerLine {
+            ticks: speed_byte(parameter, version) >> 4,
+        },
+        2 => Effect::PositionJump {
+            next_pattern: parameter,
+        },
+        3 => Effect::new(13, parameter as i8),
+        // the volume slides up or, if the high nibble is 0, down
+        4 if high != 0 => Effect::VolumeSlide {
+            volume_change: high as i8,
+        },
+        4 => Effect::VolumeSlide {
+            volume_change: -(low as i8),
+        },
+        5 => Effect::SlideDown { speed: parameter },
+        6 => Effect::SlideUp { speed: parameter },
+        7 => Effect::TonePortamento { speed: parameter },
+        8 => Effect::Vibrato {
+            speed: high,
+            amplitude: low,
+        },
+        9 => Effect::Tremor {
+            on_ticks: high + 1,
+            off_ticks: low + 1,
+        },
+        10 => Effect::Arpeggio {
+            chord_offset_1: high,
+            chord_offset_2: low,
+        },
+        _ => Effect::None,
+    }
+}
+
+fn read_pattern(data: &[u8], version: u8) -> Pattern {
+    let mut pattern = Pattern::new();
+    for (line_num, line) in pattern.lines.iter_mut().enumerate() {
+        for channel in 0..NUM_CHANNELS {
+            let offset = (line_num * NUM_CHANNELS + channel) * 4;
+            let bytes = &data[offset..offset + 4];
+            let mut note = Note::empty();
+            if EMPTY_NOTES.contains(&bytes[0]) {
+                line.push(note);
+                continue;
+            }
+            match bytes[0] {
+                NOTE_CUT => note.key = KEY_CUT,
+                // octave in the high nibble and note in the low nibble
+                key if key & 0x0f < 12 && key >> 4 < 8 => {
+                    note.key = (key >> 4) * 12 + (key & 0x0f) + 25
+                }
+                _ => {}
+            }
+            note.sample_number = bytes[1] >> 3;
+            let volume = (bytes[1] & 0x07) | (bytes[2] & 0xf0) >> 1;
+            if volume < NO_VOLUME {
+                note.volume = Some(volume.min(64));
+            }
+            note.effect_command = bytes[2] & 0x0f;
+            note.effect_parameter = bytes[3];
+            note.effect = decode_effect(note.effect_command, note.effect_parameter, version);
+            line.push(note);
+        }
+    }
+    pattern
+}
+
+fn read_sample(file_data: &[u8], header: &[u8]) -> Result<Sample, ModError> {
+    let data_offset = read_u16(header, 14)? as usize * 16;
+    let size = read_u16(header, 16)? as usize;
+    let loop_start = read_u16(header, 18)?;
+    let loop_end = read_u16(header, 20)?;
+    // The last sample is often cut short. Play what there is of it
+    let end = (data_offset + size).min(file_data.len());
+    let loops = loop_end != NO_LOOP && loop_end > loop_start;
+    let mut sample = Sample {
+        name: latin1_string(&header[0..12]),
+        size: 0,
+        volume: header[22].min(64),
+        fine_tune: 0,
+        repeat_offset: if loops { loop_start as u32 } else { 0 },
+        repeat_size: if loops {
+            (loop_end - loop_start) as u32
+        } else {
+            0
+        },
+        c2spd: read_u16(header, 24)?.max(1) as u32,
+        relative_pitch: 0,
+        pan: None,
+        sustain_loop: None,
+        samples: file_data
+            .get(data_offset..end)
+            .unwrap_or(&[])
+            .iter()
+            .map(|&byte| (byte as i8 as i16) << 8)
+            .collect(),
+    };
+    fit_loop(&mut sample);
+    Ok(sample)
+}
+
+/// Reads a Scream Tracker 2 module
+pub(crate) fn read_stm(file_data: &[u8]) -> Result<Song, ModError> {
+    let header = read_bytes(file_data, 0, HEADER_SIZE)?;
+    let version = header[31];
+    let num_patterns = header[33] as usize;
+
+    let mut offset = HEADER_SIZE;
+    let mut samples = Vec::new();
+    for _ in 0..NUM_SAMPLES {
+        let sample_header = read_bytes(file_data, offset, SAMPLE_HEADER_SIZE)?;
+        samples.push(read_sample(file_data, sample_header)?);
+        offset += SAMPLE_HEADER_SIZE;
+    }
+    let orders = read_bytes(file_data, offset, 128)?;
+    offset += 128;
+
+    let mut patterns = Vec::new();
+    for _ in 0..num_patterns {
+        patterns.push(read_pattern(
+            read_bytes(file_data, offset, PATTERN_SIZE)?,
+            version,
+        ));
+        offset += PATTERN_SIZE;
+    }
+
+    let pattern_table: Vec<u8> = orders
+        .iter()
+        .take_while(|order| **order != ORDER_END && **order != 0xff)
+        .filter(|order| (**order as usize) < num_patterns)
+        .copied()
+        .collect();
+    if pattern_table.is_empty() {
+        return Err(ModError::BadHeader(String::from(
+            "the order list does not play any patterns",
+        )));
+    }
+
+    let num_used_patterns = pattern_table.len() as u32;
+    Ok(Song {
+        name: latin1_string(&header[0..20]),
+        format: FormatDescription {
+            kind: FormatKind::Stm,
+            num_channels: NUM_CHANNELS as u32,
+            num_samples: NUM_SAMPLES as u32,
+            has_tag: true,
+            tag: latin1_string(&header[20..28]),
+            has_linear_periods: false,
+        },
+        samples,
+        instruments: Vec::new(),
+        patterns,
+        pattern_table,
+        num_used_patterns,
+        // the song ends after the last order
+        end_position: num_used_patterns,
+        has_standard_notes: false,
+        initial_speed: (speed_byte(header[32], version) >> 4).max(1) as u32,
+        initial_tempo: 125,
+        initial_global_volume: header[34].min(64) as u32,
+        channel_pans: (0..NUM_CHANNELS as u32).map(amiga_channel_pan).collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::{update_tick, PlayerState};
+    use super::*;
+
+    // A song of the version and speed given with one 8 byte sample and one pattern holding the notes given ( line,
+    // channel and the four note bytes )
+    fn build_stm(version: u8, speed: u8, notes: &[(usize, usize, [u8; 4])]) -> Vec<u8> {
+        let mut data = vec![0u8; HEADER_SIZE];
+        data[0..4].copy_from_slice(b"test");
+        data[20..28].copy_from_slice(b"!Scream!");
+        data[28] = 0x1a;
+        data[29] = 2;
+        data[30] = 2;
+        data[31] = version;
+        data[32] = speed;
+        data[33] = 1;
+        data[34] = 48;
+        let mut samples = vec![0u8; NUM_SAMPLES * SAMPLE_HEADER_SIZE];
+        samples[0..6].copy_from_slice(b"sample");
+        // the sample data is at paragraph 0x89, after the pattern
+        samples[14] = 0x89;
+        samples[16] = 8;
+        samples[18] = 2;
+        samples[20] = 6;
+        samples[22] = 64;
+        samples[24..26].copy_from_slice(&8363u16.to_le_bytes());
+        for sample in samples.chunks_mut(SAMPLE_HEADER_SIZE).skip(1) {
+            sample[20..22].copy_from_slice(&NO_LOOP.to_le_bytes());
+        }
+        data.extend(samples);
+        let mut orders = vec![ORDER_END; 128];
+        orders[0] = 0;
+        data.extend(orders);
+        let mut pattern = vec![0u8; PATTERN_SIZE];
+        for note in pattern.chunks_mut(4) {
+            note.copy_from_slice(&[253, 0x07, 0xf0, 0]);
+        }
+        for (line, channel, bytes) in notes {
+            let offset = (line * NUM_CHANNELS + channel) * 4;
+            pattern[offset..offset + 4].copy_from_slice(bytes);
+        }
+        data.extend(pattern);
+        data.extend(&[0, 16, 32, 48, 64, 80, 96, 112]);
+        data
+    }
+
+    #[test]
+    fn test_read_stm() {
+        // line 0 plays C-4 with the sample at volume 32 and slides the volume down by 2 on channel 0. Line 1 cuts the
+        // note on channel 1
+        let data = build_stm(
+            21,
+            0x30,
+            &[
+                (0, 0, [0x20, 0x08, 0x40 | 4, 0x02]),
+                (1, 1, [NOTE_CUT, 0x07, 0xf0, 0]),
+            ],
+        );
+        assert!(is_stm(&data));
+        let song = read_stm(&data).unwrap();
+        assert_eq!(song.format.kind, FormatKind::Stm);
+        assert_eq!(song.name.trim_end_matches('\0'), "test");
+        assert_eq!(song.pattern_table, vec![0]);
+        assert_eq!((song.initial_speed, song.initial_global_volume), (3, 48));
+
+        let sample = &song.samples[0];
+        assert_eq!(sample.samples()[0..2], [0, 16 << 8]);
+        assert_eq!((sample.repeat_offset(), sample.repeat_size()), (2, 4));
+        assert_eq!(song.samples[1].size(), 0);
+
+        let pattern = &song.patterns[0];
+        let note = pattern.note(0, 0).unwrap();
+        assert_eq!((note.key(), note.sample_number()), (49, 1));
+        assert_eq!(note.volume(), Some(32));
+        assert_eq!(*note.effect(), Effect::VolumeSlide { volume_change: -2 });
+        assert_eq!(pattern.note(1, 1).unwrap().key(), KEY_CUT);
+        assert_eq!(pattern.note(1, 0).unwrap().volume(), None);
+    }
+
+    #[test]
+    fn test_old_speed() {
+        // before version 2.21 the speed is decimal
+        assert_eq!(speed_byte(30, 20) >> 4, 3);
+        assert_eq!(speed_byte(0x30, 21) >> 4, 3);
+    }
+
+    #[test]
+    fn test_speed() {
+        // the header speed of 3 and a speed of 5 set on line 0, in hex and in the decimal of old versions
+        for (version, speed, parameter) in [(21, 0x30, 0x50), (20, 30, 50)] {
+            let song = read_stm(&build_stm(
+                version,
+                speed,
+                &[(0, 2, [255, 0x07, 0xf0 | 1, parameter])],
+            ))
+            .unwrap();
+            let mut player_state = PlayerState::new(song.format.num_channels, 44100);
+            // the ticks the first lines are played on
+            let mut line_ticks = Vec::new();
+            let mut tick = 0;
+            while line_ticks.len() < 3 {
+                if player_state.is_line_due() {
+                    line_ticks.push(tick);
+                }
+                update_tick(&song, &mut player_state);
+                tick += 1;
+            }
+            assert_eq!(line_ticks, vec![3, 8, 13], "version {}", version);
+        }
+    }
+}
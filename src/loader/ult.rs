@@ -0,0 +1,366 @@
+//! UltraTracker (ULT) modules
+//!
+//! Patterns are stored a channel at a time: all the patterns of the first channel, then those of the second and so on.
+//! Each channel's notes are run length encoded and carry two effects. Samples are 8 or 16 bit signed and version 4 files
+//! give each sample its own C2Spd.
+use super::super::{
+    amiga_channel_pan, latin1_string, Effect, FormatDescription, FormatKind, ModError, Note,
+    Pattern, Sample, Song, DEFAULT_C2SPD,
+};
+use super::{fit_loop, read_bytes, read_u16, read_u32};
+
+const MAGIC: &[u8] = b"MAS_UTrack_V00";
+const HEADER_SIZE: usize = 48;
+const MESSAGE_LINE_SIZE: usize = 32;
+const NUM_LINES: usize = 64;
+// The order list ends at the first end marker
+const ORDER_END: u8 = 0xff;
+// A note that is repeated, followed by the number of times and the note
+const REPEAT: u8 = 0xfc;
+
+// Sample flags
+const SAMPLE_16_BIT: u8 = 4;
+const SAMPLE_LOOPS: u8 = 8;
+
+/// Is the data an UltraTracker module
+pub(crate) fn is_ult(file_data: &[u8]) -> bool {
+    match file_data.get(0..15) {
+        Some(magic) => magic.starts_with(MAGIC) && (b'1'..=b'4').contains(&magic[14]),
+        None => false,
+    }
+}
+
+/// Decodes an UltraTracker effect. Most match the ProTracker effects. The sample reversing effects ( 5xx ) are not
+/// played
+pub(crate) fn decode_effect(command: u8, parameter: u8) -> Effect {
+    match command {
+        5 => Effect::None,
+        // the sample offset is in 1024 byte steps
+        9 => Effect::SetSampleOffset {
+            offset: parameter.saturating_mul(4),
+        },
+        11 => Effect::CoarsePan {
+            pan_pos: parameter & 0x0f,
+        },
+        12 => Effect::SetVolume {
+            volume: parameter / 4,
+        },
+        _ => Effect::new(command, parameter as i8),
+    }
+}
+
+/// Reads the five bytes of a note and the number of lines it is repeated for
+fn read_event<'a>(file_data: &'a [u8], offset: &mut usize) -> Result<(&'a [u8], usize), ModError> {
+    let mut repeat = 1;
+    if read_bytes(file_data, *offset, 1)?[0] == REPEAT {
+        repeat = read_bytes(file_data, *offset + 1, 1)?[0].max(1) as usize;
+        *offset += 2;
+    }
+    let bytes = read_bytes(file_data, *offset, 5)?;
+    *offset += 5;
+    Ok((bytes, repeat))
+}
+
+/// Can the effect be played from the volume column alongside the note's effect
+fn plays_in_volume_column(effect: &Effect) -> bool {
+    matches!(
+        effect,
+        Effect::VolumeSlide { .. }
+            | Effect::FineVolumeSlideUp { .. }
+            | Effect::FineVolumeSlideDown { .. }
+            | Effect::Vibrato { .. }
+            | Effect::Pan { .. }
+            | Effect::PanSlide { .. }
+    )
+}
+
+/// Reads a note. A set volume effect goes in the volume column. When both effects are used the one that can be played
+/// from the volume column goes there, the second effect if both can. When neither can the second effect is not played
+fn read_note(bytes: &[u8]) -> Note {
+    let mut note = Note::empty();
+    if (1..=60).contains(&bytes[0]) {
+        note.key = bytes[0] + 12;
+    }
+    note.sample_number = bytes[1];
+    // the low nibble is the first effect and the high nibble the second
+    let mut effects = Vec::new();
+    for (command, parameter) in [(bytes[2] & 0x0f, bytes[3]), (bytes[2] >> 4, bytes[4])] {
+        match decode_effect(command, parameter) {
+            Effect::SetVolume { volume } => note.volume = Some(volume.min(64)),
+            Effect::None => {}
+            effect => effects.push((command, parameter, effect)),
+        }
+    }
+    if effects.len() == 2
+        && plays_in_volume_column(&effects[0].2)
+        && !plays_in_volume_column(&effects[1].2)
+    {
+        effects.swap(0, 1);
+    }
+    if let Some((command, parameter, effect)) = effects.first().copied() {
+        note.effect_command = command;
+        note.effect_parameter = parameter;
+        note.effect = effect;
+    }
+    if let Some((_, _, effect)) = effects.get(1) {
+        if plays_in_volume_column(effect) {
+            note.volume_effect = *effect;
+        }
+    }
+    note
+}
+
+fn read_sample(header: &[u8], version: u8) -> Result<Sample, ModError> {
+    let flags = header[61];
+    let bytes_per_point = if flags & SAMPLE_16_BIT != 0 { 2 } else { 1 };
+    let loop_start = read_u32(header, 44)?;
+    let loop_end = read_u32(header, 48)?;
+    let size = read_u32(header, 56)?.saturating_sub(read_u32(header, 52)?);
+    let (c2spd, fine_tune) = if version >= 4 {
+        (read_u16(header, 62)? as u32, read_u16(header, 64)? as i16)
+    } else {
+        (DEFAULT_C2SPD, read_u16(header, 62)? as i16)
+    };
+    // the fine tune is in 1/32768 of a semitone
+    let c2spd = c2spd as f32 * 2.0f32.powf(fine_tune as f32 / (12.0 * 32768.0));
+    let loops = flags & SAMPLE_LOOPS != 0 && loop_end > loop_start;
+    Ok(Sample {
+        name: latin1_string(&header[0..32]),
+        size: size / bytes_per_point,
+        volume: header[60] / 4,
+        fine_tune: 0,
+        repeat_offset: if loops { loop_start } else { 0 },
+        repeat_size: if loops { loop_end - loop_start } else { 0 },
+        c2spd: c2spd.round().max(1.0) as u32,
+        relative_pitch: 0,
+        pan: None,
+        sustain_loop: None,
+        samples: Vec::new(),
+    })
+}
+
+/// Reads an UltraTracker module
+pub(crate) fn read_ult(file_data: &[u8]) -> Result<Song, ModError> {
+    let header = read_bytes(file_data, 0, HEADER_SIZE)?;
+    let version = header[14] - b'0';
+    let mut offset = HEADER_SIZE + header[47] as usize * MESSAGE_LINE_SIZE;
+
+    let num_samples = read_bytes(file_data, offset, 1)?[0] as usize;
+    offset += 1;
+    let sample_header_size = if version >= 4 { 66 } else { 64 };
+    let mut samples = Vec::new();
+    let mut is_16_bit = Vec::new();
+    for _ in 0..num_samples {
+        let sample_header = read_bytes(file_data, offset, sample_header_size)?;
+        samples.push(read_sample(sample_header, version)?);
+        is_16_bit.push(sample_header[61] & SAMPLE_16_BIT != 0);
+        offset += sample_header_size;
+    }
+
+    let orders = read_bytes(file_data, offset, 256)?;
+    offset += 256;
+    let counts = read_bytes(file_data, offset, 2)?;
+    let num_channels = counts[0] as usize + 1;
+    let num_patterns = counts[1] as usize + 1;
+    offset += 2;
+    let channel_pans = if version >= 3 {
+        let pans = read_bytes(file_data, offset, num_channels)?;
+        offset += num_channels;
+        pans.iter()
+            .map(|pan| (pan & 0x0f) as f32 / 15.0 * 2.0 - 1.0)
+            .collect()
+    } else {
+        (0..num_channels as u32).map(amiga_channel_pan).collect()
+    };
+
+    let mut patterns: Vec<Pattern> = (0..num_patterns)
+        .map(|_| Pattern::with_lines(NUM_LINES))
+        .collect();
+    for _channel in 0..num_channels {
+        for pattern in &mut patterns {
+            let mut line_num = 0;
+            while line_num < NUM_LINES {
+                let (bytes, repeat) = read_event(file_data, &mut offset)?;
+                // repeats do not carry on into the next pattern
+                for line in pattern.lines.iter_mut().skip(line_num).take(repeat) {
+                    line.push(read_note(bytes));
+                }
+                line_num += repeat;
+            }
+        }
+    }
+
+    // The last sample is often cut short. Play what there is of it
+    for (sample, is_16_bit) in samples.iter_mut().zip(is_16_bit) {
+        let length = sample.size as usize * if is_16_bit { 2 } else { 1 };
+        let end = (offset + length).min(file_data.len());
+        let data = file_data.get(offset..end).unwrap_or(&[]);
+        sample.samples = if is_16_bit {
+            data.chunks_exact(2)
+                .map(|point| i16::from_le_bytes([point[0], point[1]]))
+                .collect()
+        } else {
+            data.iter().map(|&byte| (byte as i8 as i16) << 8).collect()
+        };
+        offset += length;
+        fit_loop(sample);
+    }
+
+    let pattern_table: Vec<u8> = orders
+        .iter()
+        .take_while(|order| **order != ORDER_END)
+        .filter(|order| (**order as usize) < num_patterns)
+        .copied()
+        .collect();
+    if pattern_table.is_empty() {
+        return Err(ModError::BadHeader(String::from(
+            "the order list does not play any patterns",
+        )));
+    }
+
+    let num_used_patterns = pattern_table.len() as u32;
+    Ok(Song {
+        name: latin1_string(&header[15..47]),
+        format: FormatDescription {
+            kind: FormatKind::Ult,
+            num_channels: num_channels as u32,
+            num_samples: num_samples as u32,
+            has_tag: true,
+            tag: latin1_string(&header[0..15]),
+            has_linear_periods: false,
+        },
+        samples,
+        instruments: Vec::new(),
+        patterns,
+        pattern_table,
+        num_used_patterns,
+        // the song ends after the last order
+        end_position: num_used_patterns,
+        has_standard_notes: false,
+        initial_speed: 6,
+        initial_tempo: 125,
+        initial_global_volume: 64,
+        channel_pans,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::update_tick;
+    use super::super::tests::play_ticks;
+    use super::*;
+
+    // A version 4 song with two channels, one 8 byte sample and one pattern. Channel 0 plays the notes given and
+    // repeats an empty note for the rest of the pattern. Channel 1 slides the volume down on every line
+    fn build_ult(notes: &[[u8; 5]]) -> Vec<u8> {
+        let mut data = vec![0u8; HEADER_SIZE];
+        data[0..15].copy_from_slice(b"MAS_UTrack_V004");
+        data[15..19].copy_from_slice(b"test");
+        data[47] = 1;
+        data.extend(&[b' '; MESSAGE_LINE_SIZE]);
+        data.push(1);
+        let mut sample = vec![0u8; 66];
+        sample[0..6].copy_from_slice(b"sample");
+        sample[44] = 2;
+        sample[48] = 6;
+        sample[56] = 8;
+        sample[60] = 255;
+        sample[61] = SAMPLE_LOOPS;
+        sample[62..64].copy_from_slice(&8363u16.to_le_bytes());
+        data.extend(sample);
+        let mut orders = vec![ORDER_END; 256];
+        orders[0] = 0;
+        data.extend(orders);
+        data.extend(&[1, 0, 0, 15]);
+        for note in notes {
+            data.extend(note);
+        }
+        data.extend(&[REPEAT, (64 - notes.len()) as u8, 0, 0, 0, 0, 0]);
+        data.extend(&[REPEAT, 64, 0, 0, 0x0a, 0x02, 0]);
+        data.extend(&[0, 16, 32, 48, 64, 80, 96, 112]);
+        data
+    }
+
+    #[test]
+    fn test_read_ult() {
+        // C-4 with the sample and a volume of 128 as the second effect
+        let data = build_ult(&[[37, 1, 0xc0, 0, 128]]);
+        assert!(is_ult(&data));
+        let song = read_ult(&data).unwrap();
+        assert_eq!(song.format.kind, FormatKind::Ult);
+        assert_eq!(song.name.trim_end_matches('\0'), "test");
+        assert_eq!(song.format.num_channels, 2);
+        assert_eq!(song.channel_pans, vec![-1.0, 1.0]);
+        assert_eq!(song.pattern_table, vec![0]);
+
+        let sample = &song.samples[0];
+        assert_eq!((sample.c2spd(), sample.volume()), (8363, 63));
+        assert_eq!(sample.samples()[0..2], [0, 16 << 8]);
+        assert_eq!((sample.repeat_offset(), sample.repeat_size()), (2, 4));
+
+        let pattern = &song.patterns[0];
+        assert_eq!(pattern.num_lines(), NUM_LINES);
+        let note = pattern.note(0, 0).unwrap();
+        assert_eq!((note.key(), note.sample_number()), (49, 1));
+        assert_eq!(note.volume(), Some(32));
+        assert_eq!(*note.effect(), Effect::None);
+        assert_eq!(pattern.note(63, 0).unwrap().key(), 0);
+        assert_eq!(
+            *pattern.note(63, 1).unwrap().effect(),
+            Effect::VolumeSlide { volume_change: -2 }
+        );
+    }
+
+    #[test]
+    fn test_two_effects() {
+        let song = read_ult(&build_ult(&[
+            // vibrato and a volume slide down
+            [37, 1, 0xa4, 0x48, 0x02],
+            // a volume slide up and an arpeggio, the slide is moved to the volume column
+            [0, 0, 0x0a, 0x20, 0x37],
+            // an arpeggio and a speed change, only the first is played
+            [0, 0, 0xf0, 0x37, 0x03],
+        ]))
+        .unwrap();
+        let pattern = &song.patterns[0];
+        let note = pattern.note(0, 0).unwrap();
+        assert_eq!(
+            *note.effect(),
+            Effect::Vibrato {
+                speed: 4,
+                amplitude: 8
+            }
+        );
+        assert_eq!(
+            *note.volume_effect(),
+            Effect::VolumeSlide { volume_change: -2 }
+        );
+        let note = pattern.note(1, 0).unwrap();
+        assert_eq!(
+            *note.effect(),
+            Effect::Arpeggio {
+                chord_offset_1: 3,
+                chord_offset_2: 7
+            }
+        );
+        assert_eq!(
+            *note.volume_effect(),
+            Effect::VolumeSlide { volume_change: 2 }
+        );
+        assert_eq!(*pattern.note(2, 0).unwrap().volume_effect(), Effect::None);
+
+        // both effects are played
+        let mut player_state = play_ticks(&song, 10);
+        let channel = &player_state.channels[0];
+        assert_eq!(channel.volume, 63.0 - 3.0 * 2.0);
+        assert_ne!(channel.vibrato_offset, 0);
+        assert_eq!(channel.period, 1712);
+        // line 1 plays on the third tick from here
+        for _tick in 0..5 {
+            update_tick(&song, &mut player_state);
+        }
+        let channel = &player_state.channels[0];
+        assert_eq!(channel.volume, 63.0 - 6.0 * 2.0 + 2.0 * 2.0);
+        assert_eq!(channel.arpeggio_offsets, [3, 7]);
+    }
+}
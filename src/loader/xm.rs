@@ -0,0 +1,738 @@
+//! FastTracker 2 extended modules (XM)
+//!
+//! Notes refer to instruments rather than samples. Each instrument has a key map that picks one of its samples for each
+//! note, and envelopes for the volume and panning. Patterns can have from 1 to 256 lines and are packed so that empty columns
+//! take no space.
+use super::super::instrument::{AutoVibrato, DuplicateCheck, Envelope, Instrument, NewNoteAction};
+use super::super::{
+    latin1_string, Effect, FormatDescription, FormatKind, ModError, Note, Pattern, Sample, Song,
+    KEY_OFF,
+};
+use super::info::{read_at, ModInfo};
+use super::{read_bytes, read_u16, read_u32};
+use std::io::{Read, Seek};
+
+const ID_TEXT: &[u8] = b"Extended Module: ";
+// The header is followed by the order list. Everything after it is found from the header size
+const ORDER_LIST_OFFSET: usize = 80;
+const MAX_ENVELOPE_POINTS: usize = 12;
+
+// Header flags
+const LINEAR_PERIODS: u16 = 1;
+
+// Sample type flags
+const LOOP_TYPE_MASK: u8 = 3;
+const PING_PONG_LOOP: u8 = 2;
+const SAMPLE_16_BIT: u8 = 0x10;
+
+// Envelope type flags
+const ENVELOPE_ON: u8 = 1;
+const ENVELOPE_SUSTAIN: u8 = 2;
+const ENVELOPE_LOOP: u8 = 4;
+
+/// Is the data an XM module
+pub(crate) fn is_xm(file_data: &[u8]) -> bool {
+    file_data.starts_with(ID_TEXT)
+}
+
+/// The period of a note. `relative_pitch` is the tuning of the sample in 128ths of a semitone. Linear periods fall by 64 for
+/// each semitone, Amiga periods are in quarters of an Amiga period
+pub(crate) fn note_period(key: u8, relative_pitch: i16, linear_periods: bool) -> u32 {
+    // 128ths of a semitone above C-0
+    let pitch = (key.clamp(1, 120) as i32 - 1) * 128 + relative_pitch as i32;
+    if linear_periods {
+        (7680 - pitch / 2).max(1) as u32
+    } else {
+        let period = 1712.0 * ((48 * 128 - pitch) as f32 / 1536.0).exp2();
+        (period.round() as u32).max(1)
+    }
+}
+
+/// The slot of the effect memory that an effect with a parameter of 0 uses. The portamento slides each have a memory and the
+/// volume slides share one
+pub(crate) fn effect_memory_slot(command: u8) -> Option<usize> {
+    match command {
+        1 | 2 | 17 | 25 | 27 => Some(command as usize),
+        5 | 6 | 10 => Some(10),
+        _ => None,
+    }
+}
+
+// Slides up by the high nibble or, if it is 0, down by the low nibble
+fn slide(parameter: u8) -> i8 {
+    if parameter >> 4 != 0 {
+        (parameter >> 4) as i8
+    } else {
+        -((parameter & 0x0f) as i8)
+    }
+}
+
+/// Decodes an effect. Commands 0-15 are the ProTracker effects, the letters G to Z follow them
+pub(crate) fn decode_effect(command: u8, parameter: u8) -> Effect {
+    let high = parameter >> 4;
+    let low = parameter & 0x0f;
+    match command {
+        5 => Effect::TonePortamentoVolumeSlide {
+            volume_change: slide(parameter),
+        },
+        6 => Effect::VibratoVolumeSlide {
+            volume_change: slide(parameter),
+        },
+        10 => Effect::VolumeSlide {
+            volume_change: slide(parameter),
+        },
+        // F00 stops the song in FastTracker 2. It is ignored rather than playing every tick as a line
+        15 if parameter == 0 => Effect::None,
+        0..=15 => Effect::new(command, parameter as i8),
+        16 => Effect::SetGlobalVolume { volume: parameter },
+        17 => Effect::GlobalVolumeSlide {
+            volume_change: slide(parameter),
+        },
+        20 => Effect::KeyOff { tick: parameter },
+        21 => Effect::SetEnvelopePosition {
+            position: parameter,
+        },
+        25 => Effect::PanSlide {
+            pan_change: slide(parameter),
+        },
+        27 => Effect::RetriggerVolumeSlide {
+            retrigger_delay: low,
+            volume_change: high,
+        },
+        29 => Effect::Tremor {
+            on_ticks: high,
+            off_ticks: low,
+        },
+        33 => match high {
+            1 => Effect::ExtraFinePortaUp { period_change: low },
+            2 => Effect::ExtraFinePortaDown { period_change: low },
+            _ => Effect::None,
+        },
+        _ => Effect::None,
+    }
+}
+
+// Decodes the volume column. Values from 0x10 to 0x50 set the volume, higher values are effects
+fn decode_volume_column(value: u8) -> (Option<u8>, Effect) {
+    let low = value & 0x0f;
+    let effect = match value >> 4 {
+        0x1..=0x4 => return (Some(value - 0x10), Effect::None),
+        0x5 if value == 0x50 => return (Some(64), Effect::None),
+        0x6 => Effect::VolumeSlide {
+            volume_change: -(low as i8),
+        },
+        0x7 => Effect::VolumeSlide {
+            volume_change: low as i8,
+        },
+        0x8 => Effect::FineVolumeSlideDown { volume_change: low },
+        0x9 => Effect::FineVolumeSlideUp { volume_change: low },
+        0xa => Effect::Vibrato {
+            speed: low,
+            amplitude: 0,
+        },
+        0xb => Effect::Vibrato {
+            speed: 0,
+            amplitude: low,
+        },
+        0xc => Effect::Pan { position: low * 17 },
+        0xd => Effect::PanSlide {
+            pan_change: -(low as i8),
+        },
+        0xe => Effect::PanSlide {
+            pan_change: low as i8,
+        },
+        0xf => Effect::TonePortamento { speed: low << 4 },
+        _ => Effect::None,
+    };
+    (None, effect)
+}
+
+fn read_pattern(
+    file_data: &[u8],
+    offset: usize,
+    num_channels: usize,
+) -> Result<(Pattern, usize), ModError> {
+    let header_size = read_u32(file_data, offset)? as usize;
+    let num_lines = match read_u16(file_data, offset + 5)? {
+        0 => 64,
+        num_lines => num_lines as usize,
+    };
+    let packed_size = read_u16(file_data, offset + 7)? as usize;
+    let mut pattern = Pattern::with_lines(num_lines);
+    for line in &mut pattern.lines {
+        line.resize_with(num_channels, Note::empty);
+    }
+
+    let data = read_bytes(file_data, offset + header_size, packed_size)?;
+    let mut position = 0;
+    let mut next_byte = || -> Result<u8, ModError> {
+        let byte = data.get(position).copied().ok_or(ModError::Truncated {
+            offset: offset + header_size + position,
+            len: 1,
+        });
+        position += 1;
+        byte
+    };
+    // empty patterns have no data
+    if packed_size > 0 {
+        for line in &mut pattern.lines {
+            for note in line.iter_mut() {
+                // a first byte with the top bit set says which of the columns follow. Otherwise it is the note and all
+                // the columns follow
+                let first = next_byte()?;
+                let columns = if first & 0x80 != 0 { first } else { 0x1f };
+                let mut column = |bit: u8| -> Result<u8, ModError> {
+                    match columns & bit {
+                        0 => Ok(0),
+                        1 if first & 0x80 == 0 => Ok(first),
+                        _ => next_byte(),
+                    }
+                };
+                let key = column(1)?;
+                note.sample_number = column(2)?;
+                let volume = column(4)?;
+                note.effect_command = column(8)?;
+                note.effect_parameter = column(16)?;
+                note.key = match key {
+                    1..=96 => key,
+                    97 => KEY_OFF,
+                    _ => 0,
+                };
+                let (volume, volume_effect) = decode_volume_column(volume);
+                note.volume = volume;
+                note.volume_effect = volume_effect;
+                note.effect = decode_effect(note.effect_command, note.effect_parameter);
+            }
+        }
+    }
+    Ok((pattern, header_size + packed_size))
+}
+
+fn read_envelope(
+    header: &[u8],
+    points_offset: usize,
+    num_points: u8,
+    sustain_point: u8,
+    loop_start: u8,
+    loop_end: u8,
+    envelope_type: u8,
+) -> Envelope {
+    let num_points = (num_points as usize).min(MAX_ENVELOPE_POINTS);
+    if envelope_type & ENVELOPE_ON == 0 || num_points == 0 {
+        return Envelope::disabled();
+    }
+    let mut points: Vec<(u32, u8)> = Vec::new();
+    for point in header[points_offset..points_offset + num_points * 4].chunks(4) {
+        let tick = u16::from_le_bytes([point[0], point[1]]) as u32;
+        let value = u16::from_le_bytes([point[2], point[3]]).min(64) as u8;
+        // the points must move forward in time. Any after one that does not are dropped
+        if points.last().is_some_and(|last| last.0 >= tick) {
+            break;
+        }
+        points.push((tick, value));
+    }
+    let num_points = points.len();
+    Envelope {
+        points,
+        sustain_loop: Some(sustain_point as usize)
+            .filter(|point| envelope_type & ENVELOPE_SUSTAIN != 0 && *point < num_points)
+            .map(|point| (point, point)),
+        loop_points: Some((loop_start as usize, loop_end as usize)).filter(|(start, end)| {
+            envelope_type & ENVELOPE_LOOP != 0 && start <= end && *end < num_points
+        }),
+    }
+}
+
+// Reads the sample data, which is stored as the differences between sample points
+fn read_sample_data(data: &[u8], is_16_bit: bool) -> Vec<i16> {
+    if is_16_bit {
+        let mut value = 0i16;
+        data.chunks_exact(2)
+            .map(|delta| {
+                value = value.wrapping_add(i16::from_le_bytes([delta[0], delta[1]]));
+                value
+            })
+            .collect()
+    } else {
+        let mut value = 0i8;
+        data.iter()
+            .map(|delta| {
+                value = value.wrapping_add(*delta as i8);
+                (value as i16) << 8
+            })
+            .collect()
+    }
+}
+
+// Reads an instrument and its samples. Returns the size of the data that was read
+fn read_instrument(
+    file_data: &[u8],
+    offset: usize,
+    samples: &mut Vec<Sample>,
+) -> Result<(Instrument, usize), ModError> {
+    let header_size = read_u32(file_data, offset)? as usize;
+    let num_samples = read_u16(file_data, offset + 27)? as usize;
+    let mut instrument = Instrument {
+        name: latin1_string(read_bytes(file_data, offset + 4, 22)?),
+        sample_map: [0; 120],
+        volume_envelope: Envelope::disabled(),
+        panning_envelope: Envelope::disabled(),
+        pitch_envelope: Envelope::disabled(),
+        has_filter_envelope: false,
+        fadeout: 0,
+        auto_vibrato: AutoVibrato {
+            waveform: 0,
+            sweep: 0,
+            depth: 0,
+            rate: 0,
+        },
+        pan: None,
+        filter_cutoff: None,
+        filter_resonance: None,
+        new_note_action: NewNoteAction::Cut,
+        duplicate_check: DuplicateCheck::Off,
+        duplicate_action: NewNoteAction::Cut,
+        release_stops_without_envelope: true,
+    };
+    if num_samples == 0 {
+        return Ok((instrument, header_size));
+    }
+    let header = read_bytes(file_data, offset, 241)?;
+    let sample_header_size = read_u32(header, 29)? as usize;
+    let first_sample = samples.len();
+    for (key, sample) in instrument.sample_map[..96].iter_mut().enumerate() {
+        let instrument_sample = header[33 + key] as usize;
+        if instrument_sample < num_samples {
+            *sample = (first_sample + instrument_sample + 1) as u8;
+        }
+    }
+    instrument.volume_envelope = read_envelope(
+        header,
+        129,
+        header[225],
+        header[227],
+        header[228],
+        header[229],
+        header[233],
+    );
+    instrument.panning_envelope = read_envelope(
+        header,
+        177,
+        header[226],
+        header[230],
+        header[231],
+        header[232],
+        header[234],
+    );
+    instrument.auto_vibrato = AutoVibrato {
+        waveform: header[235],
+        sweep: header[236],
+        depth: header[237],
+        rate: header[238],
+    };
+    instrument.fadeout = read_u16(header, 239)? as u32;
+
+    // the sample headers come first and the data of all the samples follows them
+    let mut headers = Vec::new();
+    let mut position = offset + header_size;
+    for _ in 0..num_samples {
+        headers.push(read_bytes(file_data, position, 40)?);
+        position += sample_header_size;
+    }
+    for header in headers {
+        let size_in_bytes = read_u32(header, 0)? as usize;
+        let sample_type = header[14];
+        let is_16_bit = sample_type & SAMPLE_16_BIT != 0;
+        let bytes_per_point = if is_16_bit { 2 } else { 1 };
+        // the last sample is often cut short. Play what there is of it
+        let available = file_data.len().saturating_sub(position).min(size_in_bytes);
+        let data = &file_data[position..position + available];
+        position += size_in_bytes;
+
+        let mut sample = Sample {
+            name: latin1_string(&header[18..40]),
+            size: 0,
+            volume: header[12].min(64),
+            fine_tune: 0,
+            repeat_offset: 0,
+            repeat_size: 0,
+            c2spd: 8363,
+            relative_pitch: header[16] as i8 as i16 * 128 + header[13] as i8 as i16,
+            pan: Some(header[15]),
+            sustain_loop: None,
+            samples: read_sample_data(data, is_16_bit),
+        };
+        sample.size = sample.samples.len() as u32;
+        let loop_start = (read_u32(header, 4)? as usize / bytes_per_point) as u32;
+        let loop_length = (read_u32(header, 8)? as usize / bytes_per_point) as u32;
+        let loop_end = (loop_start + loop_length).min(sample.size);
+        if sample_type & LOOP_TYPE_MASK != 0 && loop_end > loop_start {
+            // ping-pong loops are played by appending the loop backwards so that the loop plays forwards and then backwards
+            sample.samples.truncate(loop_end as usize);
+            if sample_type & LOOP_TYPE_MASK == PING_PONG_LOOP {
+                let backwards: Vec<i16> = sample.samples[loop_start as usize..]
+                    .iter()
+                    .rev()
+                    .copied()
+                    .collect();
+                sample.samples.extend(backwards);
+            }
+            sample.size = sample.samples.len() as u32;
+            sample.repeat_offset = loop_start;
+            sample.repeat_size = sample.size - loop_start;
+        }
+        samples.push(sample);
+    }
+    Ok((instrument, position - offset))
+}
+
+/// Describes an XM module from its headers. The pattern and instrument headers are read in turn to find the sample
+/// headers and the pattern and sample data is skipped
+pub(crate) fn read_xm_info<R: Read + Seek>(reader: &mut R) -> Result<ModInfo, ModError> {
+    let header = read_at(reader, 0, ORDER_LIST_OFFSET)?;
+    let header_size = read_u32(&header, 60)? as usize;
+    let song_length = read_u16(&header, 64)? as usize;
+    let num_patterns = read_u16(&header, 70)? as usize;
+    let num_instruments = read_u16(&header, 72)? as usize;
+    let orders = read_at(reader, ORDER_LIST_OFFSET, song_length)?;
+    let num_orders = orders
+        .iter()
+        .filter(|order| (**order as usize) < num_patterns)
+        .count();
+    if num_orders == 0 {
+        return Err(ModError::BadHeader(String::from(
+            "the order list does not play any patterns",
+        )));
+    }
+
+    let mut offset = 60 + header_size;
+    for _ in 0..num_patterns {
+        let pattern_header = read_at(reader, offset, 9)?;
+        offset += read_u32(&pattern_header, 0)? as usize + read_u16(&pattern_header, 7)? as usize;
+    }
+    let mut sample_names = Vec::new();
+    let mut sample_sizes = Vec::new();
+    for _ in 0..num_instruments {
+        let instrument_header = read_at(reader, offset, 29)?;
+        let header_size = read_u32(&instrument_header, 0)? as usize;
+        let num_samples = read_u16(&instrument_header, 27)? as usize;
+        if num_samples == 0 {
+            offset += header_size;
+            continue;
+        }
+        let sample_header_size = read_u32(&read_at(reader, offset + 29, 4)?, 0)? as usize;
+        // the sample headers come first and the data of all the samples follows them
+        let mut position = offset + header_size;
+        let mut data_size = 0;
+        for _ in 0..num_samples {
+            let sample_header = read_at(reader, position, 40)?;
+            let size_in_bytes = read_u32(&sample_header, 0)? as usize;
+            let bytes_per_point = if sample_header[14] & SAMPLE_16_BIT != 0 {
+                2
+            } else {
+                1
+            };
+            sample_names.push(latin1_string(&sample_header[18..40]));
+            sample_sizes.push((size_in_bytes / bytes_per_point) as u32);
+            data_size += size_in_bytes;
+            position += sample_header_size;
+        }
+        offset = position + data_size;
+    }
+    Ok(ModInfo {
+        name: latin1_string(&header[17..37]),
+        format: FormatKind::Xm,
+        num_channels: read_u16(&header, 68)? as u32,
+        sample_names,
+        sample_sizes,
+        num_orders: num_orders as u32,
+        num_patterns: num_patterns as u32,
+    })
+}
+
+/// Reads an XM module
+pub(crate) fn read_xm(file_data: &[u8]) -> Result<Song, ModError> {
+    let header_size = read_u32(file_data, 60)? as usize;
+    let song_length = read_u16(file_data, 64)? as usize;
+    let restart_position = read_u16(file_data, 66)? as usize;
+    let num_channels = read_u16(file_data, 68)? as usize;
+    let num_patterns = read_u16(file_data, 70)? as usize;
+    let num_instruments = read_u16(file_data, 72)? as usize;
+    let flags = read_u16(file_data, 74)?;
+    if num_channels == 0 || num_channels > 32 {
+        return Err(ModError::BadHeader(format!(
+            "{} channels is not in the range 1-32",
+            num_channels
+        )));
+    }
+    if num_patterns > 256 || num_instruments > 128 || song_length > 256 {
+        return Err(ModError::BadHeader(format!(
+            "{} patterns, {} instruments and {} positions is more than an XM file can have",
+            num_patterns, num_instruments, song_length
+        )));
+    }
+
+    let mut offset = 60 + header_size;
+    let mut patterns = Vec::new();
+    for _ in 0..num_patterns {
+        let (pattern, size) = read_pattern(file_data, offset, num_channels)?;
+        patterns.push(pattern);
+        offset += size;
+    }
+    let mut instruments = Vec::new();
+    let mut samples = Vec::new();
+    for _ in 0..num_instruments {
+        let (instrument, size) = read_instrument(file_data, offset, &mut samples)?;
+        instruments.push(instrument);
+        offset += size;
+    }
+    if samples.len() > 255 {
+        return Err(ModError::BadHeader(format!(
+            "{} samples is more than can be played",
+            samples.len()
+        )));
+    }
+
+    // positions that refer to missing patterns are skipped
+    let orders = read_bytes(file_data, ORDER_LIST_OFFSET, song_length)?;
+    let pattern_table: Vec<u8> = orders
+        .iter()
+        .filter(|order| (**order as usize) < num_patterns)
+        .copied()
+        .collect();
+    if pattern_table.is_empty() {
+        return Err(ModError::BadHeader(String::from(
+            "the order list does not play any patterns",
+        )));
+    }
+    let num_used_patterns = pattern_table.len() as u32;
+    Ok(Song {
+        name: latin1_string(read_bytes(file_data, 17, 20)?),
+        format: FormatDescription {
+            kind: FormatKind::Xm,
+            num_channels: num_channels as u32,
+            num_samples: samples.len() as u32,
+            has_tag: true,
+            tag: String::from("Extended Module"),
+            has_linear_periods: flags & LINEAR_PERIODS != 0,
+        },
+        samples,
+        instruments,
+        patterns,
+        pattern_table,
+        num_used_patterns,
+        // XM songs always loop
+        end_position: if (restart_position as u32) < num_used_patterns {
+            restart_position as u32
+        } else {
+            0
+        },
+        has_standard_notes: false,
+        initial_speed: read_u16(file_data, 76)?.max(1) as u32,
+        initial_tempo: read_u16(file_data, 78)?.clamp(32, 255) as u32,
+        initial_global_volume: 64,
+        channel_pans: vec![0.0; num_channels],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::update_tick;
+    use super::super::tests::{play_ticks, put_u16, put_u32};
+    use super::*;
+    use std::io::Cursor;
+
+    // A two channel song with linear periods. The first pattern has 3 lines and the second is an empty 5 line pattern. The
+    // instrument plays an 8 bit sample below C-5 and a 16 bit ping-pong looped sample from C-5 up
+    fn build_xm() -> Vec<u8> {
+        let mut data = vec![0u8; 336];
+        data[0..17].copy_from_slice(ID_TEXT);
+        data[17..24].copy_from_slice(b"test xm");
+        data[37] = 0x1a;
+        put_u16(&mut data, 58, 0x0104);
+        put_u32(&mut data, 60, 276);
+        put_u16(&mut data, 64, 2);
+        put_u16(&mut data, 68, 2);
+        put_u16(&mut data, 70, 2);
+        put_u16(&mut data, 72, 1);
+        put_u16(&mut data, 74, LINEAR_PERIODS);
+        put_u16(&mut data, 76, 3);
+        put_u16(&mut data, 78, 125);
+        data[80..82].copy_from_slice(&[0, 1]);
+
+        let lines: Vec<u8> = vec![
+            // line 0: C-4 with instrument 1 and volume 32 on channel 0
+            49, 1, 0x30, 0, 0, 0x80, //
+            // line 1: key off on channel 0 and C-5 on channel 1
+            0x81, 97, 0x83, 61, 1, //
+            // line 2: nothing
+            0x80, 0x80,
+        ];
+        let mut pattern = vec![0u8; 9];
+        put_u32(&mut pattern, 0, 9);
+        put_u16(&mut pattern, 5, 3);
+        put_u16(&mut pattern, 7, lines.len() as u16);
+        pattern.extend(lines);
+        data.extend(pattern);
+        let mut empty_pattern = vec![0u8; 9];
+        put_u32(&mut empty_pattern, 0, 9);
+        put_u16(&mut empty_pattern, 5, 5);
+        data.extend(empty_pattern);
+
+        let mut instrument = vec![0u8; 263];
+        put_u32(&mut instrument, 0, 263);
+        instrument[4..14].copy_from_slice(b"instrument");
+        put_u16(&mut instrument, 27, 2);
+        put_u32(&mut instrument, 29, 40);
+        for key in 59..96 {
+            instrument[33 + key] = 1;
+        }
+        for (point, (tick, value)) in [(0u16, 64u16), (4, 32), (8, 0)].iter().enumerate() {
+            put_u16(&mut instrument, 129 + point * 4, *tick);
+            put_u16(&mut instrument, 131 + point * 4, *value);
+        }
+        instrument[225] = 3;
+        instrument[227] = 1;
+        instrument[233] = ENVELOPE_ON | ENVELOPE_SUSTAIN;
+        put_u16(&mut instrument, 239, 4096);
+        data.extend(instrument);
+
+        let mut sample_8_bit = vec![0u8; 40];
+        put_u32(&mut sample_8_bit, 0, 4);
+        sample_8_bit[12] = 48;
+        sample_8_bit[15] = 64;
+        let mut sample_16_bit = vec![0u8; 40];
+        put_u32(&mut sample_16_bit, 0, 8);
+        put_u32(&mut sample_16_bit, 8, 8);
+        sample_16_bit[12] = 40;
+        sample_16_bit[13] = -16i8 as u8;
+        sample_16_bit[14] = SAMPLE_16_BIT | PING_PONG_LOOP;
+        sample_16_bit[15] = 192;
+        sample_16_bit[16] = 12;
+        data.extend(sample_8_bit);
+        data.extend(sample_16_bit);
+        data.extend(&[10, 10, -30i8 as u8, 5]);
+        for delta in &[100i16, 100, 100, -300] {
+            data.extend(&delta.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn test_read_xm() {
+        let song = read_xm(&build_xm()).unwrap();
+        assert_eq!(song.name.trim_end_matches('\0'), "test xm");
+        assert_eq!(song.format.kind, FormatKind::Xm);
+        assert!(song.format.has_linear_periods);
+        assert_eq!(song.format.num_channels, 2);
+        assert_eq!(song.pattern_table, vec![0, 1]);
+        assert_eq!(song.patterns[0].num_lines(), 3);
+        assert_eq!(song.patterns[1].num_lines(), 5);
+
+        let instrument = &song.instruments[0];
+        assert_eq!(instrument.name().trim_end_matches('\0'), "instrument");
+        assert_eq!(instrument.sample_for_key(49), 1);
+        assert_eq!(instrument.sample_for_key(61), 2);
+        assert_eq!(
+            instrument.volume_envelope().points(),
+            &[(0, 64), (4, 32), (8, 0)]
+        );
+        assert_eq!(instrument.volume_envelope().sustain_loop(), Some((1, 1)));
+        assert!(!instrument.panning_envelope().is_enabled());
+        assert_eq!(instrument.fadeout(), 4096);
+
+        let sample = &song.samples[0];
+        assert_eq!(sample.samples(), &[10 << 8, 20 << 8, -10 << 8, -5 << 8]);
+        assert_eq!((sample.volume(), sample.pan()), (48, Some(64)));
+        assert_eq!(sample.repeat_size(), 0);
+        // the ping-pong loop is played forwards and then backwards
+        let sample = &song.samples[1];
+        assert_eq!(sample.samples(), &[100, 200, 300, 0, 0, 300, 200, 100]);
+        assert_eq!((sample.repeat_offset(), sample.repeat_size()), (0, 8));
+        assert_eq!(sample.relative_pitch(), 12 * 128 - 16);
+
+        let note = song.patterns[0].note(0, 0).unwrap();
+        assert_eq!(
+            (note.key(), note.sample_number(), note.volume()),
+            (49, 1, Some(32))
+        );
+        assert_eq!(song.patterns[0].note(1, 0).unwrap().key(), KEY_OFF);
+        assert_eq!(song.patterns[0].note(1, 1).unwrap().key(), 61);
+        assert_eq!(song.patterns[0].note(2, 1).unwrap().key(), 0);
+    }
+
+    #[test]
+    fn test_read_xm_info() {
+        let data = build_xm();
+        let song = read_xm(&data).unwrap();
+        // the description does not need the sample data
+        let info = read_xm_info(&mut Cursor::new(&data[0..data.len() - 12])).unwrap();
+        assert_eq!(info.name, song.name);
+        assert_eq!(info.format, FormatKind::Xm);
+        assert_eq!(info.num_channels, 2);
+        let sample_names: Vec<String> = song
+            .samples
+            .iter()
+            .map(|sample| sample.name().to_string())
+            .collect();
+        assert_eq!(info.sample_names, sample_names);
+        assert_eq!(info.sample_sizes, vec![4, 4]);
+        assert_eq!((info.num_orders, info.num_patterns), (2, 2));
+    }
+
+    #[test]
+    fn test_volume_column() {
+        assert_eq!(decode_volume_column(0x10), (Some(0), Effect::None));
+        assert_eq!(decode_volume_column(0x50), (Some(64), Effect::None));
+        assert_eq!(
+            decode_volume_column(0x65),
+            (None, Effect::VolumeSlide { volume_change: -5 })
+        );
+        assert_eq!(
+            decode_volume_column(0xf2),
+            (None, Effect::TonePortamento { speed: 0x20 })
+        );
+    }
+
+    #[test]
+    fn test_note_period() {
+        assert_eq!(note_period(49, 0, true), 4608);
+        assert_eq!(note_period(61, 0, true), 3840);
+        assert_eq!(note_period(49, 0, false), 1712);
+        assert_eq!(note_period(61, 0, false), 856);
+        // a sample tuned up an octave plays C-4 at the pitch of C-5
+        assert_eq!(note_period(49, 12 * 128, true), 3840);
+    }
+
+    #[test]
+    fn test_play_xm() {
+        let song = read_xm(&build_xm()).unwrap();
+        // lines play on ticks 3, 6 and 9. The note on channel 0 is released on tick 6 when its envelope is at tick 3
+        let mut player_state = play_ticks(&song, 9);
+        let channel = &player_state.channels[0];
+        assert_eq!(channel.volume, 32.0);
+        assert_eq!(
+            channel.volume_envelope_tick, 5,
+            "released from the sustain point"
+        );
+        assert_eq!(channel.fadeout_volume, 32768 - 2 * 4096);
+        assert_eq!(channel.envelope_volume, 0.75 * 24.0 / 64.0);
+
+        // the key map picks the second sample for C-5
+        let channel = &player_state.channels[1];
+        assert_eq!(channel.sample_num, 2);
+        assert_eq!(channel.volume, 40.0);
+        assert_eq!(channel.pan, 192.0 / 255.0 * 2.0 - 1.0);
+        assert_eq!(channel.period, note_period(61, 12 * 128 - 16, true));
+
+        // the first pattern has 3 lines
+        update_tick(&song, &mut player_state);
+        assert_eq!(
+            (
+                player_state.song_pattern_position,
+                player_state.current_line
+            ),
+            (1, 0)
+        );
+    }
+}
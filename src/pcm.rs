@@ -0,0 +1,159 @@
+//! Conversion of the mixed output to integer PCM formats
+//!
+//! The mixer works in floating point. Integer output is produced by scaling, optionally dithering, and clipping the mixed
+//! samples to the range of the target format.
+use super::{PlayerState, RenderResult, Song};
+
+/// Selects how samples are reduced to the precision of an integer output format
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dither {
+    /// Drop the extra precision. Cheap, but quiet passages pick up quantisation distortion
+    None,
+    /// Add triangular (TPDF) noise of +-1 LSB before rounding. Trades the distortion for a low constant noise floor
+    Triangular,
+}
+
+/// An integer sample format that the player can render to
+pub trait PcmSample: Copy {
+    /// The number of bits of precision in the format
+    const BITS: u32;
+    /// Converts a signed value in the range [-2^(BITS-1), 2^(BITS-1)-1] to the format
+    fn from_signed(value: i32) -> Self;
+}
+
+impl PcmSample for i16 {
+    const BITS: u32 = 16;
+    fn from_signed(value: i32) -> i16 {
+        value as i16
+    }
+}
+
+impl PcmSample for u16 {
+    const BITS: u32 = 16;
+    fn from_signed(value: i32) -> u16 {
+        (value + 0x8000) as u16
+    }
+}
+
+impl PcmSample for u8 {
+    const BITS: u32 = 8;
+    fn from_signed(value: i32) -> u8 {
+        (value + 0x80) as u8
+    }
+}
+
+/// 24 bit samples stored in the low bits of an i32, with the range [-8388608, 8388607]
+impl PcmSample for i32 {
+    const BITS: u32 = 24;
+    fn from_signed(value: i32) -> i32 {
+        value
+    }
+}
+
+/// Small xorshift generator for the dither noise. Deterministic so that renders can be reproduced
+pub(crate) struct DitherNoise {
+    state: u32,
+}
+
+impl DitherNoise {
+    pub(crate) fn new() -> DitherNoise {
+        DitherNoise { state: 0x2545_f491 }
+    }
+
+    // uniform value in [0, 1)
+    fn next(&mut self) -> f32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        (self.state >> 8) as f32 / (1 << 24) as f32
+    }
+
+    fn triangular(&mut self) -> f32 {
+        self.next() - self.next()
+    }
+}
+
+/// Converts a sample in the range [-1, 1] to a `bits` wide signed integer. Values outside the range are clipped
+fn quantize(value: f32, bits: u32, dither: Dither, noise: &mut DitherNoise) -> i32 {
+    let scale = (1u32 << (bits - 1)) as f32;
+    let scaled = value * scale;
+    let quantized = match dither {
+        Dither::None => scaled.floor(),
+        Dither::Triangular => (scaled + noise.triangular() + 0.5).floor(),
+    };
+    // a NaN stays NaN through the clamp and is converted to 0
+    quantized.clamp(-scale, scale - 1.0) as i32
+}
+
+impl PlayerState {
+    /// Renders interleaved stereo (left, right) frames as integer PCM. The dither mode is selected with `PlayerState::dither`
+    ///
+    /// # Arguments
+    /// * `song` - the song being played
+    /// * `buffer` - interleaved output buffer. Its length should be a multiple of two
+    ///
+    pub fn render_pcm<T: PcmSample>(&mut self, song: &Song, buffer: &mut [T]) -> RenderResult {
+        let num_frames = buffer.len() / 2;
+        let dither = self.dither;
+        // The noise is moved out while rendering as render_blocks holds on to the player state
+        let mut noise = std::mem::replace(&mut self.dither_noise, DitherNoise::new());
+        let result = self.render_blocks(song, num_frames, |offset, left, right| {
+            let output = &mut buffer[offset * 2..(offset + left.len()) * 2];
+            for (frame, (left, right)) in output.chunks_mut(2).zip(left.iter().zip(right)) {
+                frame[0] = T::from_signed(quantize(*left, T::BITS, dither, &mut noise));
+                frame[1] = T::from_signed(quantize(*right, T::BITS, dither, &mut noise));
+            }
+        });
+        self.dither_noise = noise;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quantize_clips() {
+        let mut noise = DitherNoise::new();
+        for dither in &[Dither::None, Dither::Triangular] {
+            assert_eq!(quantize(2.0, 16, *dither, &mut noise), 32767);
+            assert_eq!(quantize(-2.0, 16, *dither, &mut noise), -32768);
+            assert_eq!(quantize(1.0, 8, *dither, &mut noise), 127);
+            assert_eq!(quantize(-2.0, 24, *dither, &mut noise), -8388608);
+            assert_eq!(quantize(std::f32::NAN, 16, *dither, &mut noise), 0);
+        }
+    }
+
+    #[test]
+    fn test_truncation() {
+        let mut noise = DitherNoise::new();
+        assert_eq!(quantize(0.5, 16, Dither::None, &mut noise), 16384);
+        assert_eq!(quantize(0.9 / 32768.0, 16, Dither::None, &mut noise), 0);
+        assert_eq!(quantize(-0.1 / 32768.0, 16, Dither::None, &mut noise), -1);
+        assert_eq!(
+            u8::from_signed(quantize(0.0, 8, Dither::None, &mut noise)),
+            128
+        );
+        assert_eq!(
+            u16::from_signed(quantize(-1.0, 16, Dither::None, &mut noise)),
+            0
+        );
+        assert_eq!(
+            u16::from_signed(quantize(1.0, 16, Dither::None, &mut noise)),
+            65535
+        );
+    }
+
+    #[test]
+    fn test_dither_is_unbiased() {
+        let mut noise = DitherNoise::new();
+        // a value between two steps should average out to its true value
+        let value = 0.25 / 128.0;
+        let total: i32 = (0..10000)
+            .map(|_| quantize(value, 8, Dither::Triangular, &mut noise))
+            .sum();
+        let average = total as f32 / 10000.0;
+        assert!((average - 0.25).abs() < 0.03, "average was {}", average);
+    }
+}
@@ -0,0 +1,151 @@
+//! Block rendering of whole output buffers
+//!
+//! The player mixes one vblank tick at a time into an internal buffer. The render functions copy out of that buffer so that
+//! audio callbacks can be filled with a single call.
+use super::{play_tick, PlayerState, Song};
+
+/// Describes the outcome of a call to one of the render functions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RenderResult {
+    /// How many frames were written to the output. Less than requested only if the song ended
+    pub frames: usize,
+    /// Set when the song has ended, after its last line has been played out. The output past `frames` has not been written to
+    pub song_has_ended: bool,
+}
+
+impl PlayerState {
+    /// Renders interleaved stereo (left, right) frames into the buffer. The samples have the range [-1, 1]
+    ///
+    /// # Arguments
+    /// * `song` - the song being played
+    /// * `buffer` - interleaved output buffer. Its length should be a multiple of two
+    ///
+    pub fn render(&mut self, song: &Song, buffer: &mut [f32]) -> RenderResult {
+        let num_frames = buffer.len() / 2;
+        self.render_blocks(song, num_frames, |offset, left, right| {
+            let output = &mut buffer[offset * 2..(offset + left.len()) * 2];
+            for (frame, (left, right)) in output.chunks_mut(2).zip(left.iter().zip(right)) {
+                frame[0] = *left;
+                frame[1] = *right;
+            }
+        })
+    }
+
+    /// Renders stereo frames into separate left and right buffers. The number of frames rendered is limited by the shorter
+    /// of the two buffers
+    pub fn render_planar(
+        &mut self,
+        song: &Song,
+        left: &mut [f32],
+        right: &mut [f32],
+    ) -> RenderResult {
+        let num_frames = left.len().min(right.len());
+        self.render_blocks(song, num_frames, |offset, tick_left, tick_right| {
+            left[offset..offset + tick_left.len()].copy_from_slice(tick_left);
+            right[offset..offset + tick_right.len()].copy_from_slice(tick_right);
+        })
+    }
+
+    /// Renders the song downmixed to mono. The samples have the range [-1, 1]
+    pub fn render_mono(&mut self, song: &Song, buffer: &mut [f32]) -> RenderResult {
+        let num_frames = buffer.len();
+        self.render_blocks(song, num_frames, |offset, left, right| {
+            let output = &mut buffer[offset..offset + left.len()];
+            for (value, (left, right)) in output.iter_mut().zip(left.iter().zip(right)) {
+                *value = (left + right) * 0.5;
+            }
+        })
+    }
+
+    /// Steps through the song tick by tick and passes the mixed audio to `write` in blocks. `write` receives the frame offset
+    /// into the output and the left and right samples for the block
+    pub(crate) fn render_blocks<F>(
+        &mut self,
+        song: &Song,
+        num_frames: usize,
+        mut write: F,
+    ) -> RenderResult
+    where
+        F: FnMut(usize, &[f32], &[f32]),
+    {
+        let mut frames = 0;
+        let mut song_has_ended = false;
+        while frames < num_frames {
+            if self.current_vblank_sample as usize >= self.tick_left.len() {
+                // the last line is played out in full before stopping
+                if self.is_at_song_end(song) {
+                    self.song_has_ended = true;
+                    song_has_ended = true;
+                    break;
+                }
+                play_tick(song, self);
+            }
+            let start = self.current_vblank_sample as usize;
+            let count = (self.tick_left.len() - start).min(num_frames - frames);
+            write(
+                frames,
+                &self.tick_left[start..start + count],
+                &self.tick_right[start..start + count],
+            );
+            self.current_vblank_sample += count as u32;
+            frames += count;
+        }
+        RenderResult {
+            frames,
+            song_has_ended,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{next_sample, read_mod_file};
+    use super::*;
+
+    #[test]
+    fn test_render_matches_next_sample() {
+        let song = read_mod_file("mod_files/BUBBLE_BOBBLE.MOD");
+        let mut sample_state = PlayerState::new(song.format.num_channels, 44100);
+        let mut render_state = PlayerState::new(song.format.num_channels, 44100);
+
+        // use an odd buffer size so that the blocks do not line up with the ticks
+        let mut buffer = vec![0.0f32; 2 * 1001];
+        for _block in 0..200 {
+            let result = render_state.render(&song, &mut buffer);
+            assert_eq!(result.frames, 1001);
+            for frame in buffer.chunks(2) {
+                let (left, right) = next_sample(&song, &mut sample_state);
+                assert_eq!((frame[0], frame[1]), (left, right));
+            }
+        }
+
+        let mut left = vec![0.0f32; 999];
+        let mut right = vec![0.0f32; 999];
+        let mut mono = vec![0.0f32; 999];
+        let mut mono_state = PlayerState::new(song.format.num_channels, 44100);
+        let mut planar_state = PlayerState::new(song.format.num_channels, 44100);
+        planar_state.render_planar(&song, &mut left, &mut right);
+        mono_state.render_mono(&song, &mut mono);
+        for idx in 0..999 {
+            assert_eq!(mono[idx], (left[idx] + right[idx]) * 0.5);
+        }
+    }
+
+    #[test]
+    fn test_render_stops_at_song_end() {
+        let song = read_mod_file("mod_files/BUBBLE_BOBBLE.MOD");
+        let mut player_state = PlayerState::new(song.format.num_channels, 8000);
+        let mut buffer = vec![0.0f32; 2 * 8000];
+        let mut total_frames = 0;
+        loop {
+            let result = player_state.render(&song, &mut buffer);
+            total_frames += result.frames;
+            if result.song_has_ended {
+                break;
+            }
+            assert_eq!(result.frames, 8000);
+        }
+        assert!(total_frames > 0);
+        assert_eq!(player_state.render(&song, &mut buffer).frames, 0);
+    }
+}
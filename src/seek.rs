@@ -0,0 +1,132 @@
+//! Seeking within a song
+//!
+//! The player state at any point depends on everything that has been played before it ( speed and tempo changes, effect
+//! memory, sample positions ). Seeking therefore restarts the song and runs the sequencer forward without mixing until the
+//! target is reached.
+use super::{advance_tick, mix_tick, update_tick, PlayerState, Song};
+use std::time::Duration;
+
+impl PlayerState {
+    /// Restarts the song from the beginning. Playback settings ( interpolation, filters, stereo separation and dither ) are kept
+    pub fn restart(&mut self) {
+        let mut fresh = PlayerState::new(self.channels.len() as u32, self.device_sample_rate);
+        fresh.dither = self.dither;
+        fresh.interpolation = self.interpolation;
+        fresh.amiga_filter = self.amiga_filter;
+        fresh.stereo_separation = self.stereo_separation;
+        fresh.sinc_table = std::mem::take(&mut self.sinc_table);
+        *self = fresh;
+    }
+
+    /// Moves playback to the start of `row` in the pattern at position `order` of the pattern table. The song is played
+    /// from the beginning up to the target so that the tempo, effects and samples are in the same state as they would be
+    /// when playing linearly. If the song never reaches the position the player is placed there directly
+    /// with the state it has at the start of the song.
+    ///
+    /// Returns false, without changing the player state, if the position is not part of the song
+    ///
+    /// # Arguments
+    /// * `song` - the song being played
+    /// * `order` - the position in the pattern table
+    /// * `row` - the line within the pattern
+    ///
+    pub fn seek_to_position(&mut self, song: &Song, order: u32, row: u32) -> bool {
+        if order >= song.num_used_patterns || row as usize >= song.order_line_count(order) {
+            return false;
+        }
+        self.restart();
+        self.skip_initial_silence();
+        // The song can only be played through once before it ends or loops
+        while !self.song_has_ended && !self.has_looped {
+            if self.is_line_due() && self.next_line_position(song) == (order, row) {
+                return true;
+            }
+            update_tick(song, self);
+            advance_tick(song, self, self.samples_per_vblank as usize);
+        }
+
+        // The position can not be reached by playing, so go there directly
+        self.restart();
+        self.skip_initial_silence();
+        self.song_pattern_position = order;
+        self.current_line = row;
+        self.current_vblank = self.song_speed;
+        true
+    }
+
+    /// Moves playback to `time` from the start of the song. The song is played up to that point without mixing so that
+    /// the state is the same as it would be when playing linearly.
+    ///
+    /// Returns false if the song ends before `time`. The player is then left at the end of the song
+    pub fn seek_to_time(&mut self, song: &Song, time: Duration) -> bool {
+        self.restart();
+        let target_frame = (time.as_secs_f64() * self.device_sample_rate as f64).round() as u64;
+        // the player starts with a block of silence before the first tick
+        let mut frame = self.tick_left.len() as u64;
+        if target_frame < frame {
+            self.current_vblank_sample = target_frame as u32;
+            return true;
+        }
+        self.skip_initial_silence();
+        while !self.is_at_song_end(song) {
+            update_tick(song, self);
+            let tick_frames = self.samples_per_vblank as u64;
+            if frame + tick_frames > target_frame {
+                // mix the tick the target is in and start from the middle of it
+                mix_tick(song, self);
+                self.current_vblank_sample = (target_frame - frame) as u32;
+                return true;
+            }
+            advance_tick(song, self, tick_frames as usize);
+            frame += tick_frames;
+        }
+        self.song_has_ended = true;
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::read_mod_file;
+    use super::*;
+
+    #[test]
+    fn test_seek_matches_linear_playback() {
+        let song = read_mod_file("mod_files/BUBBLE_BOBBLE.MOD");
+        let mut linear_state = PlayerState::new(song.format.num_channels, 22050);
+        let mut frame = [0.0f32; 2];
+        while !(linear_state.song_pattern_position == 2 && linear_state.current_line == 17) {
+            linear_state.render(&song, &mut frame);
+        }
+        // line 16 has just been played and one frame of its first tick rendered
+        let mut linear_buffer = vec![0.0f32; 2 * 22050];
+        linear_state.render(&song, &mut linear_buffer);
+
+        let mut seek_state = PlayerState::new(song.format.num_channels, 22050);
+        assert!(seek_state.seek_to_position(&song, 2, 16));
+        let mut seek_buffer = vec![0.0f32; 2 * 22050 + 2];
+        seek_state.render(&song, &mut seek_buffer);
+        assert_eq!(&seek_buffer[2..], &linear_buffer[..]);
+
+        assert!(!seek_state.seek_to_position(&song, 200, 0));
+    }
+
+    #[test]
+    fn test_seek_to_time() {
+        let song = read_mod_file("mod_files/BUBBLE_BOBBLE.MOD");
+        let mut linear_state = PlayerState::new(song.format.num_channels, 22050);
+        let mut skipped = vec![0.0f32; 2 * 22050 * 3 + 2 * 123];
+        linear_state.render(&song, &mut skipped);
+        let mut linear_buffer = vec![0.0f32; 2 * 22050];
+        linear_state.render(&song, &mut linear_buffer);
+
+        let mut seek_state = PlayerState::new(song.format.num_channels, 22050);
+        assert!(seek_state.seek_to_time(&song, Duration::from_secs_f64(3.0 + 123.0 / 22050.0)));
+        let mut seek_buffer = vec![0.0f32; 2 * 22050];
+        seek_state.render(&song, &mut seek_buffer);
+        assert_eq!(&seek_buffer[..], &linear_buffer[..]);
+
+        assert!(!seek_state.seek_to_time(&song, Duration::from_secs(60 * 60)));
+        assert!(seek_state.song_has_ended);
+    }
+}
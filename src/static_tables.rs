@@ -5,6 +5,30 @@ pub static VIBRATO_TABLE: [i32; 64] = [
     -197, -180, -161, -141, -120, -97, -74, -49, -24,
 ];
 
+// ProTracker's ramp down vibrato waveform. It rises through the first half of the cycle and falls from the top of the
+// second, which slides the period up and so the pitch down
+pub static RAMP_DOWN_TABLE: [i32; 64] = [
+    0, 8, 16, 24, 32, 40, 48, 56, 64, 72, 80, 88, 96, 104, 112, 120, 128, 136, 144, 152, 160, 168,
+    176, 184, 192, 200, 208, 216, 224, 232, 240, 248, -255, -247, -239, -231, -223, -215, -207,
+    -199, -191, -183, -175, -167, -159, -151, -143, -135, -127, -119, -111, -103, -95, -87, -79,
+    -71, -63, -55, -47, -39, -31, -23, -15, -7,
+];
+
+pub static SQUARE_TABLE: [i32; 64] = [
+    255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255,
+    255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, -255, -255, -255, -255, -255,
+    -255, -255, -255, -255, -255, -255, -255, -255, -255, -255, -255, -255, -255, -255, -255, -255,
+    -255, -255, -255, -255, -255, -255, -255, -255, -255, -255, -255,
+];
+
+// The random waveform, fixed so that songs play the same every time
+pub static RANDOM_TABLE: [i32; 64] = [
+    242, 228, 223, -71, 162, 53, 91, 239, 54, 92, 208, 141, 209, -177, 248, -220, -20, -142, -119,
+    -6, -186, 119, 102, -225, -116, 114, 20, -252, 172, -240, 2, 44, 58, 250, -187, -11, -100, -57,
+    -67, 182, 161, -69, -232, 199, 135, -119, -193, -206, 59, -47, -99, -175, 38, 69, -119, -157,
+    -104, -196, -49, 220, 148, -240, 140, -70,
+];
+
 #[rustfmt::skip]
 pub static FREQUENCY_TABLE: [u32; 60] = [
     // B    A#      A       G#      G       F#      F       E       D#      D       C#      C
@@ -105,3 +129,6 @@ pub static SCALE_FINE_TUNE: [f32; 16] = [
     // from -8 to -1
     0.9438743, 0.950714, 0.9576033, 0.96454245, 0.9715319, 0.9785721, 0.9856632, 0.9928057,
 ];
+
+// How much ProTracker's funk repeat ( EFx ) counter moves each tick. A byte of the loop is inverted each time it passes 128
+pub static FUNK_TABLE: [u32; 16] = [0, 5, 6, 7, 8, 10, 11, 13, 16, 19, 22, 26, 32, 43, 64, 128];
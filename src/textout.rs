@@ -2,7 +2,8 @@
 //!
 //! text_out contains utility functions for printing out information about mods. Primarily intended to be used for debugging and understanding the progress of the playback
 use super::Sample;
-use super::{Effect, Note, Song};
+use super::{Effect, NewNoteAction, Note, Song};
+use std::fmt;
 
 static NOTE_FREQUENCY_STRINGS: [(u32, &str); 60] = [
     (57, "B-6"),
@@ -68,9 +69,9 @@ static NOTE_FREQUENCY_STRINGS: [(u32, &str); 60] = [
 ];
 
 #[rustfmt::skip]
-impl Effect {
-    fn to_string(&self) -> String {
-        return match self {
+impl fmt::Display for Effect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match self {
             Effect::Arpeggio { chord_offset_1, chord_offset_2 } => format!("Arpgi {:02}{:02}", chord_offset_1, chord_offset_2),
             Effect::SlideUp { speed } => format!("SldUp {:>4}", speed),
             Effect::SlideDown { speed } => format!( "SldDn {:>4}", speed ),
@@ -102,8 +103,25 @@ impl Effect {
             Effect::InvertLoop{ loop_position } => format!( "InvLp {:>4}", loop_position ),
             Effect::SetVibratoWave { wave } => format!("VibWv {:>4}", wave ),
             Effect::SetFineTune { fine_tune } => format!("FnTne {:>4}", fine_tune),
+            Effect::SetTicksPerLine { ticks } => format!("Ticks {:>4}", ticks),
+            Effect::SetTempo { bpm } => format!("Tempo {:>4}", bpm),
+            Effect::SetGlobalVolume { volume } => format!("GlVol {:>4}", volume),
+            Effect::Tremor { on_ticks, off_ticks } => format!("Tremr {:02}{:02}", on_ticks, off_ticks),
+            Effect::RetriggerVolumeSlide { retrigger_delay, volume_change } => format!("RtVoS {:02}{:02}", retrigger_delay, volume_change),
+            Effect::FineVibrato { speed, amplitude } => format!("FVibr {:02}{:02}", speed, amplitude),
+            Effect::ExtraFinePortaUp { period_change } => format!("XPoUp {:>4}", period_change),
+            Effect::ExtraFinePortaDown { period_change } => format!("XPoDn {:>4}", period_change),
+            Effect::GlobalVolumeSlide { volume_change } => format!("GVoSl {:>4}", volume_change),
+            Effect::PanSlide { pan_change } => format!("PanSl {:>4}", pan_change),
+            Effect::KeyOff { tick } => format!("KeyOf {:>4}", tick),
+            Effect::SetEnvelopePosition { position } => format!("EnvPs {:>4}", position),
+            Effect::SetFilterCutoff { cutoff } => format!("FltCo {:>4}", cutoff),
+            Effect::SetFilterResonance { resonance } => format!("FltRs {:>4}", resonance),
+            Effect::PastNoteAction { action } => format!("PstNA {:>4}", note_action_text(*action)),
+            Effect::SetNewNoteAction { action } => format!("NwNtA {:>4}", note_action_text(*action)),
             _ => String::from(".........."),
         };
+        f.write_str(&text)
     }
 }
 
@@ -149,6 +167,16 @@ fn note_string(period: u32) -> &'static str {
         }
     };
 }
+
+fn note_action_text(action: NewNoteAction) -> &'static str {
+    match action {
+        NewNoteAction::Cut => "Cut",
+        NewNoteAction::Continue => "Cont",
+        NewNoteAction::NoteOff => "Off",
+        NewNoteAction::NoteFade => "Fade",
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -181,7 +209,7 @@ pub fn print_line(line: &Vec<Note>) {
             "{} {:>2} {}   ",
             note_string(note.period),
             sample_string,
-            note.effect.to_string()
+            note.effect
         );
     }
     println!("");
@@ -0,0 +1,256 @@
+//! Saving songs as ProTracker MOD files
+//!
+//! The layout written is the one the loader reads: song name, sample headers, pattern table, format tag, pattern data and
+//! finally the sample data. Notes are encoded from their period, sample number and effect, so changes made to a song are
+//! written. Notes that are not changed keep the bytes they were loaded from, so songs loaded from valid files are written
+//! back byte for byte.
+use super::{Effect, FormatDescription, FormatKind, ModError, Note, Song};
+use std::io::Write;
+
+/// The largest sample a MOD file can describe. The length is stored in words in 16 bits
+const MAX_SAMPLE_SIZE: u32 = 0xffff * 2;
+
+/// Writes `name` as a fixed length, zero padded field. Each character is stored as a single byte
+fn write_name(writer: &mut impl Write, name: &str, len: usize) -> Result<(), ModError> {
+    let mut field = vec![0u8; len];
+    for (byte, character) in field.iter_mut().zip(name.chars()) {
+        *byte = if (character as u32) < 256 {
+            character as u8
+        } else {
+            b'?'
+        };
+    }
+    writer.write_all(&field)?;
+    Ok(())
+}
+
+fn write_word(writer: &mut impl Write, bytes: u32) -> Result<(), ModError> {
+    writer.write_all(&((bytes / 2) as u16).to_be_bytes())?;
+    Ok(())
+}
+
+/// Encodes a note as 4 bytes of pattern data. Some effects can be stored in more than one way ( A12 slides the volume
+/// up like A10 ) so the parameter the note was loaded with is kept while it still decodes to the note's effect
+fn encode_note(note: &Note, format: &FormatDescription) -> Result<[u8; 4], ModError> {
+    if Note::new(&note.data, format) == *note {
+        return Ok(note.data);
+    }
+    let loaded_effect = Effect::new(note.effect_command, note.effect_parameter as i8);
+    let (command, parameter) = if loaded_effect == note.effect {
+        (note.effect_command, note.effect_parameter)
+    } else {
+        note.effect.encode().ok_or_else(|| {
+            ModError::Unwritable(format!("{:?} can not be stored in a MOD file", note.effect))
+        })?
+    };
+    if note.sample_number > 31 || note.period > 0x0fff {
+        return Err(ModError::Unwritable(format!(
+            "a note with sample {} and period {} can not be stored in a MOD file",
+            note.sample_number, note.period
+        )));
+    }
+    Ok([
+        (note.sample_number & 0xf0) | (note.period >> 8) as u8,
+        note.period as u8,
+        (note.sample_number << 4) | command,
+        parameter,
+    ])
+}
+
+/// The tag written for the song. The tag the song was loaded with is kept, otherwise one is chosen from the number of channels
+fn format_tag(format: &FormatDescription, num_patterns: usize) -> Result<String, ModError> {
+    if format.tag.chars().count() == 4 {
+        return Ok(format.tag.clone());
+    }
+    match format.num_channels {
+        4 if num_patterns > 64 => Ok(String::from("M!K!")),
+        4 => Ok(String::from("M.K.")),
+        1..=9 => Ok(format!("{}CHN", format.num_channels)),
+        10..=32 => Ok(format!("{}CH", format.num_channels)),
+        _ => Err(ModError::Unwritable(format!(
+            "{} channels can not be stored in a MOD file",
+            format.num_channels
+        ))),
+    }
+}
+
+impl Song {
+    /// Writes the song as a ProTracker MOD file. A song loaded from a valid MOD file is written back unchanged
+    ///
+    /// Returns an error if the song can not be represented in the format ( it was loaded from another format, a sample is too long, there are too many patterns,
+    /// the channel count has no tag or a note's sample, period or effect can not be stored ) or if writing fails
+    ///
+    /// # Arguments
+    /// * `writer` - where the file is written to
+    ///
+    pub fn write_mod(&self, writer: &mut impl Write) -> Result<(), ModError> {
+        if !matches!(self.format.kind, FormatKind::Mod | FormatKind::Soundtracker) {
+            return Err(ModError::Unwritable(format!(
+                "{:?} songs can not be converted to MOD files",
+                self.format.kind
+            )));
+        }
+        if self.samples.len() != self.format.num_samples as usize {
+            return Err(ModError::Unwritable(format!(
+                "the format has {} samples but the song has {}",
+                self.format.num_samples,
+                self.samples.len()
+            )));
+        }
+        // FLT8 files store two 4 channel patterns for each pattern
+        let max_patterns = if self.format.has_split_patterns() {
+            64
+        } else {
+            128
+        };
+        if self.patterns.len() > max_patterns || self.pattern_table.len() > 128 {
+            return Err(ModError::Unwritable(format!(
+                "{} patterns and {} positions is more than a MOD file can hold",
+                self.patterns.len(),
+                self.pattern_table.len()
+            )));
+        }
+        if let Some(sample) = self.samples.iter().find(|sample| {
+            sample.size > MAX_SAMPLE_SIZE || sample.samples.len() > MAX_SAMPLE_SIZE as usize
+        }) {
+            return Err(ModError::Unwritable(format!(
+                "sample '{}' is longer than {} bytes",
+                sample.name, MAX_SAMPLE_SIZE
+            )));
+        }
+
+        write_name(writer, &self.name, 20)?;
+        for sample in &self.samples {
+            write_name(writer, &sample.name, 22)?;
+            write_word(writer, sample.samples.len() as u32)?;
+            writer.write_all(&[sample.fine_tune, sample.volume])?;
+            write_word(writer, sample.repeat_offset)?;
+            write_word(writer, sample.repeat_size)?;
+        }
+
+        let mut pattern_table = self.pattern_table.clone();
+        pattern_table.resize(128, 0);
+        if self.format.has_split_patterns() {
+            for pattern_idx in pattern_table.iter_mut() {
+                *pattern_idx *= 2;
+            }
+        }
+        writer.write_all(&[self.num_used_patterns as u8, self.end_position as u8])?;
+        writer.write_all(&pattern_table)?;
+        if self.format.has_tag {
+            write_name(writer, &format_tag(&self.format, self.patterns.len())?, 4)?;
+        }
+
+        let channel_blocks = self.format.pattern_channel_blocks();
+        for pattern in &self.patterns {
+            for channels in &channel_blocks {
+                for line in &pattern.lines {
+                    for note in &line[channels.start as usize..channels.end as usize] {
+                        writer.write_all(&encode_note(note, &self.format)?)?;
+                    }
+                }
+            }
+        }
+
+        for sample in &self.samples {
+            let data: Vec<u8> = sample
+                .samples
+                .iter()
+                .map(|&value| (value >> 8) as u8)
+                .collect();
+            writer.write_all(&data)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::read_mod_file_slice;
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_round_trip() {
+        // sarcophaser.mod has loops past the sample end, ballade_pour_adeline.mod has trailing data and JARRE.MOD is
+        // broken, so those are not written back identically
+        for file_name in &[
+            "BUBBLE_BOBBLE.MOD",
+            "CHIP_SLAYER!.MOD",
+            "cream_of_the_earth.mod",
+            "switchback.mod",
+            "stardstm.mod",
+            "overload.mod",
+            "BOG_WRAITH.MOD",
+            "wasteland.mod",
+            "1 step further.mod",
+            "BALLI.MOD",
+            "chcknbnk.mod",
+            "GSLINGER.MOD",
+            "19xx.mod",
+            "ballad_ej.mod",
+            "star-rai.mod",
+            "spacedebris.mod",
+            "bubble_bobble_flt8.mod",
+            "bubble_bobble_octa.mod",
+            "star-rai_cd61.mod",
+        ] {
+            let file_data = fs::read(format!("mod_files/{}", file_name)).unwrap();
+            let song = read_mod_file_slice(&file_data);
+            let mut written = Vec::new();
+            song.write_mod(&mut written).unwrap();
+            assert!(written == file_data, "{} changed when written", file_name);
+            let written_song = read_mod_file_slice(&written);
+            assert!(
+                written_song.patterns == song.patterns,
+                "{} notes changed when written",
+                file_name
+            );
+        }
+    }
+
+    #[test]
+    fn test_write_changed_notes() {
+        let file_data = fs::read("mod_files/BUBBLE_BOBBLE.MOD").unwrap();
+        let mut song = read_mod_file_slice(&file_data);
+        let note = &mut song.patterns[0].lines[0][0];
+        note.period = 214;
+        note.sample_number = 17;
+        note.effect = Effect::Vibrato {
+            speed: 3,
+            amplitude: 5,
+        };
+        let mut written = Vec::new();
+        song.write_mod(&mut written).unwrap();
+
+        let written_song = read_mod_file_slice(&written);
+        let note = written_song.patterns[0].note(0, 0).unwrap();
+        assert_eq!((note.period(), note.sample_number()), (214, 17));
+        assert_eq!(
+            *note.effect(),
+            Effect::Vibrato {
+                speed: 3,
+                amplitude: 5
+            }
+        );
+        assert!(written_song.patterns[1..] == song.patterns[1..]);
+
+        song.patterns[0].lines[0][0].effect = Effect::SetTempo { bpm: 20 };
+        assert!(matches!(
+            song.write_mod(&mut Vec::new()),
+            Err(ModError::Unwritable(_))
+        ));
+    }
+
+    #[test]
+    fn test_unwritable_song() {
+        let file_data = fs::read("mod_files/BUBBLE_BOBBLE.MOD").unwrap();
+        let mut song = read_mod_file_slice(&file_data);
+        song.format.num_channels = 33;
+        song.format.tag = String::new();
+        assert!(matches!(
+            song.write_mod(&mut Vec::new()),
+            Err(ModError::Unwritable(_))
+        ));
+    }
+}
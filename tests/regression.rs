@@ -42,9 +42,13 @@ fn reg_test() {
         "chcknbnk.mod",
         "GSLINGER.MOD",
         "19xx.mod",
-        "ballad_ej.mod", // rare 12 channel mod
-        "JARRE.mod",     // weird broken mod. incomplete last pattern????
-        "star-rai.mod",  // 6 channel mod
+        "ballad_ej.mod",          // rare 12 channel mod
+        "JARRE.mod",              // weird broken mod. incomplete last pattern????
+        "star-rai.mod",           // 6 channel mod
+        "star-rai_cd61.mod",      // star-rai.mod with an Octalyser tag
+        "bubble_bobble_octa.mod", // 8 channel version of BUBBLE_BOBBLE.MOD
+        "bubble_bobble_cd81.mod", // same with an Octalyser tag
+        "bubble_bobble_flt8.mod", // same in the StarTrekker split pattern layout
     ];
     let mut song_checksums: HashMap<String, u64> = HashMap::new();
     let expected_results = get_expected_results().ok();